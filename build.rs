@@ -0,0 +1,24 @@
+//! Lets `RUST_OS_INITRD` name an archive to embed into the kernel as its initrd, see `src/initrd.rs`, and creates
+//! the disk image that the test machines attach as a SATA drive, see `test-args` in `Cargo.toml`.
+
+use std::{fs, path::Path};
+
+/// Size of the test disk, enough for the tests to write a few sectors to.
+const TEST_DISK_SIZE: u64 = 1 << 20;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=RUST_OS_INITRD");
+    if let Ok(path) = std::env::var("RUST_OS_INITRD") {
+        println!("cargo:rerun-if-changed={}", path);
+        println!("cargo:rustc-cfg=embedded_initrd");
+    }
+
+    // the tests leave the disk as they found it, so an existing one is kept
+    let disk = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/ahci-test.img");
+    if !disk.exists() {
+        fs::create_dir_all(disk.parent().unwrap()).expect("failed to create the target directory");
+        fs::File::create(&disk)
+            .and_then(|file| file.set_len(TEST_DISK_SIZE))
+            .expect("failed to create the test disk image");
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    bytes::{read_at, FromLeBytes},
+    memory::phys_to_virt,
+};
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+/// Start of the BIOS area searched for the RSDP when it is not in the EBDA.
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+/// Physical address of the BIOS data area word holding the EBDA segment.
+const EBDA_POINTER: u64 = 0x40e;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid root system description pointer was found in the BIOS areas.
+    NoRsdp,
+    /// The table with this signature is not listed in the root table.
+    NotFound([u8; 4]),
+    /// The table with this signature failed its checksum.
+    BadChecksum([u8; 4]),
+    /// The table with this signature is shorter than its entries claim.
+    Malformed([u8; 4]),
+}
+
+/// The header every system description table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// A validated system description table in physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    phys: PhysAddr,
+    header: SdtHeader,
+}
+
+impl Table {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn header(&self) -> SdtHeader {
+        self.header
+    }
+
+    /// Returns the bytes following the header.
+    pub fn body(&self) -> &'static [u8] {
+        &table_bytes(self.phys, self.header.length as usize)[mem::size_of::<SdtHeader>()..]
+    }
+
+    /// Reads a `T` at `offset` bytes into the body, or `None` if it does not fit.
+    pub fn read<T: FromLeBytes>(&self, offset: usize) -> Option<T> {
+        read_at(self.body(), offset)
+    }
+}
+
+/// Looks up the table with `signature` through the RSDT or XSDT and validates its checksum.
+///
+/// Tables are read through the physical memory mapping, so this works as soon as `memory::init` was called.
+pub fn find_table(signature: &[u8; 4]) -> Result<Table, AcpiError> {
+    let root = root_table()?;
+    let entry_size = if &root.header.signature == b"XSDT" { 8 } else { 4 };
+    let body = root.body();
+    for offset in (0..body.len() / entry_size * entry_size).step_by(entry_size) {
+        let phys = if entry_size == 8 {
+            read_at::<u64>(body, offset).unwrap()
+        } else {
+            u64::from(read_at::<u32>(body, offset).unwrap())
+        };
+        let table = load_table(PhysAddr::new(phys))?;
+        if &table.header.signature == signature {
+            return Ok(table);
+        }
+    }
+    Err(AcpiError::NotFound(*signature))
+}
+
+fn root_table() -> Result<Table, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        load_table(PhysAddr::new(rsdp.xsdt_address))
+    } else {
+        load_table(PhysAddr::new(u64::from(rsdp.rsdt_address)))
+    }
+}
+
+fn load_table(phys: PhysAddr) -> Result<Table, AcpiError> {
+    let header: SdtHeader = read_phys(phys);
+    if (header.length as usize) < mem::size_of::<SdtHeader>() {
+        return Err(AcpiError::Malformed(header.signature));
+    }
+    if !checksum_ok(table_bytes(phys, header.length as usize)) {
+        return Err(AcpiError::BadChecksum(header.signature));
+    }
+    Ok(Table { phys, header })
+}
+
+/// Searches the first KiB of the EBDA and the BIOS area below 1 MiB for the RSDP.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(read_phys::<u16>(PhysAddr::new(EBDA_POINTER))) << 4;
+    let ebda_area = if ebda == 0 { 0..0 } else { ebda..ebda + 1024 };
+    let mut candidates = ebda_area.step_by(16).chain((BIOS_AREA_START..BIOS_AREA_END).step_by(16));
+    candidates.find_map(|addr| {
+        let rsdp: Rsdp = read_phys(PhysAddr::new(addr));
+        if &rsdp.signature != b"RSD PTR " || !checksum_ok(table_bytes(PhysAddr::new(addr), 20)) {
+            return None;
+        }
+        if rsdp.revision >= 2 && !checksum_ok(table_bytes(PhysAddr::new(addr), rsdp.length as usize)) {
+            return None;
+        }
+        Some(rsdp)
+    })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn table_bytes(phys: PhysAddr, len: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(phys_to_virt(phys).as_ptr(), len) }
+}
+
+fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    unsafe { phys_to_virt(phys).as_ptr::<T>().read_unaligned() }
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_ok(&[0x10, 0xf0]));
+    assert!(!checksum_ok(&[0x10, 0xef]));
+}
@@ -0,0 +1,137 @@
+use super::{find_table, load_table, read_at, AcpiError, Table};
+use x86_64::PhysAddr;
+
+/// `flags` bit telling that `reset_register` is implemented.
+pub const RESET_REG_SUPPORTED: u32 = 1 << 10;
+/// `flags` bit telling that the hardware-reduced ACPI model is used, without the fixed PM registers.
+pub const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// `boot_architecture_flags` bit telling that there are ISA devices the firmware doesn't list elsewhere.
+pub const BOOT_LEGACY_DEVICES: u16 = 1 << 0;
+/// `boot_architecture_flags` bit telling that there is an 8042 keyboard controller.
+pub const BOOT_8042: u16 = 1 << 1;
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+/// Revision 1 tables end after `flags`.
+const REVISION_1_LEN: usize = 80;
+
+/// A register as ACPI describes it, in memory, I/O or another address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub address: u64,
+}
+
+/// The parsed fixed ACPI description table, with the power management registers.
+///
+/// Block addresses are I/O ports, 0 for blocks the system doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    /// The ISA IRQ of the system control interrupt.
+    pub sci_interrupt: u16,
+    /// The port `acpi_enable` and `acpi_disable` are written to, 0 if ACPI mode is always on.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// The CMOS index of the RTC century register, 0 for none.
+    pub century_register: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    /// The register a write of `reset_value` to resets the system, if `flags` has `RESET_REG_SUPPORTED`.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Finds and parses the FADT, whose signature is `FACP`.
+    pub fn parse() -> Result<Fadt, AcpiError> {
+        Self::from_body(find_table(b"FACP")?.body())
+    }
+
+    fn from_body(body: &[u8]) -> Result<Fadt, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"FACP");
+        let field = |offset: usize| read_at::<u32>(body, offset).ok_or(malformed);
+        let byte = |offset: usize| read_at::<u8>(body, offset).ok_or(malformed);
+        let flags = field(76)?;
+        // revision 2 added the reset register and 64-bit addresses
+        let extended = body.len() > REVISION_1_LEN;
+        let reset_register = if extended && flags & RESET_REG_SUPPORTED != 0 {
+            Some(GenericAddress {
+                address_space: byte(80)?,
+                bit_width: byte(81)?,
+                bit_offset: byte(82)?,
+                address: read_at::<u64>(body, 84).ok_or(malformed)?,
+            })
+        } else {
+            None
+        };
+        let dsdt = match read_at::<u64>(body, 104) {
+            Some(x_dsdt) if extended && x_dsdt != 0 => x_dsdt,
+            _ => u64::from(field(4)?),
+        };
+        Ok(Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_at(body, 10).ok_or(malformed)?,
+            smi_command_port: field(12)?,
+            acpi_enable: byte(16)?,
+            acpi_disable: byte(17)?,
+            pm1a_event_block: field(20)?,
+            pm1b_event_block: field(24)?,
+            pm1a_control_block: field(28)?,
+            pm1b_control_block: field(32)?,
+            pm_timer_block: field(40)?,
+            century_register: byte(72)?,
+            boot_architecture_flags: read_at(body, 73).ok_or(malformed)?,
+            flags,
+            reset_register,
+            reset_value: if extended { byte(92)? } else { 0 },
+        })
+    }
+
+    /// Loads the differentiated system description table, the AML describing the devices of the system.
+    pub fn dsdt_table(&self) -> Result<Table, AcpiError> {
+        load_table(self.dsdt)
+    }
+
+    /// Returns `true` if the firmware says there is an 8042, or is too old to say.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture_flags == 0 || self.boot_architecture_flags & BOOT_8042 != 0
+    }
+}
+
+#[test_case]
+fn test_fadt_fields() {
+    let mut body = [0u8; 208];
+    body[4..8].copy_from_slice(&0x7fe_0000u32.to_le_bytes());
+    body[10] = 9;
+    body[28..32].copy_from_slice(&0x604u32.to_le_bytes());
+    body[72] = 0x32;
+    body[76..80].copy_from_slice(&RESET_REG_SUPPORTED.to_le_bytes());
+    body[80] = ADDRESS_SPACE_IO;
+    body[81] = 8;
+    body[84..92].copy_from_slice(&0xcf9u64.to_le_bytes());
+    body[92] = 0x06;
+
+    let fadt = Fadt::from_body(&body).unwrap();
+    assert_eq!(fadt.dsdt, PhysAddr::new(0x7fe_0000));
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_eq!(fadt.pm1a_control_block, 0x604);
+    assert_eq!(fadt.century_register, 0x32);
+    let reset = fadt.reset_register.unwrap();
+    assert_eq!((reset.address_space, reset.address, fadt.reset_value), (ADDRESS_SPACE_IO, 0xcf9, 0x06));
+    assert!(fadt.has_8042());
+
+    let revision_1 = Fadt::from_body(&body[..REVISION_1_LEN]).unwrap();
+    assert_eq!(revision_1.reset_register, None);
+    assert!(Fadt::from_body(&body[..40]).is_err());
+}
@@ -0,0 +1,139 @@
+use super::{find_table, AcpiError, Table};
+use crate::interrupts::ioapic::{Polarity, TriggerMode};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+/// `flags` bit telling that the system also has dual 8259 PICs.
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// A processor with its local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// Whether the processor can be started. Disabled entries describe hot-pluggable sockets.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not wired to the GSI with the same number, or not active high and edge triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The parsed multiple APIC description table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub flags: u32,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Finds and parses the MADT. Needs the heap.
+    pub fn parse() -> Result<Madt, AcpiError> {
+        Self::from_table(&find_table(b"APIC")?)
+    }
+
+    fn from_table(table: &Table) -> Result<Madt, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"APIC");
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(table.read::<u32>(0).ok_or(malformed)?)),
+            flags: table.read::<u32>(4).ok_or(malformed)?,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = 8;
+        while offset + 2 <= table.body().len() {
+            let kind: u8 = table.read(offset).unwrap();
+            let len = usize::from(table.read::<u8>(offset + 1).unwrap());
+            if len < 2 || offset + len > table.body().len() {
+                return Err(malformed);
+            }
+            let field = |at: usize| offset + at;
+            match kind {
+                ENTRY_LOCAL_APIC => madt.processors.push(Processor {
+                    processor_id: u32::from(table.read::<u8>(field(2)).ok_or(malformed)?),
+                    apic_id: u32::from(table.read::<u8>(field(3)).ok_or(malformed)?),
+                    enabled: table.read::<u32>(field(4)).ok_or(malformed)? & 1 != 0,
+                }),
+                ENTRY_LOCAL_X2APIC => madt.processors.push(Processor {
+                    apic_id: table.read::<u32>(field(4)).ok_or(malformed)?,
+                    enabled: table.read::<u32>(field(8)).ok_or(malformed)? & 1 != 0,
+                    processor_id: table.read::<u32>(field(12)).ok_or(malformed)?,
+                }),
+                ENTRY_IO_APIC => madt.io_apics.push(IoApicEntry {
+                    id: table.read(field(2)).ok_or(malformed)?,
+                    address: PhysAddr::new(u64::from(table.read::<u32>(field(4)).ok_or(malformed)?)),
+                    gsi_base: table.read(field(8)).ok_or(malformed)?,
+                }),
+                ENTRY_INTERRUPT_OVERRIDE => {
+                    let flags: u16 = table.read(field(8)).ok_or(malformed)?;
+                    madt.overrides.push(InterruptOverride {
+                        isa_irq: table.read(field(3)).ok_or(malformed)?,
+                        gsi: table.read(field(4)).ok_or(malformed)?,
+                        polarity: polarity_from_mps(flags),
+                        trigger: trigger_from_mps(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS => {
+                    madt.local_apic_address = PhysAddr::new(table.read::<u64>(field(4)).ok_or(malformed)?);
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+        Ok(madt)
+    }
+
+    /// Returns the override for `isa_irq`, if the firmware reported one.
+    pub fn isa_override(&self, isa_irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|o| o.isa_irq == isa_irq)
+    }
+}
+
+/// Decodes the polarity bits of MPS INTI flags, where "conforms to the bus" means active high for ISA.
+fn polarity_from_mps(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    }
+}
+
+/// Decodes the trigger mode bits of MPS INTI flags, where "conforms to the bus" means edge for ISA.
+fn trigger_from_mps(flags: u16) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    }
+}
+
+#[test_case]
+fn test_mps_flags() {
+    assert_eq!(polarity_from_mps(0b0000), Polarity::ActiveHigh);
+    assert_eq!(trigger_from_mps(0b0000), TriggerMode::Edge);
+    assert_eq!(polarity_from_mps(0b1111), Polarity::ActiveLow);
+    assert_eq!(trigger_from_mps(0b1111), TriggerMode::Level);
+    assert_eq!(polarity_from_mps(0b0001), Polarity::ActiveHigh);
+}
@@ -0,0 +1,48 @@
+use super::{find_table, AcpiError, Table};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+/// Size of the reserved field in front of the entries.
+const ENTRIES_OFFSET: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+/// A range of buses whose configuration space is memory mapped, 4 KiB per function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion {
+    /// The address of the configuration space of function 0 of device 0 on bus 0, even if `start_bus` is later.
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The parsed PCI Express memory mapped configuration table.
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub regions: Vec<EcamRegion>,
+}
+
+impl Mcfg {
+    /// Finds and parses the MCFG. Needs the heap.
+    pub fn parse() -> Result<Mcfg, AcpiError> {
+        Self::from_table(&find_table(b"MCFG")?)
+    }
+
+    fn from_table(table: &Table) -> Result<Mcfg, AcpiError> {
+        let malformed = AcpiError::Malformed(*b"MCFG");
+        let len = table.body().len().checked_sub(ENTRIES_OFFSET).ok_or(malformed)?;
+        let regions = (0..len / ENTRY_SIZE)
+            .map(|index| {
+                let field = |at: usize| ENTRIES_OFFSET + index * ENTRY_SIZE + at;
+                EcamRegion {
+                    base: PhysAddr::new(table.read::<u64>(field(0)).unwrap()),
+                    segment: table.read(field(8)).unwrap(),
+                    start_bus: table.read(field(10)).unwrap(),
+                    end_bus: table.read(field(11)).unwrap(),
+                }
+            })
+            .collect();
+        Ok(Mcfg { regions })
+    }
+
+}
@@ -1,4 +1,9 @@
-use fixed_size_block::FixedSizeBlockAllocator;
+use crate::memory::{self, vmm::{self, RegionKind}};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use fixed_size_block::{AllocatorStats, FixedSizeBlockAllocator};
 use x86_64::{
     structures::paging::{
         mapper::MapToError,
@@ -16,6 +21,8 @@ pub mod bump;
 pub mod linked_list;
 pub mod fixed_size_block;
 
+pub mod slab;
+
 /// Simple wrapper around spin::Mutex to permit trait implementations
 pub struct Locked<A> {
     inner: spin::Mutex<A>
@@ -31,39 +38,194 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
 }
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Initial size of the kernel heap.
 pub const HEAP_SIZE: usize = 100 * 1024;
+/// Size the kernel heap may grow to when the initial mapping is exhausted.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// Minimum number of bytes mapped each time the heap grows.
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+/// End of the virtual region reserved for the heap.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Frees memory when the heap is exhausted, returning the number of bytes released.
+/// 
+/// Called from inside a failed allocation, so it may free memory but must not rely on allocating any.
+pub type Reclaimer = fn() -> usize;
+
+const MAX_RECLAIMERS: usize = 8;
+
+static RECLAIMERS: spin::Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> = spin::Mutex::new([None; MAX_RECLAIMERS]);
+/// Set while the reclaimers run, so that a failing allocation inside one of them doesn't recurse.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
+    let heap_region = vmm::allocate(HEAP_MAX_SIZE as u64, RegionKind::Heap)
+        .expect("no kernel virtual memory left for the heap");
+    let heap_start = heap_region.start().as_u64() as usize;
+
+    map_heap_pages(heap_start, HEAP_SIZE, mapper, frame_allocator)?;
+
+    HEAP_LIMIT.store(heap_start + HEAP_MAX_SIZE, Ordering::Relaxed);
+    unsafe {
+        let mut allocator = ALLOCATOR.lock();
+        allocator.init(heap_start, HEAP_SIZE);
+        allocator.set_grow_handler(grow_heap);
+    }
+
+    Ok(())
+}
+
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start_page = Page::containing_address(VirtAddr::new(start as u64));
+    let end_page = Page::containing_address(VirtAddr::new((start + size - 1) as u64));
+
+    for page in Page::range_inclusive(start_page, end_page) {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
-    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE); }
-
     Ok(())
 }
 
+/// Maps at least `min_size` more bytes directly after `heap_end`, called by the allocator when it runs out of memory.
+/// 
+/// Returns the number of bytes mapped, which is zero if the heap reached `HEAP_MAX_SIZE`, no frames are left,
+/// or the kernel page table is busy (for example because the allocation happened while it was locked).
+fn grow_heap(heap_end: usize, min_size: usize) -> usize {
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    let size = align_up(min_size.max(HEAP_GROWTH_STEP), 4096).min(limit.saturating_sub(heap_end));
+    if size < min_size {
+        return 0;
+    }
+
+    memory::try_with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < size {
+            if map_heap_pages(heap_end + mapped, 4096, &mut memory.mapper, &mut memory.frame_allocator).is_err() {
+                break;
+            }
+            mapped += 4096;
+        }
+        mapped
+    }).unwrap_or(0)
+}
+
+/// Current state of the kernel heap, as returned by `stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// Bytes requested by live allocations.
+    pub used: usize,
+    /// Highest value `used` has reached since boot.
+    pub peak_used: usize,
+    /// Bytes available without growing the heap.
+    pub free: usize,
+    /// Largest single allocation that can be served without growing the heap.
+    pub largest_free_block: usize,
+}
+
+/// Returns the current heap usage.
+pub fn stats() -> HeapStats {
+    heap_stats(&mut ALLOCATOR.lock())
+}
+
+/// Returns the current heap usage, or `None` if the heap is locked, for panic handlers.
+pub fn try_stats() -> Option<HeapStats> {
+    let mut allocator = ALLOCATOR.try_lock()?;
+    Some(heap_stats(&mut allocator))
+}
+
+fn heap_stats(allocator: &mut FixedSizeBlockAllocator) -> HeapStats {
+    let counters = allocator.stats();
+    HeapStats {
+        heap_size: allocator.heap_size(),
+        used: counters.bytes_in_use,
+        peak_used: counters.peak_bytes_in_use,
+        free: allocator.free_bytes(),
+        largest_free_block: allocator.largest_free_block(),
+    }
+}
+
+/// Returns a snapshot of the global allocator's counters.
+pub fn allocation_stats() -> AllocatorStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Registers `reclaimer` to be called when an allocation fails even after growing the heap.
+/// 
+/// Each reclaimer should be registered once. Returns `false` if no slot is left.
+pub fn register_reclaimer(reclaimer: Reclaimer) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    match reclaimers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(reclaimer);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Releases the allocator's cached blocks and runs the registered reclaimers.
+/// 
+/// Must be called without the allocator lock held. Returns the number of bytes released.
+fn reclaim() -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let mut released = ALLOCATOR.lock().release_cached_blocks();
+    let reclaimers = *RECLAIMERS.lock();
+    for reclaimer in reclaimers.iter().flatten() {
+        released += reclaimer();
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    released
+}
+
+/// Last resort of the out of memory path, called once growing the heap and every reclaimer failed.
+/// 
+/// Panics with a report of the heap usage.
+pub fn handle_alloc_error(layout: Layout) -> ! {
+    let stats = stats();
+    let counters = allocation_stats();
+    panic!(
+        "out of memory: allocation of {} bytes (align {}) failed\n\
+         heap: {} of {} bytes used (peak {}), {} free, largest free block {}\n\
+         {} allocations, {} frees, {} failures",
+        layout.size(),
+        layout.align(),
+        stats.used,
+        stats.heap_size,
+        stats.peak_used,
+        stats.free,
+        stats.largest_free_block,
+        counters.allocations,
+        counters.frees,
+        counters.failures,
+    )
+}
+
 pub fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
\ No newline at end of file
@@ -7,6 +7,17 @@ use core::{alloc::Layout, mem, ptr::NonNull};
 /// Each size must be a power of 2 since they are also used as the block alignment.
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// Counts the blocks in a free list.
+fn list_len(head: &Option<&'static mut ListNode>) -> usize {
+    let mut len = 0;
+    let mut current = head.as_deref();
+    while let Some(node) = current {
+        len += 1;
+        current = node.next.as_deref();
+    }
+    len
+}
+
 /// Choose an appropriate block size for the given layout.
 /// 
 /// Returns an index into the `BLOCK_SIZES` array on success.
@@ -18,37 +29,98 @@ struct ListNode {
     next: Option<&'static mut ListNode>
 }
 
+/// Counters for one block size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// Allocations served from the free list of this class.
+    pub hits: u64,
+    /// Allocations that had to carve a new block from the fallback allocator.
+    pub misses: u64,
+}
+
+impl SizeClassStats {
+    /// Returns the share of allocations served from the free list, in percent.
+    pub fn hit_rate_percent(&self) -> u64 {
+        match self.hits + self.misses {
+            0 => 0,
+            total => self.hits * 100 / total,
+        }
+    }
+}
+
+/// Allocation counters kept by the `FixedSizeBlockAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub allocations: u64,
+    pub frees: u64,
+    /// Allocations that failed because no memory was left.
+    pub failures: u64,
+    /// Allocations too large for any size class, served by the fallback allocator directly.
+    pub large_allocations: u64,
+    /// Sum of the requested sizes of all live allocations.
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` has reached.
+    pub peak_bytes_in_use: usize,
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+}
+
+impl AllocatorStats {
+    const fn new() -> Self {
+        let mut size_classes = [SizeClassStats { block_size: 0, hits: 0, misses: 0 }; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            size_classes[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+        AllocatorStats {
+            allocations: 0,
+            frees: 0,
+            failures: 0,
+            large_allocations: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            size_classes,
+        }
+    }
+}
+
+/// Called when the fallback allocator runs out of memory.
+/// 
+/// Receives the current end of the heap and the minimum number of bytes needed,
+/// and returns how many bytes directly after the end it made available.
+pub type GrowHandler = fn(heap_end: usize, min_size: usize) -> usize;
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    grow_handler: Option<GrowHandler>,
+    stats: AllocatorStats,
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        if let Some(index) = list_index(&layout) {
-            match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    // No block exists in list, so allocate a new block
-                    let block_size = BLOCK_SIZES[index];
-                    // only works if all block sizes are a power of 2
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align)
-                        .unwrap();
-                    allocator.fallback_alloc(layout)
-                }
+        let ptr = self.lock().allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Still out of memory after growing the heap. The reclaimers free memory through this allocator,
+        // so they have to run without the lock held.
+        if super::reclaim() > 0 {
+            let ptr = self.lock().allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
             }
-        } else {
-            allocator.fallback_alloc(layout)
         }
+        self.lock().stats.failures += 1;
+        core::ptr::null_mut()
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.stats.frees += 1;
+        allocator.stats.bytes_in_use -= layout.size();
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -76,6 +148,8 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator { 
             list_heads: [EMPTY; BLOCK_SIZES.len()], 
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            grow_handler: None,
+            stats: AllocatorStats::new(),
         }
     }
 
@@ -87,8 +161,127 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
+    /// Returns a snapshot of the allocation counters.
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    /// Returns the number of bytes currently mapped for the heap.
+    pub fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// Returns the number of bytes available without growing the heap, including blocks in the free lists.
+    pub fn free_bytes(&self) -> usize {
+        let cached: usize = self.list_heads.iter()
+            .zip(BLOCK_SIZES)
+            .map(|(head, &block_size)| list_len(head) * block_size)
+            .sum();
+        self.fallback_allocator.free() + cached
+    }
+
+    /// Returns the size of the largest allocation that can be served without growing the heap.
+    /// 
+    /// The fallback allocator does not expose its free list, so this probes it with a binary search
+    /// over allocation sizes. Every probe is freed again right away, leaving the heap as it was.
+    pub fn largest_free_block(&mut self) -> usize {
+        let largest_cached = self.list_heads.iter()
+            .zip(BLOCK_SIZES)
+            .filter(|(head, _)| head.is_some())
+            .map(|(_, &block_size)| block_size)
+            .max()
+            .unwrap_or(0);
+
+        let (mut low, mut high) = (0, self.fallback_allocator.free());
+        while low < high {
+            let size = low + (high - low + 1) / 2;
+            let layout = Layout::from_size_align(size, mem::align_of::<usize>()).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = size;
+                }
+                Err(()) => high = size - 1,
+            }
+        }
+
+        low.max(largest_cached)
+    }
+
+    /// Registers a handler that makes more memory available when the heap is exhausted.
+    /// 
+    /// # Safety
+    /// 
+    /// The caller must guarantee that every byte reported by the handler
+    /// is mapped, unused, and directly follows the current end of the heap.
+    pub unsafe fn set_grow_handler(&mut self, grow_handler: GrowHandler) {
+        self.grow_handler = Some(grow_handler);
+    }
+
+    /// Allocates a block for `layout`, returning null if no memory is left even after growing the heap.
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = if let Some(index) = list_index(&layout) {
+            match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    self.stats.size_classes[index].hits += 1;
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block exists in list, so allocate a new block
+                    self.stats.size_classes[index].misses += 1;
+                    let block_size = BLOCK_SIZES[index];
+                    // only works if all block sizes are a power of 2
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align)
+                        .unwrap();
+                    self.fallback_alloc(layout)
+                }
+            }
+        } else {
+            self.stats.large_allocations += 1;
+            self.fallback_alloc(layout)
+        };
+
+        if !ptr.is_null() {
+            self.stats.allocations += 1;
+            self.stats.bytes_in_use += layout.size();
+            self.stats.peak_bytes_in_use = self.stats.peak_bytes_in_use.max(self.stats.bytes_in_use);
+        }
+        ptr
+    }
+
+    /// Returns every block cached in the free lists to the fallback allocator, so that they can be merged
+    /// into larger free ranges. Returns the number of bytes released.
+    pub fn release_cached_blocks(&mut self) -> usize {
+        let mut released = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                released += block_size;
+            }
+        }
+        released
+    }
+
+    /// Allocates using the fallback allocator, growing the heap once if it is exhausted.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        let grown = match self.grow_handler {
+            Some(grow) => grow(self.fallback_allocator.top(), layout.size() + layout.align()),
+            None => 0,
+        };
+        if grown == 0 {
+            return core::ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => core::ptr::null_mut(),
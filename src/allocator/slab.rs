@@ -0,0 +1,259 @@
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+
+/// Size of the pages slabs are carved from.
+const SLAB_SIZE: usize = 4096;
+
+/// Header at the start of every slab page.
+struct SlabHeader {
+    prev: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+    free: Option<NonNull<FreeSlot>>,
+    in_use: usize,
+}
+
+/// An unused slot, linked into the free list of its slab.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+struct Slabs {
+    /// Slabs with at least one free slot.
+    partial: Option<NonNull<SlabHeader>>,
+}
+
+// The slab pages are only ever accessed with the cache lock held.
+unsafe impl Send for Slabs {}
+
+/// Object cache that hands out fixed-size slots of type `T` carved from dedicated pages.
+///
+/// Keeping same-sized objects together means frequently allocated small structs don't fragment the general heap.
+/// Completely free pages are returned to the heap.
+pub struct SlabCache<T> {
+    slabs: Mutex<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+// The cache only manages uninitialized memory, values are owned by whoever holds the slot.
+unsafe impl<T> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    pub const fn new() -> Self {
+        SlabCache {
+            slabs: Mutex::new(Slabs { partial: None }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocates an uninitialized slot for a `T`.
+    ///
+    /// Returns `None` if no memory is left for a new slab page.
+    pub fn alloc(&self) -> Option<NonNull<T>> {
+        let mut slabs = self.slabs.lock();
+        if slabs.partial.is_none() {
+            // A failing heap allocation runs the reclaimers, which may shrink this cache, so the page is
+            // allocated without the cache lock. Every path that takes both takes the cache lock first.
+            drop(slabs);
+            let mut slab = Self::new_slab()?;
+            slabs = self.slabs.lock();
+            // another slab may have been added in the meantime
+            unsafe { slab.as_mut().next = slabs.partial };
+            if let Some(mut next) = slabs.partial {
+                unsafe { next.as_mut().prev = Some(slab) };
+            }
+            slabs.partial = Some(slab);
+        }
+        let mut slab = slabs.partial.expect("no partial slab after adding one");
+
+        unsafe {
+            let header = slab.as_mut();
+            let slot = header.free.expect("full slab on the partial list");
+            header.free = slot.as_ref().next;
+            header.in_use += 1;
+            if header.free.is_none() {
+                // slab is full now, so take it off the partial list
+                slabs.partial = header.next;
+                if let Some(mut next) = header.next {
+                    next.as_mut().prev = None;
+                }
+                header.next = None;
+            }
+            Some(slot.cast())
+        }
+    }
+
+    /// Returns a slot to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this cache and must not be used afterwards.
+    /// Any value stored in the slot has to be dropped by the caller beforehand.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        let mut slabs = self.slabs.lock();
+        let mut slab = NonNull::new_unchecked((ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader);
+        let header = slab.as_mut();
+        let was_full = header.free.is_none();
+
+        let mut slot = ptr.cast::<FreeSlot>();
+        slot.as_mut().next = header.free;
+        header.free = Some(slot);
+        header.in_use -= 1;
+
+        if was_full {
+            header.prev = None;
+            header.next = slabs.partial;
+            if let Some(mut next) = slabs.partial {
+                next.as_mut().prev = Some(slab);
+            }
+            slabs.partial = Some(slab);
+        }
+
+        // keep a single empty slab around to absorb alloc/free churn
+        let only_slab = header.prev.is_none() && header.next.is_none();
+        if header.in_use == 0 && !only_slab {
+            match header.prev {
+                Some(mut prev) => prev.as_mut().next = header.next,
+                None => slabs.partial = header.next,
+            }
+            if let Some(mut next) = header.next {
+                next.as_mut().prev = header.prev;
+            }
+            dealloc(slab.as_ptr().cast(), Self::slab_layout());
+        }
+    }
+
+    /// Returns every completely free slab page to the heap, including the one normally kept around.
+    /// 
+    /// Returns the number of bytes released.
+    pub fn shrink(&self) -> usize {
+        let mut slabs = self.slabs.lock();
+        let mut released = 0;
+        let mut current = slabs.partial;
+        while let Some(slab) = current {
+            let header = unsafe { slab.as_ref() };
+            current = header.next;
+            if header.in_use > 0 {
+                continue;
+            }
+            unsafe {
+                match header.prev {
+                    Some(mut prev) => prev.as_mut().next = header.next,
+                    None => slabs.partial = header.next,
+                }
+                if let Some(mut next) = header.next {
+                    next.as_mut().prev = header.prev;
+                }
+                dealloc(slab.as_ptr().cast(), Self::slab_layout());
+            }
+            released += SLAB_SIZE;
+        }
+        released
+    }
+
+    /// Size of a slot, large enough for a `T` and for the free list link.
+    fn slot_size() -> usize {
+        let align = mem::align_of::<T>().max(mem::align_of::<FreeSlot>());
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeSlot>());
+        super::align_up(size, align)
+    }
+
+    /// Offset of the first slot, directly after the header.
+    fn first_slot_offset() -> usize {
+        let align = mem::align_of::<T>().max(mem::align_of::<FreeSlot>());
+        super::align_up(mem::size_of::<SlabHeader>(), align)
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    /// Allocates a slab page and threads all of its slots onto its free list.
+    fn new_slab() -> Option<NonNull<SlabHeader>> {
+        let slot_size = Self::slot_size();
+        let first_slot = Self::first_slot_offset();
+        assert!(first_slot + slot_size <= SLAB_SIZE, "object too large for a slab");
+
+        let page = NonNull::new(unsafe { alloc(Self::slab_layout()) })?;
+        let mut free = None;
+        let mut offset = first_slot + (SLAB_SIZE - first_slot) / slot_size * slot_size;
+        while offset > first_slot {
+            offset -= slot_size;
+            unsafe {
+                let slot = page.as_ptr().add(offset).cast::<FreeSlot>();
+                slot.write(FreeSlot { next: free });
+                free = Some(NonNull::new_unchecked(slot));
+            }
+        }
+
+        let header = page.cast::<SlabHeader>();
+        unsafe {
+            header.as_ptr().write(SlabHeader {
+                prev: None,
+                next: None,
+                free,
+                in_use: 0,
+            });
+        }
+        Some(header)
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An owned `T` stored in a slot of a static `SlabCache`.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+// owns its `T` like a `Box`, the cache is `Sync`
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T: 'static> SlabBox<T> {
+    /// Moves `value` into a slot allocated from `cache`.
+    ///
+    /// Returns the value back if the cache is out of memory.
+    pub fn new_in(value: T, cache: &'static SlabCache<T>) -> Result<Self, T> {
+        match cache.alloc() {
+            Some(ptr) => {
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(SlabBox { ptr, cache })
+            }
+            None => Err(value),
+        }
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{memory::debug, serial};
+use core::{arch::asm, ops::Range};
+use x86_64::VirtAddr;
+
+pub mod symbols;
+
+/// Most frames printed, which also ends walks through corrupted frame chains.
+const MAX_FRAMES: usize = 32;
+/// Number of stack words `scan_stack` looks at when the frame pointer chain is unusable.
+const SCAN_WORDS: u64 = 512;
+
+extern "C" {
+    // defined by the linker
+    static __ehdr_start: u8;
+    static etext: u8;
+}
+
+macro_rules! out {
+    ($($arg:tt)*) => {
+        serial::write_unlocked(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Returns the address range of the kernel code.
+pub fn kernel_text() -> Range<u64> {
+    unsafe { (&__ehdr_start as *const u8 as u64)..(&etext as *const u8 as u64) }
+}
+
+/// Returns the frame pointer of the code an interrupt handler interrupted.
+///
+/// Must be called directly from an `x86-interrupt` handler, whose prologue saves the interrupted frame
+/// pointer as the first thing on its stack. Relies on the target keeping frame pointers in every function.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    read_word(rbp).unwrap_or(0)
+}
+
+/// Prints a backtrace of the code interrupted at `rip` to serial, without taking any locks.
+///
+/// Follows the frame pointer chain starting at `rbp`. If that does not lead anywhere, falls back to listing
+/// every value on the stack above `rsp` that points into the kernel code, some of which may be stale.
+/// Addresses are printed as `function+offset` once `symbols::init` found the symbol table, and together with
+/// their offset into the kernel image, which `addr2line -e target/x86_64-rust_os/debug/rust_os` resolves to a line.
+pub fn print(rip: u64, rbp: u64, rsp: u64) {
+    out!("---- backtrace ----");
+    print_frame(0, rip);
+    let frames = walk_frame_pointers(rbp, print_frame);
+    if frames == 0 {
+        out!("frame pointer chain unusable, scanning the stack at {:#x}:", rsp);
+        scan_stack(rsp, print_frame);
+    }
+    out!("-------------------");
+}
+
+/// Prints a backtrace of the caller to serial like `print`, without taking any locks, for panic handlers.
+#[inline(never)]
+pub fn print_caller() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    out!("---- backtrace ----");
+    // the first return address is the caller's
+    walk_frame_pointers(rbp, |depth, addr| print_frame(depth - 1, addr));
+    out!("-------------------");
+}
+
+fn print_frame(depth: usize, addr: u64) {
+    let text = kernel_text();
+    if let Some(symbol) = symbols::resolve(addr) {
+        out!("  #{:<2} {:#018x} {} (kernel+{:#x})", depth, addr, symbol, addr - text.start);
+    } else if text.contains(&addr) {
+        out!("  #{:<2} {:#018x} (kernel+{:#x})", depth, addr, addr - text.start);
+    } else {
+        out!("  #{:<2} {:#018x} (outside kernel code)", depth, addr);
+    }
+}
+
+/// Calls `f` with the return address of every frame in the chain starting at `rbp` and returns their number.
+///
+/// Stops at the first frame that is unmapped, misaligned, does not lie above the previous one, or whose
+/// return address does not point into the kernel code.
+fn walk_frame_pointers(mut rbp: u64, mut f: impl FnMut(usize, u64)) -> usize {
+    let text = kernel_text();
+    let mut frames = 0;
+    while frames < MAX_FRAMES && rbp != 0 && rbp % 8 == 0 {
+        let (next, ret) = match (read_word(rbp), read_word(rbp + 8)) {
+            (Some(next), Some(ret)) => (next, ret),
+            _ => break,
+        };
+        if !text.contains(&ret) {
+            break;
+        }
+        frames += 1;
+        f(frames, ret);
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    frames
+}
+
+/// Calls `f` with every word above `rsp` that points into the kernel code, up to the first unmapped page.
+fn scan_stack(rsp: u64, mut f: impl FnMut(usize, u64)) -> usize {
+    let text = kernel_text();
+    let mut found = 0;
+    let start = rsp & !7;
+    for index in 0..SCAN_WORDS {
+        let word = match read_word(start + index * 8) {
+            Some(word) => word,
+            None => break,
+        };
+        if text.contains(&word) {
+            found += 1;
+            f(found, word);
+            if found == MAX_FRAMES {
+                break;
+            }
+        }
+    }
+    found
+}
+
+/// Reads the word at `addr` if it is canonical and mapped.
+fn read_word(addr: u64) -> Option<u64> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    if !debug::is_mapped(addr) {
+        return None;
+    }
+    Some(unsafe { addr.as_ptr::<u64>().read_volatile() })
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn frames_from_here() -> usize {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    walk_frame_pointers(rbp, |_, _| {})
+}
+
+#[test_case]
+fn test_walk_frame_pointers() {
+    let text = kernel_text();
+    assert!(text.contains(&(frames_from_here as usize as u64)));
+    // this test, the test runner and the test harness at least
+    assert!(frames_from_here() >= 2);
+}
+
+#[test_case]
+fn test_scan_stack_finds_return_addresses() {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    assert!(scan_stack(rsp, |_, _| {}) > 0);
+}
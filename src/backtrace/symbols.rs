@@ -0,0 +1,262 @@
+//! Function names for code addresses, from the symbol table of the kernel ELF file.
+//!
+//! The bootloader loads the whole kernel file into physical memory, including the sections that aren't loaded
+//! into the kernel's address space, and marks it as a `Kernel` region of the memory map. `init` finds the
+//! `.symtab` and `.strtab` sections of that copy through the physical memory window, so nothing has to be
+//! generated at build time. The kernel is statically linked at a fixed address, so symbol values are the
+//! addresses the code runs at.
+
+use crate::{
+    bytes::{read_at, FromLeBytes},
+    memory::phys_to_virt,
+};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::PhysAddr;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolError {
+    /// The memory map has no `Kernel` region.
+    NoKernelImage,
+    /// The `Kernel` region doesn't start with an ELF header.
+    NotElf,
+    /// The kernel file has no symbol table, e.g. because it was stripped.
+    NoSymbolTable,
+    /// A section extends past the end of the `Kernel` region.
+    Truncated,
+    AlreadyInitialized,
+}
+
+/// Finds the symbol table of the kernel file the bootloader left in memory and returns its number of symbols.
+///
+/// Needs the physical memory window, so must be called after `memory::init`.
+pub fn init(memory_map: &MemoryMap) -> Result<usize, SymbolError> {
+    let image = kernel_image(memory_map)?;
+    let table = SymbolTable::parse(image)?;
+    let count = table.symbols.len() / SYMBOL_SIZE;
+    SYMBOLS.try_init_once(|| table).map_err(|_| SymbolError::AlreadyInitialized)?;
+    Ok(count)
+}
+
+/// Returns the function `addr` lies in, or `None` if there is none or `init` didn't succeed.
+///
+/// Takes no locks, so it can be called from panic and fault handlers.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    SYMBOLS.try_get().ok()?.lookup(addr)
+}
+
+/// A function and how far into it an address is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    /// The mangled name, as in the symbol table.
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl Symbol {
+    pub fn demangled(&self) -> Demangled<'static> {
+        Demangled(self.name)
+    }
+}
+
+/// Prints the demangled name and the offset, like `rust_os::main::kernel_main+0x2c`.
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.demangled(), self.offset)
+    }
+}
+
+/// A symbol name that is printed demangled if it uses the legacy Rust mangling, and as is otherwise.
+///
+/// The trailing hash is left out, so `_ZN7rust_os4main17h0123456789abcdefE` prints as `rust_os::main`.
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match legacy_path(self.0) {
+            Some(path) => path,
+            None => return f.write_str(self.0),
+        };
+        let mut first = true;
+        while let Some((segment, next)) = next_segment(rest) {
+            rest = next;
+            if rest.is_empty() && is_hash(segment) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+struct SymbolTable {
+    /// The entries of `.symtab`.
+    symbols: &'static [u8],
+    /// The contents of the string table `.symtab` links to.
+    strings: &'static [u8],
+}
+
+impl SymbolTable {
+    fn parse(image: &'static [u8]) -> Result<SymbolTable, SymbolError> {
+        if image.len() < ELF_HEADER_SIZE || &image[..4] != ELF_MAGIC {
+            return Err(SymbolError::NotElf);
+        }
+        let section_headers = read::<u64>(image, 0x28) as usize;
+        let section_count = read::<u16>(image, 0x3c) as usize;
+        let section = |index: usize| -> Result<(u32, &'static [u8], u32), SymbolError> {
+            let header = section_headers + index * SECTION_HEADER_SIZE;
+            if header + SECTION_HEADER_SIZE > image.len() {
+                return Err(SymbolError::Truncated);
+            }
+            let offset = read::<u64>(image, header + 0x18) as usize;
+            let size = read::<u64>(image, header + 0x20) as usize;
+            let contents = image.get(offset..offset + size).ok_or(SymbolError::Truncated)?;
+            Ok((read::<u32>(image, header + 4), contents, read::<u32>(image, header + 0x28)))
+        };
+        for index in 0..section_count {
+            let (kind, symbols, link) = section(index)?;
+            if kind == SHT_SYMTAB {
+                let (_, strings, _) = section(link as usize)?;
+                return Ok(SymbolTable { symbols, strings });
+            }
+        }
+        Err(SymbolError::NoSymbolTable)
+    }
+
+    /// Looks through every function symbol, the table isn't sorted.
+    fn lookup(&self, addr: u64) -> Option<Symbol> {
+        self.symbols.chunks_exact(SYMBOL_SIZE).find_map(|symbol| {
+            let info = symbol[4];
+            let (value, size) = (read::<u64>(symbol, 8), read::<u64>(symbol, 16));
+            if info & 0xf != STT_FUNC || addr < value || addr - value >= size.max(1) {
+                return None;
+            }
+            let name = self.strings.get(read::<u32>(symbol, 0) as usize..)?;
+            let name = &name[..name.iter().position(|&byte| byte == 0)?];
+            Some(Symbol {
+                name: core::str::from_utf8(name).ok()?,
+                offset: addr - value,
+            })
+        })
+    }
+}
+
+/// Returns the kernel file, which spans the `Kernel` regions that follow the first one without a gap.
+fn kernel_image(memory_map: &MemoryMap) -> Result<&'static [u8], SymbolError> {
+    let mut regions = memory_map.iter().filter(|region| region.region_type == MemoryRegionType::Kernel);
+    let first = regions.next().ok_or(SymbolError::NoKernelImage)?.range;
+    let (start, mut end) = (first.start_addr(), first.end_addr());
+    for region in regions {
+        if region.range.start_addr() != end {
+            break;
+        }
+        end = region.range.end_addr();
+    }
+    let virt = phys_to_virt(PhysAddr::new(start));
+    Ok(unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), (end - start) as usize) })
+}
+
+/// Returns the inside of a legacy mangled name, without the `_ZN` and the `E`.
+fn legacy_path(symbol: &str) -> Option<&str> {
+    // LLVM appends its own suffix to symbols it duplicated
+    let symbol = symbol.find(".llvm.").map_or(symbol, |end| &symbol[..end]);
+    let path = symbol.strip_prefix("_ZN")?.strip_suffix('E')?;
+    let mut rest = path;
+    while !rest.is_empty() {
+        rest = next_segment(rest)?.1;
+    }
+    Some(path)
+}
+
+/// Splits off the first length prefixed segment of `path`.
+fn next_segment(path: &str) -> Option<(&str, &str)> {
+    let digits = path.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = path[..digits].parse().ok()?;
+    let rest = &path[digits..];
+    if len == 0 || len > rest.len() || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Returns `true` for the `h` and 16 hex digits rustc ends every path with.
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17 && segment.starts_with('h') && segment[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn write_segment(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    // a leading `_` only keeps segments from starting with an escape
+    let mut rest = if segment.starts_with("_$") { &segment[1..] } else { segment };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some((escaped, after)) = rest.strip_prefix('$').and_then(|inner| inner.split_once('$')) {
+            match unescape(escaped) {
+                Some(c) => write!(f, "{}", c)?,
+                None => write!(f, "${}$", escaped)?,
+            }
+            rest = after;
+        } else {
+            let end = rest[1..].find(|c| c == '$' || c == '.').map_or(rest.len(), |end| end + 1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the escape between two `$`s of a legacy mangled segment.
+fn unescape(escaped: &str) -> Option<char> {
+    match escaped {
+        "SP" => Some('@'),
+        "BP" => Some('*'),
+        "RF" => Some('&'),
+        "LT" => Some('<'),
+        "GT" => Some('>'),
+        "LP" => Some('('),
+        "RP" => Some(')'),
+        "C" => Some(','),
+        _ => u32::from_str_radix(escaped.strip_prefix('u')?, 16).ok().and_then(char::from_u32),
+    }
+}
+
+/// Reads a field the caller checked the bounds of.
+fn read<T: FromLeBytes>(bytes: &[u8], offset: usize) -> T {
+    read_at(bytes, offset).expect("ELF field out of bounds")
+}
+
+#[test_case]
+fn test_legacy_names_are_demangled() {
+    use crate::test_util::Line;
+
+    let demangle = |name: &str, expected: &str| {
+        assert_eq!(Line::format(format_args!("{}", Demangled(name))).as_bytes(), expected.as_bytes());
+    };
+    demangle("_ZN7rust_os4main17h0123456789abcdefE", "rust_os::main");
+    demangle(
+        "_ZN64_$LT$rust_os..serial..uart..Uart$u20$as$u20$core..fmt..Write$GT$9write_str17h7e0f1a2b3c4d5e6fE",
+        "<rust_os::serial::uart::Uart as core::fmt::Write>::write_str",
+    );
+    demangle("_ZN4core3ptr13drop_in_place17h0123456789abcdefE.llvm.1234", "core::ptr::drop_in_place");
+    demangle(
+        "_ZN4sync11IrqSpinLock4lock28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE",
+        "sync::IrqSpinLock::lock::{{closure}}",
+    );
+    demangle("memcpy", "memcpy");
+    demangle("_ZN99tooshortE", "_ZN99tooshortE");
+}
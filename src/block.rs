@@ -0,0 +1,130 @@
+//! Disks and other devices that store data in fixed size blocks, and the registry of the attached ones.
+//!
+//! Drivers attach every disk they find under a name like `ata0`, which registers its partitions as well, as
+//! `ata0p1` and so on. Code that reads or writes blocks, like filesystems, finds them there and only goes through
+//! the `BlockDevice` trait, whatever the hardware.
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod ram;
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+/// What `BlockDevice` transfers return.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+#[derive(Debug)]
+pub enum BlockError {
+    /// The request reaches past the last block.
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks.
+    BufferLength(usize),
+    /// The device can't be written to.
+    ReadOnly,
+    /// A device with this name is already registered.
+    NameTaken(String),
+    Ata(ata::AtaError),
+    Ahci(ahci::AhciError),
+}
+
+/// A device that reads and writes whole blocks, numbered from 0.
+///
+/// Transfers may complete before they are first polled, which drivers that wait for the hardware by polling do.
+pub trait BlockDevice: Send + Sync {
+    /// Returns the size of a block in bytes, a power of two.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks from `start` on into `buf`, whose length must be a multiple of the block size.
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Writes `buf`, whose length must be a multiple of the block size, to the blocks from `start` on.
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a>;
+
+    /// Waits until everything written has reached the medium.
+    ///
+    /// The default does nothing, for devices that finish writes before they complete.
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Returns `true` if `write_blocks` always fails with `BlockError::ReadOnly`.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Checks that a transfer of `len` bytes from block `start` on fits `device`, for implementations.
+pub fn check_request<D: BlockDevice + ?Sized>(device: &D, start: u64, len: usize) -> Result<(), BlockError> {
+    if len % device.block_size() != 0 {
+        return Err(BlockError::BufferLength(len));
+    }
+    match start.checked_add((len / device.block_size()) as u64) {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Makes `device` available under `name`, which must be unique. Needs the heap.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(known, _)| known == name) {
+        return Err(BlockError::NameTaken(name.to_string()));
+    }
+    devices.push((name.to_string(), device));
+    Ok(())
+}
+
+/// Registers the disk `device` under `name` and each of its partitions under `name` followed by `p` and its
+/// number, returning how many partitions it has. Needs the heap.
+///
+/// Reads the partition table with `task::block_on`, so it must not be called from a task.
+pub fn attach(name: &str, device: Arc<dyn BlockDevice>) -> Result<usize, BlockError> {
+    register(name, device.clone())?;
+    let entries = crate::task::block_on(partition::read_partitions(&*device))?;
+    let mut registered = 0;
+    for entry in entries.iter() {
+        let partition_name = format!("{}p{}", name, entry.number);
+        crate::info!("block: {} is {}", partition_name, entry);
+        match register(&partition_name, Arc::new(partition::Partition::new(device.clone(), entry))) {
+            Ok(()) => registered += 1,
+            Err(err) => crate::warn!("block: {} not registered: {:?}", partition_name, err),
+        }
+    }
+    Ok(registered)
+}
+
+/// Removes the device registered under `name` and returns it.
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(|(known, _)| known == name)?;
+    Some(devices.remove(index).1)
+}
+
+/// Returns the device registered under `name`.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|(known, _)| known == name).map(|(_, device)| device.clone())
+}
+
+/// Returns every registered device with its name, in the order they were registered.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
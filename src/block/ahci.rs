@@ -0,0 +1,560 @@
+//! SATA disks behind an AHCI host bus adapter, transferring by DMA.
+//!
+//! The adapter's registers are in memory BAR 5. Every port with a disk gets a page holding its command list,
+//! its received FIS area and the one command table it uses, and a bounce buffer sectors are transferred
+//! through. Commands use 48-bit LBAs and complete by interrupt, through MSI where the adapter has it and its
+//! legacy line otherwise, or by polling while interrupts are disabled. A transfer waits for its interrupt
+//! suspended, the interrupt handler wakes it once its command slot is done. The DMA buffers lie below 4 GiB, so
+//! adapters without 64-bit addressing work too. Only one adapter is supported.
+
+use super::{
+    ata::{identify_model, model_name, SECTOR_SIZE},
+    BlockDevice, BlockError, BlockFuture,
+};
+use crate::{
+    interrupts::{self, irq::IrqError},
+    memory::{self, mmio::MmioError, DmaBuffer, MmioRegion},
+    pci::{
+        driver::{Driver, Match, ProbeError},
+        msi, Bar, PciDevice, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_MEMORY,
+    },
+    task::{
+        self,
+        sync::Mutex,
+        timer::{self, Sleep},
+    },
+    time::Instant,
+};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions;
+
+/// The BAR holding the adapter's registers.
+const ABAR: u8 = 5;
+const PORT_COUNT: usize = 32;
+
+const HBA_CONTROL: usize = 0x04;
+const HBA_INTERRUPT_STATUS: usize = 0x08;
+const HBA_PORTS_IMPLEMENTED: usize = 0x0c;
+const HBA_PORTS: usize = 0x100;
+const PORT_STRIDE: usize = 0x80;
+const REGISTERS_LEN: usize = HBA_PORTS + PORT_COUNT * PORT_STRIDE;
+
+const CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_FIS: usize = 0x08;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+/// Port interrupts for a register FIS, which ends DMA commands, and a PIO setup FIS, which ends IDENTIFY.
+const INTERRUPT_REGISTER_FIS: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP: u32 = 1 << 1;
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+const TASK_FILE_ERROR: u32 = 1 << 0;
+const TASK_FILE_DATA_REQUEST: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+const DETECTION_PRESENT: u32 = 3;
+const SIGNATURE_SATA: u32 = 0x0000_0101;
+
+/// Where the parts of the page of a port are, the command list needing 1 KiB alignment.
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+const PRDT_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+const FIS_DWORDS: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDT_LENGTH_SHIFT: u32 = 16;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_IDENTIFY: u8 = 0xec;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+
+/// Bytes a command transfers at most, through the bounce buffer.
+const BOUNCE_SIZE: usize = 64 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static HBA: OnceCell<Hba> = OnceCell::uninit();
+/// The interrupt status of every port, gathered by the interrupt handler for the command waiting on it.
+static COMPLETED: [AtomicU32; PORT_COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU32 = AtomicU32::new(0);
+    [NONE; PORT_COUNT]
+};
+/// The transfer waiting for the command of every port, woken by the interrupt handler.
+static WAKERS: [AtomicWaker; PORT_COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicWaker = AtomicWaker::new();
+    [NONE; PORT_COUNT]
+};
+static DISKS: spin::Mutex<Vec<AhciDisk>> = spin::Mutex::new(Vec::new());
+
+/// Claims an AHCI adapter and detects the disks on its ports.
+pub static DRIVER: Driver = Driver {
+    name: "ahci",
+    matches: &[Match::Class { class: 0x01, subclass: 0x06 }],
+    probe,
+};
+
+#[derive(Debug)]
+pub enum AhciError {
+    /// BAR 5 of the adapter is not a memory BAR.
+    NoRegisters,
+    Map(MmioError),
+    Dma(memory::dma::DmaError),
+    Irq(IrqError),
+    /// The port's task file reported an error, and this is its error register.
+    Device(u8),
+    /// The command didn't complete within `TIMEOUT`.
+    Timeout,
+    /// The request reaches past the end of the disk.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    BufferLength(usize),
+}
+
+struct Hba {
+    registers: MmioRegion,
+    ports: Vec<Port>,
+    /// Set if commands complete by interrupt.
+    interrupts: bool,
+}
+
+impl Hba {
+    fn port(&self, number: u8) -> &Port {
+        self.ports.iter().find(|port| port.number == number).expect("AHCI port without a disk")
+    }
+}
+
+struct Port {
+    number: u8,
+    /// Serializes the commands of the port, which all use command slot 0 and the one bounce buffer. Transfers
+    /// waiting for it are suspended while the one holding it waits for its interrupt.
+    memory: Mutex<PortMemory>,
+}
+
+struct PortMemory {
+    /// The command list, the received FIS area and the command table.
+    structures: DmaBuffer,
+    bounce: DmaBuffer,
+}
+
+/// A disk on an AHCI port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AhciDisk {
+    pub port: u8,
+    pub sectors: u64,
+    model: [u8; 40],
+}
+
+impl AhciDisk {
+    /// Returns the model name the disk reported, without the padding.
+    pub fn model(&self) -> &str {
+        model_name(&self.model)
+    }
+
+    /// Returns the name the disk is registered as a block device under, like `ahci0` for port 0.
+    pub fn name(&self) -> String {
+        format!("ahci{}", self.port)
+    }
+
+    /// Reads the sectors from `lba` on into `buf`, whose length must be a multiple of `SECTOR_SIZE`.
+    pub async fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buf.len())?;
+        let hba = HBA.try_get().expect("AHCI disk without an adapter");
+        let memory = hba.port(self.port).memory.lock().await;
+        for (index, chunk) in buf.chunks_mut(BOUNCE_SIZE).enumerate() {
+            let lba = lba + (index * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            hba.execute(self.port, &memory, CMD_READ_DMA_EXT, lba, chunk.len(), false).await?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(memory.bounce.as_mut_ptr::<u8>(), chunk.as_mut_ptr(), chunk.len())
+            };
+        }
+        Ok(())
+    }
+
+    /// Writes `buf`, whose length must be a multiple of `SECTOR_SIZE`, to the sectors from `lba` on.
+    pub async fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buf.len())?;
+        let hba = HBA.try_get().expect("AHCI disk without an adapter");
+        let memory = hba.port(self.port).memory.lock().await;
+        for (index, chunk) in buf.chunks(BOUNCE_SIZE).enumerate() {
+            let lba = lba + (index * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), memory.bounce.as_mut_ptr::<u8>(), chunk.len()) };
+            hba.execute(self.port, &memory, CMD_WRITE_DMA_EXT, lba, chunk.len(), true).await?;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), AhciError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AhciError::BufferLength(len));
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(AhciError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move { self.read_sectors(start, buf).await.map_err(BlockError::from) })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move { self.write_sectors(start, buf).await.map_err(BlockError::from) })
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(err: AhciError) -> BlockError {
+        match err {
+            AhciError::OutOfRange => BlockError::OutOfRange,
+            AhciError::BufferLength(len) => BlockError::BufferLength(len),
+            err => BlockError::Ahci(err),
+        }
+    }
+}
+
+/// Prints like `port 0: QEMU HARDDISK, 1048576 sectors`.
+impl fmt::Display for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "port {}: {}, {} sectors", self.port, self.model(), self.sectors)
+    }
+}
+
+/// Returns the disks found once `DRIVER` was bound to an adapter.
+pub fn disks() -> Vec<AhciDisk> {
+    DISKS.lock().clone()
+}
+
+impl Hba {
+    fn read(&self, offset: usize) -> u32 {
+        self.registers.read(offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.registers.write(offset, value)
+    }
+
+    fn read_port(&self, port: u8, register: usize) -> u32 {
+        self.read(HBA_PORTS + usize::from(port) * PORT_STRIDE + register)
+    }
+
+    fn write_port(&self, port: u8, register: usize, value: u32) {
+        self.write(HBA_PORTS + usize::from(port) * PORT_STRIDE + register, value)
+    }
+
+    /// Issues `command` for the `len` bytes at `lba` with the bounce buffer as data and waits for it to complete.
+    async fn execute(&self, port: u8, memory: &PortMemory, command: u8, lba: u64, len: usize, write: bool)
+        -> Result<(), AhciError>
+    {
+        let table = memory.structures.phys_addr().as_u64() + COMMAND_TABLE_OFFSET as u64;
+        let count = (len / SECTOR_SIZE) as u16;
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_HOST_TO_DEVICE;
+        fis[1] = FIS_COMMAND;
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+        fis[7] = DEVICE_LBA;
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+        let structures = &memory.structures;
+        for (index, dword) in fis.chunks_exact(4).enumerate() {
+            let dword = u32::from_le_bytes([dword[0], dword[1], dword[2], dword[3]]);
+            write_dma(structures, COMMAND_TABLE_OFFSET + index * 4, dword);
+        }
+        let bounce = memory.bounce.phys_addr().as_u64();
+        write_dma(structures, PRDT_OFFSET, bounce as u32);
+        write_dma(structures, PRDT_OFFSET + 4, (bounce >> 32) as u32);
+        write_dma(structures, PRDT_OFFSET + 12, len as u32 - 1);
+        // one PRDT entry covers the whole bounce buffer
+        let mut flags = FIS_DWORDS | 1 << HEADER_PRDT_LENGTH_SHIFT;
+        if write {
+            flags |= HEADER_WRITE;
+        }
+        write_dma(structures, COMMAND_LIST_OFFSET, flags);
+        write_dma(structures, COMMAND_LIST_OFFSET + 4, 0);
+        write_dma(structures, COMMAND_LIST_OFFSET + 8, table as u32);
+        write_dma(structures, COMMAND_LIST_OFFSET + 12, (table >> 32) as u32);
+
+        self.wait_idle(port)?;
+        COMPLETED[usize::from(port)].store(0, Ordering::Release);
+        self.write_port(port, PORT_COMMAND_ISSUE, 1);
+        let by_interrupt = self.interrupts && instructions::interrupts::are_enabled();
+        let result = Completion { hba: self, port, by_interrupt, timeout: timer::sleep(TIMEOUT) }.await;
+        if result.is_err() {
+            self.recover(port);
+        }
+        result
+    }
+
+    /// Waits until the device of `port` can take a command.
+    fn wait_idle(&self, port: u8) -> Result<(), AhciError> {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.read_port(port, PORT_TASK_FILE) & (TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST) != 0 {
+            if Instant::now() >= deadline {
+                return Err(AhciError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Restarts `port` after a failed command, which leaves the port stopped until that is done.
+    fn recover(&self, port: u8) {
+        let _ = self.stop(port);
+        self.write_port(port, PORT_SATA_ERROR, u32::MAX);
+        self.write_port(port, PORT_INTERRUPT_STATUS, u32::MAX);
+        self.start(port);
+    }
+
+    fn stop(&self, port: u8) -> Result<(), AhciError> {
+        let command = self.read_port(port, PORT_COMMAND);
+        self.write_port(port, PORT_COMMAND, command & !(COMMAND_START | COMMAND_FIS_RECEIVE));
+        let deadline = Instant::now() + TIMEOUT;
+        while self.read_port(port, PORT_COMMAND) & (COMMAND_LIST_RUNNING | COMMAND_FIS_RUNNING) != 0 {
+            if Instant::now() >= deadline {
+                return Err(AhciError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn start(&self, port: u8) {
+        let command = self.read_port(port, PORT_COMMAND);
+        self.write_port(port, PORT_COMMAND, command | COMMAND_FIS_RECEIVE);
+        self.write_port(port, PORT_COMMAND, command | COMMAND_FIS_RECEIVE | COMMAND_START);
+    }
+
+    /// Points `port` at freshly allocated structures and starts it.
+    fn set_up_port(&self, port: u8) -> Result<PortMemory, AhciError> {
+        self.stop(port)?;
+        let structures = memory::alloc_dma(4096, 4096).map_err(AhciError::Dma)?;
+        let bounce = match memory::alloc_dma(BOUNCE_SIZE, 4096) {
+            Ok(bounce) => bounce,
+            Err(err) => {
+                memory::free_dma(structures);
+                return Err(AhciError::Dma(err));
+            }
+        };
+        let base = structures.phys_addr().as_u64();
+        let fis = base + FIS_OFFSET as u64;
+        self.write_port(port, PORT_COMMAND_LIST, base as u32);
+        self.write_port(port, PORT_COMMAND_LIST + 4, (base >> 32) as u32);
+        self.write_port(port, PORT_FIS, fis as u32);
+        self.write_port(port, PORT_FIS + 4, (fis >> 32) as u32);
+        self.write_port(port, PORT_SATA_ERROR, u32::MAX);
+        self.write_port(port, PORT_INTERRUPT_STATUS, u32::MAX);
+        self.write_port(
+            port,
+            PORT_INTERRUPT_ENABLE,
+            INTERRUPT_REGISTER_FIS | INTERRUPT_PIO_SETUP | INTERRUPT_TASK_FILE_ERROR,
+        );
+        self.start(port);
+        Ok(PortMemory { structures, bounce })
+    }
+
+    /// Asks the disk on `port` for its size and model, polling since the adapter's interrupts are still off.
+    fn identify(&self, port: u8, memory: &PortMemory) -> Result<AhciDisk, AhciError> {
+        task::block_on(self.execute(port, memory, CMD_IDENTIFY, 0, SECTOR_SIZE, false))?;
+        let mut words = [0u16; 256];
+        for (index, word) in words.iter_mut().enumerate() {
+            *word = unsafe { memory.bounce.as_mut_ptr::<u16>().add(index).read_volatile() };
+        }
+        let sectors = (0..4).fold(0, |sectors, index| {
+            sectors | u64::from(words[IDENTIFY_LBA48_SECTORS + index]) << (16 * index)
+        });
+        Ok(AhciDisk { port, sectors, model: identify_model(&words) })
+    }
+}
+
+/// Resolves once command slot 0 of `port` completed, and checks for an error.
+///
+/// By interrupt, it is woken by the interrupt handler and by `timeout`. Otherwise it reads the port's interrupt
+/// status itself and wakes itself right away, so its task is polled again once others had their turn.
+struct Completion<'a> {
+    hba: &'a Hba,
+    port: u8,
+    by_interrupt: bool,
+    timeout: Sleep,
+}
+
+impl Future for Completion<'_> {
+    type Output = Result<(), AhciError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), AhciError>> {
+        let (hba, port) = (self.hba, self.port);
+        let status = if self.by_interrupt {
+            // registered before checking, so a completion in between still wakes the task
+            WAKERS[usize::from(port)].register(cx.waker());
+            COMPLETED[usize::from(port)].load(Ordering::Acquire)
+        } else {
+            let status = hba.read_port(port, PORT_INTERRUPT_STATUS);
+            hba.write_port(port, PORT_INTERRUPT_STATUS, status);
+            status
+        };
+        let task_file = hba.read_port(port, PORT_TASK_FILE);
+        if status & INTERRUPT_TASK_FILE_ERROR != 0 || task_file & TASK_FILE_ERROR != 0 {
+            return Poll::Ready(Err(AhciError::Device((task_file >> 8) as u8)));
+        }
+        if hba.read_port(port, PORT_COMMAND_ISSUE) & 1 == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if self.by_interrupt {
+            // the timer wheel keeps the deadline, which needs interrupts as well
+            if Pin::new(&mut self.timeout).poll(cx).is_ready() {
+                return Poll::Ready(Err(AhciError::Timeout));
+            }
+        } else if Instant::now() >= self.timeout.deadline() {
+            return Poll::Ready(Err(AhciError::Timeout));
+        } else {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+/// Writes a dword of a structure the adapter reads.
+fn write_dma(buffer: &DmaBuffer, offset: usize, value: u32) {
+    assert!(offset + 4 <= buffer.len());
+    unsafe { buffer.as_mut_ptr::<u8>().add(offset).cast::<u32>().write_volatile(value) }
+}
+
+/// Moves the interrupt status of every port to `COMPLETED`, acknowledges it and wakes the transfer waiting for it.
+fn handle_interrupt() {
+    let hba = match HBA.try_get() {
+        Ok(hba) => hba,
+        Err(_) => return,
+    };
+    let pending = hba.read(HBA_INTERRUPT_STATUS);
+    for port in (0..PORT_COUNT as u8).filter(|port| pending & 1 << port != 0) {
+        let status = hba.read_port(port, PORT_INTERRUPT_STATUS);
+        hba.write_port(port, PORT_INTERRUPT_STATUS, status);
+        COMPLETED[usize::from(port)].fetch_or(status, Ordering::AcqRel);
+        WAKERS[usize::from(port)].wake();
+    }
+    hba.write(HBA_INTERRUPT_STATUS, pending);
+}
+
+fn probe(device: &PciDevice) -> Result<(), ProbeError> {
+    if HBA.try_get().is_ok() {
+        return Err(ProbeError::Failed("only one AHCI adapter is supported"));
+    }
+    match init(device) {
+        Ok(disks) => {
+            for disk in disks.iter() {
+                crate::info!("ahci: {} is {}", disk.name(), disk);
+                if let Err(err) = super::attach(&disk.name(), Arc::new(*disk)) {
+                    crate::warn!("ahci: {} not registered: {:?}", disk.name(), err);
+                }
+            }
+            DISKS.lock().extend(disks);
+            Ok(())
+        }
+        Err(err) => {
+            crate::warn!("ahci: adapter {} unusable: {:?}", device.address, err);
+            Err(ProbeError::Failed("AHCI setup failed"))
+        }
+    }
+}
+
+fn init(device: &PciDevice) -> Result<Vec<AhciDisk>, AhciError> {
+    let abar = match device.bars[usize::from(ABAR)] {
+        Some(Bar::Memory { addr, .. }) => addr,
+        _ => return Err(AhciError::NoRegisters),
+    };
+    let registers = memory::map_mmio(abar, REGISTERS_LEN).map_err(AhciError::Map)?;
+    let address = device.address;
+    address.set_command(address.command() | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    registers.write::<u32>(HBA_CONTROL, registers.read::<u32>(HBA_CONTROL) | CONTROL_AHCI_ENABLE);
+
+    let mut hba = Hba { registers, ports: Vec::new(), interrupts: false };
+    let implemented = hba.read(HBA_PORTS_IMPLEMENTED);
+    let mut disks = Vec::new();
+    for port in (0..PORT_COUNT as u8).filter(|port| implemented & 1 << port != 0) {
+        let present = hba.read_port(port, PORT_SATA_STATUS) & 0xf == DETECTION_PRESENT;
+        if !present || hba.read_port(port, PORT_SIGNATURE) != SIGNATURE_SATA {
+            continue;
+        }
+        let memory = hba.set_up_port(port)?;
+        match hba.identify(port, &memory) {
+            Ok(disk) => {
+                disks.push(disk);
+                hba.ports.push(Port { number: port, memory: Mutex::new(memory) });
+            }
+            Err(err) => {
+                crate::warn!("ahci: port {} didn't identify: {:?}", port, err);
+                let _ = hba.stop(port);
+                memory::free_dma(memory.structures);
+                memory::free_dma(memory.bounce);
+            }
+        }
+    }
+
+    // the handler finds the adapter through `HBA`, so it is only enabled once that is set
+    hba.interrupts = match msi::enable_msi(address, handle_interrupt) {
+        Ok(_) => true,
+        Err(_) => match device.interrupt_line {
+            Some(line) => {
+                interrupts::register_irq(line, handle_interrupt).map_err(AhciError::Irq)?;
+                address.set_command(address.command() & !COMMAND_INTX_DISABLE);
+                true
+            }
+            None => false,
+        },
+    };
+    HBA.try_init_once(|| hba).expect("AHCI adapter initialized twice");
+    let hba = HBA.try_get().unwrap();
+    if hba.interrupts {
+        hba.write(HBA_INTERRUPT_STATUS, u32::MAX);
+        hba.write(HBA_CONTROL, hba.read(HBA_CONTROL) | CONTROL_INTERRUPT_ENABLE);
+    }
+    Ok(disks)
+}
+
+#[test_case]
+fn test_requests_are_checked() {
+    let disk = AhciDisk { port: 0, sectors: 8, model: [b' '; 40] };
+    assert!(disk.check_range(7, SECTOR_SIZE).is_ok());
+    assert!(matches!(disk.check_range(7, 2 * SECTOR_SIZE), Err(AhciError::OutOfRange)));
+    assert!(matches!(disk.check_range(u64::MAX, SECTOR_SIZE), Err(AhciError::OutOfRange)));
+    assert!(matches!(disk.check_range(0, 1), Err(AhciError::BufferLength(1))));
+}
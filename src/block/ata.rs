@@ -0,0 +1,509 @@
+//! The legacy ATA interface of IDE controllers, driven by programmed I/O.
+//!
+//! Every sector goes through the data port one word at a time, so this is slow, but it works on every PC and
+//! emulator without any setup. Commands use 28-bit LBAs, which reach the first 128 GiB of a disk. A channel
+//! waits for its drives by polling the status register, or for their interrupt once `enable_interrupts` was
+//! called for it and interrupts are enabled.
+
+use super::{BlockDevice, BlockError, BlockFuture};
+use crate::{
+    interrupts::{self, irq::IrqError},
+    pci::{
+        driver::{Driver, Match, ProbeError},
+        PciDevice,
+    },
+    time::Instant,
+};
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+use core::{
+    convert::TryFrom,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::{self, port::Port};
+
+pub const SECTOR_SIZE: usize = 512;
+/// The most sectors one command transfers, a sector count of 0 meaning 256.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// The first sector 28-bit LBAs can't address.
+pub const LBA28_LIMIT: u64 = 1 << 28;
+
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// The status register when read, the command register when written.
+const REG_STATUS_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+/// Read from a channel without drives, whose lines float high.
+const STATUS_FLOATING: u8 = 0xff;
+
+/// Device control bit that keeps the drives from raising interrupts.
+const CONTROL_INTERRUPTS_DISABLED: u8 = 1 << 1;
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_FLUSH_CACHE: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+
+/// IDENTIFY words holding the model name, the 28-bit sector count and the command sets supported.
+const IDENTIFY_MODEL: core::ops::Range<usize> = 27..47;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_COMMAND_SETS: usize = 83;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+
+/// PCI programming interface bits set for channels in native mode, whose ports are in the BARs instead.
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+
+/// How long a drive may stay busy, long enough for a disk to spin up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serializes the commands on each channel, whose two drives share the registers.
+static CHANNELS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+/// Whether a drive on each channel raised an interrupt since the last command was sent.
+static INTERRUPTED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static INTERRUPTS_ENABLED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// The disks `DRIVER` found.
+static DISKS: Mutex<Vec<AtaDisk>> = Mutex::new(Vec::new());
+
+/// Claims IDE controllers whose channels both use the legacy ports and detects their disks.
+pub static DRIVER: Driver = Driver {
+    name: "ata",
+    matches: &[Match::Class { class: 0x01, subclass: 0x01 }],
+    probe,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// The drive set its error bit, and this is its error register.
+    Device(u8),
+    /// The drive reported a fault it can't recover from.
+    DeviceFault,
+    /// The drive stayed busy for longer than `TIMEOUT`.
+    Timeout,
+    /// The request reaches past the end of the disk or the 28-bit LBAs.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    BufferLength(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+impl Channel {
+    pub fn base(&self) -> u16 {
+        match self {
+            Channel::Primary => 0x1f0,
+            Channel::Secondary => 0x170,
+        }
+    }
+
+    /// Returns the device control port, which reads as the alternate status register.
+    pub fn control(&self) -> u16 {
+        match self {
+            Channel::Primary => 0x3f6,
+            Channel::Secondary => 0x376,
+        }
+    }
+
+    pub fn irq(&self) -> u8 {
+        match self {
+            Channel::Primary => 14,
+            Channel::Secondary => 15,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base() + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base() + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt, as reading `REG_STATUS_COMMAND` would.
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control()).read() }
+    }
+
+    /// Gives the drive the 400 ns it may take to update its status after a command or a drive selection.
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Waits until the selected drive is no longer busy and returns its status.
+    fn wait_not_busy(&self) -> Result<u8, AtaError> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                return Err(AtaError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits until the selected drive has the next sector to transfer, by interrupt if `interrupt` is set and
+    /// interrupts can be used.
+    fn wait_for_data(&self, interrupt: bool) -> Result<(), AtaError> {
+        let enabled = INTERRUPTS_ENABLED[self.index()].load(Ordering::Acquire);
+        if interrupt && enabled && instructions::interrupts::are_enabled() {
+            let deadline = Instant::now() + TIMEOUT;
+            while !INTERRUPTED[self.index()].swap(false, Ordering::AcqRel) {
+                if Instant::now() >= deadline {
+                    return Err(AtaError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+        }
+        let status = self.wait_not_busy()?;
+        self.check(status)?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            // neither busy nor ready to transfer, the drive gave up on the command without saying why
+            return Err(AtaError::Device(self.read(REG_ERROR)));
+        }
+        Ok(())
+    }
+
+    fn check(&self, status: u8) -> Result<(), AtaError> {
+        if status & STATUS_DEVICE_FAULT != 0 {
+            Err(AtaError::DeviceFault)
+        } else if status & STATUS_ERROR != 0 {
+            Err(AtaError::Device(self.read(REG_ERROR)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends `command` for the `count` sectors starting at `lba` of `drive`. A `count` of 256 is sent as 0.
+    fn command(&self, drive: Drive, command: u8, lba: u32, count: usize) {
+        INTERRUPTED[self.index()].store(false, Ordering::Release);
+        self.write(REG_DRIVE, DRIVE_LBA | drive.select_bit() | (lba >> 24) as u8 & 0x0f);
+        self.settle();
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.write(REG_STATUS_COMMAND, command);
+        self.settle();
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Channel::Primary => "primary",
+            Channel::Secondary => "secondary",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    fn select_bit(&self) -> u8 {
+        match self {
+            Drive::Master => 0,
+            Drive::Slave => DRIVE_SLAVE,
+        }
+    }
+}
+
+/// A disk found by `identify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtaDisk {
+    pub channel: Channel,
+    pub drive: Drive,
+    /// The number of sectors reachable with 28-bit LBAs.
+    pub sectors: u32,
+    /// Whether the disk supports 48-bit LBAs, which this driver doesn't use.
+    pub lba48: bool,
+    model: [u8; 40],
+}
+
+impl AtaDisk {
+    /// Returns the model name the disk reported, without the padding.
+    pub fn model(&self) -> &str {
+        model_name(&self.model)
+    }
+
+    /// Returns the name the disk is registered as a block device under, `ata0` to `ata3` in the order primary
+    /// master, primary slave, secondary master, secondary slave.
+    pub fn name(&self) -> alloc::string::String {
+        format!("ata{}", self.channel.index() * 2 + self.drive as usize)
+    }
+
+    /// Reads the sectors from `lba` on into `buf`, whose length must be a multiple of `SECTOR_SIZE`.
+    pub fn read_sectors(&self, lba: u32, buf: &mut [u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buf.len())?;
+        let _channel = CHANNELS[self.channel.index()].lock();
+        for (index, chunk) in buf.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u32;
+            self.channel.wait_not_busy()?;
+            self.channel.command(self.drive, CMD_READ_SECTORS, lba, chunk.len() / SECTOR_SIZE);
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                self.channel.wait_for_data(true)?;
+                let mut data = Port::<u16>::new(self.channel.base() + REG_DATA);
+                for word in sector.chunks_exact_mut(2) {
+                    word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `buf`, whose length must be a multiple of `SECTOR_SIZE`, to the sectors from `lba` on and flushes
+    /// the disk's write cache.
+    pub fn write_sectors(&self, lba: u32, buf: &[u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buf.len())?;
+        let _channel = CHANNELS[self.channel.index()].lock();
+        for (index, chunk) in buf.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u32;
+            self.channel.wait_not_busy()?;
+            self.channel.command(self.drive, CMD_WRITE_SECTORS, lba, chunk.len() / SECTOR_SIZE);
+            for (sector_index, sector) in chunk.chunks_exact(SECTOR_SIZE).enumerate() {
+                // the interrupts come after each sector, so the first one is waited for by polling
+                self.channel.wait_for_data(sector_index > 0)?;
+                let mut data = Port::<u16>::new(self.channel.base() + REG_DATA);
+                for word in sector.chunks_exact(2) {
+                    unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+                }
+            }
+        }
+        self.channel.wait_not_busy()?;
+        self.channel.command(self.drive, CMD_FLUSH_CACHE, 0, 0);
+        let status = self.channel.wait_not_busy()?;
+        self.channel.check(status)
+    }
+
+    fn check_range(&self, lba: u32, len: usize) -> Result<(), AtaError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::BufferLength(len));
+        }
+        let end = u64::from(lba) + (len / SECTOR_SIZE) as u64;
+        if end > u64::from(self.sectors) || end > LBA28_LIMIT {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+/// Transfers complete before they are first polled, since the driver waits for the drive in place.
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        u64::from(self.sectors)
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let lba = u32::try_from(start).map_err(|_| BlockError::OutOfRange)?;
+            self.read_sectors(lba, buf).map_err(BlockError::from)
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            let lba = u32::try_from(start).map_err(|_| BlockError::OutOfRange)?;
+            self.write_sectors(lba, buf).map_err(BlockError::from)
+        })
+    }
+}
+
+impl From<AtaError> for BlockError {
+    fn from(err: AtaError) -> BlockError {
+        match err {
+            AtaError::OutOfRange => BlockError::OutOfRange,
+            AtaError::BufferLength(len) => BlockError::BufferLength(len),
+            err => BlockError::Ata(err),
+        }
+    }
+}
+
+/// Prints like `primary master: QEMU HARDDISK, 1048576 sectors`.
+impl fmt::Display for AtaDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let drive = match self.drive {
+            Drive::Master => "master",
+            Drive::Slave => "slave",
+        };
+        write!(f, "{} {}: {}, {} sectors", self.channel, drive, self.model(), self.sectors)
+    }
+}
+
+/// Asks `drive` on `channel` to identify itself, returning `None` if there is no ATA disk there.
+///
+/// Drives that answer like ATAPI or SATA devices, such as CD drives, are left out.
+pub fn identify(channel: Channel, drive: Drive) -> Result<Option<AtaDisk>, AtaError> {
+    let _channel = CHANNELS[channel.index()].lock();
+    if channel.alternate_status() == STATUS_FLOATING {
+        return Ok(None);
+    }
+    channel.command(drive, CMD_IDENTIFY, 0, 0);
+    if channel.read(REG_STATUS_COMMAND) == 0 {
+        return Ok(None);
+    }
+    let status = channel.wait_not_busy()?;
+    // packet devices put their signature into the LBA registers instead of answering
+    if channel.read(REG_LBA_MID) != 0 || channel.read(REG_LBA_HIGH) != 0 || status & STATUS_ERROR != 0 {
+        return Ok(None);
+    }
+    channel.wait_for_data(false)?;
+    let mut words = [0u16; 256];
+    let mut data = Port::<u16>::new(channel.base() + REG_DATA);
+    for word in words.iter_mut() {
+        *word = unsafe { data.read() };
+    }
+    // finish the interrupt the command raised, so it doesn't count for the next one
+    channel.read(REG_STATUS_COMMAND);
+    INTERRUPTED[channel.index()].store(false, Ordering::Release);
+    Ok(Some(disk_from_identify(channel, drive, &words)))
+}
+
+/// Identifies the drives of both channels.
+pub fn detect() -> Vec<AtaDisk> {
+    let mut disks = Vec::new();
+    for &channel in [Channel::Primary, Channel::Secondary].iter() {
+        for &drive in [Drive::Master, Drive::Slave].iter() {
+            if let Ok(Some(disk)) = identify(channel, drive) {
+                disks.push(disk);
+            }
+        }
+    }
+    disks
+}
+
+/// Returns the disks found once `DRIVER` was bound to an IDE controller.
+pub fn disks() -> Vec<AtaDisk> {
+    DISKS.lock().clone()
+}
+
+/// Makes the drives of `channel` signal completed transfers by interrupt, which saves polling between sectors.
+pub fn enable_interrupts(channel: Channel) -> Result<(), IrqError> {
+    interrupts::register_irq(channel.irq(), interrupt_handler(channel))?;
+    let _channel = CHANNELS[channel.index()].lock();
+    unsafe { Port::<u8>::new(channel.control()).write(0) };
+    INTERRUPTS_ENABLED[channel.index()].store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Makes the drives of `channel` stop raising interrupts, so transfers are polled again.
+pub fn disable_interrupts(channel: Channel) -> Result<(), IrqError> {
+    {
+        let _channel = CHANNELS[channel.index()].lock();
+        unsafe { Port::<u8>::new(channel.control()).write(CONTROL_INTERRUPTS_DISABLED) };
+        INTERRUPTS_ENABLED[channel.index()].store(false, Ordering::Release);
+    }
+    interrupts::unregister_irq(channel.irq(), interrupt_handler(channel))
+}
+
+fn interrupt_handler(channel: Channel) -> fn() {
+    match channel {
+        Channel::Primary => handle_primary_interrupt,
+        Channel::Secondary => handle_secondary_interrupt,
+    }
+}
+
+fn handle_primary_interrupt() {
+    handle_interrupt(Channel::Primary);
+}
+
+fn handle_secondary_interrupt() {
+    handle_interrupt(Channel::Secondary);
+}
+
+/// Acknowledges the interrupt by reading the status register, and lets the waiting transfer go on.
+fn handle_interrupt(channel: Channel) {
+    channel.read(REG_STATUS_COMMAND);
+    INTERRUPTED[channel.index()].store(true, Ordering::Release);
+}
+
+fn probe(device: &PciDevice) -> Result<(), ProbeError> {
+    if device.prog_if & (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE) != 0 {
+        return Err(ProbeError::Unsupported);
+    }
+    let disks = detect();
+    for disk in disks.iter() {
+        crate::info!("ata: {} is {}", disk.name(), disk);
+        if let Err(err) = super::attach(&disk.name(), Arc::new(*disk)) {
+            crate::warn!("ata: {} not registered: {:?}", disk.name(), err);
+        }
+    }
+    DISKS.lock().extend(disks);
+    Ok(())
+}
+
+fn disk_from_identify(channel: Channel, drive: Drive, words: &[u16; 256]) -> AtaDisk {
+    AtaDisk {
+        channel,
+        drive,
+        sectors: u32::from(words[IDENTIFY_LBA28_SECTORS]) | u32::from(words[IDENTIFY_LBA28_SECTORS + 1]) << 16,
+        lba48: words[IDENTIFY_COMMAND_SETS] & COMMAND_SET_LBA48 != 0,
+        model: identify_model(words),
+    }
+}
+
+/// Returns the model name of IDENTIFY data, which is ASCII with the two bytes of every word swapped.
+pub(super) fn identify_model(words: &[u16; 256]) -> [u8; 40] {
+    let mut model = [0u8; 40];
+    for (bytes, word) in model.chunks_exact_mut(2).zip(words[IDENTIFY_MODEL].iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    model
+}
+
+/// Returns the model name `identify_model` returned, without the padding.
+pub(super) fn model_name(model: &[u8; 40]) -> &str {
+    core::str::from_utf8(model).unwrap_or("").trim()
+}
+
+#[test_case]
+fn test_identify_is_decoded() {
+    let mut words = [0u16; 256];
+    let mut model = [b' '; 40];
+    model[..13].copy_from_slice(b"QEMU HARDDISK");
+    for (word, pair) in words[IDENTIFY_MODEL].iter_mut().zip(model.chunks_exact(2)) {
+        *word = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    words[IDENTIFY_LBA28_SECTORS] = 0x0000;
+    words[IDENTIFY_LBA28_SECTORS + 1] = 0x0010;
+    words[IDENTIFY_COMMAND_SETS] = COMMAND_SET_LBA48;
+    let disk = disk_from_identify(Channel::Primary, Drive::Master, &words);
+    assert_eq!(disk.model(), "QEMU HARDDISK");
+    assert_eq!(disk.sectors, 0x10_0000);
+    assert!(disk.lba48);
+    assert_eq!(disk.check_range(0x10_0000 - 1, SECTOR_SIZE), Ok(()));
+    assert_eq!(disk.check_range(0x10_0000, SECTOR_SIZE), Err(AtaError::OutOfRange));
+    assert_eq!(disk.check_range(0, 100), Err(AtaError::BufferLength(100)));
+}
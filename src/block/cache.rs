@@ -0,0 +1,257 @@
+//! A write-back cache of recently used blocks in front of a block device.
+//!
+//! Reads are served from memory when they can be and writes only mark the cached blocks dirty. The least
+//! recently used block makes room when the cache is full, written out first if it is dirty. Dirty blocks reach
+//! the device on `flush`, on `sync`, which flushes every cache, or when `write_back_periodically` gets to them.
+//! Transfers larger than the whole cache go to the device directly, so they don't push out everything else.
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use crate::task::{sync::Mutex, timer};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Every cache created, to write them all back in `sync`.
+static CACHES: spin::Mutex<Vec<Weak<BlockCache>>> = spin::Mutex::new(Vec::new());
+
+/// A `BlockDevice` that caches the blocks of another one.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    /// The most blocks kept in memory.
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct State {
+    blocks: BTreeMap<u64, Entry>,
+    /// Counts accesses, to tell which block was used least recently.
+    clock: u64,
+}
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks read from the cache.
+    pub hits: u64,
+    /// Blocks that had to be read from the device.
+    pub misses: u64,
+    pub cached: usize,
+    /// Cached blocks not written back yet.
+    pub dirty: usize,
+}
+
+impl BlockCache {
+    /// Puts a cache of up to `capacity` blocks in front of `device` and adds it to those `sync` writes back.
+    /// Needs the heap.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<BlockCache> {
+        assert!(capacity > 0, "block cache without capacity");
+        let cache = Arc::new(BlockCache {
+            device,
+            capacity,
+            state: Mutex::new(State { blocks: BTreeMap::new(), clock: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        cache
+    }
+
+    /// Returns the device the cache is in front of.
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    /// Returns the counters of the cache, or `None` if it is in use right now.
+    pub fn stats(&self) -> Option<CacheStats> {
+        let state = self.state.try_lock()?;
+        Some(CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            cached: state.blocks.len(),
+            dirty: state.blocks.values().filter(|entry| entry.dirty).count(),
+        })
+    }
+
+    /// Writes every dirty block to the device, without flushing the device itself.
+    async fn write_back(&self, state: &mut State) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        let dirty: Vec<u64> = state.blocks.iter().filter(|(_, entry)| entry.dirty).map(|(&block, _)| block).collect();
+        // consecutive blocks go out in one transfer
+        let mut index = 0;
+        while index < dirty.len() {
+            let start = dirty[index];
+            let run = dirty[index..].iter().enumerate().take_while(|&(i, &block)| block == start + i as u64).count();
+            let mut buf = Vec::with_capacity(run * block_size);
+            for block in start..start + run as u64 {
+                buf.extend_from_slice(&state.blocks[&block].data);
+            }
+            self.device.write_blocks(start, &buf).await?;
+            for block in start..start + run as u64 {
+                if let Some(entry) = state.blocks.get_mut(&block) {
+                    entry.dirty = false;
+                }
+            }
+            index += run;
+        }
+        Ok(())
+    }
+
+    /// Adds `data` as block `block`, first evicting the least recently used block if the cache is full.
+    async fn insert(&self, state: &mut State, block: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        state.clock += 1;
+        let last_used = state.clock;
+        if let Some(entry) = state.blocks.get_mut(&block) {
+            entry.data.copy_from_slice(data);
+            entry.dirty |= dirty;
+            entry.last_used = last_used;
+            return Ok(());
+        }
+        if state.blocks.len() >= self.capacity {
+            let oldest = state.blocks.iter().min_by_key(|(_, entry)| entry.last_used).map(|(&block, _)| block);
+            if let Some(oldest) = oldest {
+                if state.blocks[&oldest].dirty {
+                    self.device.write_blocks(oldest, &state.blocks[&oldest].data).await?;
+                }
+                state.blocks.remove(&oldest);
+            }
+        }
+        state.blocks.insert(block, Entry { data: data.into(), dirty, last_used });
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            let block_size = self.block_size();
+            let count = buf.len() / block_size;
+            let mut state = self.state.lock().await;
+            if count > self.capacity {
+                self.device.read_blocks(start, buf).await?;
+                // cached blocks may be newer than what the device has
+                for (&block, entry) in state.blocks.range(start..start + count as u64) {
+                    let offset = (block - start) as usize * block_size;
+                    buf[offset..offset + block_size].copy_from_slice(&entry.data);
+                }
+                self.misses.fetch_add(count as u64, Ordering::Relaxed);
+                return Ok(());
+            }
+            let mut index = 0;
+            while index < count {
+                let block = start + index as u64;
+                let chunk = &mut buf[index * block_size..(index + 1) * block_size];
+                state.clock += 1;
+                let clock = state.clock;
+                if let Some(entry) = state.blocks.get_mut(&block) {
+                    chunk.copy_from_slice(&entry.data);
+                    entry.last_used = clock;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    index += 1;
+                    continue;
+                }
+                // read the whole run of blocks that aren't cached at once
+                let run = (index..count).take_while(|&i| !state.blocks.contains_key(&(start + i as u64))).count();
+                let missing = &mut buf[index * block_size..(index + run) * block_size];
+                self.device.read_blocks(block, missing).await?;
+                for i in 0..run {
+                    let data = &missing[i * block_size..(i + 1) * block_size];
+                    self.insert(&mut state, block + i as u64, data, false).await?;
+                }
+                self.misses.fetch_add(run as u64, Ordering::Relaxed);
+                index += run;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            if self.is_read_only() {
+                return Err(BlockError::ReadOnly);
+            }
+            check_request(self, start, buf.len())?;
+            let block_size = self.block_size();
+            let count = buf.len() / block_size;
+            let mut state = self.state.lock().await;
+            if count > self.capacity {
+                self.device.write_blocks(start, buf).await?;
+                for (&block, entry) in state.blocks.range_mut(start..start + count as u64) {
+                    let offset = (block - start) as usize * block_size;
+                    entry.data.copy_from_slice(&buf[offset..offset + block_size]);
+                    entry.dirty = false;
+                }
+                return Ok(());
+            }
+            for (index, data) in buf.chunks_exact(block_size).enumerate() {
+                self.insert(&mut state, start + index as u64, data, true).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes the dirty blocks back and flushes the device.
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            self.write_back(&mut state).await?;
+            self.device.flush().await
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+/// Flushes every cache, so everything written so far is on the medium. Needs the heap.
+///
+/// Goes on with the other caches if one fails and returns the first error.
+pub async fn sync() -> Result<(), BlockError> {
+    let caches: Vec<Arc<BlockCache>> = CACHES.lock().iter().filter_map(Weak::upgrade).collect();
+    let mut result = Ok(());
+    for cache in caches {
+        if let Err(err) = cache.flush().await {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+/// Calls `sync` every `period`, so what is written doesn't stay in memory for long. Meant to be spawned.
+pub async fn write_back_periodically(period: Duration) {
+    let mut interval = timer::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = sync().await {
+            crate::warn!("block cache write-back failed: {:?}", err);
+        }
+    }
+}
+
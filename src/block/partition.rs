@@ -0,0 +1,276 @@
+//! MBR and GPT partition tables, and the partitions in them as block devices of their own.
+//!
+//! `read_partitions` finds the partitions of a disk, the four primary ones of an MBR and the logical ones in its
+//! extended partition, or the entries of a GPT if the MBR only protects one. `Partition` turns each into a
+//! `BlockDevice` that covers just its blocks, which `block::attach` registers next to the disk.
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use crate::bytes::read_at;
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+/// The bytes an MBR ends with. The top of the boot sector is the same on disks of any block size.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// Partition types of the MBR that mean something other than data.
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// The most logical partitions followed through the extended partition, whose links could form a loop.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_BLOCK: u64 = 1;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// The most entries read, which covers the 128 every GPT tool creates.
+const GPT_MAX_ENTRIES: usize = 256;
+const GPT_NAME_OFFSET: usize = 56;
+const GPT_NAME_LEN: usize = 36;
+
+/// What a partition holds, as its table records it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID of an MBR entry, like 0x0c for FAT32 or 0x83 for Linux.
+    Mbr(u8),
+    /// The type GUID of a GPT entry, in the byte order it is stored in.
+    Gpt([u8; 16]),
+}
+
+/// An entry of a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionEntry {
+    /// The number in the partition's name: 1 to 4 for primary MBR partitions, 5 on for logical ones, and the
+    /// entry index plus one in a GPT.
+    pub number: usize,
+    pub kind: PartitionKind,
+    /// The first block of the partition.
+    pub start: u64,
+    pub block_count: u64,
+    /// The name a GPT gives the partition, empty for MBR partitions.
+    pub name: String,
+}
+
+impl fmt::Display for PartitionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            PartitionKind::Mbr(system_id) => write!(f, "type {:02x}", system_id)?,
+            PartitionKind::Gpt(_) if !self.name.is_empty() => write!(f, "\"{}\"", self.name)?,
+            PartitionKind::Gpt(_) => write!(f, "GPT")?,
+        }
+        write!(f, ", {} blocks from {}", self.block_count, self.start)
+    }
+}
+
+/// The blocks of a disk one partition covers, as a block device.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    block_count: u64,
+}
+
+impl Partition {
+    /// Covers `entry` of the partition table of `device`, which must lie within it.
+    pub fn new(device: Arc<dyn BlockDevice>, entry: &PartitionEntry) -> Partition {
+        assert!(fits(&*device, entry), "partition beyond the end of the disk");
+        Partition { device, start: entry.start, block_count: entry.block_count }
+    }
+
+    /// Returns the block of the disk the partition starts at.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            self.device.read_blocks(self.start + start, buf).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            self.device.write_blocks(self.start + start, buf).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        self.device.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+/// Reads the partition table of `device`, returning its partitions by number. Needs the heap.
+///
+/// A disk without a table or with one that makes no sense has no partitions. Entries that reach past the end of
+/// the disk are left out. The CRCs of a GPT aren't checked, a GPT with the right signature is trusted.
+pub async fn read_partitions(device: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, BlockError> {
+    if device.block_size() < 512 || device.block_count() == 0 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_block(device, 0).await?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let primary: Vec<MbrEntry> = (0..4).filter_map(|slot| MbrEntry::parse(&mbr, slot)).collect();
+    let mut partitions = if primary.iter().any(|entry| entry.system_id == MBR_TYPE_GPT_PROTECTIVE) {
+        read_gpt(device).await?
+    } else {
+        read_mbr(device, &primary).await?
+    };
+    partitions.retain(|entry| fits(device, entry));
+    Ok(partitions)
+}
+
+/// A used entry of an MBR or EBR.
+#[derive(Clone, Copy)]
+struct MbrEntry {
+    /// The slot of the entry, 0 to 3.
+    slot: usize,
+    system_id: u8,
+    /// Relative to the table for primary partitions and logical ones, relative to the extended partition for the
+    /// links between EBRs.
+    start: u64,
+    block_count: u64,
+}
+
+impl MbrEntry {
+    fn parse(table: &[u8], slot: usize) -> Option<MbrEntry> {
+        let entry = &table[MBR_ENTRIES_OFFSET + slot * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let system_id = entry[4];
+        let start = u64::from(read_at::<u32>(entry, 8)?);
+        let block_count = u64::from(read_at::<u32>(entry, 12)?);
+        if system_id == MBR_TYPE_EMPTY || block_count == 0 {
+            return None;
+        }
+        Some(MbrEntry { slot, system_id, start, block_count })
+    }
+
+    fn is_extended(&self) -> bool {
+        MBR_TYPES_EXTENDED.contains(&self.system_id)
+    }
+}
+
+/// Lists the primary partitions, then follows the chain of EBRs of the extended partition for the logical ones.
+async fn read_mbr(device: &dyn BlockDevice, primary: &[MbrEntry]) -> Result<Vec<PartitionEntry>, BlockError> {
+    let mut partitions: Vec<PartitionEntry> = primary
+        .iter()
+        .filter(|entry| !entry.is_extended())
+        .map(|entry| PartitionEntry {
+            number: entry.slot + 1,
+            kind: PartitionKind::Mbr(entry.system_id),
+            start: entry.start,
+            block_count: entry.block_count,
+            name: String::new(),
+        })
+        .collect();
+    let extended = match primary.iter().find(|entry| entry.is_extended()) {
+        Some(extended) => extended.start,
+        None => return Ok(partitions),
+    };
+    let mut ebr = extended;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        if ebr >= device.block_count() {
+            break;
+        }
+        let table = read_block(device, ebr).await?;
+        if table[510..512] != MBR_SIGNATURE {
+            break;
+        }
+        if let Some(logical) = MbrEntry::parse(&table, 0) {
+            partitions.push(PartitionEntry {
+                number,
+                kind: PartitionKind::Mbr(logical.system_id),
+                start: ebr + logical.start,
+                block_count: logical.block_count,
+                name: String::new(),
+            });
+        }
+        match MbrEntry::parse(&table, 1).filter(MbrEntry::is_extended) {
+            Some(next) if extended + next.start > ebr => ebr = extended + next.start,
+            // a link back would loop
+            _ => break,
+        }
+    }
+    Ok(partitions)
+}
+
+async fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, BlockError> {
+    if device.block_count() <= GPT_HEADER_BLOCK {
+        return Ok(Vec::new());
+    }
+    let header = read_block(device, GPT_HEADER_BLOCK).await?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let (entries_start, entry_count, entry_size) = match (
+        read_at::<u64>(&header, 72),
+        read_at::<u32>(&header, 80),
+        read_at::<u32>(&header, 84),
+    ) {
+        (Some(start), Some(count), Some(size)) => (start, count as usize, size as usize),
+        _ => return Ok(Vec::new()),
+    };
+    if entry_size < GPT_MIN_ENTRY_SIZE || !entry_size.is_power_of_two() {
+        return Ok(Vec::new());
+    }
+    let entry_count = entry_count.min(GPT_MAX_ENTRIES);
+    let block_size = device.block_size();
+    let blocks = ((entry_count * entry_size + block_size - 1) / block_size) as u64;
+    if entries_start.checked_add(blocks).map_or(true, |end| end > device.block_count()) {
+        return Ok(Vec::new());
+    }
+    let mut entries = vec![0; blocks as usize * block_size];
+    device.read_blocks(entries_start, &mut entries).await?;
+    let partitions = entries
+        .chunks_exact(entry_size)
+        .take(entry_count)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let mut type_guid = [0; 16];
+            type_guid.copy_from_slice(&entry[..16]);
+            let first = read_at::<u64>(entry, 32)?;
+            let last = read_at::<u64>(entry, 40)?;
+            if type_guid == [0; 16] || last < first {
+                return None;
+            }
+            let name: Vec<u16> =
+                (0..GPT_NAME_LEN).filter_map(|i| read_at::<u16>(entry, GPT_NAME_OFFSET + i * 2)).collect();
+            let name = char::decode_utf16(name.into_iter().take_while(|&unit| unit != 0))
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+            Some(PartitionEntry {
+                number: index + 1,
+                kind: PartitionKind::Gpt(type_guid),
+                start: first,
+                block_count: last - first + 1,
+                name,
+            })
+        })
+        .collect();
+    Ok(partitions)
+}
+
+fn fits(device: &dyn BlockDevice, entry: &PartitionEntry) -> bool {
+    entry.start.checked_add(entry.block_count).map_or(false, |end| end <= device.block_count())
+}
+
+async fn read_block(device: &dyn BlockDevice, block: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0; device.block_size()];
+    device.read_blocks(block, &mut buf).await?;
+    Ok(buf)
+}
@@ -0,0 +1,65 @@
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::{boxed::Box, vec, vec::Vec};
+use spin::Mutex;
+
+/// A block device in memory, for tests and for disk images loaded some other way.
+pub struct RamDisk {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `block_count` blocks of `block_size` bytes. Needs the heap.
+    pub fn new(block_size: usize, block_count: usize) -> RamDisk {
+        RamDisk::from_bytes(block_size, vec![0; block_size * block_count])
+    }
+
+    /// Creates a disk holding `data`, which must be a whole number of blocks.
+    pub fn from_bytes(block_size: usize, data: Vec<u8>) -> RamDisk {
+        assert!(block_size.is_power_of_two(), "block size not a power of two");
+        assert_eq!(data.len() % block_size, 0, "RAM disk not a whole number of blocks");
+        RamDisk { block_size, data: Mutex::new(data), read_only: false }
+    }
+
+    /// Makes writes fail with `BlockError::ReadOnly`.
+    pub fn read_only(mut self) -> RamDisk {
+        self.read_only = true;
+        self
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            let offset = start as usize * self.block_size;
+            buf.copy_from_slice(&self.data.lock()[offset..offset + buf.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            if self.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            check_request(self, start, buf.len())?;
+            let offset = start as usize * self.block_size;
+            self.data.lock()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
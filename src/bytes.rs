@@ -0,0 +1,37 @@
+//! Reads the little endian fields of firmware tables and on-disk structures, which are not aligned to their size.
+
+use core::{convert::TryInto, mem};
+
+/// Integers stored in little endian byte order.
+pub trait FromLeBytes: Sized {
+    /// Returns `None` unless `bytes` is exactly as long as `Self`.
+    fn from_le_slice(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! from_le_bytes {
+    ($($int:ty),*) => {
+        $(impl FromLeBytes for $int {
+            fn from_le_slice(bytes: &[u8]) -> Option<Self> {
+                bytes.try_into().ok().map(<$int>::from_le_bytes)
+            }
+        })*
+    };
+}
+
+from_le_bytes!(u8, u16, u32, u64);
+
+/// Reads the `T` at `offset` bytes into `bytes`, or returns `None` if it reaches past their end.
+pub(crate) fn read_at<T: FromLeBytes>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+    T::from_le_slice(bytes.get(offset..end)?)
+}
+
+#[test_case]
+fn test_read_at_checks_bounds() {
+    let bytes = [1, 0, 0, 0, 2];
+    assert_eq!(read_at::<u32>(&bytes, 0), Some(1));
+    assert_eq!(read_at::<u32>(&bytes, 1), Some(0x0200_0000));
+    assert_eq!(read_at::<u16>(&bytes, 3), Some(0x0200));
+    assert_eq!(read_at::<u32>(&bytes, 2), None);
+    assert_eq!(read_at::<u8>(&bytes, usize::MAX), None);
+}
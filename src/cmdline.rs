@@ -0,0 +1,31 @@
+//! The kernel command line, a list of space separated `key=value` options.
+
+/// Returns the command line. The bootloader passes none, so it is set at build time through the
+/// `RUST_OS_CMDLINE` environment variable.
+pub fn get() -> &'static str {
+    option_env!("RUST_OS_CMDLINE").unwrap_or("")
+}
+
+/// Returns the value of the first option named `key`, or an empty string for a bare `key`.
+pub fn option(key: &str) -> Option<&'static str> {
+    find(get(), key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline.split_whitespace().find_map(|option| {
+        let (name, value) = option.split_once('=').unwrap_or((option, ""));
+        if name == key {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+#[test_case]
+fn test_find_option() {
+    let cmdline = "quiet keyboard.layout=de  log=debug";
+    assert_eq!(find(cmdline, "keyboard.layout"), Some("de"));
+    assert_eq!(find(cmdline, "quiet"), Some(""));
+    assert_eq!(find(cmdline, "keyboard"), None);
+}
@@ -0,0 +1,239 @@
+//! The console: the backends `print!` writes to, and line based keyboard input on the VGA screen for
+//! interactive shells.
+
+use crate::{
+    cmdline,
+    serial,
+    sync::{IrqSpinLock, IrqSpinLockGuard},
+    task::{
+        keyboard::{KeyCode, KeyEvent, KeyEventStream, KeyRepeat, KeyState, Typematic},
+        sync::Mutex,
+    },
+    vga_buffer::{self, cp437, terminal, Writer, BUFFER_WIDTH, WRITER},
+    warn,
+};
+use alloc::{collections::VecDeque, string::String};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use futures_util::stream::StreamExt;
+
+mod backend;
+
+pub use backend::{
+    add_backend,
+    backends,
+    clear,
+    has_backend,
+    remove_backend,
+    set_backends,
+    set_color,
+    with_color,
+    print_unlocked,
+    ConsoleBackend,
+    MAX_BACKENDS,
+};
+#[doc(hidden)]
+pub use backend::{_print, _print_colored};
+
+/// Number of lines `read_line` remembers for the up and down arrows.
+pub const HISTORY_SIZE: usize = 16;
+
+/// The keyboard and the history, created by the first `read_line`.
+static INPUT: Mutex<Option<Input>> = Mutex::new(None);
+/// The virtual terminal `read_line` echoes to.
+static TERMINAL: AtomicUsize = AtomicUsize::new(terminal::LOG);
+
+struct Input {
+    events: KeyRepeat<KeyEventStream>,
+    /// Earlier lines, oldest first.
+    history: VecDeque<String>,
+}
+
+/// Picks the backends `print!` writes to with the `console` command line option, a comma separated list of
+/// `vga`, `serial` and `framebuffer`, `vga` by default.
+///
+/// `console=serial` makes the kernel usable without a screen, panic messages included. The framebuffer needs
+/// the heap, which does not exist yet, so the kernel adds that backend itself later and this only checks the name.
+/// Until then the VGA screen is used if no other backend is listed.
+pub fn init_from_cmdline() {
+    let names = match cmdline::option("console") {
+        Some(names) => names,
+        None => return,
+    };
+    let mut backends: [&'static dyn ConsoleBackend; MAX_BACKENDS] = [&vga_buffer::VGA_CONSOLE; MAX_BACKENDS];
+    let mut count = 0;
+    for name in names.split(',') {
+        let backend: &'static dyn ConsoleBackend = match name {
+            "vga" => &vga_buffer::VGA_CONSOLE,
+            "serial" => &serial::SERIAL_CONSOLE,
+            "framebuffer" => continue,
+            _ => {
+                warn!("unknown console {:?}", name);
+                continue;
+            }
+        };
+        if count < MAX_BACKENDS {
+            backends[count] = backend;
+            count += 1;
+        }
+    }
+    if count > 0 {
+        set_backends(&backends[..count]);
+    }
+}
+
+/// Makes `read_line` echo to virtual terminal `index`, the kernel log by default.
+pub fn set_terminal(index: usize) {
+    TERMINAL.store(index, Ordering::Relaxed);
+}
+
+fn writer() -> IrqSpinLockGuard<'static, Writer> {
+    let writer: &IrqSpinLock<Writer> = terminal::terminal(TERMINAL.load(Ordering::Relaxed)).unwrap_or(&WRITER);
+    writer.lock()
+}
+
+/// Reads a line from the keyboard, echoing it at the cursor of the terminal set with `set_terminal`, and returns
+/// it without the line break.
+///
+/// Supports backspace and delete, the left and right arrows, home and end, and recalling earlier lines with
+/// the up and down arrows. Lines are limited to the rest of the screen row, since the screen can't wrap an
+/// edited line. The first call takes over the keyboard, so no other `KeyEventStream` may be created.
+/// Concurrent callers read one line after the other.
+pub async fn read_line() -> String {
+    let mut input = INPUT.lock().await;
+    let input = input.get_or_insert_with(Input::new);
+    let start = writer().column().min(BUFFER_WIDTH - 1);
+    let mut editor = LineEditor::new(BUFFER_WIDTH - 1 - start, &input.history);
+    let line = loop {
+        let event = match input.events.next().await {
+            Some(event) => event,
+            None => break editor.line.clone(),
+        };
+        if let Some(line) = editor.handle(&event) {
+            break line;
+        }
+        let mut writer = writer();
+        writer.set_column(start);
+        writer.write_string(editor.line());
+        writer.clear_to_end_of_line();
+        writer.set_column(start + editor.cursor());
+    };
+    writer().write_byte(b'\n');
+
+    if !line.is_empty() && input.history.back() != Some(&line) {
+        if input.history.len() == HISTORY_SIZE {
+            input.history.pop_front();
+        }
+        input.history.push_back(line.clone());
+    }
+    line
+}
+
+/// Returns the next character typed, without echoing it, or `None` if the keyboard is gone.
+///
+/// Shares the keyboard with `read_line`, so it waits while a line is read and the other way round.
+pub async fn read_char() -> Option<char> {
+    let mut input = INPUT.lock().await;
+    let input = input.get_or_insert_with(Input::new);
+    loop {
+        if let Some(c) = input.events.next().await?.unicode {
+            return Some(c);
+        }
+    }
+}
+
+impl Input {
+    fn new() -> Input {
+        Input { events: KeyEventStream::new().with_repeat(Typematic::default()), history: VecDeque::new() }
+    }
+}
+
+/// The editing state of `read_line`, apart from the keyboard and the screen.
+pub struct LineEditor<'a> {
+    line: String,
+    /// Position of the cursor in `line`, in characters. Each takes a cell, as the line only holds characters the
+    /// screen can show.
+    cursor: usize,
+    max_len: usize,
+    history: &'a VecDeque<String>,
+    /// Index of the line recalled from `history`, `history.len()` while editing a new line.
+    recalled: usize,
+    /// The new line, kept while browsing the history.
+    draft: String,
+}
+
+impl<'a> LineEditor<'a> {
+    /// Creates an editor for a line of at most `max_len` characters that recalls lines from `history`.
+    pub fn new(max_len: usize, history: &'a VecDeque<String>) -> Self {
+        LineEditor { line: String::new(), cursor: 0, max_len, history, recalled: history.len(), draft: String::new() }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Returns the position of the cursor in characters, which is its column relative to the start of the line.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    fn len(&self) -> usize {
+        self.line.chars().count()
+    }
+
+    /// Returns the byte offset in `line` of character `index`.
+    fn offset(&self, index: usize) -> usize {
+        self.line.char_indices().nth(index).map_or(self.line.len(), |(offset, _)| offset)
+    }
+
+    /// Applies a key event to the line, returning the line once enter is pressed.
+    pub fn handle(&mut self, event: &KeyEvent) -> Option<String> {
+        if event.state != KeyState::Down {
+            return None;
+        }
+        match event.code {
+            KeyCode::Enter | KeyCode::NumpadEnter => return Some(core::mem::take(&mut self.line)),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.offset(self.cursor));
+            }
+            KeyCode::Delete if self.cursor < self.len() => {
+                self.line.remove(self.offset(self.cursor));
+            }
+            KeyCode::ArrowLeft => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::ArrowRight => self.cursor = (self.cursor + 1).min(self.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.len(),
+            KeyCode::ArrowUp if self.recalled > 0 => {
+                if self.recalled == self.history.len() {
+                    self.draft = core::mem::take(&mut self.line);
+                }
+                self.recalled -= 1;
+                self.recall(self.history[self.recalled].clone());
+            }
+            KeyCode::ArrowDown if self.recalled < self.history.len() => {
+                self.recalled += 1;
+                let line = match self.history.get(self.recalled) {
+                    Some(line) => line.clone(),
+                    None => core::mem::take(&mut self.draft),
+                };
+                self.recall(line);
+            }
+            KeyCode::Backspace | KeyCode::Delete | KeyCode::ArrowUp | KeyCode::ArrowDown => {}
+            _ => match event.unicode {
+                // control characters have a glyph too, but are not meant to be shown
+                Some(c) if !c.is_control() && cp437::encode(c).is_some() && self.len() < self.max_len => {
+                    self.line.insert(self.offset(self.cursor), c);
+                    self.cursor += 1;
+                }
+                _ => {}
+            },
+        }
+        None
+    }
+
+    fn recall(&mut self, line: String) {
+        self.line = line;
+        self.line.truncate(self.offset(self.max_len));
+        self.cursor = self.len();
+    }
+}
@@ -0,0 +1,210 @@
+//! The devices `print!` writes to, the VGA text screen unless the kernel picks others.
+
+use crate::{serial, sync::IrqSpinLock, vga_buffer::{self, Color}};
+use core::fmt;
+
+/// Number of backends `print!` can write to at once.
+pub const MAX_BACKENDS: usize = 4;
+
+type Backends = [Option<&'static dyn ConsoleBackend>; MAX_BACKENDS];
+
+/// Held only to copy the list, never while writing, so that a backend may add or remove backends.
+static BACKENDS: IrqSpinLock<Backends> = IrqSpinLock::new([Some(&vga_buffer::VGA_CONSOLE), None, None, None]);
+
+/// A device that shows text, like a screen or a serial port.
+///
+/// Backends are shared by every CPU and written to from any context that prints, so they lock internally.
+/// They must not print themselves.
+pub trait ConsoleBackend: Sync {
+    fn write_str(&self, s: &str);
+
+    /// Writes `args` in one piece, so that prints of other CPUs don't end up in the middle of it.
+    ///
+    /// The default writes the pieces one by one with `write_str`.
+    fn write_fmt(&self, args: fmt::Arguments) {
+        let _ = fmt::Write::write_fmt(&mut Pieces(self), args);
+    }
+
+    /// Writes `args` from a panic handler, which may have interrupted a write that holds the locks of the backend.
+    ///
+    /// The default writes like `write_fmt`. Backends that can write without their locks should do so here.
+    fn write_unlocked(&self, args: fmt::Arguments) {
+        self.write_fmt(args);
+    }
+
+    /// Writes `args` in the given colors, keeping the colors of other output.
+    fn write_colored(&self, foreground: Color, background: Color, args: fmt::Arguments) {
+        let previous = self.color();
+        self.set_color(foreground, background);
+        self.write_fmt(args);
+        self.set_color(previous.0, previous.1);
+    }
+
+    /// Clears the whole display and moves to its top left corner.
+    fn clear(&self);
+
+    /// Sets the colors of the text written from now on.
+    fn set_color(&self, foreground: Color, background: Color);
+
+    /// Returns the foreground and background colors set.
+    fn color(&self) -> (Color, Color);
+
+    /// Returns the size of the display in columns and rows.
+    fn dimensions(&self) -> (usize, usize);
+}
+
+struct Pieces<'a, B: ?Sized>(&'a B);
+
+impl<B: ConsoleBackend + ?Sized> fmt::Write for Pieces<'_, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// Returns the backends `print!` writes to.
+pub fn backends() -> impl Iterator<Item = &'static dyn ConsoleBackend> {
+    let backends = *BACKENDS.lock();
+    IntoIterator::into_iter(backends).flatten()
+}
+
+/// Makes `print!` write to `backends` only. Panics if there are more than `MAX_BACKENDS`.
+pub fn set_backends(backends: &[&'static dyn ConsoleBackend]) {
+    assert!(backends.len() <= MAX_BACKENDS, "more than {} console backends", MAX_BACKENDS);
+    let mut list = [None; MAX_BACKENDS];
+    for (slot, backend) in list.iter_mut().zip(backends) {
+        *slot = Some(*backend);
+    }
+    *BACKENDS.lock() = list;
+}
+
+/// Makes `print!` write to `backend` too. Returns `false` if it already does or `MAX_BACKENDS` are in use.
+pub fn add_backend(backend: &'static dyn ConsoleBackend) -> bool {
+    let mut backends = BACKENDS.lock();
+    if backends.iter().flatten().any(|other| same(*other, backend)) {
+        return false;
+    }
+    match backends.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(backend);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stops `print!` from writing to `backend`, returning `false` if it didn't.
+pub fn remove_backend(backend: &'static dyn ConsoleBackend) -> bool {
+    let mut backends = BACKENDS.lock();
+    match backends.iter_mut().find(|slot| slot.map_or(false, |other| same(other, backend))) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns `true` if `print!` writes to `backend`.
+pub fn has_backend(backend: &'static dyn ConsoleBackend) -> bool {
+    backends().any(|other| same(other, backend))
+}
+
+/// Compares the objects only, as the same type can have several vtables.
+fn same(a: &dyn ConsoleBackend, b: &dyn ConsoleBackend) -> bool {
+    (a as *const dyn ConsoleBackend).cast::<()>() == (b as *const dyn ConsoleBackend).cast::<()>()
+}
+
+/// Clears every backend.
+pub fn clear() {
+    for backend in backends() {
+        backend.clear();
+    }
+}
+
+/// Sets the colors of the text printed from now on on every backend, like the color escape sequences do.
+pub fn set_color(foreground: Color, background: Color) {
+    for backend in backends() {
+        backend.set_color(foreground, background);
+    }
+}
+
+/// Runs `f` with the colors set to `foreground` and `background`, restoring the previous colors afterwards.
+///
+/// Text other tasks print while `f` runs gets the colors too. For a single message, `warn!`, `error!` or the
+/// color escape sequences are the better fit.
+pub fn with_color<R>(foreground: Color, background: Color, f: impl FnOnce() -> R) -> R {
+    let mut previous = [None; MAX_BACKENDS];
+    for (previous, backend) in previous.iter_mut().zip(backends()) {
+        *previous = Some((backend, backend.color()));
+        backend.set_color(foreground, background);
+    }
+    let result = f();
+    for (backend, (foreground, background)) in IntoIterator::into_iter(previous).flatten() {
+        backend.set_color(foreground, background);
+    }
+    result
+}
+
+/// Prints `args` to every backend from a panic handler, see `ConsoleBackend::write_unlocked`.
+///
+/// Only the serial console is written if another CPU holds the list of backends.
+pub fn print_unlocked(args: fmt::Arguments) {
+    let backends = match BACKENDS.try_lock() {
+        Some(backends) => *backends,
+        None => [Some(&serial::SERIAL_CONSOLE as &dyn ConsoleBackend), None, None, None],
+    };
+    for backend in IntoIterator::into_iter(backends).flatten() {
+        backend.write_unlocked(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    for backend in backends() {
+        backend.write_fmt(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, background: Color, args: fmt::Arguments) {
+    for backend in backends() {
+        backend.write_colored(foreground, background, args);
+    }
+}
+
+#[test_case]
+fn test_prints_reach_every_backend() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the bytes written to it.
+    struct Counter(AtomicUsize);
+
+    impl ConsoleBackend for Counter {
+        fn write_str(&self, s: &str) {
+            self.0.fetch_add(s.len(), Ordering::Relaxed);
+        }
+
+        fn clear(&self) {}
+
+        fn set_color(&self, _foreground: Color, _background: Color) {}
+
+        fn color(&self) -> (Color, Color) {
+            (Color::LightGray, Color::Black)
+        }
+
+        fn dimensions(&self) -> (usize, usize) {
+            (1, 1)
+        }
+    }
+
+    static COUNTER: Counter = Counter(AtomicUsize::new(0));
+    assert!(add_backend(&COUNTER));
+    assert!(!add_backend(&COUNTER));
+    assert!(backends().any(|backend| backend.dimensions() == (1, 1)));
+    crate::print!("{}", "abc");
+    assert!(remove_backend(&COUNTER));
+    crate::print!("def");
+    assert_eq!(COUNTER.0.load(Ordering::Relaxed), 3);
+    assert!(!remove_backend(&COUNTER));
+}
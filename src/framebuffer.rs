@@ -0,0 +1,327 @@
+//! Linear framebuffer graphics, for output beyond the 80x25 text mode.
+//!
+//! The bootloader leaves the machine in VGA text mode and passes no framebuffer, so the only source for now is
+//! the Bochs graphics adapter, which QEMU and Bochs emulate as their standard VGA card.
+
+use self::font::Font;
+use crate::{
+    memory::{map_mmio, mmio::MmioError, vmm::VmmError, MmioRegion},
+    pci::{self, Bar},
+    vga_buffer::Color,
+    vm::{self, AnonymousMapping},
+};
+use core::ops::Range;
+use x86_64::instructions::port::Port;
+
+pub mod console;
+pub mod font;
+
+const BOCHS_VENDOR_ID: u16 = 0x1234;
+const BOCHS_DEVICE_ID: u16 = 0x1111;
+
+/// Index and data ports of the Bochs display interface registers.
+const DISPI_INDEX: u16 = 0x01ce;
+const DISPI_DATA: u16 = 0x01cf;
+const DISPI_ID: u16 = 0;
+const DISPI_XRES: u16 = 1;
+const DISPI_YRES: u16 = 2;
+const DISPI_BPP: u16 = 3;
+const DISPI_ENABLE: u16 = 4;
+const DISPI_VIRT_WIDTH: u16 = 6;
+/// The first interface version with 32 bits per pixel.
+const DISPI_ID_32BPP: u16 = 0xb0c2;
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    /// No supported graphics adapter was found.
+    NoDevice,
+    /// The adapter does not support the requested mode.
+    UnsupportedMode,
+    Mmio(MmioError),
+    /// No memory for the back buffer of `enable_double_buffering`.
+    Vmm(VmmError),
+}
+
+/// A color, stored as `0x00rrggbb` in the framebuffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    const fn to_pixel(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
+    const fn from_pixel(pixel: u32) -> Self {
+        Rgb::new((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8)
+    }
+}
+
+/// The 16 colors of VGA text mode.
+impl From<Color> for Rgb {
+    fn from(color: Color) -> Rgb {
+        const PALETTE: [Rgb; 16] = [
+            Rgb::new(0x00, 0x00, 0x00),
+            Rgb::new(0x00, 0x00, 0xaa),
+            Rgb::new(0x00, 0xaa, 0x00),
+            Rgb::new(0x00, 0xaa, 0xaa),
+            Rgb::new(0xaa, 0x00, 0x00),
+            Rgb::new(0xaa, 0x00, 0xaa),
+            Rgb::new(0xaa, 0x55, 0x00),
+            Rgb::new(0xaa, 0xaa, 0xaa),
+            Rgb::new(0x55, 0x55, 0x55),
+            Rgb::new(0x55, 0x55, 0xff),
+            Rgb::new(0x55, 0xff, 0x55),
+            Rgb::new(0x55, 0xff, 0xff),
+            Rgb::new(0xff, 0x55, 0x55),
+            Rgb::new(0xff, 0x55, 0xff),
+            Rgb::new(0xff, 0xff, 0x55),
+            Rgb::new(0xff, 0xff, 0xff),
+        ];
+        PALETTE[color as usize]
+    }
+}
+
+/// The geometry of a framebuffer with 32 bits per pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels from the start of one row to the start of the next, at least `width`.
+    pub stride: usize,
+}
+
+impl FramebufferInfo {
+    fn size(&self) -> usize {
+        self.stride * self.height * BYTES_PER_PIXEL
+    }
+}
+
+/// A linear framebuffer with 32 bits per pixel.
+///
+/// Drawing is clipped to the screen. Without double buffering it goes straight to the screen; with it, it goes to
+/// a back buffer in memory that `present` copies to the screen, so that nothing is shown half drawn.
+pub struct Framebuffer {
+    info: FramebufferInfo,
+    front: MmioRegion,
+    back: Option<AnonymousMapping>,
+}
+
+impl Framebuffer {
+    /// Wraps framebuffer memory that was already set up with the geometry `info`.
+    ///
+    /// Panics if `front` is smaller than `info` says.
+    pub fn new(front: MmioRegion, info: FramebufferInfo) -> Self {
+        assert!(front.len() >= info.size(), "framebuffer memory smaller than its geometry");
+        Framebuffer { info, front, back: None }
+    }
+
+    pub fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Makes drawing go to a back buffer, which starts as a copy of the screen, until `present` shows it.
+    pub fn enable_double_buffering(&mut self) -> Result<(), FramebufferError> {
+        if self.back.is_some() {
+            return Ok(());
+        }
+        let mut back = vm::map_anonymous(self.info.size()).map_err(FramebufferError::Vmm)?;
+        let front: *const u32 = self.front.virt_addr().as_ptr();
+        let pixels: *mut u32 = back.as_mut_ptr().cast();
+        for index in 0..self.info.stride * self.info.height {
+            unsafe { pixels.add(index).write(front.add(index).read_volatile()) };
+        }
+        self.back = Some(back);
+        Ok(())
+    }
+
+    pub fn is_double_buffered(&self) -> bool {
+        self.back.is_some()
+    }
+
+    /// Copies the back buffer to the screen. Does nothing without double buffering.
+    pub fn present(&mut self) {
+        self.present_rows(0, self.info.height);
+    }
+
+    /// Copies `count` rows of the back buffer starting at `first` to the screen, for updates of part of it.
+    pub fn present_rows(&mut self, first: usize, count: usize) {
+        let back = match &self.back {
+            Some(back) => back.as_ptr().cast::<u32>(),
+            None => return,
+        };
+        let first = first.min(self.info.height);
+        let end = first.saturating_add(count).min(self.info.height);
+        let front: *mut u32 = self.front.virt_addr().as_mut_ptr();
+        for index in first * self.info.stride..end * self.info.stride {
+            unsafe { front.add(index).write_volatile(back.add(index).read()) };
+        }
+    }
+
+    /// Returns the pixel at `x`, `y` of the buffer drawn to, or `None` outside the screen.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+        let pixel = unsafe { self.target().add(y * self.info.stride + x).read_volatile() };
+        Some(Rgb::from_pixel(pixel))
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        self.fill_rect(x, y, 1, 1, color);
+    }
+
+    /// Fills the rectangle of `width` times `height` pixels with its top left corner at `x`, `y`.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let pixel = color.to_pixel();
+        let (columns, rows) = self.clip(x, y, width, height);
+        let target = self.target();
+        for row in rows {
+            for column in columns.clone() {
+                unsafe { target.add(row * self.info.stride + column).write_volatile(pixel) };
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.info.width, self.info.height, color);
+    }
+
+    /// Draws an image of `width` pixels per row, stored row by row in `pixels`, with its top left corner at `x`, `y`.
+    ///
+    /// Panics if `pixels` does not hold whole rows.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[Rgb]) {
+        if width == 0 {
+            return;
+        }
+        assert_eq!(pixels.len() % width, 0, "image does not hold whole rows");
+        let (columns, rows) = self.clip(x, y, width, pixels.len() / width);
+        let target = self.target();
+        for row in rows {
+            let source = &pixels[(row - y) * width..];
+            for column in columns.clone() {
+                let pixel = source[column - x].to_pixel();
+                unsafe { target.add(row * self.info.stride + column).write_volatile(pixel) };
+            }
+        }
+    }
+
+    /// Draws glyph `index` of `font` with its top left corner at `x`, `y`, its set pixels in `foreground` and the
+    /// others in `background`. Draws nothing if the font has no such glyph.
+    pub fn draw_glyph(&mut self, x: usize, y: usize, font: &Font, index: usize, foreground: Rgb, background: Rgb) {
+        let glyph = match font.glyph(index) {
+            Some(glyph) => glyph,
+            None => return,
+        };
+        let (foreground, background) = (foreground.to_pixel(), background.to_pixel());
+        let (columns, rows) = self.clip(x, y, font.width(), font.height());
+        let target = self.target();
+        for row in rows {
+            for column in columns.clone() {
+                let pixel = if font.is_set(glyph, column - x, row - y) { foreground } else { background };
+                unsafe { target.add(row * self.info.stride + column).write_volatile(pixel) };
+            }
+        }
+    }
+
+    /// Moves the whole picture up by `rows` rows of pixels and fills the rows uncovered at the bottom with `fill`.
+    pub fn scroll_up(&mut self, rows: usize, fill: Rgb) {
+        let rows = rows.min(self.info.height);
+        let kept = self.info.height - rows;
+        let target = self.target();
+        for index in 0..kept * self.info.stride {
+            unsafe {
+                let pixel = target.add(index + rows * self.info.stride).read_volatile();
+                target.add(index).write_volatile(pixel);
+            }
+        }
+        self.fill_rect(0, kept, self.info.width, rows, fill);
+    }
+
+    /// Returns the columns and rows of a rectangle that are on the screen.
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (Range<usize>, Range<usize>) {
+        let columns = x.min(self.info.width)..x.saturating_add(width).min(self.info.width);
+        let rows = y.min(self.info.height)..y.saturating_add(height).min(self.info.height);
+        (columns, rows)
+    }
+
+    /// Returns the first pixel of the buffer drawn to.
+    fn target(&self) -> *mut u32 {
+        match &self.back {
+            Some(back) => back.as_ptr() as *mut u32,
+            None => self.front.virt_addr().as_mut_ptr(),
+        }
+    }
+}
+
+/// Switches the Bochs graphics adapter to `width` times `height` pixels with 32 bits per pixel.
+///
+/// This ends VGA text mode, so `println!` output is no longer visible afterwards. Needs the kernel memory to be
+/// installed, to map the framebuffer.
+pub fn init_bochs(width: u16, height: u16) -> Result<Framebuffer, FramebufferError> {
+    let device = pci::devices()
+        .find(|device| device.vendor_id == BOCHS_VENDOR_ID && device.device_id == BOCHS_DEVICE_ID)
+        .ok_or(FramebufferError::NoDevice)?;
+    let lfb = match device.bars[0] {
+        Some(Bar::Memory { addr, .. }) => addr,
+        _ => return Err(FramebufferError::NoDevice),
+    };
+    if read_dispi(DISPI_ID) < DISPI_ID_32BPP {
+        return Err(FramebufferError::UnsupportedMode);
+    }
+
+    write_dispi(DISPI_ENABLE, 0);
+    write_dispi(DISPI_XRES, width);
+    write_dispi(DISPI_YRES, height);
+    write_dispi(DISPI_BPP, 32);
+    write_dispi(DISPI_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+    // the adapter silently clamps modes it can't show
+    if read_dispi(DISPI_XRES) != width || read_dispi(DISPI_YRES) != height {
+        write_dispi(DISPI_ENABLE, 0);
+        return Err(FramebufferError::UnsupportedMode);
+    }
+
+    let info = FramebufferInfo {
+        width: usize::from(width),
+        height: usize::from(height),
+        stride: usize::from(read_dispi(DISPI_VIRT_WIDTH)),
+    };
+    let front = map_mmio(lfb, info.size()).map_err(FramebufferError::Mmio)?;
+    Ok(Framebuffer::new(front, info))
+}
+
+fn read_dispi(register: u16) -> u16 {
+    unsafe {
+        Port::new(DISPI_INDEX).write(register);
+        Port::new(DISPI_DATA).read()
+    }
+}
+
+fn write_dispi(register: u16, value: u16) {
+    unsafe {
+        Port::new(DISPI_INDEX).write(register);
+        Port::new(DISPI_DATA).write(value);
+    }
+}
@@ -0,0 +1,157 @@
+//! A console backend that draws text straight onto a framebuffer, using all of it.
+//!
+//! Unlike `vga_buffer::use_framebuffer`, which draws the 80x25 cells of the VGA writer and keeps its terminals
+//! and scrollback, this needs no VGA writer at all, for configurations where there is none.
+
+use super::{font::Font, Framebuffer, FramebufferError, Rgb};
+use crate::{console::ConsoleBackend, sync::IrqSpinLock, vga_buffer::{cp437, Color}};
+use core::fmt;
+
+pub struct FramebufferConsole {
+    inner: IrqSpinLock<Inner>,
+}
+
+struct Inner {
+    framebuffer: Framebuffer,
+    font: Font,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: Color,
+    background: Color,
+    /// Set while the bytes after an ESC are dropped, up to the final byte of the escape sequence.
+    escape: bool,
+    /// The rows of cells drawn since the last present, from the first up to the end.
+    dirty: (usize, usize),
+}
+
+impl FramebufferConsole {
+    /// Takes over `framebuffer`, clearing it, to show text drawn with `font`.
+    ///
+    /// Needs memory for a back buffer, which makes scrolling fast.
+    pub fn new(mut framebuffer: Framebuffer, font: Font) -> Result<Self, FramebufferError> {
+        let columns = framebuffer.width() / font.width();
+        let rows = framebuffer.height() / font.height();
+        if columns == 0 || rows == 0 {
+            return Err(FramebufferError::UnsupportedMode);
+        }
+        framebuffer.enable_double_buffering()?;
+        let mut inner = Inner {
+            framebuffer,
+            font,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+            foreground: Color::LightGray,
+            background: Color::Black,
+            escape: false,
+            dirty: (0, 0),
+        };
+        inner.clear();
+        Ok(FramebufferConsole { inner: IrqSpinLock::new(inner) })
+    }
+}
+
+impl Inner {
+    fn write_text(&mut self, s: &str) {
+        for c in s.chars() {
+            match c {
+                // escape sequences are dropped, up to their final byte
+                '\x1b' => self.escape = true,
+                '@'..='~' if self.escape && c != '[' => self.escape = false,
+                _ if self.escape => {}
+                '\n' => self.new_line(),
+                c => {
+                    if self.column >= self.columns {
+                        self.new_line();
+                    }
+                    // the font is in code page 437 order, characters it lacks use a filled in square
+                    let glyph = cp437::encode(c).unwrap_or(0xfe);
+                    let (x, y) = (self.column * self.font.width(), self.row * self.font.height());
+                    let (foreground, background) = (Rgb::from(self.foreground), Rgb::from(self.background));
+                    self.framebuffer.draw_glyph(x, y, &self.font, usize::from(glyph), foreground, background);
+                    self.mark_dirty(self.row, self.row + 1);
+                    self.column += 1;
+                }
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        self.framebuffer.scroll_up(self.font.height(), Rgb::from(self.background));
+        self.mark_dirty(0, self.rows);
+    }
+
+    fn mark_dirty(&mut self, first: usize, end: usize) {
+        self.dirty = match self.dirty {
+            (start, stop) if start < stop => (start.min(first), stop.max(end)),
+            _ => (first, end),
+        };
+    }
+
+    /// Shows what was drawn since the last call.
+    fn present(&mut self) {
+        let (first, end) = core::mem::take(&mut self.dirty);
+        if first < end {
+            let height = self.font.height();
+            self.framebuffer.present_rows(first * height, (end - first) * height);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.framebuffer.clear(Rgb::from(self.background));
+        self.framebuffer.present();
+        self.row = 0;
+        self.column = 0;
+    }
+}
+
+impl ConsoleBackend for FramebufferConsole {
+    fn write_str(&self, s: &str) {
+        let mut inner = self.inner.lock();
+        inner.write_text(s);
+        inner.present();
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) {
+        struct Bytes<'a>(&'a mut Inner);
+
+        impl fmt::Write for Bytes<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.0.write_text(s);
+                Ok(())
+            }
+        }
+
+        let mut inner = self.inner.lock();
+        let _ = fmt::Write::write_fmt(&mut Bytes(&mut inner), args);
+        inner.present();
+    }
+
+    fn clear(&self) {
+        self.inner.lock().clear();
+    }
+
+    fn set_color(&self, foreground: Color, background: Color) {
+        let mut inner = self.inner.lock();
+        inner.foreground = foreground;
+        inner.background = background;
+    }
+
+    fn color(&self) -> (Color, Color) {
+        let inner = self.inner.lock();
+        (inner.foreground, inner.background)
+    }
+
+    fn dimensions(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        (inner.columns, inner.rows)
+    }
+}
@@ -0,0 +1,109 @@
+//! Bitmap fonts in the PC Screen Font format of the Linux console, versions 1 and 2.
+//!
+//! Glyphs are looked up by index only, the Unicode tables some fonts carry are ignored.
+
+use core::convert::TryInto;
+
+/// DejaVu Sans Mono rendered at 8x16 pixels, with its 256 glyphs in code page 437 order like the VGA font.
+static BUILTIN: &[u8] = include_bytes!("font.psf");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    glyphs: &'static [u8],
+    count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    /// Reads the header of the PSF font in `data`, returning `None` if it is not one or is cut short.
+    pub fn parse(data: &'static [u8]) -> Option<Font> {
+        let (offset, count, bytes_per_glyph, width, height) = if data.starts_with(&PSF1_MAGIC) {
+            let height = usize::from(*data.get(3)?);
+            let count = if data[2] & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            (PSF1_HEADER_SIZE, count, height, 8, height)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            let field = |index: usize| -> Option<usize> {
+                let bytes = data.get(index * 4..index * 4 + 4)?;
+                Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            };
+            // magic, version, header size, flags, glyph count, glyph size, height, width
+            (field(2)?, field(4)?, field(5)?, field(7)?, field(6)?)
+        } else {
+            return None;
+        };
+        if width == 0 || height == 0 || bytes_per_glyph < (width + 7) / 8 * height {
+            return None;
+        }
+        let glyphs = data.get(offset..offset.checked_add(count.checked_mul(bytes_per_glyph)?)?)?;
+        Some(Font { glyphs, count, bytes_per_glyph, width, height })
+    }
+
+    /// Returns the font built into the kernel.
+    pub fn builtin() -> Font {
+        Font::parse(BUILTIN).expect("built in font is not a PSF font")
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the bitmap of glyph `index`, `height` rows of whole bytes with the leftmost pixel in the top bit.
+    pub fn glyph(&self, index: usize) -> Option<&'static [u8]> {
+        if index >= self.count {
+            return None;
+        }
+        let start = index * self.bytes_per_glyph;
+        Some(&self.glyphs[start..start + self.bytes_per_glyph])
+    }
+
+    /// Returns whether the pixel at `x`, `y` of `glyph` is set.
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let row = (self.width + 7) / 8;
+        glyph[y * row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+#[test_case]
+fn test_builtin_font_is_parsed() {
+    let font = Font::builtin();
+    assert_eq!((font.width(), font.height(), font.glyph_count()), (8, 16, 256));
+    assert!(font.glyph(usize::from(b' ')).unwrap().iter().all(|row| *row == 0));
+    assert!(font.glyph(usize::from(b'A')).unwrap().iter().any(|row| *row != 0));
+    assert!(font.glyph(256).is_none());
+}
+
+#[test_case]
+fn test_psf2_headers_are_parsed() {
+    static FONT: [u8; 32 + 2 * 2] = {
+        let mut font = [0; 36];
+        let header: [u32; 8] = [0x864a_b572, 0, 32, 0, 2, 2, 2, 3];
+        let mut index = 0;
+        while index < 32 {
+            font[index] = (header[index / 4] >> (8 * (index % 4))) as u8;
+            index += 1;
+        }
+        font[34] = 0xa0;
+        font
+    };
+    let font = Font::parse(&FONT).unwrap();
+    assert_eq!((font.width(), font.height(), font.glyph_count()), (3, 2, 2));
+    let glyph = font.glyph(1).unwrap();
+    assert!(font.is_set(glyph, 0, 0) && !font.is_set(glyph, 1, 0) && font.is_set(glyph, 2, 0));
+    assert!(Font::parse(&FONT[..35]).is_none());
+    assert!(Font::parse(b"not a font").is_none());
+}
@@ -1,46 +1,134 @@
+use alloc::{boxed::Box, vec};
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+
+/// Number of interrupt stacks each CPU has, one for each of the IST indices above.
+const IST_STACKS: usize = 3;
+const IST_STACK_SIZE: usize = 4096 * 5;
+/// Size of the stack the CPU switches to when an interrupt or system call enters the kernel from ring 3.
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 4;
+
+/// Interrupt stacks of the boot CPU, which loads its tables before the heap exists.
+static mut BOOT_IST_STACKS: [[u8; IST_STACK_SIZE]; IST_STACKS] = [[0; IST_STACK_SIZE]; IST_STACKS];
+static mut BOOT_PRIVILEGE_STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+        let stacks = unsafe { &BOOT_IST_STACKS };
+        let privilege_stack = unsafe { &BOOT_PRIVILEGE_STACK };
+        new_tss(
+            core::array::from_fn(|index| VirtAddr::from_ptr(&stacks[index]) + IST_STACK_SIZE),
+            VirtAddr::from_ptr(privilege_stack) + PRIVILEGE_STACK_SIZE,
+        )
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
+/// The GDT, TSS and interrupt stacks of one CPU.
+///
+/// The CPU uses them until it halts, so they are never freed.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+    tss: &'static TaskStateSegment,
+}
+
+impl CpuTables {
+    pub fn tss(&self) -> &'static TaskStateSegment {
+        self.tss
+    }
+
+    pub fn selectors(&self) -> Selectors {
+        self.selectors
+    }
+}
+
+/// Segment selectors of the GDT entries. Every CPU's GDT has the same layout, so they apply on all CPUs.
+///
+/// The entries are ordered as `syscall` and `sysret` expect them: the kernel data segment follows the kernel
+/// code segment, and the user code segment follows the user data segment. The user selectors have RPL 3.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Loads the GDT and TSS of the boot CPU.
 pub fn init() {
+    load(&GDT.0, &GDT.1);
+}
+
+/// Returns the selectors to load when entering ring 3 with `iretq`, or to program into the `STAR` MSR.
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Builds a GDT, TSS and interrupt stacks on the heap for the calling CPU and loads them.
+///
+/// Every application processor calls this during bring-up, before it enables interrupts, since the tables of
+/// the boot CPU can't be shared: the TSS is marked busy while loaded, and each CPU needs its own interrupt stacks.
+/// The interrupt stacks are heap memory, which is always mapped, so that faults never hit an unmapped IST stack.
+pub fn init_secondary_cpu() -> &'static CpuTables {
+    let stack_tops = core::array::from_fn(|_| heap_stack(IST_STACK_SIZE));
+    let privilege_stack_top = heap_stack(PRIVILEGE_STACK_SIZE);
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss(stack_tops, privilege_stack_top)));
+    let (gdt, selectors) = new_gdt(tss);
+    let tables: &'static CpuTables = Box::leak(Box::new(CpuTables { gdt, selectors, tss }));
+    load(&tables.gdt, &tables.selectors);
+    tables
+}
+
+/// Allocates a stack that is never freed and returns its top.
+fn heap_stack(size: usize) -> VirtAddr {
+    let stack = Box::leak(vec![0u8; size].into_boxed_slice());
+    VirtAddr::from_ptr(stack.as_ptr()) + size
+}
+
+/// The IST entries point at `stack_tops`, indexed by the IST indices above, and the ring 0 entry of the
+/// privilege stack table at `privilege_stack_top`.
+fn new_tss(stack_tops: [VirtAddr; IST_STACKS], privilege_stack_top: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.privilege_stack_table[0] = privilege_stack_top;
+    // page faults get their own stack so that a stack overflow can still be reported, and an NMI can arrive at
+    // any instruction, including right after a switch to a bad stack
+    for (index, top) in [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX, NMI_IST_INDEX].iter().zip(stack_tops) {
+        tss.interrupt_stack_table[usize::from(*index)] = top;
+    }
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors { kernel_code, kernel_data, user_data, user_code, tss })
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 }
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-}
\ No newline at end of file
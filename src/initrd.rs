@@ -0,0 +1,38 @@
+//! The initial ramdisk: a ustar or cpio archive of the files the kernel starts with, mounted as the root.
+//!
+//! A bootloader that loads an initrd marks the memory it is in as `Package` regions of the memory map. The 0.9
+//! bootloader has the region type but loads nothing into it, so an archive can instead be embedded into the
+//! kernel at build time by setting `RUST_OS_INITRD` to its absolute path.
+
+use crate::memory::phys_to_virt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::PhysAddr;
+
+#[cfg(embedded_initrd)]
+static EMBEDDED: &[u8] = include_bytes!(env!("RUST_OS_INITRD"));
+#[cfg(not(embedded_initrd))]
+static EMBEDDED: &[u8] = &[];
+
+/// Returns the initrd, the bootloader's if it loaded one and the embedded one otherwise, if there is either.
+///
+/// Needs the physical memory window, so must be called after `memory::init`.
+pub fn find(memory_map: &MemoryMap) -> Option<&'static [u8]> {
+    loaded(memory_map).or(Some(EMBEDDED).filter(|archive| !archive.is_empty()))
+}
+
+/// Returns the archive the bootloader loaded, which spans the `Package` regions that follow the first one
+/// without a gap. The end may be padded to a whole page.
+fn loaded(memory_map: &MemoryMap) -> Option<&'static [u8]> {
+    let mut regions = memory_map.iter().filter(|region| region.region_type == MemoryRegionType::Package);
+    let first = regions.next()?.range;
+    let (start, mut end) = (first.start_addr(), first.end_addr());
+    for region in regions {
+        if region.range.start_addr() != end {
+            break;
+        }
+        end = region.range.end_addr();
+    }
+    let virt = phys_to_virt(PhysAddr::new(start));
+    // the frame allocator only hands out `Usable` frames, so the archive stays as it is
+    Some(unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), (end - start) as usize) })
+}
@@ -3,10 +3,33 @@ use spin::Mutex;
 use x86_64::structures::idt::{
     InterruptDescriptorTable, 
     InterruptStackFrame,
-    PageFaultErrorCode,
 };
 use  lazy_static::lazy_static;
-use crate::{print, println, hlt_loop};
+use crate::info;
+
+/// Expands to the 16 instances of the const generic handler `$stub` for the vectors `0xN0` to `0xNf`,
+/// since the CPU does not tell a handler which vector it was entered through.
+macro_rules! stub_row {
+    ($stub:ident, $high:literal) => {
+        [
+            $stub::<{ $high * 16 }>, $stub::<{ $high * 16 + 1 }>, $stub::<{ $high * 16 + 2 }>,
+            $stub::<{ $high * 16 + 3 }>, $stub::<{ $high * 16 + 4 }>, $stub::<{ $high * 16 + 5 }>,
+            $stub::<{ $high * 16 + 6 }>, $stub::<{ $high * 16 + 7 }>, $stub::<{ $high * 16 + 8 }>,
+            $stub::<{ $high * 16 + 9 }>, $stub::<{ $high * 16 + 10 }>, $stub::<{ $high * 16 + 11 }>,
+            $stub::<{ $high * 16 + 12 }>, $stub::<{ $high * 16 + 13 }>, $stub::<{ $high * 16 + 14 }>,
+            $stub::<{ $high * 16 + 15 }>,
+        ]
+    };
+}
+
+pub mod apic;
+pub mod ioapic;
+pub mod irq;
+pub mod nmi;
+pub mod page_fault;
+pub mod stats;
+pub mod unhandled;
+pub mod vector;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -17,65 +40,37 @@ pub static PICS: Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        unhandled::install(&mut idt);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(nmi::nmi_handler)
+                .set_stack_index(crate::gdt::NMI_IST_INDEX);
+        }
+        irq::install(&mut idt);
+        idt[usize::from(crate::memory::tlb::SHOOTDOWN_VECTOR)].set_handler_fn(tlb_shootdown_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        vector::install(&mut idt);
+        page_fault::install(&mut idt);
 
         idt
     };
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+pub use irq::{register_irq, unregister_irq};
+pub use stats::{print_stats, stats};
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    stats::count(crate::memory::tlb::SHOOTDOWN_VECTOR);
+    crate::memory::tlb::handle_shootdown_interrupt();
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}",error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = x86_64::instructions::port::Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+/// Spurious interrupts of the local APIC don't set an in-service bit, so they must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    stats::count(apic::SPURIOUS_VECTOR);
 }
 
 pub fn init_idt() {
@@ -83,10 +78,14 @@ pub fn init_idt() {
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    stats::count(3);
+    info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let rbp = crate::backtrace::interrupted_frame_pointer();
+    stats::count(8);
+    crate::backtrace::print(stack_frame.instruction_pointer.as_u64(), rbp, stack_frame.stack_pointer.as_u64());
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
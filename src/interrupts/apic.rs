@@ -0,0 +1,231 @@
+use super::irq;
+use crate::{
+    memory::{self, tlb, MmioRegion},
+    time,
+};
+use core::arch::x86_64::__cpuid;
+use conquer_once::spin::OnceCell;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+/// Vector the local APIC delivers spurious interrupts on. Its low four bits must be set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// First of the MSRs the x2APIC registers are mapped to, one MSR per 16 byte xAPIC register.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const REG_ID: u32 = 0x20;
+const REG_EOI: u32 = 0xb0;
+/// First of the eight 32-bit in-service registers, 16 bytes apart.
+const REG_ISR: u32 = 0x100;
+const REG_SPURIOUS: u32 = 0xf0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_DELIVERY_EXTINT: u32 = 0b111 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+/// Divide configuration value for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const CALIBRATION_MS: u32 = 10;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID reports no local APIC.
+    NotPresent,
+    /// The xAPIC register page could not be mapped.
+    Map(memory::mmio::MmioError),
+}
+
+enum Registers {
+    /// Registers accessed through a memory mapped page.
+    XApic(MmioRegion),
+    /// Registers accessed through MSRs.
+    X2Apic,
+}
+
+/// The local APIC of the current CPU, in xAPIC or x2APIC mode.
+pub struct LocalApic {
+    registers: Registers,
+    /// Rate the timer counts down at, with the divide configuration set by `init`.
+    timer_ticks_per_second: u32,
+}
+
+impl LocalApic {
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self.registers, Registers::X2Apic)
+    }
+
+    /// Returns the APIC ID of the current CPU.
+    pub fn id(&self) -> u32 {
+        match self.registers {
+            Registers::XApic(_) => self.read(REG_ID) >> 24,
+            Registers::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    /// Signals the end of the interrupt currently being handled.
+    pub fn end_of_interrupt(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Returns `true` if the local APIC delivered `vector` and awaits its end of interrupt.
+    pub fn is_in_service(&self, vector: u8) -> bool {
+        let reg = REG_ISR + u32::from(vector / 32) * 0x10;
+        self.read(reg) & (1 << (vector % 32)) != 0
+    }
+
+    /// Returns the remaining count of the current timer period.
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(REG_TIMER_CURRENT_COUNT)
+    }
+
+    /// Makes the timer interrupt `hz` times per second on the timer IRQ vector.
+    pub fn set_timer_periodic(&self, hz: u32) {
+        self.write(REG_LVT_TIMER, u32::from(irq::vector(irq::TIMER)) | LVT_TIMER_PERIODIC);
+        self.write(REG_TIMER_INITIAL_COUNT, (self.timer_ticks_per_second / hz.max(1)).max(1));
+    }
+
+    /// Stops the periodic timer and raises a single timer interrupt after `delay_ns`, capped to
+    /// `max_timer_oneshot_ns`.
+    pub fn set_timer_oneshot(&self, delay_ns: u64) {
+        let count = u128::from(delay_ns) * u128::from(self.timer_ticks_per_second) / u128::from(time::NANOS_PER_SECOND);
+        self.write(REG_LVT_TIMER, u32::from(irq::vector(irq::TIMER)));
+        self.write(REG_TIMER_INITIAL_COUNT, count.clamp(1, u128::from(u32::MAX)) as u32);
+    }
+
+    /// Returns the longest delay `set_timer_oneshot` can count down.
+    pub fn max_timer_oneshot_ns(&self) -> u64 {
+        u64::from(u32::MAX) * time::NANOS_PER_SECOND / u64::from(self.timer_ticks_per_second.max(1))
+    }
+
+    /// Sends a fixed interrupt with `vector` to every CPU except the current one.
+    pub fn send_ipi_to_others(&self, vector: u8) {
+        let command = u32::from(vector) | ICR_ALL_EXCLUDING_SELF;
+        match &self.registers {
+            Registers::XApic(_) => {
+                self.write(REG_ICR_HIGH, 0);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // the x2APIC ICR is a single 64-bit MSR
+            Registers::X2Apic => unsafe { msr(REG_ICR_LOW).write(u64::from(command)) },
+        }
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        match &self.registers {
+            Registers::XApic(mmio) => mmio.read::<u32>(reg as usize),
+            Registers::X2Apic => unsafe { msr(reg).read() as u32 },
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        match &self.registers {
+            Registers::XApic(mmio) => mmio.write::<u32>(reg as usize, value),
+            Registers::X2Apic => unsafe { msr(reg).write(u64::from(value)) },
+        }
+    }
+
+    /// Measures how many timer ticks (at divide by 16) elapse in `CALIBRATION_MS`.
+    fn calibrate_timer(&self) -> u32 {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+        time::pit::busy_wait_ns(u64::from(CALIBRATION_MS) * 1_000_000);
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT_COUNT);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        elapsed
+    }
+}
+
+/// Returns the local APIC once `init` succeeded.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
+
+/// Returns `true` once interrupts are delivered through the local APIC.
+pub fn is_enabled() -> bool {
+    local_apic().is_some()
+}
+
+/// Signals the end of an interrupt delivered by the local APIC.
+pub fn end_of_interrupt() {
+    if let Some(apic) = local_apic() {
+        apic.end_of_interrupt();
+    }
+}
+
+/// Enables the local APIC of the current CPU and moves the timer interrupt from the PIC to the APIC timer.
+///
+/// Uses x2APIC mode when the CPU supports it, otherwise maps the xAPIC registers, so `memory::install`
+/// must have been called. The PICs keep delivering the other legacy IRQs through LINT0 in virtual wire mode.
+pub fn init() -> Result<(), ApicError> {
+    let features = unsafe { __cpuid(1) };
+    if features.edx & (1 << 9) == 0 {
+        return Err(ApicError::NotPresent);
+    }
+    let x2apic = features.ecx & (1 << 21) != 0;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut base_msr = Msr::new(IA32_APIC_BASE);
+        let base = unsafe { base_msr.read() };
+        let registers = if x2apic {
+            unsafe { base_msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC) };
+            Registers::X2Apic
+        } else {
+            unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+            let phys = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+            Registers::XApic(memory::map_mmio(phys, 4096).map_err(ApicError::Map)?)
+        };
+        let mut apic = LocalApic { registers, timer_ticks_per_second: 0 };
+
+        apic.write(REG_SPURIOUS, SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+        apic.write(REG_LVT_LINT0, LVT_DELIVERY_EXTINT);
+        apic.write(REG_LVT_LINT1, LVT_DELIVERY_NMI);
+        apic.write(REG_LVT_ERROR, LVT_MASKED);
+
+        apic.timer_ticks_per_second = apic.calibrate_timer() * (1000 / CALIBRATION_MS);
+        apic.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        // keep ticking at the rate the PIT was programmed to
+        let tick_hz = time::pit::tick_hz();
+        apic.set_timer_periodic(tick_hz);
+        time::pit::set_tick_period_ns(time::NANOS_PER_SECOND / u64::from(tick_hz));
+
+        // the PIT would deliver a second timer interrupt on the same vector
+        unsafe {
+            let mut pics = super::PICS.lock();
+            let [master, slave] = pics.read_masks();
+            pics.write_masks(master | 1, slave);
+        }
+
+        LOCAL_APIC.try_init_once(|| apic).expect("local APIC initialized twice");
+        tlb::register_ipi(tlb::ShootdownIpi {
+            send_to_others: |vector| local_apic().expect("local APIC not initialized").send_ipi_to_others(vector),
+            end_of_interrupt,
+        });
+        Ok(())
+    })
+}
+
+fn msr(reg: u32) -> Msr {
+    Msr::new(X2APIC_MSR_BASE + (reg >> 4))
+}
@@ -0,0 +1,218 @@
+use crate::{
+    acpi::{AcpiError, Madt},
+    memory::{self, MmioRegion},
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const REG_SELECT: usize = 0x00;
+const REG_WINDOW: usize = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_BASE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+static IO_APICS: Mutex<Option<IoApics>> = Mutex::new(None);
+/// Set once the PICs are masked, checked by interrupt handlers which must not take `IO_APICS`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum IoApicError {
+    Acpi(AcpiError),
+    /// The MADT lists no I/O APIC.
+    NotPresent,
+    Map(memory::mmio::MmioError),
+    /// The local APIC has to be enabled first, since it receives the routed interrupts.
+    NoLocalApic,
+    /// `init` has not been called yet.
+    NotInitialized,
+    /// No I/O APIC handles this global system interrupt.
+    NoSuchGsi(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Where and how a global system interrupt is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub vector: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    /// APIC ID of the CPU receiving the interrupt.
+    pub destination: u8,
+    pub masked: bool,
+}
+
+impl Route {
+    fn to_bits(self) -> u64 {
+        let mut bits = u64::from(self.vector) | u64::from(self.destination) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            bits |= ENTRY_ACTIVE_LOW;
+        }
+        if self.trigger == TriggerMode::Level {
+            bits |= ENTRY_LEVEL;
+        }
+        if self.masked {
+            bits |= ENTRY_MASKED;
+        }
+        bits
+    }
+
+    fn from_bits(bits: u64) -> Route {
+        Route {
+            vector: bits as u8,
+            polarity: if bits & ENTRY_ACTIVE_LOW != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            trigger: if bits & ENTRY_LEVEL != 0 { TriggerMode::Level } else { TriggerMode::Edge },
+            destination: (bits >> 56) as u8,
+            masked: bits & ENTRY_MASKED != 0,
+        }
+    }
+}
+
+struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        self.registers.write::<u32>(REG_SELECT, reg);
+        self.registers.read::<u32>(REG_WINDOW)
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        self.registers.write::<u32>(REG_SELECT, reg);
+        self.registers.write::<u32>(REG_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn entry(&self, gsi: u32) -> u64 {
+        let reg = REG_REDIRECTION_BASE + 2 * (gsi - self.gsi_base);
+        u64::from(self.read(reg)) | u64::from(self.read(reg + 1)) << 32
+    }
+
+    fn set_entry(&self, gsi: u32, bits: u64) {
+        let reg = REG_REDIRECTION_BASE + 2 * (gsi - self.gsi_base);
+        // mask the entry while it is half written
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (bits >> 32) as u32);
+        self.write(reg, bits as u32);
+    }
+}
+
+struct IoApics {
+    apics: Vec<IoApic>,
+    madt: Madt,
+}
+
+impl IoApics {
+    fn containing(&self, gsi: u32) -> Result<&IoApic, IoApicError> {
+        self.apics.iter().find(|apic| apic.handles(gsi)).ok_or(IoApicError::NoSuchGsi(gsi))
+    }
+}
+
+/// Maps every I/O APIC listed in the MADT, masks all of their inputs and moves the lines that have handlers
+/// registered over from the PICs.
+///
+/// The 8259 PICs are masked completely afterwards. `irq::register_irq` routes lines registered later.
+/// Needs the heap and an enabled local APIC, see `apic::init`.
+pub fn init() -> Result<(), IoApicError> {
+    if !super::apic::is_enabled() {
+        return Err(IoApicError::NoLocalApic);
+    }
+    let madt = Madt::parse().map_err(IoApicError::Acpi)?;
+    if madt.io_apics.is_empty() {
+        return Err(IoApicError::NotPresent);
+    }
+
+    let mut apics = Vec::new();
+    for entry in &madt.io_apics {
+        let registers = memory::map_mmio(entry.address, REG_WINDOW + 4).map_err(IoApicError::Map)?;
+        let mut apic = IoApic { registers, gsi_base: entry.gsi_base, entries: 0 };
+        apic.entries = ((apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        for gsi in apic.gsi_base..apic.gsi_base + apic.entries {
+            apic.set_entry(gsi, ENTRY_MASKED);
+        }
+        apics.push(apic);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *IO_APICS.lock() = Some(IoApics { apics, madt });
+        super::irq::route_registered_lines()?;
+        unsafe { super::PICS.lock().write_masks(0xff, 0xff) };
+        ENABLED.store(true, Ordering::Release);
+        Ok(())
+    })
+}
+
+/// Returns `true` once legacy IRQs are delivered through the I/O APIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Programs the redirection entry of global system interrupt `gsi`.
+pub fn route_gsi(gsi: u32, route: Route) -> Result<(), IoApicError> {
+    let apics = IO_APICS.lock();
+    let apics = apics.as_ref().ok_or(IoApicError::NotInitialized)?;
+    apics.containing(gsi)?.set_entry(gsi, route.to_bits());
+    Ok(())
+}
+
+/// Reads back the redirection entry of `gsi`.
+pub fn route_of(gsi: u32) -> Result<Route, IoApicError> {
+    let apics = IO_APICS.lock();
+    let apics = apics.as_ref().ok_or(IoApicError::NotInitialized)?;
+    Ok(Route::from_bits(apics.containing(gsi)?.entry(gsi)))
+}
+
+/// Returns the global system interrupt and its configuration that ISA IRQ `irq` is wired to.
+pub fn isa_irq_to_gsi(irq: u8) -> Result<(u32, Polarity, TriggerMode), IoApicError> {
+    let apics = IO_APICS.lock();
+    let apics = apics.as_ref().ok_or(IoApicError::NotInitialized)?;
+    Ok(match apics.madt.isa_override(irq) {
+        Some(o) => (o.gsi, o.polarity, o.trigger),
+        None => (u32::from(irq), Polarity::ActiveHigh, TriggerMode::Edge),
+    })
+}
+
+/// Delivers ISA IRQ `irq` on `vector` to the CPU with APIC ID `destination`, honoring MADT overrides.
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u8) -> Result<(), IoApicError> {
+    let (gsi, polarity, trigger) = isa_irq_to_gsi(irq)?;
+    route_gsi(gsi, Route { vector, polarity, trigger, destination, masked: false })
+}
+
+/// Masks or unmasks `gsi` without changing the rest of its route.
+pub fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    let route = route_of(gsi)?;
+    route_gsi(gsi, Route { masked, ..route })
+}
+
+#[test_case]
+fn test_route_bits_round_trip() {
+    let route = Route {
+        vector: 0x42,
+        polarity: Polarity::ActiveLow,
+        trigger: TriggerMode::Level,
+        destination: 3,
+        masked: true,
+    };
+    assert_eq!(route.to_bits(), 0x0300_0000_0001_a042);
+    assert_eq!(Route::from_bits(route.to_bits()), route);
+}
@@ -0,0 +1,233 @@
+use super::{apic, ioapic, PIC_1_OFFSET, PICS};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::port::Port,
+    structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame},
+};
+
+/// Legacy ISA IRQ lines, numbered as on the PICs before I/O APIC overrides are applied.
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+/// Line the slave PIC is cascaded through.
+const CASCADE: u8 = 2;
+pub const COM1: u8 = 4;
+pub const RTC: u8 = 8;
+pub const MOUSE: u8 = 12;
+/// The lines the PICs report spurious interrupts on.
+const MASTER_SPURIOUS: u8 = 7;
+const SLAVE_SPURIOUS: u8 = 15;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_EOI: u8 = 0x20;
+/// OCW3 selecting the in-service register for the next read of the command port.
+const PIC_READ_ISR: u8 = 0x0b;
+
+pub const LINES: u8 = 16;
+/// Number of handlers that can share one line.
+pub const MAX_SHARED: usize = 4;
+
+/// Handlers of each line as `fn()` pointers in registration order, 0 for free slots.
+static HANDLERS: [[AtomicUsize; MAX_SHARED]; LINES as usize] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const LINE: [AtomicUsize; MAX_SHARED] = [FREE; MAX_SHARED];
+    [LINE; LINES as usize]
+};
+
+#[derive(Debug)]
+pub enum IrqError {
+    NoSuchIrq(u8),
+    /// All `MAX_SHARED` slots of the line are taken.
+    LineFull(u8),
+    /// The handler was not registered for the line.
+    NotRegistered(u8),
+    Route(ioapic::IoApicError),
+}
+
+/// Returns the vector IRQ `irq` is delivered on, whether it comes from the PICs or the I/O APIC.
+pub const fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Makes `handler` run whenever `irq` fires, and enables the line if it was disabled.
+///
+/// Handlers of a shared line are called in registration order, and each has to check whether its device raised
+/// the interrupt. The end of interrupt is signaled after the last one returns. Handlers run with interrupts
+/// disabled and must neither block nor allocate.
+pub fn register_irq(irq: u8, handler: fn()) -> Result<(), IrqError> {
+    let slots = HANDLERS.get(usize::from(irq)).ok_or(IrqError::NoSuchIrq(irq))?;
+    slots.iter()
+        .find(|slot| slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire).is_ok())
+        .ok_or(IrqError::LineFull(irq))?;
+    if let Err(err) = set_line_enabled(irq, true) {
+        remove(slots, handler);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Removes a handler added with `register_irq`, disabling the line once it has no handlers left.
+pub fn unregister_irq(irq: u8, handler: fn()) -> Result<(), IrqError> {
+    let slots = HANDLERS.get(usize::from(irq)).ok_or(IrqError::NoSuchIrq(irq))?;
+    if !remove(slots, handler) {
+        return Err(IrqError::NotRegistered(irq));
+    }
+    if !has_handlers(irq) {
+        set_line_enabled(irq, false)?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if any handler is registered for `irq`.
+pub fn has_handlers(irq: u8) -> bool {
+    HANDLERS.get(usize::from(irq))
+        .map_or(false, |slots| slots.iter().any(|slot| slot.load(Ordering::Acquire) != 0))
+}
+
+fn remove(slots: &[AtomicUsize; MAX_SHARED], handler: fn()) -> bool {
+    slots.iter()
+        .any(|slot| slot.compare_exchange(handler as usize, 0, Ordering::AcqRel, Ordering::Acquire).is_ok())
+}
+
+/// Returns `false` for the timer line once the local APIC timer has replaced the PIT.
+fn line_is_external(irq: u8) -> bool {
+    !(irq == TIMER && apic::is_enabled())
+}
+
+/// Unmasks or masks `irq` at whichever controller currently delivers it.
+fn set_line_enabled(irq: u8, enabled: bool) -> Result<(), IrqError> {
+    if !line_is_external(irq) {
+        return Ok(());
+    }
+    if ioapic::is_enabled() {
+        if enabled {
+            let destination = apic::local_apic().map_or(0, |apic| apic.id() as u8);
+            ioapic::route_isa_irq(irq, vector(irq), destination)
+        } else {
+            ioapic::isa_irq_to_gsi(irq).and_then(|(gsi, _, _)| ioapic::set_masked(gsi, true))
+        }
+        .map_err(IrqError::Route)
+    } else {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut pics = PICS.lock();
+            let masks = unsafe { pics.read_masks() };
+            let mut mask = u16::from(masks[0]) | u16::from(masks[1]) << 8;
+            if enabled {
+                mask &= !(1 << irq);
+                if irq >= 8 {
+                    mask &= !(1 << CASCADE);
+                }
+            } else {
+                mask |= 1 << irq;
+            }
+            unsafe { pics.write_masks(mask as u8, (mask >> 8) as u8) };
+        });
+        Ok(())
+    }
+}
+
+/// Routes every line that has handlers through the I/O APIC. Called by `ioapic::init` before masking the PICs.
+pub(super) fn route_registered_lines() -> Result<(), ioapic::IoApicError> {
+    let destination = apic::local_apic().map_or(0, |apic| apic.id() as u8);
+    for irq in (0..LINES).filter(|irq| has_handlers(*irq) && line_is_external(*irq)) {
+        ioapic::route_isa_irq(irq, vector(irq), destination)?;
+    }
+    Ok(())
+}
+
+fn dispatch(irq: u8) {
+    super::stats::count(vector(irq));
+    if is_spurious_pic_irq(irq) {
+        // the master still saw IRQ 2 from the slave, but the slave has nothing in service
+        if irq == SLAVE_SPURIOUS {
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        return;
+    }
+    run_handlers(irq);
+    end_of_interrupt(irq);
+    if irq == TIMER {
+        // after the end of interrupt, since the thread switched to may not return here for a while
+        crate::task::thread::preempt();
+    }
+}
+
+fn run_handlers(irq: u8) {
+    for slot in &HANDLERS[usize::from(irq)] {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            // only `register_irq` stores non-zero values, and it stores `fn()` pointers
+            let handler: fn() = unsafe { mem::transmute(handler) };
+            handler();
+        }
+    }
+}
+
+/// Returns `true` if the PICs raised `irq` but withdrew the request before the CPU acknowledged it.
+///
+/// The PICs then report their lowest priority line, 7 or 15, without setting its in-service bit.
+fn is_spurious_pic_irq(irq: u8) -> bool {
+    if (irq != MASTER_SPURIOUS && irq != SLAVE_SPURIOUS) || ioapic::is_enabled() {
+        return false;
+    }
+    let command = if irq == MASTER_SPURIOUS { PIC_1_COMMAND } else { PIC_2_COMMAND };
+    let isr = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    isr & (1 << (irq % 8)) == 0
+}
+
+/// Acknowledges `irq` at the controller that delivered it.
+///
+/// Once the local APIC is enabled it delivers the timer interrupt, and once the I/O APIC is enabled it delivers
+/// the other lines as well. Until then they come from the PICs through LINT0, which the local APIC does not track.
+fn end_of_interrupt(irq: u8) {
+    if !line_is_external(irq) || ioapic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector(irq)) };
+    }
+}
+
+extern "x86-interrupt" fn stub<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(IRQ);
+}
+
+/// Points the IDT entries of all IRQ vectors at their dispatch stubs.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let stubs: [HandlerFunc; LINES as usize] = [
+        stub::<0>, stub::<1>, stub::<2>, stub::<3>, stub::<4>, stub::<5>, stub::<6>, stub::<7>,
+        stub::<8>, stub::<9>, stub::<10>, stub::<11>, stub::<12>, stub::<13>, stub::<14>, stub::<15>,
+    ];
+    for (irq, handler) in stubs.iter().enumerate() {
+        idt[usize::from(vector(irq as u8))].set_handler_fn(*handler);
+    }
+}
+
+#[test_case]
+fn test_shared_handlers_are_chained() {
+    use core::sync::atomic::AtomicU8;
+    static ORDER: AtomicU8 = AtomicU8::new(0);
+    fn first() {
+        assert_eq!(ORDER.fetch_add(1, Ordering::SeqCst), 0);
+    }
+    fn second() {
+        assert_eq!(ORDER.fetch_add(1, Ordering::SeqCst), 1);
+    }
+
+    register_irq(RTC, first).unwrap();
+    register_irq(RTC, second).unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| run_handlers(RTC));
+    assert_eq!(ORDER.load(Ordering::SeqCst), 2);
+    unregister_irq(RTC, first).unwrap();
+    unregister_irq(RTC, second).unwrap();
+    assert!(!has_handlers(RTC));
+    assert!(unregister_irq(RTC, first).is_err());
+}
@@ -0,0 +1,50 @@
+use crate::{serial, task, vga_buffer};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        model_specific::Efer,
+    },
+    structures::idt::InterruptStackFrame,
+};
+
+macro_rules! dump {
+    ($($arg:tt)*) => {
+        serial::write_unlocked(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Writes everything known about the interrupted state to serial, without taking any lock.
+///
+/// The general purpose registers are not included, since the interrupt handler prologue has already
+/// reused them by the time this runs.
+pub fn dump_machine_state(reason: &str, stack_frame: &InterruptStackFrame) {
+    dump!("==== {} ====", reason);
+    dump!("rip: {:#018x}  cs: {:#06x}  rflags: {:#010x}",
+        stack_frame.instruction_pointer.as_u64(), stack_frame.code_segment, stack_frame.cpu_flags);
+    dump!("rsp: {:#018x}  ss: {:#06x}", stack_frame.stack_pointer.as_u64(), stack_frame.stack_segment);
+    let (l4_frame, cr3_flags) = Cr3::read();
+    dump!("cr0: {:?}", Cr0::read());
+    dump!("cr2: {:?}", Cr2::read());
+    dump!("cr3: {:#x} {:?}", l4_frame.start_address().as_u64(), cr3_flags);
+    dump!("cr4: {:?}", Cr4::read());
+    dump!("efer: {:?}", Efer::read());
+    match task::current_task_id() {
+        Some(id) => dump!("task: {}", id),
+        None => dump!("task: none (executor or boot code)"),
+    }
+    dump!("uptime: {} ms", crate::time::uptime_ms());
+    dump!("---- screen ----");
+    vga_buffer::for_each_screen_line(|line| dump!("{}", line));
+    dump!("================");
+}
+
+/// Handles a non-maskable interrupt, which is only ever raised here by a hardware watchdog or a fatal
+/// hardware error, by dumping the machine state and halting.
+pub(super) extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    super::stats::count(2);
+    dump_machine_state("NON-MASKABLE INTERRUPT", &stack_frame);
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,205 @@
+use super::stats;
+use crate::{backtrace, error, hlt_loop, memory::{cow, debug, demand, stack, swap}};
+use core::{
+    fmt,
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Number of recovery handlers that can be registered, including the built-in ones.
+pub const MAX_RECOVERY_HANDLERS: usize = 8;
+
+/// Recovery handlers as `fn(&PageFault) -> bool` pointers in registration order, 0 for free slots.
+static HANDLERS: [AtomicUsize; MAX_RECOVERY_HANDLERS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; MAX_RECOVERY_HANDLERS]
+};
+
+/// Tries to resolve a page fault, returning `true` if the faulting instruction can be retried.
+pub type RecoveryHandler = fn(&PageFault) -> bool;
+
+#[derive(Debug)]
+pub enum PageFaultError {
+    /// All `MAX_RECOVERY_HANDLERS` slots are taken.
+    Full,
+    /// The handler was not registered.
+    NotRegistered,
+}
+
+/// A page fault as seen by recovery handlers.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The accessed address, from CR2.
+    pub addr: VirtAddr,
+    pub error_code: PageFaultErrorCode,
+    pub stack_frame: InterruptStackFrameValue,
+    /// Frame pointer of the faulting code, for `backtrace::print`.
+    pub frame_pointer: u64,
+}
+
+impl PageFault {
+    /// Returns `true` if the page was present and the access violated its protection, `false` if it was not present.
+    pub fn is_protection_violation(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    }
+
+    /// Returns `true` if the access was made in ring 3.
+    pub fn is_user(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::USER_MODE)
+    }
+
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    }
+
+    /// Returns `true` if a reserved bit was set in one of the page table entries of the address.
+    pub fn is_malformed_table(&self) -> bool {
+        self.error_code.contains(PageFaultErrorCode::MALFORMED_TABLE)
+    }
+}
+
+impl fmt::Display for PageFault {
+    /// Prints a summary like `write to present page in kernel mode`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.is_instruction_fetch() {
+            "instruction fetch from"
+        } else if self.is_write() {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.is_protection_violation() { "present" } else { "not present" };
+        let mode = if self.is_user() { "user" } else { "kernel" };
+        write!(f, "{} {} page in {} mode", access, page, mode)?;
+        if self.is_malformed_table() {
+            write!(f, ", reserved bit set in page table")?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes `handler` run on page faults the handlers registered before it did not resolve.
+///
+/// Handlers run in the page fault handler with interrupts disabled, possibly while the faulting code holds
+/// any lock, so they must only try to lock. They must return `false` for faults they don't recognize.
+pub fn register_recovery(handler: RecoveryHandler) -> Result<(), PageFaultError> {
+    HANDLERS.iter()
+        .find(|slot| slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire).is_ok())
+        .map(|_| ())
+        .ok_or(PageFaultError::Full)
+}
+
+/// Removes a handler added with `register_recovery`.
+pub fn unregister_recovery(handler: RecoveryHandler) -> Result<(), PageFaultError> {
+    HANDLERS.iter()
+        .find(|slot| slot.compare_exchange(handler as usize, 0, Ordering::AcqRel, Ordering::Acquire).is_ok())
+        .map(|_| ())
+        .ok_or(PageFaultError::NotRegistered)
+}
+
+/// Sets the page fault handler and registers the built-in recovery handlers.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.page_fault.set_handler_fn(page_fault_handler)
+            .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+    }
+    for handler in [copy_on_write as RecoveryHandler, swap_in, demand_page, stack_guard] {
+        register_recovery(handler).expect("no room for built-in page fault handlers");
+    }
+}
+
+fn recover(fault: &PageFault) -> bool {
+    HANDLERS.iter().any(|slot| {
+        let handler = slot.load(Ordering::Acquire);
+        // only `register_recovery` stores non-zero values, and it stores `RecoveryHandler` pointers
+        handler != 0 && unsafe { mem::transmute::<usize, RecoveryHandler>(handler) }(fault)
+    })
+}
+
+fn copy_on_write(fault: &PageFault) -> bool {
+    fault.is_protection_violation() && fault.is_write() && cow::handle_page_fault(fault.addr)
+}
+
+fn swap_in(fault: &PageFault) -> bool {
+    !fault.is_protection_violation() && swap::handle_page_fault(fault.addr)
+}
+
+fn demand_page(fault: &PageFault) -> bool {
+    !fault.is_protection_violation() && demand::handle_page_fault(fault.addr)
+}
+
+/// Reports a stack overflow and halts when the fault hit the guard page of a stack.
+fn stack_guard(fault: &PageFault) -> bool {
+    if let Some(owner) = stack::guard_page_owner(fault.addr) {
+        error!(
+            "EXCEPTION: STACK OVERFLOW in task {}\nAccessed Address: {:?}\n{:#?}",
+            owner,
+            fault.addr,
+            fault.stack_frame,
+        );
+        let frame = &fault.stack_frame;
+        backtrace::print(frame.instruction_pointer.as_u64(), fault.frame_pointer, frame.stack_pointer.as_u64());
+        hlt_loop();
+    }
+    false
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let frame_pointer = backtrace::interrupted_frame_pointer();
+    stats::count(14);
+    let fault = PageFault { addr: Cr2::read(), error_code, stack_frame: *stack_frame, frame_pointer };
+    if recover(&fault) {
+        return;
+    }
+
+    let frame = &fault.stack_frame;
+    backtrace::print(frame.instruction_pointer.as_u64(), frame_pointer, frame.stack_pointer.as_u64());
+    match debug::translate(fault.addr) {
+        Some(mapping) => panic!(
+            "EXCEPTION: PAGE FAULT\nAccessed Address (CR2): {:?}\nError Code: {:?} ({})\nMapping: {}\n{:#?}",
+            fault.addr, error_code, fault, mapping, frame
+        ),
+        None => panic!(
+            "EXCEPTION: PAGE FAULT\nAccessed Address (CR2): {:?}\nError Code: {:?} ({})\nMapping: none\n{:#?}",
+            fault.addr, error_code, fault, frame
+        ),
+    }
+}
+
+#[test_case]
+fn test_decode_error_code() {
+    let fault = PageFault {
+        addr: VirtAddr::new(0x1000),
+        error_code: PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        stack_frame: InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0),
+            code_segment: 0,
+            cpu_flags: 0,
+            stack_pointer: VirtAddr::new(0),
+            stack_segment: 0,
+        },
+        frame_pointer: 0,
+    };
+    assert!(fault.is_protection_violation() && fault.is_write());
+    assert!(!fault.is_user() && !fault.is_instruction_fetch());
+}
+
+#[test_case]
+fn test_register_recovery() {
+    fn never(_fault: &PageFault) -> bool {
+        false
+    }
+    register_recovery(never).unwrap();
+    assert!(unregister_recovery(never).is_ok());
+    assert!(unregister_recovery(never).is_err());
+}
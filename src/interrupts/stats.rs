@@ -0,0 +1,124 @@
+use super::{apic, irq, vector};
+use crate::memory::tlb;
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Number of CPUs with their own counters. CPUs with higher APIC IDs share the counters modulo this.
+pub const MAX_CPUS: usize = 8;
+
+/// Interrupt counts per CPU and vector.
+static COUNTS: [[AtomicU64; 256]; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const CPU: [AtomicU64; 256] = [ZERO; 256];
+    [CPU; MAX_CPUS]
+};
+
+const EXCEPTION_NAMES: [&str; 21] = [
+    "divide error", "debug", "non-maskable interrupt", "breakpoint", "overflow", "bound range exceeded",
+    "invalid opcode", "device not available", "double fault", "coprocessor segment overrun", "invalid TSS",
+    "segment not present", "stack-segment fault", "general protection fault", "page fault", "reserved",
+    "x87 floating-point", "alignment check", "machine check", "SIMD floating-point", "virtualization",
+];
+const VMM_COMMUNICATION: u8 = 29;
+const SECURITY_EXCEPTION: u8 = 30;
+
+/// The counts of one vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub per_cpu: [u64; MAX_CPUS],
+}
+
+impl VectorStats {
+    pub fn total(&self) -> u64 {
+        self.per_cpu.iter().sum()
+    }
+
+    /// Returns a human readable name of the vector.
+    pub fn name(&self) -> &'static str {
+        name(self.vector)
+    }
+}
+
+impl fmt::Display for VectorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x} {:<28} {:>10}", self.vector, self.name(), self.total())?;
+        for count in &self.per_cpu {
+            write!(f, " {:>8}", count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts an interrupt on `vector` for the current CPU. Called first thing by every handler.
+pub(super) fn count(vector: u8) {
+    COUNTS[cpu_index()][usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how often `vector` fired on all CPUs together. Works without the heap.
+pub fn count_of(vector: u8) -> u64 {
+    COUNTS.iter().map(|cpu| cpu[usize::from(vector)].load(Ordering::Relaxed)).sum()
+}
+
+/// Returns the counts of every vector that fired at least once, in vector order.
+pub fn stats() -> Vec<VectorStats> {
+    (0..=255u8)
+        .map(|vector| VectorStats {
+            vector,
+            per_cpu: core::array::from_fn(|cpu| COUNTS[cpu][usize::from(vector)].load(Ordering::Relaxed)),
+        })
+        .filter(|stats| stats.total() > 0)
+        .collect()
+}
+
+/// Prints a table of `stats` to serial.
+pub fn print_stats() {
+    crate::serial_print!("vector {:<28} {:>10}", "name", "total");
+    for cpu in 0..MAX_CPUS {
+        crate::serial_print!(" {:>8}", alloc::format!("cpu{}", cpu));
+    }
+    crate::serial_println!();
+    for stats in stats() {
+        crate::serial_println!("{}", stats);
+    }
+}
+
+/// Returns the index of the current CPU among `MAX_CPUS` slots, 0 until the local APIC is enabled.
+pub(crate) fn cpu_index() -> usize {
+    apic::local_apic().map_or(0, |apic| apic.id() as usize % MAX_CPUS)
+}
+
+/// Returns a human readable name of `vector`.
+pub fn name(vector: u8) -> &'static str {
+    match vector {
+        v if usize::from(v) < EXCEPTION_NAMES.len() => EXCEPTION_NAMES[usize::from(v)],
+        VMM_COMMUNICATION => "VMM communication",
+        SECURITY_EXCEPTION => "security exception",
+        v if v < irq::vector(0) => "reserved exception",
+        v if v == irq::vector(irq::TIMER) => "timer",
+        v if v == irq::vector(irq::KEYBOARD) => "keyboard",
+        v if v == irq::vector(irq::COM1) => "serial",
+        v if v == irq::vector(irq::RTC) => "rtc",
+        v if v < irq::vector(irq::LINES) => "legacy irq",
+        v if (vector::FIRST_DYNAMIC..vector::END_DYNAMIC).contains(&v) => "dynamic",
+        tlb::SHOOTDOWN_VECTOR => "tlb shootdown",
+        apic::SPURIOUS_VECTOR => "spurious",
+        _ => "unassigned",
+    }
+}
+
+#[test_case]
+fn test_timer_is_counted() {
+    let timer = irq::vector(irq::TIMER);
+    let start = count_of(timer);
+    while count_of(timer) == start {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(name(timer), "timer");
+    assert_eq!(name(14), "page fault");
+}
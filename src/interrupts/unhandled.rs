@@ -0,0 +1,74 @@
+use super::{apic, stats};
+use crate::irq_println;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+extern "x86-interrupt" fn exception<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    stats::count(VECTOR);
+    panic!("EXCEPTION: {} (vector {})\n{:#?}", stats::name(VECTOR), VECTOR, stack_frame);
+}
+
+extern "x86-interrupt" fn exception_with_code<const VECTOR: u8>(stack_frame: InterruptStackFrame, error_code: u64) {
+    stats::count(VECTOR);
+    panic!(
+        "EXCEPTION: {} (vector {}, error code {:#x})\n{:#?}",
+        stats::name(VECTOR), VECTOR, error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
+    stats::count(18);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+/// Logs an interrupt on a vector nothing was installed for and acknowledges it if the local APIC delivered it.
+extern "x86-interrupt" fn interrupt<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    stats::count(VECTOR);
+    irq_println!("WARNING: unhandled interrupt on vector {:#04x}", VECTOR);
+    if let Some(apic) = apic::local_apic() {
+        // software interrupts and interrupts from the PICs are not in service at the APIC
+        if apic.is_in_service(VECTOR) {
+            apic.end_of_interrupt();
+        }
+    }
+}
+
+/// Points every exception and interrupt vector at a catch-all handler. Called before the real handlers are set.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(exception::<0>);
+    idt.debug.set_handler_fn(exception::<1>);
+    idt.non_maskable_interrupt.set_handler_fn(exception::<2>);
+    idt.breakpoint.set_handler_fn(exception::<3>);
+    idt.overflow.set_handler_fn(exception::<4>);
+    idt.bound_range_exceeded.set_handler_fn(exception::<5>);
+    idt.invalid_opcode.set_handler_fn(exception::<6>);
+    idt.device_not_available.set_handler_fn(exception::<7>);
+    idt.invalid_tss.set_handler_fn(exception_with_code::<10>);
+    idt.segment_not_present.set_handler_fn(exception_with_code::<11>);
+    idt.stack_segment_fault.set_handler_fn(exception_with_code::<12>);
+    idt.general_protection_fault.set_handler_fn(exception_with_code::<13>);
+    idt.x87_floating_point.set_handler_fn(exception::<16>);
+    idt.alignment_check.set_handler_fn(exception_with_code::<17>);
+    idt.machine_check.set_handler_fn(machine_check);
+    idt.simd_floating_point.set_handler_fn(exception::<19>);
+    idt.virtualization.set_handler_fn(exception::<20>);
+    idt.vmm_communication_exception.set_handler_fn(exception_with_code::<29>);
+    idt.security_exception.set_handler_fn(exception_with_code::<30>);
+
+    let rows: [[HandlerFunc; 16]; 14] = [
+        stub_row!(interrupt, 0x2), stub_row!(interrupt, 0x3), stub_row!(interrupt, 0x4), stub_row!(interrupt, 0x5),
+        stub_row!(interrupt, 0x6), stub_row!(interrupt, 0x7), stub_row!(interrupt, 0x8), stub_row!(interrupt, 0x9),
+        stub_row!(interrupt, 0xa), stub_row!(interrupt, 0xb), stub_row!(interrupt, 0xc), stub_row!(interrupt, 0xd),
+        stub_row!(interrupt, 0xe), stub_row!(interrupt, 0xf),
+    ];
+    for (index, handler) in rows.iter().flatten().enumerate() {
+        idt[32 + index].set_handler_fn(*handler);
+    }
+}
+
+#[test_case]
+fn test_unhandled_vector_is_counted() {
+    // 0x4f lies between the legacy IRQs and the dynamic vectors and is never installed
+    let before = stats::count_of(0x4f);
+    unsafe { core::arch::asm!("int 0x4f") };
+    assert_eq!(stats::count_of(0x4f), before + 1);
+}
@@ -0,0 +1,87 @@
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+/// First vector handed out by `allocate`, above the remapped PIC vectors and the exceptions.
+pub const FIRST_DYNAMIC: u8 = 0x50;
+/// One past the last vector handed out by `allocate`. The vectors above are used by the TLB shootdown and
+/// spurious interrupts.
+pub const END_DYNAMIC: u8 = 0xf0;
+
+const COUNT: usize = (END_DYNAMIC - FIRST_DYNAMIC) as usize;
+
+/// Handlers of the dynamic vectors as `fn()` pointers, 0 for free vectors.
+static HANDLERS: [AtomicUsize; COUNT] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; COUNT]
+};
+
+/// Reserves a free vector and makes the interrupt handler call `handler` on it.
+///
+/// `handler` runs with interrupts disabled and must neither block nor allocate. The end of interrupt is
+/// signaled to the local APIC after it returns, so the vector is meant for APIC delivered interrupts like MSIs.
+pub fn allocate(handler: fn()) -> Option<u8> {
+    let value = handler as usize;
+    HANDLERS.iter().position(|slot| {
+        slot.compare_exchange(0, value, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    })
+    .map(|index| FIRST_DYNAMIC + index as u8)
+}
+
+/// Releases a vector returned by `allocate`. The device must not raise it anymore.
+pub fn free(vector: u8) {
+    let previous = slot(vector).expect("not a dynamic vector").swap(0, Ordering::AcqRel);
+    assert!(previous != 0, "freeing unallocated vector {:#x}", vector);
+}
+
+/// Returns `true` if `vector` is currently allocated.
+pub fn is_allocated(vector: u8) -> bool {
+    slot(vector).map_or(false, |slot| slot.load(Ordering::Acquire) != 0)
+}
+
+fn slot(vector: u8) -> Option<&'static AtomicUsize> {
+    HANDLERS.get(vector.checked_sub(FIRST_DYNAMIC)? as usize)
+}
+
+fn dispatch(vector: u8) {
+    super::stats::count(vector);
+    let handler = slot(vector).map_or(0, |slot| slot.load(Ordering::Acquire));
+    if handler != 0 {
+        // only `allocate` stores non-zero values, and it stores `fn()` pointers
+        let handler: fn() = unsafe { mem::transmute(handler) };
+        handler();
+    }
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+/// Points the IDT entries of all dynamic vectors at their dispatch stubs.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let rows: [[HandlerFunc; 16]; COUNT / 16] = [
+        stub_row!(stub, 0x5), stub_row!(stub, 0x6), stub_row!(stub, 0x7), stub_row!(stub, 0x8), stub_row!(stub, 0x9),
+        stub_row!(stub, 0xa), stub_row!(stub, 0xb), stub_row!(stub, 0xc), stub_row!(stub, 0xd), stub_row!(stub, 0xe),
+    ];
+    for (index, handler) in rows.iter().flatten().enumerate() {
+        idt[usize::from(FIRST_DYNAMIC) + index].set_handler_fn(*handler);
+    }
+}
+
+#[test_case]
+fn test_allocated_vector_dispatches() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let vector = allocate(|| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    })
+    .expect("no free vector");
+    assert!(is_allocated(vector));
+    dispatch(vector);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    free(vector);
+    assert!(!is_allocated(vector));
+}
@@ -10,13 +10,31 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod block;
+mod bytes;
+pub mod cmdline;
+pub mod console;
+pub mod framebuffer;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod logger;
 pub mod memory;
+pub mod oops;
+pub mod pci;
+pub mod power;
 pub mod serial;
+pub mod sync;
 pub mod task;
+#[cfg(test)]
+mod test_util;
+pub mod time;
+pub mod vfs;
 pub mod vga_buffer;
+pub mod vm;
 
 pub trait Testable {
     fn run(&self) -> ();
@@ -32,9 +50,28 @@ where T: Fn() {
 }
 
 pub fn init() {
+    logger::init();
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    let mouse = match task::keyboard::i8042::init() {
+        Ok(info) => info.mouse,
+        Err(err) => {
+            warn!("PS/2 controller initialization failed: {:?}", err);
+            false
+        }
+    };
+    time::pit::set_frequency(time::pit::DEFAULT_TICK_HZ);
+    interrupts::register_irq(interrupts::irq::TIMER, time::tick).expect("failed to register timer IRQ");
+    interrupts::register_irq(interrupts::irq::KEYBOARD, task::keyboard::handle_interrupt)
+        .expect("failed to register keyboard IRQ");
+    if mouse {
+        interrupts::register_irq(interrupts::irq::MOUSE, task::mouse::handle_interrupt)
+            .expect("failed to register mouse IRQ");
+    }
+    serial::init();
+    console::init_from_cmdline();
+    task::keyboard::layout::init_from_cmdline();
     x86_64::instructions::interrupts::enable();
 }
 
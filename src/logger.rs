@@ -0,0 +1,289 @@
+//! The kernel's `log::Log`, behind the `error!`, `warn!`, `info!`, `debug!` and `trace!` macros.
+//!
+//! The `log` command line option sets the levels, as a comma separated list of a default level and
+//! `<module>=<level>` overrides, e.g. `log=warn,rust_os::memory=debug`. A module's level applies to its submodules
+//! too, and the longest matching module wins. Without the option everything up to `info` is logged.
+//! `log.output` picks where records go: `console` for the `print!` backends, `serial` for the first serial port,
+//! or `both`, the default.
+//!
+//! Every line starts with the uptime in seconds, the CPU and the task being polled, if any, like
+//! `[    2.015347 0 7:shell]`, so that output of tasks and interrupt handlers on several CPUs can be told apart.
+//! The last lines are also kept in memory, for the panic screen, see `for_each_recent_line`.
+
+use crate::{cmdline, console, interrupts::stats::cpu_index, serial, sync::IrqSpinLock, task, time, vga_buffer::Color};
+use core::fmt;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+#[doc(hidden)]
+pub use log;
+
+/// The level of modules the `log` option doesn't mention.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Number of lines `for_each_recent_line` keeps, and the bytes kept of each.
+const RECENT_LINES: usize = 16;
+const RECENT_LINE_BYTES: usize = 120;
+
+static LOGGER: KernelLogger = KernelLogger;
+static RECENT: IrqSpinLock<RecentLines> = IrqSpinLock::new(RecentLines::new());
+
+/// Logs an error, printed with `ERROR: ` after the context and in white on red.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::logger::log::error!($($arg)*));
+}
+
+/// Logs a warning, printed with `WARNING: ` after the context and in light red.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::logger::log::warn!($($arg)*));
+}
+
+/// Logs a message that is printed like with `println!`, after the context.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::logger::log::info!($($arg)*));
+}
+
+/// Logs a message for debugging a subsystem, off unless the `log` option enables it.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::logger::log::debug!($($arg)*));
+}
+
+/// Logs a message for following a subsystem step by step, off unless the `log` option enables it.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::logger::log::trace!($($arg)*));
+}
+
+/// Installs the logger. Records logged before are dropped.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(max_level(filters()));
+    }
+}
+
+/// Calls `f` with the last lines logged, oldest first, each cut off after 120 bytes, without the console colors.
+///
+/// Doesn't wait for a CPU that is logging at the moment and calls `f` with nothing then, so it can be used from
+/// panic handlers.
+pub fn for_each_recent_line(f: impl FnMut(&str)) {
+    if let Some(recent) = RECENT.try_lock() {
+        recent.for_each(f);
+    }
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(filters(), metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (to_console, to_serial) = match cmdline::option("log.output") {
+            Some("console") => (true, false),
+            Some("serial") => (false, true),
+            // the serial console already shows what is printed
+            _ => (true, !console::has_backend(&serial::SERIAL_CONSOLE)),
+        };
+        let context = Context::current();
+        let (level, target) = (record.level(), record.target());
+        RECENT.lock().push(format_args!("{} {:<5} {}: {}", context, level, target, record.args()));
+        if to_console {
+            let args = record.args();
+            match record.level() {
+                Level::Error => {
+                    console::_print_colored(Color::White, Color::Red, format_args!("{} ERROR: {}", context, args))
+                }
+                Level::Warn => console::_print_colored(
+                    Color::LightRed,
+                    Color::Black,
+                    format_args!("{} WARNING: {}", context, args),
+                ),
+                Level::Info => console::_print(format_args!("{} {}", context, args)),
+                level => console::_print_colored(
+                    Color::DarkGray,
+                    Color::Black,
+                    format_args!("{} {} {}: {}", context, level, record.target(), args),
+                ),
+            }
+            // outside the colors, or the row it starts would be cleared in them
+            console::_print(format_args!("\n"));
+        }
+        if to_serial {
+            serial::_print(format_args!("{} {:<5} {}: {}\n", context, level, target, record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Where and when a record was logged, the prefix of its line.
+struct Context {
+    uptime_ns: u64,
+    cpu: usize,
+    task_id: Option<u64>,
+    task_name: Option<&'static str>,
+}
+
+impl Context {
+    fn current() -> Context {
+        Context {
+            uptime_ns: time::now(),
+            cpu: cpu_index(),
+            task_id: task::current_task_id(),
+            task_name: task::current_task_name(),
+        }
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.uptime_ns / time::NANOS_PER_SECOND;
+        let micros = self.uptime_ns % time::NANOS_PER_SECOND / 1000;
+        write!(f, "[{:>5}.{:06} {} ", seconds, micros, self.cpu)?;
+        match (self.task_id, self.task_name) {
+            (Some(id), Some(name)) => write!(f, "{}:{}]", id, name),
+            (Some(id), None) => write!(f, "{}]", id),
+            (None, _) => write!(f, "-]"),
+        }
+    }
+}
+
+/// A ring buffer of the last lines logged.
+struct RecentLines {
+    lines: [[u8; RECENT_LINE_BYTES]; RECENT_LINES],
+    lens: [usize; RECENT_LINES],
+    /// Number of lines pushed so far, of which the last `RECENT_LINES` are kept.
+    pushed: usize,
+}
+
+impl RecentLines {
+    const fn new() -> RecentLines {
+        RecentLines {
+            lines: [[0; RECENT_LINE_BYTES]; RECENT_LINES],
+            lens: [0; RECENT_LINES],
+            pushed: 0,
+        }
+    }
+
+    /// Replaces the oldest line with `args`, cut off at the last character that fits.
+    fn push(&mut self, args: fmt::Arguments) {
+        struct Truncated<'a> {
+            bytes: &'a mut [u8],
+            len: usize,
+        }
+
+        impl fmt::Write for Truncated<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let mut end = s.len().min(self.bytes.len() - self.len);
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+                self.len += end;
+                Ok(())
+            }
+        }
+
+        let slot = self.pushed % RECENT_LINES;
+        let mut line = Truncated { bytes: &mut self.lines[slot], len: 0 };
+        let _ = fmt::Write::write_fmt(&mut line, args);
+        self.lens[slot] = line.len;
+        self.pushed += 1;
+    }
+
+    fn for_each(&self, mut f: impl FnMut(&str)) {
+        for pushed in self.pushed.saturating_sub(RECENT_LINES)..self.pushed {
+            let slot = pushed % RECENT_LINES;
+            // only whole characters are copied in
+            f(core::str::from_utf8(&self.lines[slot][..self.lens[slot]]).unwrap_or(""));
+        }
+    }
+}
+
+fn filters() -> &'static str {
+    cmdline::option("log").unwrap_or("")
+}
+
+/// Returns the level `filters` set for records of `target`, a module path.
+fn level_for(filters: &str, target: &str) -> LevelFilter {
+    let mut level = DEFAULT_LEVEL;
+    let mut matched: Option<usize> = None;
+    for directive in filters.split(',') {
+        match directive.split_once('=') {
+            Some((module, module_level)) => {
+                let rest = target.strip_prefix(module);
+                let matches = rest.map_or(false, |rest| rest.is_empty() || rest.starts_with("::"));
+                if matches && matched.map_or(true, |len| module.len() >= len) {
+                    if let Ok(module_level) = module_level.parse() {
+                        level = module_level;
+                        matched = Some(module.len());
+                    }
+                }
+            }
+            None if matched.is_none() => level = directive.parse().unwrap_or(level),
+            None => {}
+        }
+    }
+    level
+}
+
+/// Returns the highest level `filters` enable for any module, so that the macros skip everything above it.
+fn max_level(filters: &str) -> LevelFilter {
+    // no module path is empty, so this is the default level
+    let default = level_for(filters, "");
+    filters
+        .split(',')
+        .filter_map(|directive| directive.split_once('=')?.1.parse().ok())
+        .fold(default, Ord::max)
+}
+
+#[test_case]
+fn test_module_levels_are_filtered() {
+    let filters = "warn,rust_os::memory=debug,rust_os::memory::swap=error";
+    assert_eq!(level_for(filters, "rust_os::task"), LevelFilter::Warn);
+    assert_eq!(level_for(filters, "rust_os::memory"), LevelFilter::Debug);
+    assert_eq!(level_for(filters, "rust_os::memory::vmm"), LevelFilter::Debug);
+    assert_eq!(level_for(filters, "rust_os::memory::swap"), LevelFilter::Error);
+    assert_eq!(level_for(filters, "rust_os::memory_map"), LevelFilter::Warn);
+    assert_eq!(level_for("", "rust_os"), DEFAULT_LEVEL);
+    assert_eq!(max_level(filters), LevelFilter::Debug);
+    assert_eq!(max_level("warn"), LevelFilter::Warn);
+}
+
+#[test_case]
+fn test_lines_start_with_the_context() {
+    use crate::test_util::Line;
+
+    let line = Line::format(format_args!(
+        "{}",
+        Context { uptime_ns: 12_345_678_901, cpu: 1, task_id: Some(7), task_name: Some("shell") }
+    ));
+    assert_eq!(line.as_bytes(), b"[   12.345678 1 7:shell]");
+    let line = Line::format(format_args!("{}", Context { uptime_ns: 5_000, cpu: 0, task_id: None, task_name: None }));
+    assert_eq!(line.as_bytes(), b"[    0.000005 0 -]");
+}
+
+#[test_case]
+fn test_recent_lines_keep_the_last_ones() {
+    let mut recent = RecentLines::new();
+    for index in 0..RECENT_LINES + 4 {
+        recent.push(format_args!("line {}", index));
+    }
+    recent.push(format_args!("{:x<200}", "long "));
+    let (mut count, mut first, mut last_len) = (0, false, 0);
+    recent.for_each(|line| {
+        first |= count == 0 && line == "line 5";
+        count += 1;
+        last_len = line.len();
+    });
+    assert!(first);
+    assert_eq!(count, RECENT_LINES);
+    assert_eq!(last_len, RECENT_LINE_BYTES);
+}
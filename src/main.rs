@@ -7,21 +7,32 @@
 
 extern crate alloc;
 
-use core::panic::PanicInfo;
+use alloc::{boxed::Box, sync::Arc};
+use core::{panic::PanicInfo, time::Duration};
 use rust_os::{
-    println, 
-    memory::BootInfoFrameAllocator,
+    cmdline,
+    console::{self, ConsoleBackend},
+    framebuffer::{self, console::FramebufferConsole, font::Font},
+    info,
+    println,
+    warn,
+    vga_buffer,
+    memory::{
+        stack::{Stack, KERNEL_STACK_SIZE},
+        BootInfoFrameAllocator,
+    },
     task::{
         keyboard,
+        Priority,
         Task, 
         executor::Executor,
     },
 };
-use bootloader::{BootInfo, entry_point};
+use bootloader::{bootinfo::MemoryMap, BootInfo, entry_point};
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("Allocation error: {:?}", layout)
+    rust_os::allocator::handle_alloc_error(layout)
 }
 
 entry_point!(kernel_main);
@@ -30,27 +41,194 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use rust_os::{memory, allocator};
     use x86_64::VirtAddr;
 
-    println!("Hello World{}", "!");
     rust_os::init();
+    println!("Hello World{}", "!");
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    if let Err(err) = rust_os::backtrace::symbols::init(&boot_info.memory_map) {
+        warn!("no kernel symbols ({:?}), backtraces show bare addresses", err);
+    }
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
-    
+    memory::install(mapper, frame_allocator);
+    memory::wx::enforce();
+    memory::wx::check(memory::wx::Policy::Panic);
+    if let Err(err) = rust_os::pci::ecam::init() {
+        info!("no PCIe ECAM ({:?}), PCI configuration space stays on the I/O ports", err);
+    }
+    if let Err(err) = rust_os::interrupts::apic::init() {
+        warn!("local APIC unavailable ({:?}), staying on the 8259 PIC", err);
+    } else if let Err(err) = rust_os::interrupts::ioapic::init() {
+        warn!("I/O APIC unavailable ({:?}), legacy IRQs stay on the 8259 PIC", err);
+    }
+    rust_os::time::init();
+    let boot_time = rust_os::time::rtc::DateTime::from_unix_timestamp(rust_os::time::wall_clock().as_secs());
+    info!("wall clock: {} UTC", boot_time);
+    // drivers time out on devices that don't answer, which needs the clock
+    rust_os::pci::driver::register(&rust_os::block::ata::DRIVER);
+    rust_os::pci::driver::register(&rust_os::block::ahci::DRIVER);
+    info!("{} PCI functions bound to drivers", rust_os::pci::driver::probe_all());
+    mount_root(&boot_info.memory_map);
+    enable_swap();
+
+    let kernel_stack = Stack::new(KERNEL_STACK_SIZE, "kernel")
+        .expect("kernel stack allocation failed");
+    unsafe { kernel_stack.switch_to(kernel_run) }
+}
+
+/// Mounts the initrd as the root, or a RAM filesystem if there is none, another RAM filesystem on `/tmp` and the
+/// devices on `/dev`.
+///
+/// That is all there is until disks are mounted. The initrd is read-only, so it needs a `/tmp` of its own, and
+/// `/dev` and `/tmp` in the archive to mount them on.
+fn mount_root(memory_map: &MemoryMap) {
+    use rust_os::{
+        task::block_on,
+        vfs::{self, archive::ArchiveFs, devfs::DevFs, ramfs::RamFs, FileSystem, FsError},
+    };
+
+    let root: Arc<dyn FileSystem> = match rust_os::initrd::find(memory_map).map(ArchiveFs::new) {
+        Some(Ok(initrd)) => {
+            info!("initrd: {} archive", initrd.name());
+            Arc::new(initrd)
+        }
+        Some(Err(err)) => {
+            warn!("initrd unreadable ({:?}), the root is empty", err);
+            Arc::new(RamFs::new())
+        }
+        None => Arc::new(RamFs::new()),
+    };
+    if let Err(err) = block_on(vfs::mount("/", root)) {
+        warn!("root filesystem not mounted: {:?}", err);
+        return;
+    }
+    let below: [(&str, Arc<dyn FileSystem>); 2] =
+        [("/tmp", Arc::new(RamFs::new())), ("/dev", Arc::new(DevFs::new()))];
+    for (path, fs) in below {
+        let mounted = block_on(async {
+            match vfs::create_dir(path).await {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(err) => return Err(err),
+            }
+            vfs::mount(path, fs).await
+        });
+        if let Err(err) = mounted {
+            warn!("{} not mounted: {:?}", path, err);
+        }
+    }
+}
+
+/// Swaps to the block device named by the `swap` command line option, such as `swap=ata0p2`.
+fn enable_swap() {
+    use rust_os::memory::swap::{self, BlockSwap};
+
+    let name = match cmdline::option("swap") {
+        Some(name) => name,
+        None => return,
+    };
+    match rust_os::block::find(name).map(BlockSwap::new) {
+        Some(Some(device)) => {
+            info!("swap: {} slots on {}", swap::SwapDevice::slot_count(&device), name);
+            swap::enable(Box::new(device));
+        }
+        Some(None) => warn!("swap device {} is read-only or has blocks larger than a page", name),
+        None => warn!("swap device {} not found", name),
+    }
+}
+
+/// How long written blocks may stay in the block caches before they are written back.
+const BLOCK_WRITE_BACK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Continues booting on the guard-paged kernel stack.
+extern "C" fn kernel_run() -> ! {
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!");
 
+    rust_os::task::thread::init();
+    vga_buffer::init_scrollback();
+    vga_buffer::terminal::init();
+    init_consoles();
+    register_hotkeys();
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn_named("example", example_task());
+    executor.spawn_named("block write-back", rust_os::block::cache::write_back_periodically(BLOCK_WRITE_BACK_PERIOD));
+    executor.spawn(Task::new(shell()).with_name("shell").with_priority(Priority::Interrupt));
     executor.run();
 }
 
+/// Adds the framebuffer console if the `console` command line option lists it, see `console::init_from_cmdline`.
+/// `vga=framebuffer` draws the VGA console, terminals and all, on the framebuffer instead of in text mode.
+fn init_consoles() {
+    if cmdline::option("vga") == Some("framebuffer") {
+        if let Err(err) = framebuffer::init_bochs(1024, 768).and_then(vga_buffer::use_framebuffer) {
+            warn!("framebuffer unavailable ({:?}), staying in text mode", err);
+        }
+    }
+    let names = match cmdline::option("console") {
+        Some(names) => names,
+        None => return,
+    };
+    if !names.split(',').any(|name| name == "framebuffer") {
+        return;
+    }
+    let framebuffer = framebuffer::init_bochs(1024, 768)
+        .and_then(|framebuffer| FramebufferConsole::new(framebuffer, Font::builtin()));
+    let console: &'static dyn ConsoleBackend = match framebuffer {
+        Ok(console) => Box::leak(Box::new(console)),
+        Err(err) => {
+            warn!("framebuffer console unavailable: {:?}", err);
+            return;
+        }
+    };
+    // the VGA screen only filled in until now if nothing else was listed
+    if names.split(',').all(|name| name == "framebuffer") {
+        console::set_backends(&[console]);
+    } else {
+        console::add_backend(console);
+    }
+}
+
+fn register_hotkeys() {
+    use keyboard::{hotkey, Hotkey, KeyCode};
+
+    let taken = "hotkey registered twice";
+    hotkey::register(Hotkey::new(KeyCode::Delete).with_ctrl().with_alt(), || rust_os::power::reboot()).expect(taken);
+    for (index, key) in [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4].iter().enumerate() {
+        hotkey::register(Hotkey::new(*key).with_alt(), move || {
+            vga_buffer::terminal::switch(index);
+        })
+        .expect(taken);
+    }
+    hotkey::register(Hotkey::new(KeyCode::PageUp).with_shift(), || {
+        vga_buffer::scroll_back(vga_buffer::BUFFER_HEIGHT - 1)
+    })
+    .expect(taken);
+    hotkey::register(Hotkey::new(KeyCode::PageDown).with_shift(), || {
+        vga_buffer::scroll_forward(vga_buffer::BUFFER_HEIGHT - 1)
+    })
+    .expect(taken);
+}
+
+/// Reads lines on the shell terminal, which has no commands yet.
+async fn shell() {
+    use vga_buffer::terminal::{self, SHELL};
+
+    console::set_terminal(SHELL);
+    terminal::print(SHELL, format_args!("Shell on terminal {}, Alt+F1 shows the kernel log.\n", SHELL + 1));
+    loop {
+        terminal::print(SHELL, format_args!("> "));
+        let line = console::read_line().await;
+        if !line.is_empty() {
+            terminal::print(SHELL, format_args!("unknown command: {}\n", line));
+        }
+    }
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -63,8 +241,7 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{info}");
-    loop {}
+    rust_os::oops::show(info)
 }
 
 #[cfg(test)]
@@ -1,12 +1,17 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
+        mapper::MapToError,
         Page, 
         PhysFrame, 
         PageTable, 
+        PageTableFlags,
         OffsetPageTable,
         Mapper,
         Size4KiB,
         FrameAllocator,
+        FrameDeallocator,
     },
     PhysAddr,
     VirtAddr,
@@ -17,9 +22,101 @@ use bootloader::bootinfo::{
     MemoryRegionType,
 };
 
+pub mod address_space;
+pub mod aslr;
+pub mod cow;
+pub mod debug;
+pub mod demand;
+pub mod dma;
+pub mod mmio;
+pub mod stack;
+pub mod swap;
+pub mod tlb;
+pub mod vmm;
+pub mod wx;
+pub mod zone;
+
+pub use address_space::AddressSpace;
+pub use dma::{alloc_dma, alloc_dma_in, free_dma, DmaBuffer};
+pub use mmio::{map_mmio, MmioRegion};
+pub use zone::Zone;
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// The kernel page table together with the frame allocator that backs it.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+impl KernelMemory {
+    /// Maps `page` to a newly allocated, zeroed frame.
+    pub fn map_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let frame = self.frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_ptr: *mut u8 = (self.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe {
+            frame_ptr.write_bytes(0, 4096);
+            self.mapper.map_to(page, frame, flags, &mut self.frame_allocator)?.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Hands the kernel page table and frame allocator over to the memory subsystem.
+///
+/// Must be called once after the heap has been initialized. Afterwards the page fault handler and any
+/// subsystem that creates mappings access them through `with_kernel_memory`.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    assert!(kernel_memory.is_none(), "kernel memory installed twice");
+    address_space::capture_kernel_table();
+    *kernel_memory = Some(KernelMemory { mapper, frame_allocator });
+}
+
+/// Runs `f` with exclusive access to the kernel page table and frame allocator.
+///
+/// Interrupts are disabled while `f` runs. Returns `None` if `install` has not been called yet.
+///
+/// Interrupts stay enabled while waiting for the lock, so a waiting CPU can still answer TLB shootdowns
+/// issued by the current holder.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    let mut f = Some(f);
+    loop {
+        let result = x86_64::instructions::interrupts::without_interrupts(|| {
+            KERNEL_MEMORY.try_lock().map(|mut memory| memory.as_mut().map(f.take().unwrap()))
+        });
+        if let Some(result) = result {
+            return result;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Like `with_kernel_memory`, but returns `None` instead of spinning if the lock is already held.
+///
+/// Used from exception handlers, which may have interrupted the current holder of the lock.
+pub fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+}
+
+/// Allocation state of one zone.
+#[derive(Clone, Copy)]
+struct ZoneState {
+    /// Number of usable frames of the zone handed out so far, in memory map order.
+    next: usize,
+    /// Head of the list of deallocated frames.
+    ///
+    /// Each free frame stores the next list entry in its first bytes, accessed through the physical memory mapping.
+    free_list: Option<PhysFrame>,
+}
+
+/// Hands out the usable frames of the boot memory map, keeping separate pools per `Zone`.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    zones: [ZoneState; 3],
 }
 
 impl BootInfoFrameAllocator {
@@ -30,30 +127,111 @@ impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator { 
             memory_map, 
-            next: 0 
+            zones: [ZoneState { next: 0, free_list: None }; 3],
+        }
+    }
+
+    /// Returns an iterator over the usable frames of `zone` specified in the memory map.
+    fn usable_frames(&self, zone: Zone) -> impl Iterator<Item = PhysFrame> {
+        usable_frames_in(self.memory_map, zone)
+    }
+
+    /// Allocates a frame from `zone` or, if it is exhausted, from a lower zone.
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Option<PhysFrame> {
+        zone.fallbacks().find_map(|zone| self.allocate_from(zone))
+    }
+
+    /// Allocates `count` physically contiguous frames from `zone` or a lower zone and returns the first one.
+    ///
+    /// The first frame is aligned to `align` bytes. Frames skipped while searching for a suitable run
+    /// are put on the free list, so they are not lost.
+    pub fn allocate_contiguous_in(&mut self, zone: Zone, count: usize, align: u64) -> Option<PhysFrame> {
+        zone.fallbacks().find_map(|zone| self.allocate_contiguous_from(zone, count, align))
+    }
+
+    /// Like `allocate_contiguous_in`, without restricting the zone.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        self.allocate_contiguous_in(Zone::Normal, count, align)
+    }
+
+    /// Returns the number of usable frames in `zone`, allocated or not.
+    pub fn zone_size(&self, zone: Zone) -> usize {
+        self.usable_frames(zone).count()
+    }
+
+    fn allocate_from(&mut self, zone: Zone) -> Option<PhysFrame> {
+        let state = &mut self.zones[zone.index()];
+        if let Some(frame) = state.free_list {
+            let link: *const Option<PhysFrame> = phys_to_virt(frame.start_address()).as_ptr();
+            state.free_list = unsafe { link.read() };
+            return Some(frame);
         }
+
+        let frame = usable_frames_in(self.memory_map, zone).nth(state.next)?;
+        state.next += 1;
+        Some(frame)
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
-            .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn allocate_contiguous_from(&mut self, zone: Zone, count: usize, align: u64) -> Option<PhysFrame> {
+        let next = self.zones[zone.index()].next;
+        let mut run: Option<(usize, PhysFrame)> = None;
+        let mut run_len = 0;
+        let mut prev: Option<PhysFrame> = None;
+        let mut found = None;
+
+        for (index, frame) in self.usable_frames(zone).enumerate().skip(next) {
+            if run_len > 0 && prev.map_or(false, |prev| prev + 1 == frame) {
+                run_len += 1;
+            } else if frame.start_address().is_aligned(align) {
+                run = Some((index, frame));
+                run_len = 1;
+            } else {
+                run_len = 0;
+            }
+            prev = Some(frame);
+
+            if run_len == count {
+                found = run.map(|(start, first)| (start, index + 1, first));
+                break;
+            }
+        }
+
+        let (start, end, first) = found?;
+        for skipped in usable_frames_in(self.memory_map, zone).skip(next).take(start - next) {
+            unsafe { self.deallocate_frame(skipped) };
+        }
+        self.zones[zone.index()].next = end;
+        Some(first)
     }
 }
 
+fn usable_frames_in(memory_map: &'static MemoryMap, zone: Zone) -> impl Iterator<Item = PhysFrame> {
+    let zone = zone.range();
+    memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(move |r| r.range.start_addr().max(zone.start)..r.range.end_addr().min(zone.end))
+        .flat_map(|r| r.step_by(4096))
+        .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+}
+
 /// Initialize a new OffsetPageTable
 /// 
 /// This function is unsafe because the caller must guarantee that the complete physical memory is mapped to virtual memory
 /// at the passed `physical_memory_offset`. Also, this function must only be called once to avoid aliasing `&mut` references.
 pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
     let lvl_4_tbl = active_lvl_4_tbl(phys_mem_offset);
     OffsetPageTable::new(lvl_4_tbl, phys_mem_offset)
 }
 
+/// Returns the virtual address at which the given physical address is mapped.
+///
+/// Only valid after `init`, which records the offset of the complete physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
 /// Returns a mutable reference to the active level 4 table.
 /// 
 /// This function is unsafe because the caller must guarantee that the complete phisical memory
@@ -77,7 +255,7 @@ pub fn create_example_mapping(
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
 
     let map_to_result = unsafe {
         mapper.map_to(page, frame, flags, frame_allocator)
@@ -86,9 +264,17 @@ pub fn create_example_mapping(
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Allocates a frame from the highest zone that has one left.
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.allocate_frame_in(Zone::Normal)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let state = &mut self.zones[Zone::containing(frame.start_address()).index()];
+        let link: *mut Option<PhysFrame> = phys_to_virt(frame.start_address()).as_mut_ptr();
+        link.write(state.free_list);
+        state.free_list = Some(frame);
     }
-}
\ No newline at end of file
+}
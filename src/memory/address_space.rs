@@ -0,0 +1,156 @@
+use super::{cow, phys_to_virt, KernelMemory};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator,
+        FrameDeallocator,
+        OffsetPageTable,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+
+/// Physical address of the boot level 4 table, which holds the kernel mappings.
+static KERNEL_L4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// `memory::install` has not been called yet.
+    NotInstalled,
+    OutOfMemory,
+}
+
+/// A set of page tables with its own level 4 table.
+///
+/// Every level 4 entry the kernel uses is copied from the kernel table, so the lower level tables behind
+/// them are shared and kernel mappings stay visible in every address space. All other level 4 entries are
+/// private: page tables created below them belong to this address space and are freed together with it,
+/// as are the frames they map.
+///
+/// Level 4 entries the kernel starts using after an address space was created are not propagated, which
+/// is why the kernel table is captured only after the heap and the VMM window have been mapped.
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space that contains only the kernel mappings.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        super::with_kernel_memory(Self::new_in)
+            .ok_or(AddressSpaceError::NotInstalled)?
+    }
+
+    /// Like `new`, for callers that already hold the kernel memory lock.
+    pub fn new_in(memory: &mut KernelMemory) -> Result<Self, AddressSpaceError> {
+        let l4_frame = memory.frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let kernel_table = unsafe { &*table_ptr(kernel_l4_frame()) };
+        let table = unsafe {
+            let table = table_ptr(l4_frame);
+            table.write(PageTable::new());
+            &mut *table
+        };
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+            if !kernel_entry.is_unused() {
+                *entry = kernel_entry.clone();
+            }
+        }
+        Ok(AddressSpace { l4_frame })
+    }
+
+    /// The frame holding the level 4 table, which is what gets loaded into CR3.
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4_frame
+    }
+
+    /// Returns a mapper for this address space, independent of whether it is active.
+    ///
+    /// Mappings created through it below private level 4 entries are owned by the address space.
+    /// Changes below kernel entries affect all address spaces.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.l4_frame), phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Returns whether the level 4 entry covering `addr` is private to this address space.
+    pub fn is_private(&self, addr: VirtAddr) -> bool {
+        let kernel_table = unsafe { &*table_ptr(kernel_l4_frame()) };
+        kernel_table[addr.p4_index()].is_unused()
+    }
+
+    /// Returns whether this address space is the one currently loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4_frame
+    }
+
+    /// Loads this address space into CR3, which also flushes all non-global TLB entries.
+    pub fn activate(&self) {
+        switch_to(self.l4_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let l4_frame = self.l4_frame;
+        super::with_kernel_memory(|memory| unsafe {
+            let kernel_table = &*table_ptr(kernel_l4_frame());
+            let table = &*table_ptr(l4_frame);
+            for (entry, kernel_entry) in table.iter().zip(kernel_table.iter()) {
+                if kernel_entry.is_unused() && !entry.is_unused() {
+                    free_table(memory, entry.frame().expect("huge page in level 4 table"), 3);
+                }
+            }
+            memory.frame_allocator.deallocate_frame(l4_frame);
+        })
+        .expect("address space outlived the kernel memory");
+    }
+}
+
+/// Switches back to the kernel page table.
+pub fn activate_kernel() {
+    switch_to(kernel_l4_frame());
+}
+
+/// Records the active level 4 table as the kernel table new address spaces are cloned from.
+pub(super) fn capture_kernel_table() {
+    let (frame, _) = Cr3::read();
+    KERNEL_L4_TABLE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+}
+
+fn kernel_l4_frame() -> PhysFrame {
+    let addr = KERNEL_L4_TABLE.load(Ordering::Relaxed);
+    assert!(addr != 0, "kernel page table not captured yet");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+fn switch_to(frame: PhysFrame) {
+    let (current, flags) = Cr3::read();
+    if current != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Frees a private page table at `level` together with every table and frame below it.
+unsafe fn free_table(memory: &mut KernelMemory, frame: PhysFrame, level: u8) {
+    let table = &*table_ptr(frame);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        if level == 1 {
+            let leaf = entry.frame().expect("present entry without frame");
+            if cow::release_frame(leaf) {
+                memory.frame_allocator.deallocate_frame(leaf);
+            }
+        } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            free_table(memory, entry.frame().expect("present entry without frame"), level - 1);
+        }
+    }
+    memory.frame_allocator.deallocate_frame(frame);
+}
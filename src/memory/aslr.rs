@@ -0,0 +1,33 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::random::RdRand;
+
+/// Maximum number of unused pages the VMM leaves in front of a randomized region (4 GiB).
+///
+/// Gives 20 bits of entropy per region while keeping 128 regions well inside the 512 GiB window.
+pub const MAX_GAP_PAGES: u64 = 1 << 20;
+
+/// Whether kernel virtual memory placement is randomized, selected by the `aslr` feature.
+pub const ENABLED: bool = cfg!(feature = "aslr");
+
+/// State of the fallback generator used when the CPU has no RDRAND.
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a random number for address space layout randomization.
+///
+/// Uses RDRAND when the CPU supports it. Otherwise the time stamp counter is mixed into a splitmix64
+/// generator, which is predictable for an attacker who can measure boot timing but still removes fixed addresses.
+pub fn random_u64() -> u64 {
+    if let Some(value) = RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        return value;
+    }
+    let tsc = crate::time::tsc::read();
+    let state = FALLBACK_STATE.fetch_add(tsc | 1, Ordering::Relaxed).wrapping_add(tsc | 1);
+    splitmix64(state)
+}
+
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
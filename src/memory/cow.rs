@@ -0,0 +1,149 @@
+use super::{phys_to_virt, tlb, KernelMemory};
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator,
+        FrameDeallocator,
+        Mapper,
+        Page,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+/// Marks a read-only page as copy-on-write. Uses one of the page table entry bits reserved for the OS.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Reference counts of frames mapped by more than one page.
+///
+/// Frames without an entry are owned by exactly one mapping.
+static SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The source page is not mapped, or mapped as part of a huge page.
+    SourceNotMapped,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        CowError::Map(err)
+    }
+}
+
+/// Maps `dst` to the frame backing `src` and turns both mappings into read-only copy-on-write mappings.
+///
+/// The first write through either page gives the writer its own copy of the frame.
+pub fn share(memory: &mut KernelMemory, src: Page, dst: Page) -> Result<(), CowError> {
+    let (frame, flags) = match memory.mapper.translate(src.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => return Err(CowError::SourceNotMapped),
+    };
+
+    let cow_flags = cow_flags(flags);
+    unsafe {
+        memory.mapper.map_to(dst, frame, cow_flags, &mut memory.frame_allocator)?.flush();
+        if flags != cow_flags {
+            memory.mapper
+                .update_flags(src, cow_flags)
+                .expect("source page vanished while sharing it")
+                .ignore();
+            tlb::flush(src);
+        }
+    }
+
+    *SHARED_FRAMES.lock().entry(frame).or_insert(1) += 1;
+    Ok(())
+}
+
+/// Unmaps a page that may be copy-on-write, freeing its frame once no other mapping references it.
+pub fn unmap(memory: &mut KernelMemory, page: Page) -> Result<(), UnmapError> {
+    let (frame, flush) = memory.mapper.unmap(page)?;
+    flush.ignore();
+    tlb::flush(page);
+    if release_frame(frame) {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    }
+    Ok(())
+}
+
+/// Drops one reference to `frame`.
+///
+/// Returns `true` if that was the last reference, in which case the caller is responsible for freeing the frame.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame) {
+        Some(count) if *count > 2 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            shared.remove(&frame);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Resolves a write fault on a present copy-on-write page.
+///
+/// Returns `true` if the page is writable now and the faulting instruction can be retried.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    super::try_with_kernel_memory(|memory| resolve(memory, Page::containing_address(addr)))
+        .unwrap_or(false)
+}
+
+fn resolve(memory: &mut KernelMemory, page: Page) -> bool {
+    let (frame, flags) = match memory.mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => return false,
+    };
+    if !flags.contains(COW) {
+        return false;
+    }
+    let writable = (flags - COW) | PageTableFlags::WRITABLE;
+
+    if !SHARED_FRAMES.lock().contains_key(&frame) {
+        // sole owner, so the page can simply take over the frame
+        return unsafe { memory.mapper.update_flags(page, writable) }
+            .map(|flush| {
+                flush.ignore();
+                tlb::flush(page);
+            })
+            .is_ok();
+    }
+
+    let copy = match memory.frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    unsafe {
+        let src: *const u8 = phys_to_virt(frame.start_address()).as_ptr();
+        let dst: *mut u8 = phys_to_virt(copy.start_address()).as_mut_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, 4096);
+
+        let (_, flush) = memory.mapper.unmap(page).expect("translated page is not mapped");
+        flush.ignore();
+        memory.mapper
+            .map_to(page, copy, writable, &mut memory.frame_allocator)
+            .expect("failed to remap copied page")
+            .ignore();
+    }
+    tlb::flush(page);
+    release_frame(frame);
+    true
+}
+
+fn cow_flags(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        (flags - PageTableFlags::WRITABLE) | COW
+    } else {
+        flags
+    }
+}
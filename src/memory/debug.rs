@@ -0,0 +1,182 @@
+use super::phys_to_virt;
+use crate::serial_println;
+use core::{fmt, ops::Range};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr,
+    VirtAddr,
+};
+
+/// A run of virtually and physically contiguous pages with identical flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Returns the first address past the end of the range, saturating for a range at the top of the address space
+    /// like `walk` does.
+    pub fn end(&self) -> u64 {
+        self.start.as_u64().saturating_add(self.size)
+    }
+
+    fn extends_to(&self, next: &MappedRange) -> bool {
+        self.end() == next.start.as_u64()
+            && self.phys.as_u64() + self.size == next.phys.as_u64()
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>8} KiB {}",
+            self.start.as_u64(),
+            self.end(),
+            self.phys.as_u64(),
+            self.size / 1024,
+            FlagsDisplay(self.flags),
+        )
+    }
+}
+
+/// Prints the set flags other than `PRESENT` as short names, e.g. `W G NX`.
+struct FlagsDisplay(PageTableFlags);
+
+impl fmt::Display for FlagsDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (PageTableFlags::WRITABLE, "W"),
+            (PageTableFlags::USER_ACCESSIBLE, "U"),
+            (PageTableFlags::WRITE_THROUGH, "T"),
+            (PageTableFlags::NO_CACHE, "C"),
+            (PageTableFlags::GLOBAL, "G"),
+            (PageTableFlags::HUGE_PAGE, "H"),
+            (PageTableFlags::NO_EXECUTE, "NX"),
+            (super::cow::COW, "COW"),
+        ];
+        for (flag, name) in flags {
+            if self.0.contains(flag) {
+                write!(f, "{} ", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints the mappings of the active page table that overlap `range` to serial.
+pub fn dump(range: Range<VirtAddr>) {
+    dump_table(Cr3::read().0, range);
+}
+
+/// Prints the mappings of the page table rooted at `l4_frame` that overlap `range` to serial.
+///
+/// Works for any address space, see `AddressSpace::l4_frame`.
+pub fn dump_table(l4_frame: PhysFrame, range: Range<VirtAddr>) {
+    print_table(l4_frame, range.start.as_u64()..range.end.as_u64());
+}
+
+/// Prints every mapping of the active page table to serial.
+pub fn dump_all() {
+    print_table(Cr3::read().0, 0..u64::MAX);
+}
+
+fn print_table(l4_frame: PhysFrame, range: Range<u64>) {
+    serial_println!("page table {:#x}, range {:#x}-{:#x}:", l4_frame.start_address().as_u64(), range.start, range.end);
+    let mut count = 0;
+    for_each_mapping(l4_frame, range, |mapping| {
+        serial_println!("  {}", mapping);
+        count += 1;
+    });
+    serial_println!("{} mapped ranges", count);
+}
+
+/// Walks the page table rooted at `l4_frame` and calls `f` for every coalesced run of mappings overlapping `range`.
+///
+/// `range` holds raw addresses so that it can cover the whole address space. Ranges are reported in ascending
+/// address order and are not clipped to `range`. The accessed and dirty bits are ignored, so that they don't split runs.
+pub fn for_each_mapping(l4_frame: PhysFrame, range: Range<u64>, mut f: impl FnMut(MappedRange)) {
+    let mut run: Option<MappedRange> = None;
+    walk(l4_frame, 4, 0, &range, &mut |mapping| {
+        match &mut run {
+            Some(current) if current.extends_to(&mapping) => current.size += mapping.size,
+            _ => {
+                if let Some(done) = run.replace(mapping) {
+                    f(done);
+                }
+            }
+        }
+    });
+    if let Some(done) = run {
+        f(done);
+    }
+}
+
+fn walk(frame: PhysFrame, level: u8, base: u64, range: &Range<u64>, f: &mut impl FnMut(MappedRange)) {
+    let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags() - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = canonical(base + index as u64 * entry_size);
+        if start >= range.end || start.saturating_add(entry_size) <= range.start {
+            continue;
+        }
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(MappedRange {
+                start: VirtAddr::new(start),
+                phys: entry.addr(),
+                size: entry_size,
+                flags,
+            });
+        } else {
+            walk(PhysFrame::containing_address(entry.addr()), level - 1, start, range, f);
+        }
+    }
+}
+
+/// Returns `true` if `addr` is mapped in the active page table.
+///
+/// Reads the tables directly without taking the kernel memory lock, so it is usable from fault handlers.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    translate(addr).is_some()
+}
+
+/// Returns the page or huge page mapping `addr` in the active page table, without taking any locks.
+pub fn translate(addr: VirtAddr) -> Option<MappedRange> {
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut frame = Cr3::read().0;
+    for (depth, index) in indices.iter().enumerate() {
+        let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+        let entry = &table[*index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if depth == 3 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let size = 1u64 << (12 + 9 * (3 - depth as u64));
+            return Some(MappedRange {
+                start: VirtAddr::new(addr.as_u64() & !(size - 1)),
+                phys: entry.addr(),
+                size,
+                flags,
+            });
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    None
+}
+
+/// Sign extends bit 47, as level 4 entries 256 and up map the upper half of the address space.
+fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
@@ -0,0 +1,21 @@
+use super::vmm::{self, Backing};
+use x86_64::{structures::paging::Page, VirtAddr};
+
+/// Resolves a page fault on a not-present page inside an on-demand region.
+/// 
+/// Maps the faulting page to a zeroed frame and returns `true` if the faulting instruction can be retried.
+/// Returns `false` if `addr` is not in an on-demand region or no frame could be mapped.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let region = match vmm::try_region_containing(addr) {
+        Some(region) if !region.is_guard(addr) => region,
+        _ => return false,
+    };
+    let flags = match region.backing() {
+        Backing::OnDemand(flags) | Backing::Swappable(flags) => flags,
+        Backing::Eager => return false,
+    };
+
+    let page = Page::containing_address(addr);
+    super::try_with_kernel_memory(|memory| memory.map_zeroed(page, flags).is_ok())
+        .unwrap_or(false)
+}
@@ -0,0 +1,117 @@
+use super::{tlb, vmm::{self, RegionKind, VmmError}, Zone};
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameDeallocator, Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+#[derive(Debug)]
+pub enum DmaError {
+    Vmm(VmmError),
+    /// No physically contiguous run of frames of the requested size and alignment is left.
+    OutOfMemory,
+    Map(MapToError<Size4KiB>),
+    /// `install` has not been called yet.
+    NotInstalled,
+}
+
+/// A physically contiguous, uncached buffer that a device can access directly.
+/// 
+/// The buffer stays allocated until it is passed to `free_dma`, since the device may still be using it.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl DmaBuffer {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Returns the address to program into the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+/// Allocates a zeroed buffer of `len` bytes in physically contiguous memory, aligned to `align` bytes.
+/// 
+/// The buffer is mapped with caching disabled, so CPU accesses are never served from stale cache lines
+/// while a device reads or writes the memory. It lies below 4 GiB, so devices limited to 32-bit addresses can reach it.
+pub fn alloc_dma(len: usize, align: usize) -> Result<DmaBuffer, DmaError> {
+    alloc_dma_in(Zone::Low, len, align)
+}
+
+/// Like `alloc_dma`, but places the buffer in `zone` or below, e.g. `Zone::Dma` for ISA devices.
+pub fn alloc_dma_in(zone: Zone, len: usize, align: usize) -> Result<DmaBuffer, DmaError> {
+    let region = vmm::allocate(len as u64, RegionKind::Dma).map_err(DmaError::Vmm)?;
+    let frame_count = region.page_count() as usize;
+    let align = (align as u64).max(4096);
+
+    let mapped = super::with_kernel_memory(|memory| {
+        let first = memory.frame_allocator
+            .allocate_contiguous_in(zone, frame_count, align)
+            .ok_or(DmaError::OutOfMemory)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE;
+        for (page, frame) in region.pages().zip(PhysFrame::range(first, first + frame_count as u64)) {
+            unsafe {
+                memory.mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+                    .map_err(DmaError::Map)?
+                    .flush();
+            }
+        }
+        Ok(first)
+    });
+
+    match mapped.unwrap_or(Err(DmaError::NotInstalled)) {
+        Ok(first) => {
+            let buffer = DmaBuffer { virt: region.start(), phys: first.start_address(), len };
+            unsafe { buffer.as_mut_ptr::<u8>().write_bytes(0, region.size() as usize) };
+            Ok(buffer)
+        }
+        Err(err) => {
+            // pages mapped before the failure are leaked along with their frames, which only happens
+            // if page table allocation runs out of frames
+            vmm::release(region.start()).expect("DMA region vanished");
+            Err(err)
+        }
+    }
+}
+
+/// Unmaps a buffer returned by `alloc_dma` and frees its memory.
+/// 
+/// The device must have been told to stop using the buffer beforehand.
+pub fn free_dma(buffer: DmaBuffer) {
+    let region = vmm::release(buffer.virt).expect("freeing a DMA buffer that was not allocated");
+    super::with_kernel_memory(|memory| {
+        for page in region.pages() {
+            let (_, flush) = memory.mapper.unmap(page).expect("DMA page not mapped");
+            flush.ignore();
+        }
+        // the frames may only be reused once no CPU can reach them through a stale translation
+        tlb::flush_range(region.pages());
+        let first = PhysFrame::<Size4KiB>::containing_address(buffer.phys);
+        for frame in PhysFrame::range(first, first + region.page_count()) {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+        }
+    });
+}
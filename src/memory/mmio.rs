@@ -0,0 +1,128 @@
+use super::{tlb, vmm::{self, RegionKind, VirtualRegion, VmmError}};
+use core::mem;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+#[derive(Debug)]
+pub enum MmioError {
+    Vmm(VmmError),
+    Map(MapToError<Size4KiB>),
+    /// `install` has not been called yet.
+    NotInstalled,
+}
+
+/// Device registers mapped uncached into kernel virtual memory.
+/// 
+/// All accesses are volatile and bounds checked. The mapping is removed when the region is dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    region: VirtualRegion,
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// Returns the virtual address corresponding to the physical address passed to `map_mmio`.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the register of type `T` at `offset` bytes into the region.
+    /// 
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes the register of type `T` at `offset` bytes into the region.
+    /// 
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + mem::size_of::<T>() <= self.len, "MMIO access out of bounds");
+        let ptr: *mut T = (self.base + offset).as_mut_ptr();
+        assert_eq!(ptr as usize % mem::align_of::<T>(), 0, "misaligned MMIO access");
+        ptr
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        super::with_kernel_memory(|memory| {
+            for page in self.region.pages() {
+                if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                    flush.ignore();
+                }
+            }
+            tlb::flush_range(self.region.pages());
+        });
+        vmm::release(self.region.start()).expect("MMIO region released twice");
+    }
+}
+
+/// Maps `len` bytes of device memory starting at `phys_addr` into a free range of kernel virtual memory.
+/// 
+/// The pages are mapped uncached and write-through, so register accesses always reach the device, and non-executable.
+/// `phys_addr` does not need to be page aligned.
+pub fn map_mmio(phys_addr: PhysAddr, len: usize) -> Result<MmioRegion, MmioError> {
+    let page_offset = phys_addr.as_u64() % 4096;
+    let region = vmm::allocate(page_offset + len as u64, RegionKind::Mmio).map_err(MmioError::Vmm)?;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    let mapped = super::with_kernel_memory(|memory| {
+        for (page, frame) in region.pages().zip(PhysFrame::range(first_frame, first_frame + region.page_count())) {
+            unsafe {
+                memory.mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+                    .map_err(MmioError::Map)?
+                    .flush();
+            }
+        }
+        Ok(())
+    });
+
+    match mapped.unwrap_or(Err(MmioError::NotInstalled)) {
+        Ok(()) => Ok(MmioRegion {
+            region,
+            base: region.start() + page_offset,
+            phys: phys_addr,
+            len,
+        }),
+        Err(err) => {
+            super::with_kernel_memory(|memory| {
+                for page in region.pages() {
+                    if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                        flush.ignore();
+                    }
+                }
+                tlb::flush_range(region.pages());
+            });
+            vmm::release(region.start()).expect("MMIO region vanished");
+            Err(err)
+        }
+    }
+}
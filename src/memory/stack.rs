@@ -0,0 +1,94 @@
+use super::{tlb, vmm::{self, RegionKind, VirtualRegion, VmmError}};
+use core::arch::asm;
+use x86_64::{
+    structures::paging::{FrameDeallocator, Mapper, PageTableFlags},
+    VirtAddr,
+};
+
+/// Size of the stack the kernel switches to once memory management is up.
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+
+/// A kernel stack with an unmapped guard page below it.
+/// 
+/// The stack pages are mapped on first touch, so large stacks only cost the memory they actually use.
+/// Running off the bottom hits the guard page, which the page fault handler reports as a stack overflow.
+#[derive(Debug)]
+pub struct Stack {
+    region: VirtualRegion,
+}
+
+impl Stack {
+    /// Reserves a stack of at least `size` bytes for `owner`.
+    pub fn new(size: u64, owner: &'static str) -> Result<Self, VmmError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let region = vmm::allocate_guarded(size, 1, RegionKind::Stack(owner), flags)?;
+        Ok(Stack { region })
+    }
+
+    /// Returns the initial stack pointer, the stack grows down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.usable_start()
+    }
+
+    pub fn owner(&self) -> &'static str {
+        match self.region.kind() {
+            RegionKind::Stack(owner) => owner,
+            _ => unreachable!("stack allocated from a non-stack region"),
+        }
+    }
+
+    /// Switches to this stack and calls `entry` on it.
+    /// 
+    /// The stack is leaked, since nothing ever returns to the previous stack.
+    /// 
+    /// # Safety
+    /// 
+    /// References into the current stack must not be passed to `entry` through other means,
+    /// as the current stack is abandoned and may be reused.
+    pub unsafe fn switch_to(self, entry: extern "C" fn() -> !) -> ! {
+        let top = self.top().as_u64();
+        core::mem::forget(self);
+        asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {entry}",
+            "ud2",
+            top = in(reg) top,
+            entry = in(reg) entry,
+            options(noreturn),
+        );
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        super::with_kernel_memory(|memory| {
+            for page in self.region.pages() {
+                if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+                    flush.ignore();
+                    tlb::flush(page);
+                    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        vmm::release(self.region.start()).expect("stack region released twice");
+    }
+}
+
+/// Returns the owner of the stack whose guard page contains `addr`, if any.
+///
+/// Called from the page fault handler, so it gives up and returns `None` if the region table is locked.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    match vmm::try_region_containing(addr) {
+        Some(region) if region.is_guard(addr) => match region.kind() {
+            RegionKind::Stack(owner) => Some(owner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
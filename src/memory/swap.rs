@@ -0,0 +1,329 @@
+use super::{phys_to_virt, tlb, vmm::{self, Backing, VirtualRegion}, KernelMemory};
+use crate::block::{BlockDevice, BlockFuture};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        page_table::PageTableEntry,
+        FrameAllocator,
+        FrameDeallocator,
+        Page,
+        PageTable,
+        PageTableFlags,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+
+/// Marks a not-present page table entry whose address bits hold a swap slot instead of a frame.
+/// Uses one of the page table entry bits reserved for the OS.
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+pub const SLOT_SIZE: usize = 4096;
+
+/// The swap space, locked after the kernel memory lock whenever both are needed.
+static SWAP: Mutex<Option<SwapSpace>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// `enable` has not been called yet.
+    NotEnabled,
+    /// Every slot of the swap device is in use.
+    Full,
+    /// The swap device failed to transfer a slot.
+    Io,
+}
+
+/// Storage for swapped out pages, addressed in page sized slots.
+///
+/// Transfers are synchronous because pages are swapped in from the page fault handler. Implementations
+/// must not touch swappable memory and must not wait for the kernel memory lock, which is held during transfers.
+pub trait SwapDevice {
+    /// Number of slots the device can hold.
+    fn slot_count(&self) -> u64;
+
+    fn read_slot(&mut self, slot: u64, buf: &mut [u8; SLOT_SIZE]) -> Result<(), SwapError>;
+
+    fn write_slot(&mut self, slot: u64, buf: &[u8; SLOT_SIZE]) -> Result<(), SwapError>;
+}
+
+/// Swap space on a block device, such as a partition set aside for it.
+///
+/// Transfers are driven by polling them once, which the ATA and RAM disk drivers complete in. A transfer that
+/// would have to wait for a wakeup fails with `SwapError::Io`, nothing can wake the page fault handler, so AHCI
+/// disks, whose transfers wait for their interrupt, can't hold swap.
+pub struct BlockSwap {
+    device: Arc<dyn BlockDevice>,
+    blocks_per_slot: u64,
+}
+
+impl BlockSwap {
+    /// Returns `None` if `device` is read-only or its blocks are larger than a slot.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Option<BlockSwap> {
+        let block_size = device.block_size();
+        if device.is_read_only() || block_size > SLOT_SIZE {
+            return None;
+        }
+        Some(BlockSwap { device, blocks_per_slot: (SLOT_SIZE / block_size) as u64 })
+    }
+}
+
+impl SwapDevice for BlockSwap {
+    fn slot_count(&self) -> u64 {
+        self.device.block_count() / self.blocks_per_slot
+    }
+
+    fn read_slot(&mut self, slot: u64, buf: &mut [u8; SLOT_SIZE]) -> Result<(), SwapError> {
+        poll_once(self.device.read_blocks(slot * self.blocks_per_slot, buf))
+    }
+
+    fn write_slot(&mut self, slot: u64, buf: &[u8; SLOT_SIZE]) -> Result<(), SwapError> {
+        poll_once(self.device.write_blocks(slot * self.blocks_per_slot, buf))
+    }
+}
+
+fn poll_once(mut transfer: BlockFuture) -> Result<(), SwapError> {
+    const VTABLE: RawWakerVTable =
+        RawWakerVTable::new(|_| RawWaker::new(core::ptr::null(), &VTABLE), |_| {}, |_| {}, |_| {});
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    match transfer.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(Ok(())) => Ok(()),
+        Poll::Ready(Err(_)) | Poll::Pending => Err(SwapError::Io),
+    }
+}
+
+struct SwapSpace {
+    device: Box<dyn SwapDevice + Send>,
+    /// One bit per slot, set if the slot holds a page.
+    used: Vec<u64>,
+    used_count: u64,
+    /// Position of the clock hand, the next page looked at when picking a victim.
+    hand: VirtAddr,
+}
+
+impl SwapSpace {
+    fn allocate_slot(&mut self) -> Option<u64> {
+        let slots = self.device.slot_count();
+        let (word_index, word) = self.used.iter_mut().enumerate().find(|(_, word)| **word != u64::MAX)?;
+        let bit = word.trailing_ones() as u64;
+        let slot = word_index as u64 * 64 + bit;
+        if slot >= slots {
+            return None;
+        }
+        *word |= 1 << bit;
+        self.used_count += 1;
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, slot: u64) {
+        let word = &mut self.used[(slot / 64) as usize];
+        assert!(*word & (1 << (slot % 64)) != 0, "freeing unused swap slot {}", slot);
+        *word &= !(1 << (slot % 64));
+        self.used_count -= 1;
+    }
+}
+
+/// Starts swapping to `device`.
+pub fn enable(device: Box<dyn SwapDevice + Send>) {
+    let words = ((device.slot_count() + 63) / 64) as usize;
+    let swap = SwapSpace {
+        device,
+        used: vec![0; words],
+        used_count: 0,
+        hand: VirtAddr::new(vmm::KERNEL_VM_START),
+    };
+    super::with_kernel_memory(|_| {
+        let mut current = SWAP.lock();
+        assert!(current.is_none(), "swap enabled twice");
+        *current = Some(swap);
+    });
+}
+
+/// Returns the number of slots holding swapped out pages.
+pub fn used_slots() -> u64 {
+    super::with_kernel_memory(|_| SWAP.lock().as_ref().map_or(0, |swap| swap.used_count)).unwrap_or(0)
+}
+
+/// Writes up to `count` cold pages of swappable regions to the swap device and frees their frames.
+///
+/// Victims are picked with the clock algorithm: a page that was accessed since the hand last passed it
+/// gets its accessed bit cleared and a second chance. Returns the number of pages swapped out.
+pub fn swap_out(count: usize) -> Result<usize, SwapError> {
+    super::with_kernel_memory(|memory| {
+        let mut guard = SWAP.lock();
+        let swap = guard.as_mut().ok_or(SwapError::NotEnabled)?;
+        let mut swapped = 0;
+        while swapped < count {
+            let page = match next_victim(memory, swap) {
+                Some(page) => page,
+                None => break,
+            };
+            swap_out_page(memory, swap, page)?;
+            swapped += 1;
+        }
+        Ok(swapped)
+    })
+    .unwrap_or(Err(SwapError::NotEnabled))
+}
+
+/// Resolves a page fault on a swapped out page by reading it back into a new frame.
+///
+/// Returns `true` if the faulting instruction can be retried.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let page = Page::containing_address(addr);
+    super::try_with_kernel_memory(|memory| {
+        let mut guard = match SWAP.try_lock() {
+            Some(guard) => guard,
+            None => return false,
+        };
+        let swap = match guard.as_mut() {
+            Some(swap) => swap,
+            None => return false,
+        };
+        swap_in_page(memory, swap, page)
+    })
+    .unwrap_or(false)
+}
+
+/// Frees the swap slot of `page` if it is swapped out, clearing its page table entry.
+///
+/// Owners of swappable regions call this for every page that could not be unmapped when tearing the region down.
+/// Returns `true` if a slot was freed.
+pub fn discard(memory: &mut KernelMemory, page: Page) -> bool {
+    let entry = match leaf_entry(memory, page) {
+        Some(entry) if entry.flags().contains(SWAPPED) => entry,
+        _ => return false,
+    };
+    let slot = slot_of(entry);
+    entry.set_unused();
+    if let Some(swap) = SWAP.lock().as_mut() {
+        swap.free_slot(slot);
+    }
+    true
+}
+
+fn swap_out_page(memory: &mut KernelMemory, swap: &mut SwapSpace, page: Page) -> Result<(), SwapError> {
+    let slot = swap.allocate_slot().ok_or(SwapError::Full)?;
+    let entry = leaf_entry(memory, page).expect("victim page has no page table entry");
+    let frame = entry.frame().expect("victim page is not mapped");
+
+    // take the page away before writing it, so that no update after the copy is lost
+    entry.set_addr(PhysAddr::new(slot * SLOT_SIZE as u64), SWAPPED);
+    tlb::flush(page);
+    let data = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<[u8; SLOT_SIZE]>() };
+    if let Err(err) = swap.device.write_slot(slot, data) {
+        let flags = swappable_flags(page.start_address()).expect("victim outside swappable region");
+        leaf_entry(memory, page).unwrap().set_frame(frame, flags);
+        swap.free_slot(slot);
+        return Err(err);
+    }
+    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    Ok(())
+}
+
+/// Reads `page` back from its swap slot, returning `false` if it is not swapped out or can't be restored.
+fn swap_in_page(memory: &mut KernelMemory, swap: &mut SwapSpace, page: Page) -> bool {
+    let slot = match leaf_entry(memory, page) {
+        Some(entry) if entry.flags().contains(SWAPPED) => slot_of(entry),
+        _ => return false,
+    };
+    // in the page fault handler, which must not wait for the region table
+    let region = vmm::try_region_containing(page.start_address());
+    let flags = match region.and_then(|region| swappable(region.backing())) {
+        Some(flags) => flags,
+        None => return false,
+    };
+    let frame = match memory.frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let data = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<[u8; SLOT_SIZE]>() };
+    if swap.device.read_slot(slot, data).is_err() {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+        return false;
+    }
+    leaf_entry(memory, page).unwrap().set_frame(frame, flags);
+    swap.free_slot(slot);
+    true
+}
+
+/// Advances the clock hand to the next resident page that was not accessed since the hand last passed it.
+///
+/// Gives up after two full sweeps, which means every resident page is in active use.
+fn next_victim(memory: &mut KernelMemory, swap: &mut SwapSpace) -> Option<Page> {
+    let mut budget = 2 * swappable_pages();
+    while budget > 0 {
+        let region = next_swappable_region(swap.hand)
+            .or_else(|| next_swappable_region(VirtAddr::new(vmm::KERNEL_VM_START)))?;
+        let start = swap.hand.max(region.usable_start());
+        for page in Page::range(Page::containing_address(start), Page::containing_address(region.end())) {
+            budget = budget.saturating_sub(1);
+            swap.hand = page.start_address() + 4096u64;
+            let entry = match leaf_entry(memory, page) {
+                Some(entry) if entry.flags().contains(PageTableFlags::PRESENT) => entry,
+                _ => continue,
+            };
+            if !entry.flags().contains(PageTableFlags::ACCESSED) {
+                return Some(page);
+            }
+            entry.set_flags(entry.flags() - PageTableFlags::ACCESSED);
+            tlb::flush(page);
+            if budget == 0 {
+                break;
+            }
+        }
+        swap.hand = region.end();
+    }
+    None
+}
+
+fn swappable_pages() -> u64 {
+    let mut pages = 0;
+    vmm::for_each_region(|region| {
+        if matches!(region.backing(), Backing::Swappable(_)) {
+            pages += region.pages().count() as u64;
+        }
+    });
+    pages
+}
+
+/// Returns the first swappable region ending above `addr`.
+fn next_swappable_region(addr: VirtAddr) -> Option<VirtualRegion> {
+    let mut found = None;
+    vmm::for_each_region(|region| {
+        if found.is_none() && region.end() > addr && matches!(region.backing(), Backing::Swappable(_)) {
+            found = Some(region);
+        }
+    });
+    found
+}
+
+fn swappable_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    swappable(vmm::region_containing(addr)?.backing())
+}
+
+fn swappable(backing: Backing) -> Option<PageTableFlags> {
+    match backing {
+        Backing::Swappable(flags) => Some(flags),
+        _ => None,
+    }
+}
+
+fn slot_of(entry: &PageTableEntry) -> u64 {
+    entry.addr().as_u64() / SLOT_SIZE as u64
+}
+
+/// Returns the level 1 entry for `page` in the kernel page table, if all tables above it exist.
+fn leaf_entry(memory: &mut KernelMemory, page: Page) -> Option<&mut PageTableEntry> {
+    let mut table: &mut PageTable = memory.mapper.level_4_table();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr() };
+    }
+    Some(&mut table[page.p1_index()])
+}
@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{page::PageRange, Page},
+};
+
+/// Interrupt vector the shootdown IPI is delivered on.
+pub const SHOOTDOWN_VECTOR: u8 = 0xfd;
+
+/// Ranges with more pages than this are flushed by reloading CR3 instead of one `invlpg` per page.
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+/// Number of CPUs that have to take part in a shootdown, including the initiator.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static IPI: Mutex<Option<ShootdownIpi>> = Mutex::new(None);
+
+/// Serializes shootdowns, since there is only one request slot.
+static REQUEST_LOCK: Mutex<()> = Mutex::new(());
+/// First page of the pending request.
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+/// Number of pages of the pending request, 0 requests a full flush.
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+/// Number of CPUs that have not acknowledged the pending request yet.
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt controller operations needed to reach the other CPUs, provided by the APIC driver.
+#[derive(Clone, Copy)]
+pub struct ShootdownIpi {
+    /// Sends an IPI with the given vector to every CPU except the current one.
+    pub send_to_others: fn(u8),
+    /// Acknowledges the IPI on the current CPU.
+    pub end_of_interrupt: fn(),
+}
+
+/// Makes shootdowns reach other CPUs through `ipi`.
+///
+/// Until this is called every flush is local only, which is correct as long as a single CPU is running.
+pub fn register_ipi(ipi: ShootdownIpi) {
+    *IPI.lock() = Some(ipi);
+}
+
+/// Records that another CPU has started and has to take part in shootdowns.
+pub fn cpu_online() {
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Invalidates the translation of `page` on every CPU.
+///
+/// Must be called after a mapping was removed, or changed to point at another frame or to have other flags,
+/// and before the old frame is reused.
+pub fn flush(page: Page) {
+    shootdown(page.start_address().as_u64(), 1);
+}
+
+/// Invalidates the translations of all pages in `pages` on every CPU.
+pub fn flush_range(pages: PageRange) {
+    let count = pages.end - pages.start;
+    if count > 0 {
+        shootdown(pages.start.start_address().as_u64(), count);
+    }
+}
+
+/// Invalidates all non-global translations on every CPU.
+pub fn flush_all() {
+    shootdown(0, 0);
+}
+
+/// Handles the shootdown IPI on a CPU that did not initiate it.
+pub fn handle_shootdown_interrupt() {
+    flush_local(REQUEST_START.load(Ordering::Acquire), REQUEST_PAGES.load(Ordering::Acquire));
+    // a spurious IPI without a pending request must not underflow the counter
+    let _ = PENDING_ACKS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |acks| acks.checked_sub(1));
+    if let Some(ipi) = *IPI.lock() {
+        (ipi.end_of_interrupt)();
+    }
+}
+
+fn shootdown(start: u64, pages: u64) {
+    let pages = if pages > FULL_FLUSH_THRESHOLD { 0 } else { pages };
+    flush_local(start, pages);
+
+    let others = ONLINE_CPUS.load(Ordering::SeqCst) - 1;
+    let ipi = match *IPI.lock() {
+        Some(ipi) if others > 0 => ipi,
+        _ => return,
+    };
+
+    let _request = REQUEST_LOCK.lock();
+    REQUEST_START.store(start, Ordering::Release);
+    REQUEST_PAGES.store(pages, Ordering::Release);
+    PENDING_ACKS.store(others, Ordering::Release);
+    (ipi.send_to_others)(SHOOTDOWN_VECTOR);
+    while PENDING_ACKS.load(Ordering::Acquire) > 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn flush_local(start: u64, pages: u64) {
+    if pages == 0 {
+        tlb::flush_all();
+        return;
+    }
+    for index in 0..pages {
+        tlb::flush(x86_64::VirtAddr::new(start + index * 4096));
+    }
+}
+
+#[test_case]
+fn test_shootdowns_wait_for_the_other_cpus() {
+    static SENT: AtomicUsize = AtomicUsize::new(0);
+    static ACKNOWLEDGED: AtomicUsize = AtomicUsize::new(0);
+
+    fn send_to_others(vector: u8) {
+        assert_eq!(vector, SHOOTDOWN_VECTOR);
+        SENT.fetch_add(1, Ordering::SeqCst);
+        // stands in for a second CPU, whose IPI comes in through the IDT like this one
+        unsafe { core::arch::asm!("int 0xfd") };
+    }
+
+    fn end_of_interrupt() {
+        ACKNOWLEDGED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let handled = crate::interrupts::stats::count_of(SHOOTDOWN_VECTOR);
+    let previous = IPI.lock().replace(ShootdownIpi { send_to_others, end_of_interrupt });
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    let page = Page::containing_address(x86_64::VirtAddr::new(0x4000_0000));
+    flush(page);
+    ONLINE_CPUS.fetch_sub(1, Ordering::SeqCst);
+    *IPI.lock() = previous;
+
+    assert_eq!(SENT.load(Ordering::SeqCst), 1);
+    assert_eq!(ACKNOWLEDGED.load(Ordering::SeqCst), 1);
+    assert_eq!(crate::interrupts::stats::count_of(SHOOTDOWN_VECTOR), handled + 1);
+    assert_eq!(PENDING_ACKS.load(Ordering::Acquire), 0);
+    assert_eq!(REQUEST_START.load(Ordering::Acquire), page.start_address().as_u64());
+    assert_eq!(REQUEST_PAGES.load(Ordering::Acquire), 1);
+}
@@ -0,0 +1,389 @@
+use super::aslr;
+use crate::sync::IrqSpinLock;
+use x86_64::{
+    structures::paging::{
+        page::PageRange,
+        Page,
+        PageSize,
+        PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Start of the kernel virtual window handed out by the VMM.
+///
+/// The window spans level 4 entry 136 (512 GiB), which the bootloader never uses for the kernel image,
+/// the boot info or the physical memory mapping.
+pub const KERNEL_VM_START: u64 = 0x_4400_0000_0000;
+/// End (exclusive) of the kernel virtual window.
+pub const KERNEL_VM_END: u64 = 0x_4480_0000_0000;
+
+/// Maximum number of regions the VMM can track at once.
+///
+/// The region table is a fixed array so that the VMM works before the heap exists (the heap itself is a region).
+const MAX_REGIONS: usize = 128;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// An `IrqSpinLock`, so that its holder can't be preempted and leave fault handlers spinning on it.
+static KERNEL_SPACE: IrqSpinLock<VirtualSpace> = IrqSpinLock::new(
+    VirtualSpace::new(KERNEL_VM_START, KERNEL_VM_END)
+        .randomized(if aslr::ENABLED { aslr::MAX_GAP_PAGES } else { 0 })
+);
+
+/// What a region of kernel virtual memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    /// Anonymous memory that is not tied to a particular subsystem.
+    Anonymous,
+    Mmio,
+    Dma,
+    /// A stack, labelled with the name of the task or subsystem running on it.
+    Stack(&'static str),
+}
+
+/// How the pages of a region get mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// The owner of the region maps it itself.
+    Eager,
+    /// Pages are mapped to zeroed frames with the given flags by the page fault handler on first access.
+    OnDemand(PageTableFlags),
+    /// Like `OnDemand`, but resident pages may also be written to the swap device to free their frames.
+    Swappable(PageTableFlags),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// No gap in the window is large enough for the requested size.
+    OutOfVirtualMemory,
+    /// The region table is full.
+    TooManyRegions,
+    /// The requested range overlaps an existing region.
+    Overlap,
+    /// The requested range is not inside the managed window.
+    OutOfWindow,
+    /// No region starts at the given address.
+    NotFound,
+}
+
+/// A page aligned range of kernel virtual memory owned by one subsystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    start: u64,
+    page_count: u64,
+    /// Number of pages at the bottom of the region that are never mapped.
+    guard_pages: u64,
+    kind: RegionKind,
+    backing: Backing,
+}
+
+impl VirtualRegion {
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.start)
+    }
+
+    /// Returns the first address past the end of the region.
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new(self.end_u64())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.page_count * PAGE_SIZE
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    pub fn guard_pages(&self) -> u64 {
+        self.guard_pages
+    }
+
+    /// Returns `true` if `addr` lies in the unmapped guard pages at the bottom of the region.
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        (self.start..self.usable_start().as_u64()).contains(&addr.as_u64())
+    }
+
+    /// Returns the first address above the guard pages.
+    pub fn usable_start(&self) -> VirtAddr {
+        VirtAddr::new(self.start + self.guard_pages * PAGE_SIZE)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end_u64()).contains(&addr.as_u64())
+    }
+
+    /// Returns the range of pages covered by the region, excluding guard pages.
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.usable_start()),
+            Page::containing_address(self.end()),
+        )
+    }
+
+    fn end_u64(&self) -> u64 {
+        self.start + self.size()
+    }
+}
+
+/// Tracks the regions allocated inside a window of virtual memory.
+///
+/// Regions are kept sorted by start address, so allocation is a first fit walk over the gaps between them.
+pub struct VirtualSpace {
+    window_start: u64,
+    window_end: u64,
+    regions: [Option<VirtualRegion>; MAX_REGIONS],
+    len: usize,
+    /// Upper bound of the random number of pages left free in front of each allocated region.
+    max_gap_pages: u64,
+}
+
+impl VirtualSpace {
+    /// Creates an empty address space managing `window_start..window_end`.
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        VirtualSpace {
+            window_start,
+            window_end,
+            regions: [None; MAX_REGIONS],
+            len: 0,
+            max_gap_pages: 0,
+        }
+    }
+
+    /// Places every allocated region at a random offset of up to `max_gap_pages` pages into the gap it is
+    /// allocated from, so that region addresses differ from boot to boot.
+    pub const fn randomized(mut self, max_gap_pages: u64) -> Self {
+        self.max_gap_pages = max_gap_pages;
+        self
+    }
+
+    /// Allocates a region of at least `size` usable bytes anywhere in the window.
+    ///
+    /// `guard_pages` additional pages are reserved below the usable part of the region.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        guard_pages: u64,
+        kind: RegionKind,
+        backing: Backing,
+    ) -> Result<VirtualRegion, VmmError> {
+        let page_count = pages_for(size) + guard_pages;
+        let size = page_count * PAGE_SIZE;
+
+        let mut gap_start = self.window_start;
+        for index in 0..=self.len {
+            let gap_end = if index < self.len { self.get(index).start } else { self.window_end };
+            if gap_end - gap_start >= size {
+                let start = gap_start + self.random_gap(gap_end - gap_start - size) * PAGE_SIZE;
+                let region = VirtualRegion { start, page_count, guard_pages, kind, backing };
+                self.insert_at(index, region)?;
+                return Ok(region);
+            }
+            if index < self.len {
+                gap_start = self.get(index).end_u64();
+            }
+        }
+
+        Err(VmmError::OutOfVirtualMemory)
+    }
+
+    /// Reserves the region of `size` bytes starting at the page aligned address `start`.
+    pub fn reserve(&mut self, start: VirtAddr, size: u64, kind: RegionKind) -> Result<VirtualRegion, VmmError> {
+        assert!(start.is_aligned(PAGE_SIZE), "region start must be page aligned");
+        let region = VirtualRegion {
+            start: start.as_u64(),
+            page_count: pages_for(size),
+            guard_pages: 0,
+            kind,
+            backing: Backing::Eager,
+        };
+        if region.start < self.window_start || region.end_u64() > self.window_end {
+            return Err(VmmError::OutOfWindow);
+        }
+
+        let index = self.regions().position(|r| r.start >= region.start).unwrap_or(self.len);
+        let overlaps_prev = index > 0 && self.get(index - 1).end_u64() > region.start;
+        let overlaps_next = index < self.len && self.get(index).start < region.end_u64();
+        if overlaps_prev || overlaps_next {
+            return Err(VmmError::Overlap);
+        }
+
+        self.insert_at(index, region)?;
+        Ok(region)
+    }
+
+    /// Releases the region starting at `start`, returning it.
+    pub fn release(&mut self, start: VirtAddr) -> Result<VirtualRegion, VmmError> {
+        let index = self.regions()
+            .position(|r| r.start == start.as_u64())
+            .ok_or(VmmError::NotFound)?;
+        let region = self.get(index);
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.regions[self.len] = None;
+        Ok(region)
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub fn region_containing(&self, addr: VirtAddr) -> Option<VirtualRegion> {
+        self.regions().find(|r| r.contains(addr))
+    }
+
+    /// Returns an iterator over all regions in ascending address order.
+    pub fn regions(&self) -> impl Iterator<Item = VirtualRegion> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    /// Picks the number of pages to skip in front of a region, given `slack` spare bytes in its gap.
+    fn random_gap(&self, slack: u64) -> u64 {
+        let max = self.max_gap_pages.min(slack / PAGE_SIZE);
+        if max == 0 {
+            return 0;
+        }
+        aslr::random_u64() % (max + 1)
+    }
+
+    fn get(&self, index: usize) -> VirtualRegion {
+        self.regions[index].expect("region table out of sync with its length")
+    }
+
+    fn insert_at(&mut self, index: usize, region: VirtualRegion) -> Result<(), VmmError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(())
+    }
+}
+
+fn pages_for(size: u64) -> u64 {
+    ((size + PAGE_SIZE - 1) / PAGE_SIZE).max(1)
+}
+
+/// Allocates a region of at least `size` bytes from the kernel virtual window.
+///
+/// The region is only reserved, mapping it is up to the caller.
+pub fn allocate(size: u64, kind: RegionKind) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().allocate(size, 0, kind, Backing::Eager)
+}
+
+/// Allocates a region of at least `size` bytes that is mapped lazily, one page per page fault.
+///
+/// No physical memory is committed until a page is first touched.
+pub fn allocate_on_demand(size: u64, kind: RegionKind, flags: PageTableFlags) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().allocate(size, 0, kind, Backing::OnDemand(flags))
+}
+
+/// Like `allocate_on_demand`, but keeps `guard_pages` unmapped pages below the usable part of the region.
+pub fn allocate_guarded(
+    size: u64,
+    guard_pages: u64,
+    kind: RegionKind,
+    flags: PageTableFlags,
+) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().allocate(size, guard_pages, kind, Backing::OnDemand(flags))
+}
+
+/// Allocates a region of at least `size` bytes that is mapped lazily and whose pages may be swapped out.
+///
+/// Interrupt handlers and code holding the kernel memory lock must not touch the region, since accessing
+/// a swapped out page needs the page fault handler.
+pub fn allocate_swappable(size: u64, kind: RegionKind, flags: PageTableFlags) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().allocate(size, 0, kind, Backing::Swappable(flags))
+}
+
+/// Reserves a fixed region of the kernel virtual window.
+pub fn reserve(start: VirtAddr, size: u64, kind: RegionKind) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().reserve(start, size, kind)
+}
+
+/// Returns the region starting at `start` to the kernel virtual window.
+///
+/// The caller must have unmapped the region beforehand.
+pub fn release(start: VirtAddr) -> Result<VirtualRegion, VmmError> {
+    KERNEL_SPACE.lock().release(start)
+}
+
+/// Returns the kernel region containing `addr`, if any.
+pub fn region_containing(addr: VirtAddr) -> Option<VirtualRegion> {
+    KERNEL_SPACE.lock().region_containing(addr)
+}
+
+/// Like `region_containing`, but also returns `None` if the region table is locked, for page fault handlers,
+/// which must not wait for the code they interrupted.
+pub fn try_region_containing(addr: VirtAddr) -> Option<VirtualRegion> {
+    KERNEL_SPACE.try_lock()?.region_containing(addr)
+}
+
+/// Calls `f` for every allocated kernel region in ascending address order.
+pub fn for_each_region(mut f: impl FnMut(VirtualRegion)) {
+    KERNEL_SPACE.lock().regions().for_each(&mut f);
+}
+
+#[test_case]
+fn test_allocate_does_not_overlap() {
+    let mut space = VirtualSpace::new(0x1000_0000, 0x2000_0000);
+    let a = space.allocate(3 * PAGE_SIZE, 0, RegionKind::Heap, Backing::Eager).unwrap();
+    let b = space.allocate(1, 0, RegionKind::Mmio, Backing::Eager).unwrap();
+    assert_eq!(a.start().as_u64(), 0x1000_0000);
+    assert_eq!(b.page_count(), 1);
+    assert!(b.start() >= a.end());
+}
+
+#[test_case]
+fn test_release_makes_room_for_reuse() {
+    let mut space = VirtualSpace::new(0x1000_0000, 0x1000_0000 + 4 * PAGE_SIZE);
+    let a = space.allocate(2 * PAGE_SIZE, 0, RegionKind::Stack("test"), Backing::Eager).unwrap();
+    let _b = space.allocate(2 * PAGE_SIZE, 0, RegionKind::Stack("test"), Backing::Eager).unwrap();
+    assert_eq!(space.allocate(PAGE_SIZE, 0, RegionKind::Stack("test"), Backing::Eager), Err(VmmError::OutOfVirtualMemory));
+    space.release(a.start()).unwrap();
+    assert_eq!(space.allocate(PAGE_SIZE, 0, RegionKind::Stack("test"), Backing::Eager).unwrap().start(), a.start());
+}
+
+#[test_case]
+fn test_reserve_rejects_overlap() {
+    let mut space = VirtualSpace::new(0x1000_0000, 0x2000_0000);
+    let a = space.reserve(VirtAddr::new(0x1001_0000), 2 * PAGE_SIZE, RegionKind::Heap).unwrap();
+    assert_eq!(
+        space.reserve(VirtAddr::new(0x1001_1000), PAGE_SIZE, RegionKind::Mmio),
+        Err(VmmError::Overlap),
+    );
+    assert_eq!(space.region_containing(VirtAddr::new(0x1001_1fff)), Some(a));
+    assert_eq!(space.region_containing(a.end()), None);
+}
+
+#[test_case]
+fn test_guard_pages_precede_usable_range() {
+    let mut space = VirtualSpace::new(0x1000_0000, 0x2000_0000);
+    let stack = space.allocate(2 * PAGE_SIZE, 1, RegionKind::Stack("test"), Backing::Eager).unwrap();
+    assert_eq!(stack.page_count(), 3);
+    assert!(stack.is_guard(stack.start()));
+    assert!(!stack.is_guard(stack.usable_start()));
+    assert_eq!(stack.pages().count(), 2);
+}
+
+#[test_case]
+fn test_randomized_regions_stay_in_window() {
+    let window_end = 0x1000_0000 + 64 * PAGE_SIZE;
+    let mut space = VirtualSpace::new(0x1000_0000, window_end).randomized(16);
+    let mut previous_end = VirtAddr::new(0x1000_0000);
+    for _ in 0..4 {
+        let region = space.allocate(PAGE_SIZE, 1, RegionKind::Stack("test"), Backing::Eager).unwrap();
+        assert!(region.start() >= previous_end);
+        assert!(region.end().as_u64() <= window_end);
+        previous_end = region.end();
+    }
+}
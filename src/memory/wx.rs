@@ -0,0 +1,76 @@
+use super::{debug, phys_to_virt, tlb, vmm};
+use crate::warn;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+};
+
+/// What `check` does when it finds a mapping that is both writable and executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Warn,
+    Panic,
+}
+
+/// Marks every writable and executable page the bootloader left behind as non-executable.
+///
+/// The bootloader maps the physical memory window and its own memory writable and executable. The kernel
+/// only ever executes its own code, which it maps read-only, so none of those pages has to stay executable.
+/// Mappings in the kernel virtual window are left alone, so that `check` still catches mistakes there.
+/// Returns the number of entries changed.
+pub fn enforce() -> usize {
+    super::with_kernel_memory(|memory| {
+        let mut changed = 0;
+        fix_table(memory.mapper.level_4_table(), 4, 0, &mut changed);
+        if changed > 0 {
+            tlb::flush_all();
+        }
+        changed
+    })
+    .expect("kernel memory not installed")
+}
+
+/// Walks the active page table and reports every range that is mapped both writable and executable.
+///
+/// Returns the number of offending ranges, after panicking on the first one with `Policy::Panic`.
+pub fn check(policy: Policy) -> usize {
+    let mut violations = 0;
+    debug::for_each_mapping(Cr3::read().0, 0..u64::MAX, |mapping| {
+        if is_violation(mapping.flags) {
+            violations += 1;
+            match policy {
+                Policy::Panic => panic!("W^X violation: {}", mapping),
+                Policy::Warn => warn!("W^X violation: {}", mapping),
+            }
+        }
+    });
+    violations
+}
+
+fn is_violation(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+fn fix_table(table: &mut PageTable, level: u8, base: u64, changed: &mut usize) {
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * entry_size;
+        if start >= vmm::KERNEL_VM_START && start + entry_size <= vmm::KERNEL_VM_END {
+            continue;
+        }
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            if is_violation(flags) {
+                entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+                *changed += 1;
+            }
+        } else {
+            let next = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+            fix_table(next, level - 1, start, changed);
+        }
+    }
+}
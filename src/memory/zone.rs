@@ -0,0 +1,60 @@
+use core::ops::Range;
+use x86_64::PhysAddr;
+
+/// A range of physical memory that devices with limited addressing can be restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16 MiB, reachable by ISA DMA.
+    Dma,
+    /// Below 4 GiB, reachable by devices that only use 32-bit addresses.
+    Low,
+    /// Everything above 4 GiB.
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Low, Zone::Normal];
+
+    /// Returns the physical address range covered by the zone.
+    pub const fn range(self) -> Range<u64> {
+        match self {
+            Zone::Dma => 0..16 << 20,
+            Zone::Low => 16 << 20..4 << 30,
+            Zone::Normal => 4 << 30..u64::MAX,
+        }
+    }
+
+    /// Returns the zone `addr` lies in.
+    pub fn containing(addr: PhysAddr) -> Zone {
+        Zone::ALL.iter().copied()
+            .find(|zone| zone.range().contains(&addr.as_u64()))
+            .unwrap_or(Zone::Normal)
+    }
+
+    /// Returns the zones an allocation restricted to this zone may be served from, best fit first.
+    ///
+    /// Allocations fall back to lower zones only, so memory that legacy devices depend on is used last.
+    pub fn fallbacks(self) -> impl Iterator<Item = Zone> {
+        Zone::ALL.iter().copied().rev().filter(move |zone| *zone <= self)
+    }
+
+    pub(super) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[test_case]
+fn test_zone_boundaries() {
+    assert_eq!(Zone::containing(PhysAddr::new(0xb8000)), Zone::Dma);
+    assert_eq!(Zone::containing(PhysAddr::new(16 << 20)), Zone::Low);
+    assert_eq!(Zone::containing(PhysAddr::new((4 << 30) - 4096)), Zone::Low);
+    assert_eq!(Zone::containing(PhysAddr::new(4 << 30)), Zone::Normal);
+}
+
+#[test_case]
+fn test_fallbacks_prefer_higher_zones() {
+    let mut fallbacks = Zone::Low.fallbacks();
+    assert_eq!(fallbacks.next(), Some(Zone::Low));
+    assert_eq!(fallbacks.next(), Some(Zone::Dma));
+    assert_eq!(fallbacks.next(), None);
+}
@@ -0,0 +1,161 @@
+//! The panic screen: everything needed to triage a crash on one screen, and the same on serial.
+//!
+//! `show` takes the screen over from the terminals and prints the panic message, the registers, the current
+//! task, the heap usage and the last lines logged, then reboots once a key is pressed on the keyboard or a byte
+//! arrives on COM1. It takes no lock it might wait for, so it works on a panic in the middle of a print.
+
+use crate::{
+    allocator, backtrace,
+    interrupts::stats::cpu_index,
+    logger, power, serial,
+    serial::uart::{ComPort, Uart},
+    task::{self, keyboard::i8042},
+    time,
+    vga_buffer::{self, Color, RawScreen, BUFFER_HEIGHT, BUFFER_WIDTH},
+};
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr2, Cr3},
+};
+
+/// Set by the first panic, so that a panic while drawing the panic screen doesn't draw it again.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Writes a line to both the screen and serial.
+macro_rules! out {
+    ($screen:expr, $($arg:tt)*) => {{
+        let _ = $screen.write_fmt(format_args!("{}\n", format_args!($($arg)*)));
+        serial::write_unlocked(format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+/// The general purpose registers, `rip` and `rflags`.
+struct Registers {
+    general: [u64; 16],
+    rip: u64,
+    rflags: u64,
+}
+
+impl Registers {
+    const NAMES: [&'static str; 16] = [
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    ];
+
+    /// Reads the registers as they are where this is inlined.
+    #[inline(always)]
+    fn capture() -> Registers {
+        let mut general = [0u64; 16];
+        let (rip, rflags): (u64, u64);
+        unsafe {
+            asm!(
+                "mov [{0}], rax", "mov [{0} + 8], rbx", "mov [{0} + 16], rcx", "mov [{0} + 24], rdx",
+                "mov [{0} + 32], rsi", "mov [{0} + 40], rdi", "mov [{0} + 48], rbp", "mov [{0} + 56], rsp",
+                "mov [{0} + 64], r8", "mov [{0} + 72], r9", "mov [{0} + 80], r10", "mov [{0} + 88], r11",
+                "mov [{0} + 96], r12", "mov [{0} + 104], r13", "mov [{0} + 112], r14", "mov [{0} + 120], r15",
+                in(reg) general.as_mut_ptr(),
+                options(nostack, preserves_flags),
+            );
+            asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+            asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+        }
+        Registers { general, rip, rflags }
+    }
+}
+
+/// Four registers to a line, like `rax 0x0000000000000000`.
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (name, value)) in Registers::NAMES.iter().zip(self.general.iter()).enumerate() {
+            let separator = if index % 4 == 3 { "\n" } else { "  " };
+            write!(f, "{:<3} {:#018x}{}", name, value, separator)?;
+        }
+        write!(f, "rip {:#018x}  rflags {:#010x}", self.rip, self.rflags)
+    }
+}
+
+/// Shows the panic screen for `info` and reboots once a key is pressed. For panic handlers.
+///
+/// The registers are the panic handler's, whose `rsp` and `rbp` lead to the stack of the code that panicked.
+/// A backtrace is printed to serial only, since the screen has no room for it.
+pub fn show(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    interrupts::disable();
+    if SHOWN.swap(true, Ordering::Relaxed) {
+        serial::write_unlocked(format_args!("panic while showing the panic screen: {}\n", info));
+        crate::hlt_loop();
+    }
+
+    let mut screen = vga_buffer::take_over_screen(Color::White, Color::Blue);
+    screen.set_color(Color::Blue, Color::White);
+    out!(screen, "{:^width$}", "KERNEL PANIC", width = BUFFER_WIDTH);
+    screen.set_color(Color::White, Color::Blue);
+    out!(screen, "{}", info);
+    out!(screen, "");
+    out!(screen, "{}", registers);
+    let (l4_frame, _) = Cr3::read();
+    out!(screen, "cr2 {:#018x}  cr3 {:#018x}", Cr2::read().as_u64(), l4_frame.start_address().as_u64());
+    let uptime = time::uptime_ms();
+    match (task::current_task_id(), task::current_task_name()) {
+        (Some(id), name) => {
+            out!(screen, "cpu {}  task {} {}  uptime {} ms", cpu_index(), id, name.unwrap_or(""), uptime)
+        }
+        (None, _) => out!(screen, "cpu {}  no task  uptime {} ms", cpu_index(), uptime),
+    }
+    match allocator::try_stats() {
+        Some(heap) => out!(
+            screen,
+            "heap {} of {} bytes used, {} at most, largest free block {}",
+            heap.used,
+            heap.heap_size,
+            heap.peak_used,
+            heap.largest_free_block
+        ),
+        None => out!(screen, "heap locked"),
+    }
+    print_recent_lines(&mut screen);
+    backtrace::print_caller();
+
+    screen.set_position(BUFFER_HEIGHT - 1, 0);
+    screen.set_color(Color::Blue, Color::White);
+    out!(screen, "{:^width$}", "press any key to reboot", width = BUFFER_WIDTH);
+    screen.present();
+    wait_for_key();
+    power::reboot()
+}
+
+/// Prints the last lines logged to serial, and as many of the last ones as fit above the bottom row to the screen,
+/// cut off at its right edge.
+fn print_recent_lines(screen: &mut RawScreen) {
+    out!(screen, "---- log ----");
+    let mut count = 0;
+    logger::for_each_recent_line(|_| count += 1);
+    let rows = (BUFFER_HEIGHT - 1).saturating_sub(screen.row());
+    let mut index = 0;
+    logger::for_each_recent_line(|line| {
+        serial::write_unlocked(format_args!("{}\n", line));
+        if index + rows >= count {
+            let line = line.split('\n').next().unwrap_or("");
+            let end = line.char_indices().nth(BUFFER_WIDTH).map_or(line.len(), |(end, _)| end);
+            let _ = writeln!(screen, "{}", &line[..end]);
+        }
+        index += 1;
+    });
+}
+
+/// Polls the keyboard and COM1 until a key is pressed or a byte is received.
+fn wait_for_key() {
+    let mut com1 = Uart::new(ComPort::Com1);
+    while com1.try_receive().is_some() {}
+    loop {
+        if i8042::poll_key_press() || com1.try_receive().is_some() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,319 @@
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+pub mod driver;
+pub mod ecam;
+pub mod msi;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0e;
+const REG_BAR0: u16 = 0x10;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3c;
+/// Where the extended capabilities start, past the part of configuration space the ports reach.
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+/// The interrupt line of a function that isn't connected to the legacy interrupt controller.
+const INTERRUPT_LINE_NONE: u8 = 0xff;
+
+/// Command register bit that keeps the device from asserting its legacy INTx line.
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+
+/// Serializes the two-step accesses through the configuration address and data ports.
+static CONFIG_PORTS: Mutex<()> = Mutex::new(());
+
+/// The location of a PCI function in configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A memory or I/O base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { addr: PhysAddr, prefetchable: bool },
+    Io { port: u16 },
+}
+
+/// What a function reports about itself in the header of its configuration space, read once by `devices`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// The base address registers by index, `None` for unimplemented ones and the upper halves of 64-bit ones.
+    pub bars: [Option<Bar>; 6],
+    /// The legacy PIC line firmware routed the function's INTx pin to, if any.
+    pub interrupt_line: Option<u8>,
+}
+
+impl PciDevice {
+    /// Reads the header of the function at `address`, which must exist.
+    pub fn read(address: PciAddress) -> PciDevice {
+        let (class, subclass, prog_if) = address.class();
+        let bar_count = match address.read_u8(REG_HEADER_TYPE) & HEADER_TYPE_MASK {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [None; 6];
+        let mut index = 0;
+        while index < bar_count {
+            bars[index as usize] = address.bar(index);
+            index += if address.is_64_bit_bar(index) { 2 } else { 1 };
+        }
+        let interrupt_line = match address.read_u8(REG_INTERRUPT_LINE) {
+            INTERRUPT_LINE_NONE => None,
+            line => Some(line),
+        };
+        PciDevice {
+            address,
+            vendor_id: address.vendor_id(),
+            device_id: address.device_id(),
+            class,
+            subclass,
+            prog_if,
+            bars,
+            interrupt_line,
+        }
+    }
+}
+
+/// Prints the address, the IDs and the class, like `00:02.0 1234:1111 class 03.00.00`.
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress { bus, device, function }
+    }
+
+    /// Reads the dword at `offset`, which reads as all ones past the first 256 bytes unless ECAM is in use.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        assert!(offset < ecam::CONFIG_SPACE_SIZE, "configuration space offset out of range");
+        if let Some(value) = ecam::read_u32(self, offset) {
+            return value;
+        }
+        if offset >= EXTENDED_CAPABILITIES {
+            return u32::MAX;
+        }
+        let _ports = CONFIG_PORTS.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    /// Writes the dword at `offset`, which is dropped past the first 256 bytes unless ECAM is in use.
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        assert!(offset < ecam::CONFIG_SPACE_SIZE, "configuration space offset out of range");
+        if ecam::write_u32(self, offset, value) || offset >= EXTENDED_CAPABILITIES {
+            return;
+        }
+        let _ports = CONFIG_PORTS.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Writes the 16-bit register at `offset` by rewriting the whole dword around it.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xffff << shift);
+        self.write_u32(offset, dword | u32::from(value) << shift);
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Returns the vendor ID, which is `0xffff` if no function exists at this address.
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(REG_VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(REG_DEVICE_ID)
+    }
+
+    /// Returns the class code, subclass and programming interface.
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(REG_CLASS);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(REG_COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.write_u16(REG_COMMAND, command);
+    }
+
+    /// Decodes base address register `index`, or `None` if it is unimplemented or there is no BAR `index`, like
+    /// for the reserved BAR indicators 6 and 7 of a capability.
+    ///
+    /// The upper half of a 64-bit memory BAR occupies index `index + 1`.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index > 5 {
+            return None;
+        }
+        let offset = REG_BAR0 + u16::from(index) * 4;
+        let low = self.read_u32(offset);
+        if low & 1 != 0 {
+            let port = (low & !0b11) as u16;
+            return if port == 0 { None } else { Some(Bar::Io { port }) };
+        }
+        let mut addr = u64::from(low & !0xf);
+        if (low >> 1) & 0b11 == 0b10 {
+            // the last BAR has no upper half after it
+            if index == 5 {
+                return None;
+            }
+            addr |= u64::from(self.read_u32(offset + 4)) << 32;
+        }
+        if addr == 0 {
+            return None;
+        }
+        Some(Bar::Memory { addr: PhysAddr::new(addr), prefetchable: low & (1 << 3) != 0 })
+    }
+
+    fn is_64_bit_bar(&self, index: u8) -> bool {
+        let low = self.read_u32(REG_BAR0 + u16::from(index) * 4);
+        low & 1 == 0 && (low >> 1) & 0b11 == 0b10
+    }
+
+    /// Returns the configuration space offset of the first capability with `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().find(|&(cap_id, _)| cap_id == id).map(|(_, offset)| offset)
+    }
+
+    /// Iterates over the capability list as `(id, offset)` pairs.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut next = if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES != 0 {
+            u16::from(self.read_u8(REG_CAPABILITIES) & !0b11)
+        } else {
+            0
+        };
+        // a malformed list could loop, but it can't hold more than 48 capabilities
+        let mut remaining = 48;
+        core::iter::from_fn(move || {
+            if next == 0 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let offset = next;
+            let header = self.read_u16(offset);
+            next = (header >> 8) & 0xfc;
+            Some((header as u8, offset))
+        })
+    }
+
+    /// Returns the configuration space offset of the first extended capability with `id`.
+    pub fn find_extended_capability(&self, id: u16) -> Option<u16> {
+        self.extended_capabilities().find(|&(cap_id, _)| cap_id == id).map(|(_, offset)| offset)
+    }
+
+    /// Iterates over the PCI Express extended capability list as `(id, offset)` pairs.
+    ///
+    /// The list is only reachable through ECAM, so it is empty without it.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let mut next = if ecam::is_enabled() { EXTENDED_CAPABILITIES } else { 0 };
+        let mut remaining = (ecam::CONFIG_SPACE_SIZE - EXTENDED_CAPABILITIES) / 4;
+        core::iter::from_fn(move || {
+            if next < EXTENDED_CAPABILITIES || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let offset = next;
+            let header = self.read_u32(offset);
+            // functions without extended capabilities have a zero header, absent ones all ones
+            if header == 0 || header == u32::MAX {
+                return None;
+            }
+            next = (header >> 20) as u16 & !0b11;
+            Some((header as u16, offset))
+        })
+    }
+
+    fn config_address(&self, offset: u16) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc)
+    }
+}
+
+/// Finds every function on every bus by brute force.
+pub fn enumerate() -> Vec<PciAddress> {
+    let mut found = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress::new(bus, device, 0);
+            if first.vendor_id() == 0xffff {
+                continue;
+            }
+            found.push(first);
+            if first.read_u8(REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+                found.extend((1..8).map(|function| PciAddress::new(bus, device, function))
+                    .filter(|function| function.vendor_id() != 0xffff));
+            }
+        }
+    }
+    found
+}
+
+/// Scans every bus and iterates over the functions found, with their headers read.
+pub fn devices() -> impl Iterator<Item = PciDevice> {
+    enumerate().into_iter().map(PciDevice::read)
+}
+
+/// Scans every bus for the functions of class `class` and subclass `subclass`, like `(0x01, 0x06)` for SATA
+/// controllers, which is where drivers find their devices.
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = PciDevice> {
+    devices().filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+#[test_case]
+fn test_config_address() {
+    assert_eq!(PciAddress::new(1, 2, 3).config_address(0x11), 0x8001_1310);
+}
@@ -0,0 +1,118 @@
+//! Binding PCI functions to the drivers that handle them.
+//!
+//! Drivers register a table of the devices they support and a probe function. `probe_all` scans the bus once
+//! and offers every function nobody has yet to the matching drivers, drivers that name its vendor and device
+//! ID before those that only name its class, until one probe takes it. Drivers registered after the scan are
+//! offered the functions still free right away, so a function never ends up with two drivers.
+
+use super::{PciAddress, PciDevice};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+/// The functions found by the last `probe_all`, each with the name of its driver, if any.
+static DEVICES: Mutex<Vec<(PciDevice, Option<&'static str>)>> = Mutex::new(Vec::new());
+
+/// A device a driver supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl Match {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            Match::Class { class, subclass } => device.class == class && device.subclass == subclass,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The driver matched the function but can't handle it, e.g. because it is a revision it doesn't know.
+    Unsupported,
+    /// Setting the device up failed.
+    Failed(&'static str),
+}
+
+/// A driver for PCI functions.
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Sets up the function, which is then the driver's. Called without any of the registry's locks held.
+    pub probe: fn(&PciDevice) -> Result<(), ProbeError>,
+}
+
+impl Driver {
+    /// Returns how well the driver matches `device`: 2 for its ID, 1 for its class, 0 for not at all.
+    fn rank(&self, device: &PciDevice) -> u8 {
+        self.matches
+            .iter()
+            .filter(|m| m.matches(device))
+            .map(|m| match m {
+                Match::Id { .. } => 2,
+                Match::Class { .. } => 1,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Adds `driver` and offers it the functions `probe_all` found that are still without a driver. Needs the heap.
+pub fn register(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    let free: Vec<PciDevice> =
+        DEVICES.lock().iter().filter(|(_, bound)| bound.is_none()).map(|&(device, _)| device).collect();
+    for device in free {
+        if driver.rank(&device) > 0 && (driver.probe)(&device).is_ok() {
+            bind(device.address, driver.name);
+        }
+    }
+}
+
+/// Scans the bus and offers every function that has no driver yet to the registered drivers, returning how many
+/// got one. Needs the heap.
+///
+/// Drivers are tried best match first, and in the order they were registered among equal matches.
+pub fn probe_all() -> usize {
+    let found: Vec<PciDevice> = super::devices().collect();
+    {
+        let mut devices = DEVICES.lock();
+        for device in found.iter() {
+            if !devices.iter().any(|(known, _)| known.address == device.address) {
+                devices.push((*device, None));
+            }
+        }
+    }
+    let drivers = DRIVERS.lock().clone();
+    let mut bound = 0;
+    for device in found.iter().filter(|device| bound_driver(device.address).is_none()) {
+        let mut candidates: Vec<&'static Driver> =
+            drivers.iter().copied().filter(|driver| driver.rank(device) > 0).collect();
+        // the sort is stable, so registration order stays among drivers that match equally well
+        candidates.sort_by_key(|driver| core::cmp::Reverse(driver.rank(device)));
+        if let Some(driver) = candidates.into_iter().find(|driver| (driver.probe)(device).is_ok()) {
+            bind(device.address, driver.name);
+            bound += 1;
+        }
+    }
+    bound
+}
+
+/// Returns the name of the driver bound to the function at `address`.
+pub fn bound_driver(address: PciAddress) -> Option<&'static str> {
+    DEVICES.lock().iter().find(|(device, _)| device.address == address).and_then(|&(_, driver)| driver)
+}
+
+/// Returns every function `probe_all` found, with the name of its driver, if any.
+pub fn bindings() -> Vec<(PciDevice, Option<&'static str>)> {
+    DEVICES.lock().clone()
+}
+
+fn bind(address: PciAddress, driver: &'static str) {
+    if let Some(entry) = DEVICES.lock().iter_mut().find(|(device, _)| device.address == address) {
+        entry.1 = Some(driver);
+    }
+}
@@ -0,0 +1,95 @@
+//! Memory mapped access to the whole 4 KiB configuration space of every function, as PCI Express defines it.
+//!
+//! The configuration ports only reach the first 256 bytes, which leaves out the extended capabilities. Once
+//! `init` mapped the region the ACPI MCFG lists for segment 0, `PciAddress` reads and writes go through it
+//! instead; without an MCFG they keep using the ports.
+
+use super::PciAddress;
+use crate::{
+    acpi::{AcpiError, Mcfg},
+    memory::{self, MmioRegion},
+};
+use conquer_once::spin::OnceCell;
+
+/// Size of the configuration space of a function.
+pub const CONFIG_SPACE_SIZE: u16 = 0x1000;
+
+static ECAM: OnceCell<Ecam> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum EcamError {
+    Acpi(AcpiError),
+    /// The MCFG lists no region for segment 0, the only one reachable through `PciAddress`.
+    NoSegment0,
+    Map(memory::mmio::MmioError),
+    AlreadyInitialized,
+}
+
+struct Ecam {
+    registers: MmioRegion,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// Maps the configuration space of the buses of segment 0 from the MCFG. Needs the kernel memory installed.
+///
+/// Maps a megabyte of address space per bus, all of it uncached.
+pub fn init() -> Result<(), EcamError> {
+    if is_enabled() {
+        return Err(EcamError::AlreadyInitialized);
+    }
+    let mcfg = Mcfg::parse().map_err(EcamError::Acpi)?;
+    let region = mcfg.regions.iter().find(|region| region.segment == 0).ok_or(EcamError::NoSegment0)?;
+    let buses = usize::from(region.end_bus.saturating_sub(region.start_bus)) + 1;
+    let base = region.base + (u64::from(region.start_bus) << 20);
+    let registers = memory::map_mmio(base, buses << 20).map_err(EcamError::Map)?;
+    let ecam = Ecam { registers, start_bus: region.start_bus, end_bus: region.end_bus };
+    ECAM.try_init_once(|| ecam).map_err(|_| EcamError::AlreadyInitialized)
+}
+
+/// Returns `true` if configuration space is accessed through ECAM, so the extended part can be reached.
+pub fn is_enabled() -> bool {
+    ECAM.try_get().is_ok()
+}
+
+/// Reads the dword at `offset` of the configuration space of `address`, `None` if ECAM isn't in use for its bus.
+pub(super) fn read_u32(address: &PciAddress, offset: u16) -> Option<u32> {
+    let ecam = ECAM.try_get().ok()?;
+    Some(ecam.registers.read(ecam.offset(address, offset)?))
+}
+
+/// Writes the dword at `offset` of the configuration space of `address`, `false` if ECAM isn't in use for its bus.
+pub(super) fn write_u32(address: &PciAddress, offset: u16, value: u32) -> bool {
+    let ecam = match ECAM.try_get() {
+        Ok(ecam) => ecam,
+        Err(_) => return false,
+    };
+    match ecam.offset(address, offset) {
+        Some(offset) => {
+            ecam.registers.write(offset, value);
+            true
+        }
+        None => false,
+    }
+}
+
+impl Ecam {
+    fn offset(&self, address: &PciAddress, offset: u16) -> Option<usize> {
+        if !(self.start_bus..=self.end_bus).contains(&address.bus) {
+            return None;
+        }
+        let function = function_offset(address.bus - self.start_bus, address.device, address.function);
+        Some(function + usize::from(offset & !0b11))
+    }
+}
+
+/// Returns the offset of the configuration space of a function from that of the first bus of a region.
+fn function_offset(bus: u8, device: u8, function: u8) -> usize {
+    usize::from(bus) << 20 | usize::from(device) << 15 | usize::from(function) << 12
+}
+
+#[test_case]
+fn test_function_offset() {
+    assert_eq!(function_offset(1, 2, 3), 0x0011_3000);
+    assert_eq!(function_offset(255, 31, 7), 0x0fff_f000);
+}
@@ -0,0 +1,180 @@
+use super::{Bar, PciAddress, COMMAND_INTX_DISABLE, COMMAND_MEMORY};
+use crate::{
+    interrupts::{apic, vector},
+    memory::{self, MmioRegion},
+};
+use x86_64::PhysAddr;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// Messages written below this address are delivered to local APICs.
+const MESSAGE_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+#[derive(Debug)]
+pub enum MsiError {
+    /// The device lacks the MSI or MSI-X capability.
+    Unsupported,
+    /// Every dynamic interrupt vector is taken.
+    NoFreeVector,
+    /// Interrupts can't be delivered as messages before the local APIC is enabled.
+    NoLocalApic,
+    /// The BAR holding the MSI-X table is not a memory BAR.
+    BadTableBar,
+    Map(memory::mmio::MmioError),
+    /// The MSI-X table has no entry with this index.
+    NoSuchEntry(u16),
+}
+
+/// The address and data a device writes to raise `vector` on the CPU with APIC ID `destination`.
+///
+/// Uses fixed delivery and edge triggering.
+pub fn message(vector: u8, destination: u8) -> (u64, u32) {
+    (u64::from(MESSAGE_ADDRESS_BASE | u32::from(destination) << 12), u32::from(vector))
+}
+
+fn current_apic_id() -> Result<u8, MsiError> {
+    Ok(apic::local_apic().ok_or(MsiError::NoLocalApic)?.id() as u8)
+}
+
+/// Makes `device` signal a single MSI that runs `handler` on the current CPU, returning the vector used.
+///
+/// Disables the legacy INTx line of the device.
+pub fn enable_msi(device: PciAddress, handler: fn()) -> Result<u8, MsiError> {
+    let cap = device.find_capability(CAPABILITY_MSI).ok_or(MsiError::Unsupported)?;
+    let destination = current_apic_id()?;
+    let vector = vector::allocate(handler).ok_or(MsiError::NoFreeVector)?;
+    let (address, data) = message(vector, destination);
+
+    let control = device.read_u16(cap + 2);
+    device.write_u32(cap + 4, address as u32);
+    if control & MSI_64_BIT != 0 {
+        device.write_u32(cap + 8, (address >> 32) as u32);
+        device.write_u16(cap + 12, data as u16);
+    } else {
+        device.write_u16(cap + 8, data as u16);
+    }
+    device.set_command(device.command() | COMMAND_INTX_DISABLE);
+    device.write_u16(cap + 2, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+    Ok(vector)
+}
+
+/// Stops `device` from signaling MSIs and frees the vector returned by `enable_msi`.
+pub fn disable_msi(device: PciAddress, vector: u8) {
+    if let Some(cap) = device.find_capability(CAPABILITY_MSI) {
+        device.write_u16(cap + 2, device.read_u16(cap + 2) & !MSI_ENABLE);
+    }
+    vector::free(vector);
+}
+
+/// The MSI-X table of a device, with one separately routed vector per entry.
+///
+/// Entries start out masked. Dropping the table disables MSI-X and frees every vector assigned through it.
+pub struct MsiX {
+    device: PciAddress,
+    cap: u16,
+    table: MmioRegion,
+    size: u16,
+    vectors: [Option<u8>; 32],
+}
+
+impl MsiX {
+    /// Maps the MSI-X table of `device`, masks all entries and enables MSI-X in place of INTx.
+    ///
+    /// Only the first 32 entries can be assigned vectors.
+    pub fn new(device: PciAddress) -> Result<MsiX, MsiError> {
+        let cap = device.find_capability(CAPABILITY_MSIX).ok_or(MsiError::Unsupported)?;
+        let control = device.read_u16(cap + 2);
+        let size = (control & MSIX_TABLE_SIZE) + 1;
+        let table_location = device.read_u32(cap + 4);
+        let bar = match device.bar((table_location & 0b111) as u8) {
+            Some(Bar::Memory { addr, .. }) => addr,
+            _ => return Err(MsiError::BadTableBar),
+        };
+        let table_addr = PhysAddr::new(bar.as_u64() + u64::from(table_location & !0b111));
+        let table = memory::map_mmio(table_addr, usize::from(size) * MSIX_ENTRY_SIZE).map_err(MsiError::Map)?;
+
+        let msix = MsiX { device, cap, table, size, vectors: [None; 32] };
+        for entry in 0..size {
+            msix.set_masked_unchecked(entry, true);
+        }
+        device.set_command(device.command() | COMMAND_MEMORY | COMMAND_INTX_DISABLE);
+        device.write_u16(cap + 2, (control & !MSIX_FUNCTION_MASK) | MSIX_ENABLE);
+        Ok(msix)
+    }
+
+    /// Returns the number of entries in the table.
+    pub fn table_size(&self) -> u16 {
+        self.size
+    }
+
+    /// Routes `entry` to a new vector running `handler` on the current CPU and unmasks it.
+    ///
+    /// Any vector previously assigned to the entry is freed. Returns the new vector.
+    pub fn assign(&mut self, entry: u16, handler: fn()) -> Result<u8, MsiError> {
+        if entry >= self.size || usize::from(entry) >= self.vectors.len() {
+            return Err(MsiError::NoSuchEntry(entry));
+        }
+        let destination = current_apic_id()?;
+        let vector = vector::allocate(handler).ok_or(MsiError::NoFreeVector)?;
+        let (address, data) = message(vector, destination);
+
+        let offset = usize::from(entry) * MSIX_ENTRY_SIZE;
+        self.set_masked_unchecked(entry, true);
+        self.table.write::<u32>(offset, address as u32);
+        self.table.write::<u32>(offset + 4, (address >> 32) as u32);
+        self.table.write::<u32>(offset + 8, data);
+        self.set_masked_unchecked(entry, false);
+
+        if let Some(old) = self.vectors[usize::from(entry)].replace(vector) {
+            vector::free(old);
+        }
+        Ok(vector)
+    }
+
+    /// Masks or unmasks `entry`. A masked entry records its message in the pending bit array instead.
+    pub fn set_masked(&self, entry: u16, masked: bool) -> Result<(), MsiError> {
+        if entry >= self.size {
+            return Err(MsiError::NoSuchEntry(entry));
+        }
+        self.set_masked_unchecked(entry, masked);
+        Ok(())
+    }
+
+    /// Returns the vector assigned to `entry`, if any.
+    pub fn vector(&self, entry: u16) -> Option<u8> {
+        self.vectors.get(usize::from(entry)).copied().flatten()
+    }
+
+    fn set_masked_unchecked(&self, entry: u16, masked: bool) {
+        let offset = usize::from(entry) * MSIX_ENTRY_SIZE + 12;
+        let control = self.table.read::<u32>(offset);
+        let control = if masked { control | MSIX_VECTOR_MASKED } else { control & !MSIX_VECTOR_MASKED };
+        self.table.write::<u32>(offset, control);
+    }
+}
+
+impl Drop for MsiX {
+    fn drop(&mut self) {
+        let control = self.device.read_u16(self.cap + 2);
+        self.device.write_u16(self.cap + 2, control & !MSIX_ENABLE);
+        for vector in self.vectors.iter().flatten() {
+            vector::free(*vector);
+        }
+    }
+}
+
+#[test_case]
+fn test_message_encoding() {
+    assert_eq!(message(0x51, 3), (0xfee0_3000, 0x51));
+}
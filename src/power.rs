@@ -0,0 +1,202 @@
+//! Turning the machine off and restarting it.
+//!
+//! Both go the ACPI way first, which works on real hardware, and fall back to what emulators and older machines
+//! understand: the shutdown ports of QEMU, Bochs and VirtualBox, QEMU's `isa-debug-exit` device, and a reset
+//! through the keyboard controller or a triple fault. Neither takes a lock, so they work from panic handlers.
+
+use crate::{
+    acpi::{
+        fadt::{ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY},
+        Fadt,
+    },
+    exit_qemu,
+    memory::phys_to_virt,
+    task::keyboard::i8042,
+    time, QemuExitCode,
+};
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+/// PM1 control bit that is set once the firmware handed the power management registers to the OS.
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// Milliseconds the firmware gets to switch to ACPI mode.
+const ACPI_ENABLE_TIMEOUT_MS: u32 = 300;
+/// Milliseconds a reset or a sleep request gets to take effect before the next method is tried.
+const SETTLE_MS: u32 = 50;
+
+/// The AML opcodes around the `_S5_` package.
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+
+/// Ports and values that power off emulators without ACPI support: QEMU, Bochs and old QEMU, VirtualBox.
+const EMULATOR_SHUTDOWN: [(u16, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+/// Turns the machine off.
+///
+/// Enters ACPI sleep state S5 if the DSDT says how, then tries the emulator shutdown ports, then exits QEMU with
+/// `QemuExitCode::Success` through `isa-debug-exit`. Halts if all of that fails, so the machine has to be turned
+/// off by hand then.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Ok(fadt) = Fadt::parse() {
+        acpi_shutdown(&fadt);
+    }
+    for &(port, value) in EMULATOR_SHUTDOWN.iter() {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    exit_qemu(QemuExitCode::Success);
+    crate::hlt_loop()
+}
+
+/// Restarts the machine.
+///
+/// Writes the ACPI reset register if the FADT has one, then pulses the reset line through the keyboard
+/// controller, and triple faults as a last resort, which resets every x86 CPU.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = Fadt::parse().ok().filter(|fadt| fadt.reset_register.is_some()) {
+        acpi_reset(&fadt);
+        settle();
+    }
+    if i8042::pulse_reset().is_ok() {
+        settle();
+    }
+    triple_fault()
+}
+
+/// Puts the system into S5 through the PM1 control blocks, returning only if it is still running afterwards.
+fn acpi_shutdown(fadt: &Fadt) {
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    let (sleep_type_a, sleep_type_b) = match fadt.dsdt_table().ok().and_then(|dsdt| sleep_types(dsdt.body())) {
+        Some(types) => types,
+        None => return,
+    };
+    if !enable_acpi_mode(fadt) {
+        return;
+    }
+    let write = |block: u32, sleep_type: u8| {
+        let mut port = Port::<u16>::new(block as u16);
+        unsafe {
+            let control = port.read() & !(0b111 << PM1_SLEEP_TYPE_SHIFT);
+            port.write(control | u16::from(sleep_type) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+        }
+    };
+    write(fadt.pm1a_control_block, sleep_type_a);
+    if fadt.pm1b_control_block != 0 {
+        write(fadt.pm1b_control_block, sleep_type_b);
+    }
+    settle();
+}
+
+/// Asks the firmware to hand over the power management registers if it hasn't yet, returning whether it did.
+fn enable_acpi_mode(fadt: &Fadt) -> bool {
+    let mut control = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    if unsafe { control.read() } & PM1_SCI_ENABLE != 0 {
+        return true;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return false;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+        if unsafe { control.read() } & PM1_SCI_ENABLE != 0 {
+            return true;
+        }
+        time::delay_ms(1);
+    }
+    false
+}
+
+/// Waits `SETTLE_MS`, in steps `time::delay_ms` can spin for.
+fn settle() {
+    let step = time::MAX_DELAY_US / 1000;
+    for _ in 0..SETTLE_MS / step {
+        time::delay_ms(step);
+    }
+}
+
+fn acpi_reset(fadt: &Fadt) {
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+    match register.address_space {
+        ADDRESS_SPACE_IO => unsafe { Port::<u8>::new(register.address as u16).write(fadt.reset_value) },
+        ADDRESS_SPACE_MEMORY => unsafe {
+            phys_to_virt(PhysAddr::new(register.address)).as_mut_ptr::<u8>().write_volatile(fadt.reset_value)
+        },
+        // the PCI configuration space variant only names a function of bus 0, which the chipset resets
+        _ => {}
+    }
+}
+
+/// Loads an empty IDT and raises an exception, which can't be delivered, so the CPU shuts down and resets.
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop()
+}
+
+/// Returns `SLP_TYPa` and `SLP_TYPb` of the `\_S5` object in the AML of the DSDT, the values that turn the
+/// system off.
+///
+/// Rather than interpreting the AML, this looks for the `Name(_S5, Package() {…})` that virtually every
+/// firmware uses.
+fn sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = aml.windows(4).enumerate().find_map(|(index, window)| {
+        let named = match index {
+            0 => false,
+            1 => aml[0] == AML_NAME_OP,
+            _ => aml[index - 1] == AML_NAME_OP || aml[index - 2..index] == [AML_NAME_OP, b'\\'],
+        };
+        if window == b"_S5_" && named && aml.get(index + 4) == Some(&AML_PACKAGE_OP) {
+            Some(index)
+        } else {
+            None
+        }
+    })?;
+    // the package length takes one to four bytes, the count of bits 6 and 7 of the first one
+    let length_byte = *aml.get(name + 5)?;
+    let mut rest = aml.get(name + 5 + usize::from(length_byte >> 6) + 1 + 1..)?;
+    let mut element = || -> Option<u8> {
+        let (value, len) = match *rest.first()? {
+            AML_BYTE_PREFIX => (*rest.get(1)?, 2),
+            AML_ZERO_OP => (0, 1),
+            AML_ONE_OP => (1, 1),
+            _ => return None,
+        };
+        rest = &rest[len..];
+        Some(value)
+    };
+    let sleep_type_a = element()?;
+    let sleep_type_b = element()?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+#[test_case]
+fn test_sleep_types_are_found() {
+    // Name(\_S5, Package(4) { 0x05, 0x05, Zero, Zero }), after some other AML
+    let aml = [
+        0x10, 0x0a, b'_', b'S', b'B', b'_', AML_NAME_OP, b'\\', b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x0a,
+        0x04, AML_BYTE_PREFIX, 0x05, AML_BYTE_PREFIX, 0x05, AML_ZERO_OP, AML_ZERO_OP,
+    ];
+    assert_eq!(sleep_types(&aml), Some((5, 5)));
+    // Name(_S5, Package(2) { Zero, One }), as QEMU's is for some machines
+    let short = [AML_NAME_OP, b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x04, 0x02, AML_ZERO_OP, AML_ONE_OP];
+    assert_eq!(sleep_types(&short), Some((0, 1)));
+    assert_eq!(sleep_types(b"_S5_ is just mentioned here"), None);
+}
@@ -1,25 +1,188 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
+use crate::{
+    console::ConsoleBackend,
+    sync::IrqSpinLock,
+    task::channel::{self, Receiver, Sender},
+    vga_buffer::Color,
+};
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::Stream;
 use lazy_static::lazy_static;
 
+pub mod uart;
+
+pub use uart::{ComPort, Fifo, Parity, StopBits, Uart, UartConfig, UartError};
+
+/// Capacity of the byte queue of `SerialStream::new`.
+pub const DEFAULT_QUEUE_SIZE: usize = 256;
+
+static RECEIVED: OnceCell<Sender<u8>> = OnceCell::uninit();
+/// Received bytes thrown away because the queue was full, or the stream did not exist yet or was dropped.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+    /// COM1, the port `serial_print!` writes to, programmed with `UartConfig::DEFAULT` on first use.
+    pub static ref SERIAL1: IrqSpinLock<Uart> = {
+        let mut uart = Uart::new(ComPort::Com1);
+        // without a working port the output goes nowhere, which is all there is to do about it
+        let _ = uart.init(UartConfig::DEFAULT);
+        IrqSpinLock::new(uart)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Writes `args` to the first serial port without taking the `SERIAL1` lock.
+///
+/// Meant for crash handlers, which may have interrupted a print that holds the lock. Output can interleave
+/// with that print.
+pub fn write_unlocked(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    // the port was already initialized by whoever first locked SERIAL1
+    let _ = Uart::new(ComPort::Com1).write_fmt(args);
+}
+
+/// Reprograms COM1, the port `serial_print!` writes to, for example for a slower line on real hardware.
+pub fn configure(config: UartConfig) -> Result<(), UartError> {
+    SERIAL1.lock().init(config)
+}
+
+/// Initializes the first serial port and starts queueing the bytes it receives for `SerialStream`.
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    crate::interrupts::register_irq(ComPort::Com1.irq(), handle_interrupt)
+        .expect("failed to register serial IRQ");
+}
+
+/// Reads every byte the UART received. Registered as the handler of the COM1 IRQ.
+///
+/// Reads the receive registers directly instead of taking `SERIAL1`, so a print on another CPU doesn't make it
+/// wait. Sending only uses the transmit registers.
+fn handle_interrupt() {
+    let mut uart = Uart::new(ComPort::Com1);
+    // the FIFO raises the interrupt once for all bytes it holds, so all of them have to be read
+    while let Some(byte) = uart.try_receive() {
+        add_byte(byte);
+    }
+}
+
+/// Called by the serial interrupt handler
+///
+/// Must not block or allocate
+fn add_byte(byte: u8) {
+    let queued = match RECEIVED.try_get() {
+        Ok(received) => received.try_send(byte).is_ok(),
+        Err(_) => false,
+    };
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of received bytes dropped since boot, because the queue was full or there was no stream.
+pub fn dropped_bytes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Stream of the bytes received on the first serial port, as typed into `-serial stdio` in QEMU.
+///
+/// Only one may be created, since it takes over the queue fed by the serial interrupt. Terminals send the bytes
+/// as typed, so the stream holds UTF-8 text with `\r` for enter and escape sequences for other keys.
+pub struct SerialStream {
+    receiver: Receiver<u8>,
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Creates the stream with room for `capacity` bytes that were not read yet. Bytes arriving while the queue
+    /// is full are dropped and counted, see `dropped_bytes`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (sender, receiver) = channel::channel(capacity);
+        RECEIVED.try_init_once(|| sender)
+            .expect("SerialStream should only be created once");
+        SerialStream { receiver }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// The console backend of the first serial port, for machines without a screen.
+///
+/// Colors are sent as SGR escape sequences. Light gray on black stands for the colors the terminal on the other
+/// end starts with.
+pub struct SerialConsole {
+    /// Foreground in the low 4 bits, background in the high ones.
+    color: AtomicU8,
+}
+
+pub static SERIAL_CONSOLE: SerialConsole = SerialConsole { color: AtomicU8::new(DEFAULT_COLOR) };
+
+const DEFAULT_COLOR: u8 = Color::LightGray as u8;
+
+impl ConsoleBackend for SerialConsole {
+    fn write_str(&self, s: &str) {
+        _print(format_args!("{}", s));
+    }
+
+    fn write_fmt(&self, args: ::core::fmt::Arguments) {
+        _print(args);
+    }
+
+    fn write_unlocked(&self, args: ::core::fmt::Arguments) {
+        write_unlocked(args);
+    }
+
+    fn clear(&self) {
+        _print(format_args!("\x1b[2J\x1b[H"));
+    }
+
+    fn set_color(&self, foreground: Color, background: Color) {
+        let color = (background as u8) << 4 | foreground as u8;
+        if self.color.swap(color, Ordering::Relaxed) == color {
+            return;
+        }
+        if color == DEFAULT_COLOR {
+            _print(format_args!("\x1b[0m"));
+        } else {
+            _print(format_args!("\x1b[{};{}m", foreground.ansi_code(), background.ansi_code() + 10));
+        }
+    }
+
+    fn color(&self) -> (Color, Color) {
+        let color = self.color.load(Ordering::Relaxed);
+        (Color::ALL[usize::from(color & 0x0f)], Color::ALL[usize::from(color >> 4)])
+    }
+
+    /// Returns the size most terminals start with, as the serial port can't tell.
+    fn dimensions(&self) -> (usize, usize) {
+        (80, 24)
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,296 @@
+//! Driver of the 16550 UARTs behind the four legacy serial ports.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+/// Registers, as offsets from the base port. The first two are the divisor latch while `LCR_DLAB` is set.
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_SCRATCH: u16 = 7;
+
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+/// Enables the FIFOs and clears both of them.
+const FCR_ENABLE: u8 = 0x07;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+/// Connects the interrupt output of the UART to the interrupt controller.
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The clock of the baud rate generator divided by 16, the highest baud rate.
+const MAX_BAUD_RATE: u32 = 115_200;
+/// Sent to itself in loopback mode to check that the UART works.
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+/// Line status reads until a looped back byte counts as lost.
+const LOOPBACK_POLLS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// No UART answers at the port, or it did not receive what it sent in loopback mode.
+    NotPresent,
+    /// The baud rate is zero or does not divide 115200.
+    InvalidBaudRate(u32),
+    /// Only 5 to 8 data bits are supported.
+    InvalidDataBits(u8),
+}
+
+/// The legacy serial ports, at the I/O ports the BIOS puts them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// Returns the legacy IRQ line of the port, which COM1 shares with COM3 and COM2 with COM4.
+    pub const fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    /// Returns `true` if a UART answers at the port.
+    ///
+    /// Only the scratch register is written, so this is safe for ports in use.
+    pub fn exists(self) -> bool {
+        let mut uart = Uart::new(self);
+        let previous = uart.read(REG_SCRATCH);
+        let answers = [0x55, 0xaa].iter().all(|&value| {
+            uart.write(REG_SCRATCH, value);
+            uart.read(REG_SCRATCH) == value
+        });
+        uart.write(REG_SCRATCH, previous);
+        answers
+    }
+
+    /// Returns the ports a UART answers at, in order.
+    pub fn detect() -> impl Iterator<Item = ComPort> {
+        IntoIterator::into_iter(ComPort::ALL).filter(|port| port.exists())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// Two stop bits, or one and a half with 5 data bits.
+    Two,
+}
+
+/// When the UART raises the received data interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fifo {
+    /// No FIFOs, an interrupt for every byte.
+    Disabled,
+    /// The FIFOs are enabled, and the interrupt is raised once the receive FIFO holds this many bytes, 1, 4, 8
+    /// or 14, or when bytes waited in it for a while. Other values are rounded down.
+    Trigger(u8),
+}
+
+/// The line settings a UART is programmed with, 115200 baud 8N1 with a 14 byte FIFO trigger by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub fifo: Fifo,
+}
+
+impl UartConfig {
+    pub const DEFAULT: UartConfig = UartConfig {
+        baud_rate: MAX_BAUD_RATE,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        fifo: Fifo::Trigger(14),
+    };
+
+    fn divisor(&self) -> Result<u16, UartError> {
+        if self.baud_rate == 0 || MAX_BAUD_RATE % self.baud_rate != 0 {
+            return Err(UartError::InvalidBaudRate(self.baud_rate));
+        }
+        Ok((MAX_BAUD_RATE / self.baud_rate) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, UartError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(UartError::InvalidDataBits(self.data_bits));
+        }
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => LCR_TWO_STOP_BITS,
+        };
+        Ok((self.data_bits - 5) | stop_bits | parity << 3)
+    }
+
+    fn fifo_control(&self) -> u8 {
+        match self.fifo {
+            Fifo::Disabled => 0,
+            Fifo::Trigger(0..=3) => FCR_ENABLE,
+            Fifo::Trigger(4..=7) => FCR_ENABLE | 0x40,
+            Fifo::Trigger(8..=13) => FCR_ENABLE | 0x80,
+            Fifo::Trigger(_) => FCR_ENABLE | 0xc0,
+        }
+    }
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        UartConfig::DEFAULT
+    }
+}
+
+/// A 16550 UART at one of the legacy serial ports.
+///
+/// Several values may drive the same port, since the registers are accessed one at a time. Writes of different
+/// values just interleave.
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// Returns the UART of `port`, without programming it. See `open` for one that is ready to use.
+    pub const fn new(port: ComPort) -> Uart {
+        Uart { base: port.base() }
+    }
+
+    /// Programs the UART of `port` with `config`, after checking that it exists and works.
+    pub fn open(port: ComPort, config: UartConfig) -> Result<Uart, UartError> {
+        if !port.exists() {
+            return Err(UartError::NotPresent);
+        }
+        let mut uart = Uart::new(port);
+        uart.init(config)?;
+        Ok(uart)
+    }
+
+    /// Programs the UART with `config` and enables the received data interrupt.
+    ///
+    /// Sends a byte to itself in loopback mode first, and fails with `NotPresent` if it doesn't come back.
+    pub fn init(&mut self, config: UartConfig) -> Result<(), UartError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+
+        self.write(REG_INTERRUPT_ENABLE, 0);
+        self.write(REG_LINE_CONTROL, LCR_DLAB);
+        self.write(REG_DATA, divisor as u8);
+        self.write(REG_INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(REG_LINE_CONTROL, line_control);
+        self.write(REG_FIFO_CONTROL, config.fifo_control());
+
+        self.write(REG_MODEM_CONTROL, MCR_LOOPBACK | MCR_RTS | MCR_OUT1 | MCR_OUT2);
+        while self.try_receive().is_some() {}
+        self.write(REG_DATA, LOOPBACK_TEST_BYTE);
+        let looped_back = (0..LOOPBACK_POLLS).find_map(|_| self.try_receive());
+        if looped_back != Some(LOOPBACK_TEST_BYTE) {
+            self.write(REG_MODEM_CONTROL, 0);
+            return Err(UartError::NotPresent);
+        }
+
+        self.write(REG_MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+        self.write(REG_INTERRUPT_ENABLE, IER_RECEIVED_DATA);
+        Ok(())
+    }
+
+    /// Makes the UART receive what it sends instead of using the line, or goes back to the line.
+    ///
+    /// For testing the receive path. A real 16550 disconnects its interrupt output in loopback mode, QEMU's keeps
+    /// raising the received data interrupt.
+    pub fn set_loopback(&mut self, loopback: bool) {
+        let control = if loopback { MCR_LOOPBACK | MCR_OUT1 } else { MCR_DTR };
+        self.write(REG_MODEM_CONTROL, control | MCR_RTS | MCR_OUT2);
+    }
+
+    /// Sends `byte`, waiting until the UART can take it.
+    pub fn send(&mut self, byte: u8) {
+        while self.read(REG_LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(REG_DATA, byte);
+    }
+
+    /// Returns the next received byte, or `None` if none is waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(REG_LINE_STATUS) & LSR_DATA_READY == 0 {
+            return None;
+        }
+        Some(self.read(REG_DATA))
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_config_is_encoded() {
+    assert_eq!(UartConfig::DEFAULT.divisor(), Ok(1));
+    assert_eq!(UartConfig::DEFAULT.line_control(), Ok(0x03));
+    assert_eq!(UartConfig::DEFAULT.fifo_control(), 0xc7);
+    let config = UartConfig {
+        baud_rate: 9600,
+        data_bits: 7,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        fifo: Fifo::Trigger(4),
+    };
+    assert_eq!(config.divisor(), Ok(12));
+    assert_eq!(config.line_control(), Ok(0x1e));
+    assert_eq!(config.fifo_control(), 0x47);
+    assert_eq!(UartConfig { baud_rate: 7, ..config }.divisor(), Err(UartError::InvalidBaudRate(7)));
+    assert_eq!(UartConfig { data_bits: 9, ..config }.line_control(), Err(UartError::InvalidDataBits(9)));
+}
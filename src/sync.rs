@@ -0,0 +1,124 @@
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Data shared with interrupt handlers must be protected by this instead of a plain `spin::Mutex`. Otherwise an
+/// interrupt arriving while the lock is held deadlocks as soon as its handler tries to take the lock too.
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+/// Unlocks the `IrqSpinLock` when dropped and then restores the interrupt state from before locking.
+pub struct IrqSpinLockGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock { inner: Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Disables interrupts and spins until the lock is free.
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard { guard: ManuallyDrop::new(self.inner.lock()), interrupts_were_enabled }
+    }
+
+    /// Takes the lock if it is free, leaving the interrupt state unchanged if it is not.
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard { guard: ManuallyDrop::new(guard), interrupts_were_enabled }),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// Only for crash handlers that will never return to the holder. Does not restore the holder's interrupt state.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner.try_lock() {
+            Some(guard) => write!(f, "IrqSpinLock {{ data: {:?} }}", &*guard),
+            None => write!(f, "IrqSpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock first, so that a pending interrupt can take the lock as soon as it is delivered
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let lock = IrqSpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_nested_locks_restore_state() {
+    let outer = IrqSpinLock::new(());
+    let inner = IrqSpinLock::new(());
+    let outer_guard = outer.lock();
+    drop(inner.lock());
+    // the inner guard found interrupts disabled and must leave them so
+    assert!(!interrupts::are_enabled());
+    drop(outer_guard);
+    assert!(interrupts::are_enabled());
+}
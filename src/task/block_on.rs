@@ -0,0 +1,69 @@
+use super::work;
+use crate::interrupts::stats::{cpu_index, MAX_CPUS};
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use x86_64::instructions::interrupts;
+
+/// Set by the waker of the `block_on` call running on each CPU.
+static WOKEN: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FALSE: AtomicBool = AtomicBool::new(false);
+    [FALSE; MAX_CPUS]
+};
+
+const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop_waker);
+
+/// Drives `future` to completion on the current thread, without an executor and without allocating.
+///
+/// Halts the CPU until an interrupt whenever the future is pending, and runs pending work items in between,
+/// so that timers and other interrupt driven wakeups work before the executor is running. If interrupts are
+/// disabled, as in panic paths, it spins instead and only futures that make progress on their own complete.
+/// Must not be called from interrupt handlers or from a task.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let woken = &WOKEN[cpu_index()];
+    let waker = unsafe { Waker::from_raw(raw_waker(woken)) };
+    let mut context = Context::from_waker(&waker);
+    let can_halt = interrupts::are_enabled();
+    loop {
+        woken.store(false, Ordering::Release);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        if can_halt {
+            work::run_pending();
+            park(woken);
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Halts until the next interrupt unless the future was woken already.
+fn park(woken: &AtomicBool) {
+    interrupts::disable();
+    if woken.load(Ordering::Acquire) || !work::is_idle() {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+fn raw_waker(woken: &'static AtomicBool) -> RawWaker {
+    RawWaker::new(woken as *const AtomicBool as *const (), &VTABLE)
+}
+
+fn clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+fn wake(data: *const ()) {
+    // points into `WOKEN`, so wakers stay valid after `block_on` returned
+    unsafe { &*(data as *const AtomicBool) }.store(true, Ordering::Release);
+}
+
+fn drop_waker(_: *const ()) {}
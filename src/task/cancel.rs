@@ -0,0 +1,96 @@
+use super::sync::WaitQueue;
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+struct TokenState {
+    cancelled: AtomicBool,
+    waiters: WaitQueue,
+}
+
+/// A flag that asks tasks to stop, shared by cloning.
+///
+/// Cancellation is cooperative: tasks check `is_cancelled` at convenient points, or wrap their work in
+/// `run_until_cancelled`, and clean up themselves. Cancelling can't be undone.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let state = TokenState { cancelled: AtomicBool::new(false), waiters: WaitQueue::new() };
+        CancellationToken { state: Arc::new(state) }
+    }
+
+    /// Cancels the token and wakes every task waiting for it.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+        self.state.waiters.wake_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+
+    /// Runs `future` until it completes or the token is cancelled, whichever comes first.
+    ///
+    /// Resolves to `None` if the token was cancelled, after dropping `future` mid-way.
+    pub async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        RunUntilCancelled { token: self, future: Box::pin(future) }.await
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `CancellationToken::cancelled`.
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+}
+
+impl<'a> Future for Cancelled<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        self.token.state.waiters.register(cx.waker());
+        // `cancel` may have run before the waker was registered
+        if self.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+struct RunUntilCancelled<'a, F: Future> {
+    token: &'a CancellationToken,
+    future: Pin<Box<F>>,
+}
+
+impl<'a, F: Future> Future for RunUntilCancelled<'a, F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<F::Output>> {
+        let mut cancelled = self.token.cancelled();
+        if Pin::new(&mut cancelled).poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        self.future.as_mut().poll(cx).map(Some)
+    }
+}
@@ -0,0 +1,179 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{task::AtomicWaker, Stream};
+use spin::Mutex;
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    /// Waker of the receiver, woken on every send and when the last sender is dropped.
+    receiver_waker: AtomicWaker,
+    /// Wakers of `send` futures waiting for room, all woken whenever the receiver takes a value.
+    sender_wakers: Mutex<Vec<Waker>>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel holds `capacity` values. Returns the value that was not sent.
+    Full(T),
+    /// The receiver was dropped. Returns the value that was not sent.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+/// The sending half of a channel created by `channel`. Clone it to get more senders.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a channel created by `channel`, a stream of the sent values.
+///
+/// The stream ends once every sender was dropped and all values were received.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a bounded multi-producer, single-consumer channel that holds up to `capacity` values.
+///
+/// The buffer is allocated up front, so `Sender::try_send` never allocates or blocks and may be called from
+/// interrupt handlers. Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        receiver_waker: AtomicWaker::new(),
+        sender_wakers: Mutex::new(Vec::new()),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` if there is room, without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        self.shared.queue.push(value).map_err(|crossbeam_queue::PushError(value)| TrySendError::Full(value))?;
+        self.shared.receiver_waker.wake();
+        Ok(())
+    }
+
+    /// Sends `value`, waiting for room if the channel is full. Resolves to `Err(value)` if the receiver is gone.
+    ///
+    /// Must not be used from interrupt handlers, use `try_send` there.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send { sender: self, value: Some(value) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.receiver_waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+}
+
+// the value is never pinned
+impl<'a, T> Unpin for Send<'a, T> {}
+
+impl<'a, T> Future for Send<'a, T> {
+    type Output = Result<(), T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T>> {
+        let value = self.value.take().expect("Send polled after completion");
+        let value = match self.sender.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => return Poll::Ready(Err(value)),
+            Err(TrySendError::Full(value)) => value,
+        };
+        self.sender.shared.sender_wakers.lock().push(cx.waker().clone());
+        // the receiver may have made room before the waker was registered
+        match self.sender.try_send(value) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(value)),
+            Err(TrySendError::Full(value)) => {
+                self.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the oldest value, if any, without waiting.
+    pub fn try_recv(&self) -> Option<T> {
+        let value = self.shared.queue.pop().ok()?;
+        let waiting = core::mem::take(&mut *self.shared.sender_wakers.lock());
+        for waker in waiting {
+            waker.wake();
+        }
+        Some(value)
+    }
+
+    /// Returns `true` if every sender was dropped. Values sent before may still be waiting.
+    pub fn is_closed(&self) -> bool {
+        self.shared.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        self.shared.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Some(value) => {
+                self.shared.receiver_waker.take();
+                Poll::Ready(Some(value))
+            }
+            None if self.is_closed() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        let waiting = core::mem::take(&mut *self.shared.sender_wakers.lock());
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
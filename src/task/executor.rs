@@ -1,110 +1,441 @@
-use super::{Task, TaskId};
+use super::{
+    cancel::CancellationToken,
+    join::JoinHandle,
+    metrics::{ExecutorStats, Metrics, TaskCounters, TaskState, TaskStats},
+    work,
+    Priority,
+    Task,
+    TaskId,
+};
+use crate::{
+    allocator::{self, slab::{SlabBox, SlabCache}},
+    interrupts::stats::{cpu_index, MAX_CPUS},
+    time::{self, tsc},
+};
 use alloc::{
     collections::BTreeMap, 
     sync::Arc,
     task::Wake,
+    vec::Vec,
 };
-use core::task::{
-    Context,
-    Poll,
-    Waker,
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Waker},
 };
+use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Task structs are small and allocated for every spawn, so they live in their own slab cache.
+static TASK_CACHE: SlabCache<Task> = SlabCache::new();
+/// Makes sure the task cache is shrunk under memory pressure, however many executors are created.
+static TASK_CACHE_RECLAIMER: spin::Once<()> = spin::Once::new();
+
+/// Number of tasks of higher classes polled in a row while `Priority::Background` tasks are ready, before one
+/// background task is polled anyway.
+const STARVATION_LIMIT: usize = 16;
+/// Number of ready tasks of each class a worker can hold.
+const READY_QUEUE_SIZE: usize = 100;
+/// Number of tasks that can be spawned through `Spawner`s before the executor picks them up.
+const SPAWN_QUEUE_SIZE: usize = 100;
+
+/// Spawner of the running executor, set by `Executor::run`.
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// No executor is running yet.
+    NotRunning,
+    /// `SPAWN_QUEUE_SIZE` spawned tasks are waiting for the executor.
+    QueueFull,
+}
+
+/// A handle for adding tasks to an executor from anywhere, including other tasks and work items, even after
+/// `Executor::run` was called.
+///
+/// Queueing a task never blocks or allocates, but creating one allocates, so interrupt handlers should leave
+/// spawning to a work item. The next worker that wakes up moves queued tasks to its own ready queues.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
 
+impl Spawner {
+    pub fn spawn(&self, task: Task) -> Result<(), SpawnError> {
+        self.shared.spawned.push(task).map_err(|_| SpawnError::QueueFull)
+    }
+
+    /// Spawns `future` as a `Priority::Normal` task and returns a handle to await its return value.
+    pub fn spawn_joinable<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn(task)?;
+        Ok(handle)
+    }
+
+    /// Spawns `future` as a `Priority::Normal` task named `name`.
+    pub fn spawn_named<F>(&self, name: &'static str, future: F) -> Result<(), SpawnError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(Task::new(future).with_name(name))
+    }
+
+    /// Asks the executor to shut down: the shutdown token is cancelled, and every task that is still around
+    /// the next time a worker wakes up is dropped.
+    pub fn shutdown(&self) {
+        self.shared.shutdown.cancel();
+    }
+
+    /// Returns the token cancelled by `shutdown`, for tasks that want to finish cleanly before being dropped.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shared.shutdown.clone()
+    }
+
+    /// Returns a snapshot of the executor's metrics, see `Executor::stats`.
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
+
+    /// Returns the live tasks of the executor, in spawn order, see `Executor::tasks`.
+    pub fn tasks(&self) -> Vec<TaskStats> {
+        self.shared.tasks()
+    }
+}
+
+/// Returns a spawner for the running executor.
+pub fn spawner() -> Result<&'static Spawner, SpawnError> {
+    SPAWNER.try_get().map_err(|_| SpawnError::NotRunning)
+}
+
+/// Runs a worker of the running executor on the current CPU until shutdown.
+///
+/// Application processors call this at the end of bring-up to take part in polling. The worker steals ready
+/// tasks from the other workers whenever its own queues are empty.
+pub fn run_worker() -> Result<(), SpawnError> {
+    let spawner = spawner()?;
+    spawner.shared.run_worker(cpu_index());
+    Ok(())
+}
+
+/// A work-stealing executor with one worker per CPU.
+///
+/// Every worker has its own ready queues. A woken task is queued on the worker that polled it last, and a worker
+/// without ready tasks takes them from the others. Tasks woken from another CPU while their worker halts are
+/// picked up on that CPU's next interrupt, at the latest on the next timer tick.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+}
+
+/// State shared by the workers, spawners and wakers of an executor.
+struct Shared {
+    /// Indexed by `cpu_index`.
+    workers: [Worker; MAX_CPUS],
+    /// Tasks added through `Spawner`s.
+    spawned: ArrayQueue<Task>,
+    /// Every live task, for stats and for dropping the tasks at shutdown. Workers only touch it when a task is
+    /// spawned or finishes, never to poll one.
+    tasks: Mutex<BTreeMap<TaskId, Arc<TaskCell>>>,
+    shutdown: CancellationToken,
+    metrics: Metrics,
+}
+
+struct Worker {
+    /// Ready tasks of each class, indexed by `Priority as usize`.
+    queues: [ArrayQueue<Arc<TaskCell>>; Priority::COUNT],
+    running: AtomicBool,
+}
+
+/// Local state of a running worker.
+struct WorkerState {
+    index: usize,
+    /// Tasks polled since the last background task while background tasks were ready.
+    background_skipped: usize,
 }
 
-pub struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// A spawned task, which is also its own waker.
+struct TaskCell {
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    /// Locked by the worker polling the task, `None` once it finished or was dropped at shutdown.
+    task: Mutex<Option<SlabBox<Task>>>,
+    /// Index of the worker whose queues the task is pushed to when woken, the one that polled it last.
+    owner: AtomicUsize,
+    /// Set while the task is in a ready queue, so that it is never queued twice.
+    queued: AtomicBool,
+    counters: TaskCounters,
+    shared: Arc<Shared>,
 }
 
-impl Wake for TaskWaker {
+impl Wake for TaskCell {
     fn wake(self: Arc<Self>) {
-        self.wake_task();
+        self.wake_by_ref();
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+        self.counters.record_wakeup();
+        self.enqueue();
     }
 }
 
-impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker{
-            task_id,
-            task_queue,
-        }))
+impl TaskCell {
+    fn stats(&self) -> TaskStats {
+        let state = if self.task.try_lock().is_none() {
+            TaskState::Running
+        } else if self.queued.load(Ordering::Acquire) {
+            TaskState::Queued
+        } else {
+            TaskState::Waiting
+        };
+        self.counters.stats(self.id, self.name, self.priority, state, self.owner.load(Ordering::Relaxed))
     }
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full.");
+
+    /// Pushes the task to the ready queue of its worker unless it is queued already. May be called from
+    /// interrupt handlers, since it neither blocks nor allocates.
+    fn enqueue(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let worker = &self.shared.workers[self.owner.load(Ordering::Acquire)];
+        if worker.queues[self.priority as usize].push(self.clone()).is_err() {
+            panic!("ready queue of worker {} full", self.owner.load(Ordering::Relaxed));
+        }
     }
 }
 
-impl Executor {
-    pub fn new() -> Self {
-        Executor { 
-            tasks: BTreeMap::new(), 
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+impl Worker {
+    fn new() -> Self {
+        Worker {
+            queues: core::array::from_fn(|_| ArrayQueue::new(READY_QUEUE_SIZE)),
+            running: AtomicBool::new(false),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    fn is_idle(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+}
+
+impl Shared {
+    fn spawn(self: &Arc<Self>, task: Task, worker: usize) {
         let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
+        let name = task.name;
+        let priority = task.priority;
+        let task = SlabBox::new_in(task, &TASK_CACHE)
+            .unwrap_or_else(|_| panic!("out of memory for task {:?}", task_id));
+        let cell = Arc::new(TaskCell {
+            id: task_id,
+            name,
+            priority,
+            task: Mutex::new(Some(task)),
+            owner: AtomicUsize::new(worker),
+            queued: AtomicBool::new(false),
+            counters: TaskCounters::default(),
+            shared: self.clone(),
+        });
+        if self.tasks.lock().insert(task_id, cell.clone()).is_some() {
             panic!("Task with same ID already exists");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        cell.enqueue();
     }
 
-    pub fn run(&mut self) -> ! {
-        use x86_64::instructions::interrupts;
+    fn run_worker(self: &Arc<Self>, index: usize) {
+        let worker = &self.workers[index];
+        assert!(!worker.running.swap(true, Ordering::AcqRel), "a worker already runs on CPU {}", index);
+        let mut state = WorkerState { index, background_skipped: 0 };
         loop {
-            self.run_ready_tasks();
-
-            interrupts::disable();
-            if self.task_queue.is_empty() {
-                interrupts::enable_and_hlt();
-            } else {
-                interrupts::enable();
+            if self.shutdown.is_cancelled() {
+                self.drop_all_tasks();
+                worker.running.store(false, Ordering::Release);
+                return;
             }
+            work::run_pending();
+            self.spawn_queued(index);
+            self.run_ready_tasks(&mut state);
             self.sleep_if_idle();
         }
     }
 
+    fn drop_all_tasks(&self) {
+        while self.spawned.pop().is_ok() {}
+        for worker in &self.workers {
+            for queue in &worker.queues {
+                while queue.pop().is_ok() {}
+            }
+        }
+        // the futures are dropped outside the task list lock, since dropping them may wake other tasks
+        let tasks = core::mem::take(&mut *self.tasks.lock());
+        for cell in tasks.values() {
+            cell.task.lock().take();
+        }
+    }
+
+    /// Halts the CPU until the next interrupt if there is nothing to do, with the periodic timer stopped until
+    /// the next timer deadline where possible.
     fn sleep_if_idle(&self) {
-        if self.task_queue.is_empty() {
-            x86_64::instructions::hlt();
+        interrupts::disable();
+        if !self.is_idle() {
+            interrupts::enable();
+            return;
+        }
+        let tickless = time::tickless::enter_idle();
+        interrupts::enable_and_hlt();
+        if tickless {
+            time::tickless::exit_idle();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+    /// Returns `true` if no worker has a ready task to take and nothing waits to be spawned or run.
+    fn is_idle(&self) -> bool {
+        self.workers.iter().all(Worker::is_idle) && self.spawned.is_empty() && work::is_idle()
+    }
+
+    fn spawn_queued(self: &Arc<Self>, worker: usize) {
+        while let Ok(task) = self.spawned.pop() {
+            self.spawn(task, worker);
+        }
+    }
+
+    /// Pops the next ready task of the worker, stealing one from another worker if it has none.
+    fn next_ready_task(&self, state: &mut WorkerState) -> Option<Arc<TaskCell>> {
+        self.next_local_task(state).or_else(|| self.steal(state.index))
+    }
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
+    /// Pops the next ready task from the highest class of the worker's own queues that has one, unless background
+    /// tasks are starving.
+    fn next_local_task(&self, state: &mut WorkerState) -> Option<Arc<TaskCell>> {
+        let queues = &self.workers[state.index].queues;
+        let background = &queues[Priority::Background as usize];
+        if background.is_empty() {
+            state.background_skipped = 0;
+        } else if state.background_skipped >= STARVATION_LIMIT {
+            state.background_skipped = 0;
+            return background.pop().ok();
+        }
+        let (priority, cell) = queues.iter()
+            .enumerate()
+            .find_map(|(priority, queue)| queue.pop().ok().map(|cell| (priority, cell)))?;
+        if priority == Priority::Background as usize {
+            state.background_skipped = 0;
+        } else if !background.is_empty() {
+            state.background_skipped += 1;
+        }
+        Some(cell)
+    }
+
+    /// Takes the highest class ready task of the first other worker that has one, and makes `thief` its owner.
+    fn steal(&self, thief: usize) -> Option<Arc<TaskCell>> {
+        (1..MAX_CPUS).find_map(|offset| {
+            let victim = &self.workers[(thief + offset) % MAX_CPUS];
+            let cell = victim.queues.iter().find_map(|queue| queue.pop().ok())?;
+            // the task is still marked as queued, so no wakeup can push it to the old owner in between
+            cell.owner.store(thief, Ordering::Release);
+            self.metrics.record_steal();
+            Some(cell)
+        })
+    }
+
+    fn run_ready_tasks(&self, state: &mut WorkerState) {
+        while let Some(cell) = self.next_ready_task(state) {
+            // cleared before polling, so that a wakeup during the poll queues the task again
+            cell.queued.store(false, Ordering::Release);
+            let waker = Waker::from(cell.clone());
+            let mut context = Context::from_waker(&waker);
+            // a worker that stole the task after such a wakeup waits here until the poll is done
+            let mut slot = cell.task.lock();
+            let task = match slot.as_mut() {
                 Some(task) => task,
                 None => continue,
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+            let start = tsc::read();
+            let result = task.poll(&mut context);
+            cell.counters.record_poll(tsc::read().wrapping_sub(start));
+            if result.is_ready() {
+                *slot = None;
+                drop(slot);
+                self.tasks.lock().remove(&cell.id);
+                self.metrics.record_finished(&cell.counters);
             }
         }
     }
-}
\ No newline at end of file
+
+    fn tasks(&self) -> Vec<TaskStats> {
+        self.tasks.lock().values().map(|cell| cell.stats()).collect()
+    }
+
+    fn stats(&self) -> ExecutorStats {
+        let tasks = self.tasks();
+        let queue_depths = core::array::from_fn(|priority| {
+            self.workers.iter().map(|worker| worker.queues[priority].len()).sum()
+        });
+        let workers = self.workers.iter().filter(|worker| worker.running.load(Ordering::Relaxed)).count();
+        self.metrics.snapshot(tasks, queue_depths, self.spawned.len(), workers)
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        TASK_CACHE_RECLAIMER.call_once(|| {
+            allocator::register_reclaimer(|| TASK_CACHE.shrink());
+        });
+        work::init();
+        Executor {
+            shared: Arc::new(Shared {
+                workers: core::array::from_fn(|_| Worker::new()),
+                spawned: ArrayQueue::new(SPAWN_QUEUE_SIZE),
+                tasks: Mutex::new(BTreeMap::new()),
+                shutdown: CancellationToken::new(),
+                metrics: Metrics::default(),
+            }),
+        }
+    }
+
+    /// Adds `task` to the ready queues of the current CPU's worker.
+    pub fn spawn(&mut self, task: Task) {
+        self.shared.spawn(task, cpu_index());
+    }
+
+    /// Spawns `future` as a `Priority::Normal` task named `name`, see `spawn`.
+    pub fn spawn_named(&mut self, name: &'static str, future: impl Future<Output = ()> + Send + 'static) {
+        self.spawn(Task::new(future).with_name(name));
+    }
+
+    /// Returns a handle that spawns tasks on this executor.
+    pub fn spawner(&self) -> Spawner {
+        Spawner { shared: self.shared.clone() }
+    }
+
+    /// Returns a snapshot of the poll counts, poll times and wakeups of every task, and of the queue depths.
+    ///
+    /// Poll times are measured with the TSC, so they are only comparable between tasks on the same machine.
+    pub fn stats(&self) -> ExecutorStats {
+        self.shared.stats()
+    }
+
+    /// Returns the ID, name, state and poll count of every live task, in spawn order, for a task listing like
+    /// `metrics::print_tasks`.
+    pub fn tasks(&self) -> Vec<TaskStats> {
+        self.shared.tasks()
+    }
+
+    /// Polls tasks until shutdown, then halts the CPU.
+    pub fn run(&mut self) -> ! {
+        self.run_until_shutdown();
+        crate::hlt_loop();
+    }
+
+    /// Runs the current CPU's worker until `Spawner::shutdown` is called, then drops every remaining task and
+    /// returns. Other CPUs join in with `run_worker`.
+    ///
+    /// Makes this executor the one `spawner()` returns, if no other executor ran before.
+    pub fn run_until_shutdown(&mut self) {
+        let _ = SPAWNER.try_init_once(|| self.spawner());
+        self.shared.run_worker(cpu_index());
+    }
+}
@@ -0,0 +1,122 @@
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// The result of a joinable task, shared between the task and its `JoinHandle`.
+struct JoinState<T> {
+    result: Mutex<Option<T>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    /// Waker of the task awaiting the handle.
+    waker: AtomicWaker,
+    /// Waker of the joinable task itself, woken by `abort` so that it gets dropped.
+    task_waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, result: Option<T>) {
+        *self.result.lock() = result;
+        self.finished.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was stopped by `JoinHandle::abort` before it returned.
+    Aborted,
+}
+
+/// Resolves to the return value of a task created with `Task::joinable`.
+///
+/// Dropping the handle detaches the task, which keeps running and drops its result.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns `true` once the task has returned or was aborted, even if its result was already taken.
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// Takes the return value if the task has finished, without waiting.
+    pub fn try_take(&self) -> Option<T> {
+        self.state.result.lock().take()
+    }
+
+    /// Stops the task at its next suspension point. It is dropped without running any further, so it should
+    /// not be aborted while it holds state others rely on. Does nothing if it already returned.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        // fast path, avoids registering a waker for finished tasks
+        if let Some(result) = self.try_take() {
+            return Poll::Ready(Ok(result));
+        }
+        self.state.waker.register(cx.waker());
+        match self.try_take() {
+            Some(result) => Poll::Ready(Ok(result)),
+            None if self.is_finished() && self.state.aborted.load(Ordering::Acquire) => {
+                Poll::Ready(Err(JoinError::Aborted))
+            }
+            None if self.is_finished() => panic!("JoinHandle polled after completion"),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The future of a joinable task, which passes the output of `future` to the handle.
+pub(super) struct JoinTask<F: Future> {
+    future: Pin<alloc::boxed::Box<F>>,
+    state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Future for JoinTask<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.state.aborted.load(Ordering::Acquire) {
+            self.state.finish(None);
+            return Poll::Ready(());
+        }
+        self.state.task_waker.register(cx.waker());
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                self.state.finish(Some(result));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `future` so that its output is passed to the returned handle.
+pub(super) fn joinable<F>(future: F) -> (JoinTask<F>, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(JoinState {
+        result: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+        task_waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle { state: state.clone() };
+    (JoinTask { future: alloc::boxed::Box::pin(future), state }, handle)
+}
@@ -1,86 +1,460 @@
+use super::channel::{self, Receiver, Sender};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
     task::{Poll, Context},
 };
-use crate::{print, println};
-use crossbeam_queue::ArrayQueue;
+use crate::print;
 use futures_util::{
     stream::StreamExt,
     Stream,
-    task::AtomicWaker,
 };
 use pc_keyboard::{
-    layouts,
+    DecodeState,
     DecodedKey,
     HandleControl,
-    Keyboard,
+    Modifiers,
+    ScancodeSet as _,
     ScancodeSet1,
+    ScancodeSet2,
 };
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+pub use pc_keyboard::{KeyCode, KeyState};
+
+pub mod hotkey;
+pub mod i8042;
+pub mod layout;
+pub mod repeat;
+
+pub use hotkey::Hotkey;
+pub use repeat::{KeyRepeat, Typematic};
+
+/// Capacity of the scancode queue of `ScancodeStream::new` and `KeyEventStream::new`.
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+
+static SCANCODES: OnceCell<Sender<u8>> = OnceCell::uninit();
+/// Scancodes thrown away because the queue was full, or the stream did not exist yet or was dropped.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// The lock key states as sent with the set LEDs command, with num lock on at boot like most BIOSes leave it.
+static LEDS: AtomicU8 = AtomicU8::new(Leds::NUM_LOCK);
+/// Set once `i8042::init` switched the keyboard to untranslated scancode set 2.
+static SET_2: AtomicBool = AtomicBool::new(false);
+
+/// A scancode set the keyboard can send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// The set the controller translates to by default, assumed until `i8042::init` ran.
+    Set1,
+    Set2,
+}
+
+/// Returns the scancode set key events are decoded from.
+pub fn scancode_set() -> ScancodeSet {
+    if SET_2.load(Ordering::Relaxed) {
+        ScancodeSet::Set2
+    } else {
+        ScancodeSet::Set1
+    }
+}
+
+fn set_scancode_set(set: ScancodeSet) {
+    SET_2.store(set == ScancodeSet::Set2, Ordering::Relaxed);
+}
+
+/// State of the lock keys, which the keyboard LEDs show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    const SCROLL_LOCK: u8 = 1 << 0;
+    const NUM_LOCK: u8 = 1 << 1;
+    const CAPS_LOCK: u8 = 1 << 2;
+
+    fn from_bits(bits: u8) -> Self {
+        Leds {
+            scroll_lock: bits & Self::SCROLL_LOCK != 0,
+            num_lock: bits & Self::NUM_LOCK != 0,
+            caps_lock: bits & Self::CAPS_LOCK != 0,
+        }
+    }
+
+    /// Returns the LEDs in the format of the set LEDs command.
+    fn bits(self) -> u8 {
+        let mut bits = 0;
+        if self.scroll_lock {
+            bits |= Self::SCROLL_LOCK;
+        }
+        if self.num_lock {
+            bits |= Self::NUM_LOCK;
+        }
+        if self.caps_lock {
+            bits |= Self::CAPS_LOCK;
+        }
+        bits
+    }
+}
+
+/// Returns the state of the lock keys.
+pub fn leds() -> Leds {
+    Leds::from_bits(LEDS.load(Ordering::Relaxed))
+}
+
+/// Sets the lock keys to `leds` and lights the keyboard LEDs to match.
+///
+/// The new state applies to the next decoded key, as if the lock keys had been pressed.
+pub fn set_leds(leds: Leds) -> Result<(), i8042::Ps2Error> {
+    LEDS.store(leds.bits(), Ordering::Relaxed);
+    i8042::set_leds(leds.bits())
+}
+
+fn toggle_lock(bit: u8) {
+    LEDS.fetch_xor(bit, Ordering::Relaxed);
+}
+
+/// Reads the scancode from the PS/2 controller. Registered as the handler of the keyboard IRQ.
+pub(crate) fn handle_interrupt() {
+    // a byte the command code in `i8042` already took leaves a spurious interrupt behind
+    if let Some(scancode) = i8042::try_read() {
+        add_scancode(scancode);
+    }
+}
 
 /// Called by the keyboard interrupt handler
 /// 
 /// Must not block or allocate
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(q) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = q.push(scancode) {
-            println!("WARNING: scancode queue full, dropping keyboard input.");
-        } else {
-            WAKER.wake();
-        }
-    } else {
-        println!("WARNING: scancode queue uninitialized.");
+    let queued = match SCANCODES.try_get() {
+        Ok(scancodes) => scancodes.try_send(scancode).is_ok(),
+        Err(_) => false,
+    };
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+/// Returns the number of scancodes dropped since boot, because the queue was full or there was no stream.
+///
+/// Keys pressed before the first stream is created count too, so compare against an earlier value.
+pub fn dropped_scancodes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Echoes typed characters to the screen, and the names of other keys that are pressed.
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(c) => print!("{c}"),
-                    DecodedKey::RawKey(k) => print!("{:?}", k),
-                }
-            }
+    let mut events = KeyEventStream::new();
+    while let Some(event) = events.next().await {
+        if event.state != KeyState::Down || event.is_modifier() {
+            continue;
+        }
+        match event.unicode {
+            Some(c) => print!("{c}"),
+            None => print!("{:?}", event.code),
         }
     }
 }
 
-pub struct ScancodeStream {
-    _private: (),
+/// State of the modifier keys when a key event happened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModifierState {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
 }
 
-impl Stream for ScancodeStream {
-    type Item = u8;
+/// A decoded key press or release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// The character the key produces with the active layout and modifiers, for presses only.
+    pub unicode: Option<char>,
+    /// The modifiers after this event was applied.
+    pub modifiers: ModifierState,
+    /// Set for presses of a key that was already down, sent by the keyboard's typematic or by `KeyRepeat`.
+    pub repeat: bool,
+}
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let q = SCANCODE_QUEUE.try_get().expect("not initialized");
+impl KeyEvent {
+    /// Returns `true` for shift, control, alt and lock keys.
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.code,
+            KeyCode::ShiftLeft
+                | KeyCode::ShiftRight
+                | KeyCode::ControlLeft
+                | KeyCode::ControlRight
+                | KeyCode::AltLeft
+                | KeyCode::AltRight
+                | KeyCode::CapsLock
+                | KeyCode::NumpadLock
+                | KeyCode::ScrollLock
+        )
+    }
+}
+
+/// Turns scancodes into key events, keeping track of the modifier keys.
+struct Decoder {
+    state: DecodeState,
+    modifiers: Modifiers,
+    alt: bool,
+    /// The key pressed last and not yet released, the only one the keyboard repeats.
+    held: Option<KeyCode>,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        let modifiers = Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            numlock: false,
+            capslock: false,
+            alt_gr: false,
+        };
+        Decoder { state: DecodeState::Start, modifiers, alt: false, held: None }
+    }
 
-        if let Ok(scancode) = q.pop() {
-            return Poll::Ready(Some(scancode));
+    /// Feeds one scancode byte, returning an event once a whole key code was read.
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let decoded = match scancode_set() {
+            ScancodeSet::Set1 => ScancodeSet1::advance_state(&mut self.state, scancode),
+            ScancodeSet::Set2 => ScancodeSet2::advance_state(&mut self.state, scancode),
+        };
+        let event = match decoded {
+            Ok(Some(event)) => event,
+            Ok(None) | Err(_) => return None,
+        };
+        let down = event.state == KeyState::Down;
+        let repeat = down && self.held == Some(event.code);
+        if down {
+            self.held = Some(event.code);
+        } else if self.held == Some(event.code) {
+            self.held = None;
         }
+        let modifiers = &mut self.modifiers;
+        match event.code {
+            _ if repeat => {}
+            KeyCode::ShiftLeft => modifiers.lshift = down,
+            KeyCode::ShiftRight => modifiers.rshift = down,
+            KeyCode::ControlLeft => modifiers.lctrl = down,
+            KeyCode::ControlRight => modifiers.rctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => modifiers.alt_gr = down,
+            KeyCode::CapsLock if down => toggle_lock(Leds::CAPS_LOCK),
+            KeyCode::NumpadLock if down => toggle_lock(Leds::NUM_LOCK),
+            KeyCode::ScrollLock if down => toggle_lock(Leds::SCROLL_LOCK),
+            _ => {}
+        }
+        // the lock state is global, so that `set_leds` changes it too
+        let leds = leds();
+        modifiers.capslock = leds.caps_lock;
+        modifiers.numlock = leds.num_lock;
+        let unicode = match layout::current().map_keycode(event.code, &self.modifiers, HandleControl::Ignore) {
+            DecodedKey::Unicode(c) if down => Some(c),
+            _ => None,
+        };
+        Some(KeyEvent { code: event.code, state: event.state, unicode, modifiers: self.modifier_state(), repeat })
+    }
 
-        WAKER.register(&cx.waker());
-        match q.pop() {
-            Ok(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+    fn modifier_state(&self) -> ModifierState {
+        ModifierState {
+            shift: self.modifiers.is_shifted(),
+            ctrl: self.modifiers.is_ctrl(),
+            alt: self.alt,
+            alt_gr: self.modifiers.alt_gr,
+            caps_lock: self.modifiers.capslock,
+            num_lock: self.modifiers.numlock,
+            scroll_lock: leds().scroll_lock,
+        }
+    }
+}
+
+/// Stream of decoded keyboard events, for shells, hotkey handlers and anything else reading input.
+///
+/// Only one may be created, since it takes over the scancode queue fed by the keyboard interrupt.
+///
+/// Lock key presses light the keyboard LEDs to match. Keys that complete a registered `Hotkey` run its handlers
+/// and are not passed on, neither the press nor the release.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    decoder: Decoder,
+    /// The LEDs state last sent to the keyboard.
+    leds: u8,
+    /// The key of the last hotkey pressed, whose release is swallowed too.
+    hotkey: Option<KeyCode>,
+}
+
+impl KeyEventStream {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Creates the stream with room for `capacity` scancodes that were not decoded yet.
+    pub fn with_capacity(capacity: usize) -> Self {
+        KeyEventStream {
+            scancodes: ScancodeStream::with_capacity(capacity),
+            decoder: Decoder::new(),
+            leds: LEDS.load(Ordering::Relaxed),
+            hotkey: None,
+        }
+    }
+
+    /// Wraps the stream so that held keys repeat with the timing of `typematic`, see `KeyRepeat`.
+    pub fn with_repeat(self, typematic: Typematic) -> KeyRepeat<Self> {
+        KeyRepeat::new(self, typematic)
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        loop {
+            let scancode = match Pin::new(&mut self.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = self.decoder.add_byte(scancode) {
+                if hotkey::dispatch(&event) {
+                    self.hotkey = Some(event.code);
+                    continue;
+                }
+                if event.state == KeyState::Up && self.hotkey == Some(event.code) {
+                    self.hotkey = None;
+                    continue;
+                }
+                let leds = LEDS.load(Ordering::Relaxed);
+                // the LEDs only show the state, so a keyboard that missed the command is not worth failing for
+                if leds != self.leds && i8042::set_leds(leds).is_ok() {
+                    self.leds = leds;
+                }
+                return Poll::Ready(Some(event));
             }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
         }
     }
 }
 
+pub struct ScancodeStream {
+    receiver: Receiver<u8>,
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        Self::with_capacity(DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Creates the stream with room for `capacity` scancodes that were not read yet. Scancodes arriving while
+    /// the queue is full are dropped and counted, see `dropped_scancodes`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (sender, receiver) = channel::channel(capacity);
+        SCANCODES.try_init_once(|| sender)
+            .expect("ScancodeStream should only be created once");
+        ScancodeStream { receiver }
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_decoder_tracks_modifiers() {
+    let previous = scancode_set();
+    set_scancode_set(ScancodeSet::Set1);
+    let mut decoder = Decoder::new();
+    // left shift down, A down, A up, left shift up
+    assert!(decoder.add_byte(0x2a).unwrap().modifiers.shift);
+    let press = decoder.add_byte(0x1e).unwrap();
+    assert_eq!((press.code, press.state, press.unicode), (KeyCode::A, KeyState::Down, Some('A')));
+    assert_eq!(decoder.add_byte(0x9e).unwrap().unicode, None);
+    assert!(!decoder.add_byte(0xaa).unwrap().modifiers.shift);
+    assert_eq!(decoder.add_byte(0x1e).unwrap().unicode, Some('a'));
+    set_scancode_set(previous);
+}
+
+#[test_case]
+fn test_layout_switch_applies_to_next_key() {
+    use layout::Layout;
+
+    let previous = scancode_set();
+    set_scancode_set(ScancodeSet::Set1);
+    let mut decoder = Decoder::new();
+    layout::set(Layout::De105);
+    // the Y key of a US keyboard is Z on a German one
+    assert_eq!(decoder.add_byte(0x15).unwrap().unicode, Some('z'));
+    layout::set(Layout::Us104);
+    assert_eq!(decoder.add_byte(0x15).unwrap().unicode, Some('y'));
+    set_scancode_set(previous);
+}
+
+#[test_case]
+fn test_decoder_reads_set_2() {
+    let previous = scancode_set();
+    set_scancode_set(ScancodeSet::Set2);
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.add_byte(0x1c).unwrap().unicode, Some('a'));
+    // release codes are prefixed with 0xf0
+    assert_eq!(decoder.add_byte(0xf0), None);
+    assert_eq!(decoder.add_byte(0x1c).unwrap().state, KeyState::Up);
+    set_scancode_set(previous);
+}
+
+#[test_case]
+fn test_decoder_marks_repeats() {
+    let previous = scancode_set();
+    set_scancode_set(ScancodeSet::Set1);
+    let mut decoder = Decoder::new();
+    assert!(!decoder.add_byte(0x1e).unwrap().repeat);
+    assert!(decoder.add_byte(0x1e).unwrap().repeat);
+    assert_eq!(decoder.add_byte(0x9e).unwrap().state, KeyState::Up);
+    assert!(!decoder.add_byte(0x1e).unwrap().repeat);
+    set_scancode_set(previous);
+}
+
+#[test_case]
+fn test_lock_keys_toggle_leds() {
+    let previous = (scancode_set(), LEDS.load(Ordering::Relaxed));
+    set_scancode_set(ScancodeSet::Set1);
+    LEDS.store(0, Ordering::Relaxed);
+    let mut decoder = Decoder::new();
+    // caps lock down and up, then A
+    assert!(decoder.add_byte(0x3a).unwrap().modifiers.caps_lock);
+    decoder.add_byte(0xba);
+    assert_eq!(decoder.add_byte(0x1e).unwrap().unicode, Some('A'));
+    assert_eq!(leds(), Leds { caps_lock: true, ..Leds::default() });
+    // scroll lock
+    assert!(decoder.add_byte(0x46).unwrap().modifiers.scroll_lock);
+    assert_eq!(LEDS.load(Ordering::Relaxed), Leds::CAPS_LOCK | Leds::SCROLL_LOCK);
+    set_scancode_set(previous.0);
+    LEDS.store(previous.1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_dropped_scancodes_are_counted() {
+    // the library tests create no stream, so every scancode is dropped
+    let before = dropped_scancodes();
+    add_scancode(0x1e);
+    add_scancode(0x9e);
+    assert_eq!(dropped_scancodes(), before + 2);
+}
@@ -0,0 +1,106 @@
+use super::{KeyCode, KeyEvent, KeyState};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+static HOTKEYS: Mutex<Vec<(HotkeyId, Hotkey, Handler)>> = Mutex::new(Vec::new());
+
+/// A key combination: a key pressed while exactly the given modifiers are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    pub code: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+impl Hotkey {
+    /// The key pressed without modifiers.
+    pub const fn new(code: KeyCode) -> Self {
+        Hotkey { code, ctrl: false, alt: false, shift: false }
+    }
+
+    pub const fn with_ctrl(self) -> Self {
+        Hotkey { ctrl: true, ..self }
+    }
+
+    pub const fn with_alt(self) -> Self {
+        Hotkey { alt: true, ..self }
+    }
+
+    pub const fn with_shift(self) -> Self {
+        Hotkey { shift: true, ..self }
+    }
+
+    fn matches(&self, event: &KeyEvent) -> bool {
+        let modifiers = &event.modifiers;
+        event.code == self.code
+            && modifiers.ctrl == self.ctrl
+            && modifiers.alt == self.alt
+            && modifiers.shift == self.shift
+    }
+}
+
+/// Identifies a registered hotkey, see `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HotkeyId(u64);
+
+impl HotkeyId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        HotkeyId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Returned by `register` for a hotkey that already has a handler, the one with this id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HotkeyTaken(pub HotkeyId);
+
+/// Calls `handler` whenever `hotkey` is pressed, instead of passing the key to readers of `KeyEventStream`.
+///
+/// Handlers run in the task reading the `KeyEventStream`, so key events are only decoded while one is polled,
+/// and a slow handler holds up input. Each hotkey has at most one handler, so that two subsystems can't both
+/// claim a key combination without noticing.
+pub fn register(hotkey: Hotkey, handler: impl Fn() + Send + Sync + 'static) -> Result<HotkeyId, HotkeyTaken> {
+    let handler: Handler = Arc::new(handler);
+    let mut hotkeys = HOTKEYS.lock();
+    if let Some((taken, _, _)) = hotkeys.iter().find(|(_, registered, _)| *registered == hotkey) {
+        return Err(HotkeyTaken(*taken));
+    }
+    let id = HotkeyId::new();
+    hotkeys.push((id, hotkey, handler));
+    Ok(id)
+}
+
+/// Removes the hotkey handler `id`, returning `false` if it was not registered.
+pub fn unregister(id: HotkeyId) -> bool {
+    let mut hotkeys = HOTKEYS.lock();
+    let len = hotkeys.len();
+    hotkeys.retain(|(registered, _, _)| *registered != id);
+    hotkeys.len() < len
+}
+
+/// Runs the handler of the hotkey `event` completes, returning `true` if there is one.
+///
+/// Only presses trigger hotkeys, repeats included, so that a held hotkey keeps firing like any held key.
+/// `KeyEventStream` calls this for every event it decodes.
+pub fn dispatch(event: &KeyEvent) -> bool {
+    if event.state != KeyState::Down {
+        return false;
+    }
+    // handlers may register or unregister hotkeys themselves, so they run with the lock released
+    let handler = HOTKEYS
+        .lock()
+        .iter()
+        .find(|(_, hotkey, _)| hotkey.matches(event))
+        .map(|(_, _, handler)| handler.clone());
+    match handler {
+        Some(handler) => {
+            handler();
+            true
+        }
+        None => false,
+    }
+}
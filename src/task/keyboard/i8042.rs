@@ -0,0 +1,375 @@
+use super::{ScancodeSet, Typematic};
+use crate::time;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+
+const DATA: u16 = 0x60;
+/// Status register when read, command register when written.
+const STATUS_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Set along with `STATUS_OUTPUT_FULL` if the byte came from the second port.
+const STATUS_AUX_OUTPUT: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT_2: u8 = 0xa7;
+const CMD_ENABLE_PORT_2: u8 = 0xa8;
+const CMD_TEST_PORT_2: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_PORT_1: u8 = 0xab;
+const CMD_DISABLE_PORT_1: u8 = 0xad;
+const CMD_ENABLE_PORT_1: u8 = 0xae;
+const CMD_WRITE_PORT_2: u8 = 0xd4;
+const CMD_PULSE_RESET: u8 = 0xfe;
+
+const CONFIG_PORT_1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT_2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT_2_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_SET_LEDS: u8 = 0xed;
+const DEVICE_SET_TYPEMATIC: u8 = 0xf3;
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_SET_SCANCODE_SET: u8 = 0xf0;
+const DEVICE_GET_ID: u8 = 0xf2;
+/// Shares its value with `DEVICE_SET_TYPEMATIC`, which mice don't have.
+const DEVICE_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEVICE_SET_DEFAULTS: u8 = 0xf6;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+/// ID of a mouse that sends 4 byte packets with a wheel movement.
+const MOUSE_ID_WHEEL: u8 = 0x03;
+
+/// Status polls before a controller or device is considered unresponsive, 100 ms with `POLL_INTERVAL_US`.
+const TIMEOUT_POLLS: u32 = 10_000;
+/// Status polls before a device is considered unresponsive after a reset, 500 ms, since its self-test takes a
+/// few hundred milliseconds on real keyboards.
+const RESET_TIMEOUT_POLLS: u32 = 50_000;
+const POLL_INTERVAL_US: u32 = 10;
+/// Times a device command is sent again after the device asked for a resend.
+const RESEND_ATTEMPTS: u32 = 3;
+
+/// Set by `poll_key_press` after the first byte of a scancode set 2 release.
+static RELEASE_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or the keyboard did not answer in time, or there is no controller.
+    Timeout,
+    /// The controller self-test answered with this byte instead of `0x55`.
+    SelfTest(u8),
+    /// The test of the first port answered with this error code.
+    PortTest(u8),
+    /// The keyboard answered a command with this byte instead of an acknowledgement.
+    NoAck(u8),
+    /// The self-test of the keyboard or the mouse after its reset answered with this byte instead of `0xaa`.
+    DeviceReset(u8),
+}
+
+/// What `init` found and configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Info {
+    /// The controller has a second port, usually for a mouse.
+    pub dual_channel: bool,
+    /// The scancode set the keyboard sends, which `KeyEventStream` decodes from now on.
+    pub scancode_set: ScancodeSet,
+    /// A mouse answered on the second port and sends packets on IRQ 12 now.
+    pub mouse: bool,
+}
+
+/// One of the two devices behind the controller.
+#[derive(Clone, Copy)]
+enum Device {
+    Keyboard,
+    Mouse,
+}
+
+/// Resets and configures the PS/2 controller and the keyboard on its first port, instead of relying on
+/// whatever the BIOS left behind.
+///
+/// Runs the controller and port self-tests, resets the keyboard, and switches it to scancode set 2 with
+/// translation turned off. Keyboards that refuse set 2 stay on translated set 1. Must be called before the
+/// keyboard interrupt is enabled, since the interrupt handler would take the answers.
+///
+/// If anything fails, the first port is turned back on with translation and its interrupt, so that a keyboard
+/// the BIOS set up keeps working, on scancode set 1.
+pub fn init() -> Result<Ps2Info, Ps2Error> {
+    interrupts::without_interrupts(|| {
+        configure().map_err(|err| {
+            recover();
+            err
+        })
+    })
+}
+
+fn configure() -> Result<Ps2Info, Ps2Error> {
+    command(CMD_DISABLE_PORT_1)?;
+    command(CMD_DISABLE_PORT_2)?;
+    flush_output();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_PORT_1_IRQ | CONFIG_PORT_2_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    command(CMD_SELF_TEST)?;
+    match read()? {
+        SELF_TEST_PASSED => {}
+        answer => return Err(Ps2Error::SelfTest(answer)),
+    }
+    // some controllers reset their configuration during the self-test
+    write_config(config)?;
+
+    let dual_channel = config & CONFIG_PORT_2_CLOCK_DISABLED != 0 && {
+        command(CMD_ENABLE_PORT_2)?;
+        let enabled = read_config()? & CONFIG_PORT_2_CLOCK_DISABLED == 0;
+        command(CMD_DISABLE_PORT_2)?;
+        enabled
+    };
+
+    command(CMD_TEST_PORT_1)?;
+    match read()? {
+        PORT_TEST_PASSED => {}
+        answer => return Err(Ps2Error::PortTest(answer)),
+    }
+    // a failing second port only costs the mouse
+    let port_2 = dual_channel && {
+        command(CMD_TEST_PORT_2)?;
+        read()? == PORT_TEST_PASSED
+    };
+
+    command(CMD_ENABLE_PORT_1)?;
+    if port_2 {
+        command(CMD_ENABLE_PORT_2)?;
+    }
+    reset_keyboard()?;
+    let scancode_set = if device_command(Device::Keyboard, &[DEVICE_SET_SCANCODE_SET, 2]).is_ok() {
+        ScancodeSet::Set2
+    } else {
+        config |= CONFIG_TRANSLATION;
+        ScancodeSet::Set1
+    };
+    // the reset turned the LEDs off
+    device_command(Device::Keyboard, &[DEVICE_SET_LEDS, super::leds().bits()])?;
+    device_command(Device::Keyboard, &[DEVICE_ENABLE_SCANNING])?;
+    // a missing or broken mouse only costs the mouse too
+    let mouse = port_2 && init_mouse().is_ok();
+
+    config |= CONFIG_PORT_1_IRQ;
+    if mouse {
+        config |= CONFIG_PORT_2_IRQ;
+    }
+    write_config(config)?;
+    super::set_scancode_set(scancode_set);
+    Ok(Ps2Info { dual_channel, scancode_set, mouse })
+}
+
+/// Puts the controller back into the state the BIOS usually leaves, as far as it still answers: the first port
+/// on, translating to scancode set 1 and raising its interrupt, and the second port off.
+fn recover() {
+    let _ = command(CMD_DISABLE_PORT_2);
+    let _ = command(CMD_ENABLE_PORT_1);
+    if let Ok(config) = read_config() {
+        let _ = write_config((config | CONFIG_PORT_1_IRQ | CONFIG_TRANSLATION) & !CONFIG_PORT_2_IRQ);
+    }
+    // a reset keyboard doesn't send until it is told to
+    let _ = device_command(Device::Keyboard, &[DEVICE_ENABLE_SCANNING]);
+    flush_output();
+    super::set_scancode_set(ScancodeSet::Set1);
+}
+
+/// Pulses the reset line of the CPU, which is wired to the controller, and returns only if that didn't work.
+///
+/// `power::reboot` tries this after the ACPI reset register.
+pub fn pulse_reset() -> Result<(), Ps2Error> {
+    command(CMD_PULSE_RESET)
+}
+
+/// Returns `true` once a key is pressed, reading what the keyboard sent by polling the controller.
+///
+/// For code that runs with interrupts off, like the panic screen, and must be called in a loop. Releases,
+/// in scancode set 1 and 2, are skipped.
+pub fn poll_key_press() -> bool {
+    match try_read() {
+        // set 2 sends a release as 0xf0 followed by the key's make code
+        Some(0xf0) => RELEASE_PENDING.store(true, Ordering::Relaxed),
+        Some(0xe0 | 0xe1) => {}
+        Some(code) => return !RELEASE_PENDING.swap(false, Ordering::Relaxed) && code & 0x80 == 0,
+        None => {}
+    }
+    false
+}
+
+/// Lights the keyboard LEDs, `leds` holding scroll lock in bit 0, num lock in bit 1 and caps lock in bit 2.
+pub(super) fn set_leds(leds: u8) -> Result<(), Ps2Error> {
+    interrupts::without_interrupts(|| device_command(Device::Keyboard, &[DEVICE_SET_LEDS, leds]))
+}
+
+/// Sets the delay and rate at which the keyboard repeats a held key, rounded to what it supports.
+///
+/// The keyboard supports delays of 250 to 1000 ms in steps of 250 ms and periods of 33 to 500 ms. Keys
+/// pressed while the command is sent may be lost or make it fail with `NoAck`.
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    interrupts::without_interrupts(|| {
+        device_command(Device::Keyboard, &[DEVICE_SET_TYPEMATIC, typematic_byte(typematic)])
+    })
+}
+
+/// Encodes `typematic` for the set typematic command: the delay in bits 5 and 6, the period in bits 0 to 4.
+fn typematic_byte(typematic: Typematic) -> u8 {
+    let delay = ((typematic.delay.as_millis() + 125) / 250).clamp(1, 4) as u8 - 1;
+    // the period is (8 + low three bits) * 2 ^ (upper two bits) * 4.17 ms
+    let target_us = typematic.period.as_micros();
+    let rate = (0..32u8)
+        .min_by_key(|rate| {
+            let period_us = ((8 + u128::from(rate & 7)) << (rate >> 3)) * 4167;
+            period_us.abs_diff(target_us)
+        })
+        .unwrap();
+    delay << 5 | rate
+}
+
+fn reset_keyboard() -> Result<(), Ps2Error> {
+    device_command(Device::Keyboard, &[DEVICE_RESET])?;
+    match read_within(RESET_TIMEOUT_POLLS)? {
+        DEVICE_SELF_TEST_PASSED => Ok(()),
+        answer => Err(Ps2Error::DeviceReset(answer)),
+    }
+}
+
+/// Resets the mouse on the second port, turns on its wheel if it has one, and makes it report movements.
+fn init_mouse() -> Result<(), Ps2Error> {
+    device_command(Device::Mouse, &[DEVICE_RESET])?;
+    match read_within(RESET_TIMEOUT_POLLS)? {
+        DEVICE_SELF_TEST_PASSED => {}
+        answer => return Err(Ps2Error::DeviceReset(answer)),
+    }
+    // the device ID of a plain mouse
+    read()?;
+    device_command(Device::Mouse, &[DEVICE_SET_DEFAULTS])?;
+    // this sequence of sample rates is the knock that makes IntelliMouse compatible mice report the wheel
+    for rate in [200, 100, 80] {
+        device_command(Device::Mouse, &[DEVICE_SET_SAMPLE_RATE, rate])?;
+    }
+    device_command(Device::Mouse, &[DEVICE_GET_ID])?;
+    crate::task::mouse::set_wheel(read()? == MOUSE_ID_WHEEL);
+    device_command(Device::Mouse, &[DEVICE_ENABLE_SCANNING])
+}
+
+/// Sends `bytes` to `device`, each of which it has to acknowledge, resending on request.
+fn device_command(device: Device, bytes: &[u8]) -> Result<(), Ps2Error> {
+    for &byte in bytes {
+        let mut attempts = 0;
+        loop {
+            if let Device::Mouse = device {
+                command(CMD_WRITE_PORT_2)?;
+            }
+            write_data(byte)?;
+            match read()? {
+                DEVICE_ACK => break,
+                DEVICE_RESEND if attempts < RESEND_ATTEMPTS => attempts += 1,
+                answer => return Err(Ps2Error::NoAck(answer)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    command(CMD_READ_CONFIG)?;
+    read()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+fn command(command: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    unsafe { Port::new(STATUS_COMMAND).write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    unsafe { Port::new(DATA).write(byte) };
+    Ok(())
+}
+
+/// Reads the next byte from the controller, waiting until there is one.
+fn read() -> Result<u8, Ps2Error> {
+    read_within(TIMEOUT_POLLS)
+}
+
+/// Like `read`, waiting for `polls` status polls at most.
+fn read_within(polls: u32) -> Result<u8, Ps2Error> {
+    for _ in 0..polls {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(read_data());
+        }
+        time::delay_us(POLL_INTERVAL_US);
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Reads the data port if the controller has a byte from the keyboard, for its interrupt handler.
+pub(super) fn try_read() -> Option<u8> {
+    match status() & (STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT) {
+        STATUS_OUTPUT_FULL => Some(read_data()),
+        _ => None,
+    }
+}
+
+/// Reads the data port if the controller has a byte from the mouse, for its interrupt handler.
+pub(crate) fn try_read_aux() -> Option<u8> {
+    match status() & (STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT) {
+        flags if flags == STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT => Some(read_data()),
+        _ => None,
+    }
+}
+
+fn read_data() -> u8 {
+    unsafe { Port::new(DATA).read() }
+}
+
+/// Throws away bytes left in the output buffer, like keys pressed during boot.
+fn flush_output() {
+    for _ in 0..TIMEOUT_POLLS {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        read_data();
+    }
+}
+
+fn wait_for_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_POLLS {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        time::delay_us(POLL_INTERVAL_US);
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn status() -> u8 {
+    unsafe { Port::new(STATUS_COMMAND).read() }
+}
+
+#[test_case]
+fn test_typematic_byte() {
+    use core::time::Duration;
+
+    assert_eq!(typematic_byte(Typematic::default()), 0x2b);
+    let fastest = Typematic { delay: Duration::from_millis(250), period: Duration::from_millis(1) };
+    assert_eq!(typematic_byte(fastest), 0x00);
+    let slowest = Typematic { delay: Duration::from_secs(2), period: Duration::from_secs(1) };
+    assert_eq!(typematic_byte(slowest), 0x7f);
+}
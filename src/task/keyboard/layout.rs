@@ -0,0 +1,116 @@
+use crate::{cmdline, warn};
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+/// Index of the active layout in `Layout::ALL`.
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+/// A keyboard layout the decoder can map key codes with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    /// German QWERTZ.
+    De105,
+    /// French AZERTY.
+    Fr105,
+    Dvorak104,
+}
+
+impl Layout {
+    /// Every layout, the default first.
+    pub const ALL: [Layout; 5] = [Layout::Us104, Layout::Uk105, Layout::De105, Layout::Fr105, Layout::Dvorak104];
+
+    /// Returns the short name used on the command line, like `de`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Fr105 => "fr",
+            Layout::Dvorak104 => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Self::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    pub(super) fn map_keycode(self, code: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match self {
+            Layout::Us104 => layouts::Us104Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Uk105 => layouts::Uk105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::De105 => De105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Fr105 => layouts::Azerty::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Dvorak104 => layouts::Dvorak104Key::map_keycode(code, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// Returns the layout key events are decoded with.
+pub fn current() -> Layout {
+    Layout::ALL[usize::from(ACTIVE.load(Ordering::Relaxed))]
+}
+
+/// Switches the layout. Takes effect with the next scancode, queued scancodes are kept.
+pub fn set(layout: Layout) {
+    let index = Layout::ALL.iter().position(|candidate| *candidate == layout).unwrap();
+    ACTIVE.store(index as u8, Ordering::Relaxed);
+}
+
+/// Selects the layout named by the `keyboard.layout` command line option, if there is one.
+pub fn init_from_cmdline() {
+    let name = match cmdline::option("keyboard.layout") {
+        Some(name) => name,
+        None => return,
+    };
+    match Layout::from_name(name) {
+        Some(layout) => set(layout),
+        None => warn!("unknown keyboard layout {:?}, keeping {}", name, current().name()),
+    }
+}
+
+/// A German 105-key QWERTZ keyboard. Keys it has in common with the US layout are mapped by `Us104Key`.
+struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(code: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        // (plain, shifted, with AltGr)
+        let chars = match code {
+            KeyCode::Y => return layouts::Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => return layouts::Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            KeyCode::BracketSquareLeft => return umlaut(modifiers, 'ü', 'Ü'),
+            KeyCode::SemiColon => return umlaut(modifiers, 'ö', 'Ö'),
+            KeyCode::Quote => return umlaut(modifiers, 'ä', 'Ä'),
+            KeyCode::BackTick => ('^', '°', None),
+            KeyCode::Key2 => ('2', '"', Some('²')),
+            KeyCode::Key3 => ('3', '§', Some('³')),
+            KeyCode::Key6 => ('6', '&', None),
+            KeyCode::Key7 => ('7', '/', Some('{')),
+            KeyCode::Key8 => ('8', '(', Some('[')),
+            KeyCode::Key9 => ('9', ')', Some(']')),
+            KeyCode::Key0 => ('0', '=', Some('}')),
+            KeyCode::Minus => ('ß', '?', Some('\\')),
+            KeyCode::Equals => ('´', '`', None),
+            KeyCode::BracketSquareRight => ('+', '*', Some('~')),
+            KeyCode::BackSlash | KeyCode::HashTilde => ('#', '\'', None),
+            KeyCode::Comma => (',', ';', None),
+            KeyCode::Fullstop => ('.', ':', None),
+            KeyCode::Slash => ('-', '_', None),
+            KeyCode::Q if modifiers.alt_gr => return DecodedKey::Unicode('@'),
+            KeyCode::E if modifiers.alt_gr => return DecodedKey::Unicode('€'),
+            code => return layouts::Us104Key::map_keycode(code, modifiers, handle_ctrl),
+        };
+        let c = match chars {
+            (_, _, Some(alt_gr)) if modifiers.alt_gr => alt_gr,
+            (_, shifted, _) if modifiers.is_shifted() => shifted,
+            (plain, _, _) => plain,
+        };
+        DecodedKey::Unicode(c)
+    }
+}
+
+/// Umlauts are letters, so caps lock applies to them.
+fn umlaut(modifiers: &Modifiers, lower: char, upper: char) -> DecodedKey {
+    DecodedKey::Unicode(if modifiers.is_caps() { upper } else { lower })
+}
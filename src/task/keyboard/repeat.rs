@@ -0,0 +1,86 @@
+use super::{KeyEvent, KeyState};
+use crate::task::timer::{self, Sleep};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+
+/// When a held key starts repeating and how fast it repeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// Time between the press and the first repeat.
+    pub delay: Duration,
+    /// Time between two repeats.
+    pub period: Duration,
+}
+
+impl Default for Typematic {
+    /// The keyboard's own defaults after a reset: 500 ms delay and 10.9 repeats per second.
+    fn default() -> Self {
+        Typematic { delay: Duration::from_millis(500), period: Duration::from_micros(91_700) }
+    }
+}
+
+/// Key events with repeats of the held key synthesized in software, see `KeyEventStream::with_repeat`.
+///
+/// Repeats sent by the keyboard itself are dropped, so that the timing only depends on `Typematic` and not
+/// on what the hardware was configured to. Only the last pressed key repeats, like on real keyboards, and
+/// modifiers never do. Synthesized events are copies of the press with `repeat` set.
+pub struct KeyRepeat<S> {
+    events: S,
+    typematic: Typematic,
+    held: Option<KeyEvent>,
+    next: Option<Sleep>,
+}
+
+impl<S: Stream<Item = KeyEvent> + Unpin> KeyRepeat<S> {
+    pub fn new(events: S, typematic: Typematic) -> Self {
+        KeyRepeat { events, typematic, held: None, next: None }
+    }
+
+    fn track(&mut self, event: &KeyEvent) {
+        match event.state {
+            KeyState::Down if !event.is_modifier() => {
+                self.held = Some(KeyEvent { repeat: true, ..*event });
+                self.next = Some(timer::sleep(self.typematic.delay));
+            }
+            KeyState::Up if self.held.map(|held| held.code) == Some(event.code) => {
+                self.held = None;
+                self.next = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<S: Stream<Item = KeyEvent> + Unpin> Stream for KeyRepeat<S> {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        loop {
+            match Pin::new(&mut self.events).poll_next(cx) {
+                Poll::Ready(Some(event)) if event.repeat => continue,
+                Poll::Ready(Some(event)) => {
+                    self.track(&event);
+                    return Poll::Ready(Some(event));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+        let this = &mut *self;
+        match (&mut this.next, this.held) {
+            (Some(next), Some(held)) => match Pin::new(next).poll(cx) {
+                Poll::Ready(()) => {
+                    this.next = Some(timer::sleep(this.typematic.period));
+                    Poll::Ready(Some(held))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            _ => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::{executor, timer, Priority, TaskId};
+use crate::{serial_println, time::{tsc, NANOS_PER_SECOND}};
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Counters of one task, updated by its waker too, so that wakeups from interrupt handlers are counted.
+#[derive(Default)]
+pub(super) struct TaskCounters {
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+    wakeups: AtomicU64,
+}
+
+impl TaskCounters {
+    pub(super) fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_wakeup(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(
+        &self,
+        task_id: TaskId,
+        name: Option<&'static str>,
+        priority: Priority,
+        state: TaskState,
+        worker: usize,
+    ) -> TaskStats {
+        TaskStats {
+            id: task_id.0,
+            name,
+            priority,
+            state,
+            worker,
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, other: &TaskCounters) {
+        self.polls.fetch_add(other.polls.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.poll_cycles.fetch_add(other.poll_cycles.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.wakeups.fetch_add(other.wakeups.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Executor wide counters that outlive single tasks.
+#[derive(Default)]
+pub(super) struct Metrics {
+    /// Totals of the tasks that already finished.
+    finished: TaskCounters,
+    finished_tasks: AtomicU64,
+    steals: AtomicU64,
+}
+
+impl Metrics {
+    /// Adds the counters of a finished task to the totals.
+    pub(super) fn record_finished(&self, counters: &TaskCounters) {
+        self.finished.add(counters);
+        self.finished_tasks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_steal(&self) {
+        self.steals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(
+        &self,
+        tasks: Vec<TaskStats>,
+        queue_depths: [usize; Priority::COUNT],
+        spawn_queue_depth: usize,
+        workers: usize,
+    ) -> ExecutorStats {
+        ExecutorStats {
+            tasks,
+            queue_depths,
+            spawn_queue_depth,
+            workers,
+            steals: self.steals.load(Ordering::Relaxed),
+            finished_tasks: self.finished_tasks.load(Ordering::Relaxed),
+            finished_polls: self.finished.polls.load(Ordering::Relaxed),
+            finished_poll_cycles: self.finished.poll_cycles.load(Ordering::Relaxed),
+            finished_wakeups: self.finished.wakeups.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What a live task is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// In a ready queue, waiting to be polled.
+    Queued,
+    /// Being polled by a worker.
+    Running,
+    /// Waiting to be woken.
+    Waiting,
+}
+
+/// The counters and state of one live task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    /// The ID `current_task_id` returns while the task is polled.
+    pub id: u64,
+    /// The name given with `Task::with_name`, if any.
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    /// The worker whose queues the task is pushed to when woken.
+    pub worker: usize,
+    pub polls: u64,
+    /// Time spent in the task's `poll`, in TSC cycles.
+    pub poll_cycles: u64,
+    /// Number of times the task was woken, by itself or by others.
+    pub wakeups: u64,
+}
+
+impl TaskStats {
+    /// Returns the time spent polling the task, if the TSC is calibrated.
+    pub fn poll_time(&self) -> Option<Duration> {
+        cycles_to_duration(self.poll_cycles)
+    }
+}
+
+impl fmt::Display for TaskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:<10} {:>6} {:>10} {:>10} {:>16}",
+            self.id,
+            alloc::format!("{:?}", self.priority),
+            self.worker,
+            self.polls,
+            self.wakeups,
+            self.poll_cycles,
+        )?;
+        if let Some(time) = self.poll_time() {
+            write!(f, " {:>10} us", time.as_micros())?;
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the metrics of an executor, see `Executor::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// Live tasks, in spawn order.
+    pub tasks: Vec<TaskStats>,
+    /// Number of ready tasks of each class on all workers, indexed by `Priority as usize`.
+    pub queue_depths: [usize; Priority::COUNT],
+    /// Number of tasks spawned through `Spawner`s that no worker has picked up yet.
+    pub spawn_queue_depth: usize,
+    /// Number of workers running, one per CPU.
+    pub workers: usize,
+    /// Number of tasks a worker took from the queues of another.
+    pub steals: u64,
+    pub finished_tasks: u64,
+    /// Polls, poll cycles and wakeups of the finished tasks together.
+    pub finished_polls: u64,
+    pub finished_poll_cycles: u64,
+    pub finished_wakeups: u64,
+}
+
+impl ExecutorStats {
+    /// Returns the number of polls of every task so far, including the finished ones.
+    pub fn total_polls(&self) -> u64 {
+        self.finished_polls + self.tasks.iter().map(|task| task.polls).sum::<u64>()
+    }
+
+    /// Returns the TSC cycles spent polling tasks so far, including the finished ones.
+    pub fn total_poll_cycles(&self) -> u64 {
+        self.finished_poll_cycles + self.tasks.iter().map(|task| task.poll_cycles).sum::<u64>()
+    }
+
+    /// Returns the number of wakeups of every task so far, including the finished ones.
+    pub fn total_wakeups(&self) -> u64 {
+        self.finished_wakeups + self.tasks.iter().map(|task| task.wakeups).sum::<u64>()
+    }
+
+    /// Returns the live task that spent the most time being polled.
+    pub fn busiest_task(&self) -> Option<&TaskStats> {
+        self.tasks.iter().max_by_key(|task| task.poll_cycles)
+    }
+}
+
+/// Prints `stats` to serial, the busiest tasks first.
+pub fn print_stats(stats: &ExecutorStats) {
+    serial_println!(
+        "executor: {} workers, {} tasks, {} finished, ready {:?}, spawn queue {}, {} steals, {} polls, {} wakeups, \
+         {} cycles",
+        stats.workers,
+        stats.tasks.len(),
+        stats.finished_tasks,
+        stats.queue_depths,
+        stats.spawn_queue_depth,
+        stats.steals,
+        stats.total_polls(),
+        stats.total_wakeups(),
+        stats.total_poll_cycles(),
+    );
+    serial_println!(
+        "{:>6} {:<10} {:>6} {:>10} {:>10} {:>16}",
+        "task", "priority", "worker", "polls", "wakeups", "cycles",
+    );
+    let mut tasks = stats.tasks.clone();
+    tasks.sort_unstable_by_key(|task| core::cmp::Reverse(task.poll_cycles));
+    for task in tasks {
+        serial_println!("{}", task);
+    }
+}
+
+/// Prints a list of `tasks` to serial, one line with ID, name, state, priority, worker and poll count per task.
+pub fn print_tasks(tasks: &[TaskStats]) {
+    serial_println!("{:>6} {:<16} {:<8} {:<10} {:>6} {:>10}", "task", "name", "state", "priority", "worker", "polls");
+    for task in tasks {
+        serial_println!(
+            "{:>6} {:<16} {:<8} {:<10} {:>6} {:>10}",
+            task.id,
+            task.name.unwrap_or("-"),
+            alloc::format!("{:?}", task.state),
+            alloc::format!("{:?}", task.priority),
+            task.worker,
+            task.polls,
+        );
+    }
+}
+
+/// Prints the stats of the running executor to serial every `period`, for spawning as a background task.
+///
+/// Returns right away if no executor is running.
+pub async fn dump_periodically(period: Duration) {
+    let spawner = match executor::spawner() {
+        Ok(spawner) => spawner,
+        Err(_) => return,
+    };
+    let mut interval = timer::interval(period);
+    loop {
+        interval.tick().await;
+        print_stats(&spawner.stats());
+    }
+}
+
+fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    let hz = tsc::tsc()?.hz();
+    Some(Duration::from_nanos((u128::from(cycles) * u128::from(NANOS_PER_SECOND) / u128::from(hz)) as u64))
+}
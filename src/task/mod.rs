@@ -1,17 +1,97 @@
 use core::{
     future::Future, 
     pin::Pin,
-    task::{Context, Poll}, sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll}, sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
 };
 use alloc::boxed::Box;
+use crate::{
+    interrupts::stats::{cpu_index, MAX_CPUS},
+    memory::{address_space, AddressSpace},
+};
 
+pub mod block_on;
+pub mod cancel;
+pub mod channel;
 pub mod simple_executor;
+pub mod sync;
 pub mod keyboard;
 pub mod executor;
+pub mod join;
+pub mod metrics;
+pub mod mouse;
+pub mod thread;
+pub mod timer;
+pub mod work;
+
+pub use block_on::block_on;
+pub use timer::{timeout, Elapsed};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
+/// ID of the task each CPU is polling, `u64::MAX` while it polls none.
+static CURRENT_TASK: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU64 = AtomicU64::new(u64::MAX);
+    [NONE; MAX_CPUS]
+};
+
+/// Name of the task each CPU is polling, as pointer and length. The pointer is null while the task has none.
+///
+/// Only the CPU itself writes and reads its entry, and the pointer is set last, so interrupt handlers see
+/// either no name or a whole one.
+static CURRENT_NAME: [(AtomicPtr<u8>, AtomicUsize); MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: (AtomicPtr<u8>, AtomicUsize) = (AtomicPtr::new(core::ptr::null_mut()), AtomicUsize::new(0));
+    [NONE; MAX_CPUS]
+};
+
+/// Returns the ID of the task the current CPU is polling, for diagnostics.
+pub fn current_task_id() -> Option<u64> {
+    match CURRENT_TASK[cpu_index()].load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(id),
+    }
+}
+
+/// Returns the name of the task the current CPU is polling, if it was given one with `Task::with_name`.
+pub fn current_task_name() -> Option<&'static str> {
+    let (ptr, len) = &CURRENT_NAME[cpu_index()];
+    let ptr = ptr.load(Ordering::Acquire);
+    if ptr.is_null() {
+        return None;
+    }
+    // the pointer and length were taken from a `&'static str`
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len.load(Ordering::Relaxed)) };
+    Some(unsafe { core::str::from_utf8_unchecked(bytes) })
+}
+
+/// Lets the executor poll the other ready tasks before the current one continues.
+///
+/// CPU-bound tasks should await this now and then, since the executor can't preempt them.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        // puts the task at the back of its ready queue
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -19,20 +99,102 @@ impl TaskId {
     }
 }
 
+/// Scheduling class of a task. The executor polls ready tasks of a higher class first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Follow-up work of interrupts, like handling keyboard input or received packets.
+    Interrupt,
+    Normal,
+    /// Bulk work that may wait. Still polled now and then while higher classes are busy.
+    Background,
+}
+
+impl Priority {
+    /// Number of classes, highest first when indexed by `as usize`.
+    pub const COUNT: usize = 3;
+}
+
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    name: Option<&'static str>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Address space the task is polled in, the kernel page table if `None`.
+    address_space: Option<AddressSpace>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(), 
+            priority: Priority::Normal,
+            name: None,
             future: Box::pin(future),
+            address_space: None,
         }
     }
 
+    /// Creates a task and a handle that resolves to the return value of `future`.
+    pub fn joinable<F>(future: F) -> (Task, join::JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        (Task::new(future), handle)
+    }
+
+    /// Creates a task that runs with `address_space` active whenever it is polled.
+    pub fn with_address_space(future: impl Future<Output = ()> + Send + 'static, address_space: AddressSpace) -> Task {
+        Task {
+            address_space: Some(address_space),
+            ..Task::new(future)
+        }
+    }
+
+    /// Sets the scheduling class, `Priority::Normal` by default.
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Names the task in task listings, see `metrics::print_tasks`.
+    pub fn with_name(mut self, name: &'static str) -> Task {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let cpu = cpu_index();
+        let (name_ptr, name_len) = &CURRENT_NAME[cpu];
+        CURRENT_TASK[cpu].store(self.id.0, Ordering::Relaxed);
+        if let Some(name) = self.name {
+            name_len.store(name.len(), Ordering::Relaxed);
+            name_ptr.store(name.as_ptr() as *mut u8, Ordering::Release);
+        }
+        let result = self.poll_in_address_space(context);
+        name_ptr.store(core::ptr::null_mut(), Ordering::Relaxed);
+        CURRENT_TASK[cpu].store(u64::MAX, Ordering::Relaxed);
+        result
+    }
+
+    fn poll_in_address_space(&mut self, context: &mut Context) -> Poll<()> {
+        match &self.address_space {
+            Some(address_space) => {
+                address_space.activate();
+                let result = self.future.as_mut().poll(context);
+                address_space::activate_kernel();
+                result
+            }
+            None => self.future.as_mut().poll(context),
+        }
     }
 }
\ No newline at end of file
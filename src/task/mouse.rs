@@ -0,0 +1,185 @@
+use super::channel::{self, Receiver, Sender, TrySendError};
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::Stream;
+
+static PACKET_BYTES: OnceCell<Sender<u8>> = OnceCell::uninit();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Set by `i8042::init` if the mouse sends 4 byte packets with a wheel movement.
+static WHEEL: AtomicBool = AtomicBool::new(false);
+
+/// Bits of the first byte of a packet.
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set, which is how the start of a packet is found again after a byte was lost.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+pub(crate) fn set_wheel(wheel: bool) {
+    WHEEL.store(wheel, Ordering::Relaxed);
+}
+
+/// Returns `true` if the mouse has a wheel, so that `MouseEvent::wheel` can be non-zero.
+pub fn has_wheel() -> bool {
+    WHEEL.load(Ordering::Relaxed)
+}
+
+/// Reads a packet byte from the PS/2 controller. Registered as the handler of the mouse IRQ.
+pub(crate) fn handle_interrupt() {
+    if let Some(byte) = crate::task::keyboard::i8042::try_read_aux() {
+        add_packet_byte(byte);
+    }
+}
+
+/// Called by the mouse interrupt handler
+///
+/// Must not block or allocate
+fn add_packet_byte(byte: u8) {
+    // without a reader the bytes are dropped without counting them, since nobody might ever want them
+    if let Ok(bytes) = PACKET_BYTES.try_get() {
+        match bytes.try_send(byte) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_) | TrySendError::Closed(_)) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Returns the number of packet bytes dropped since boot, because the `MouseStream` queue was full or the stream gone.
+pub fn dropped_packet_bytes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// State of the mouse buttons.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// A movement or button change of the mouse, decoded from one packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right since the last event, in mouse units.
+    pub dx: i16,
+    /// Movement downwards since the last event, in mouse units. The mouse itself counts upwards, this is
+    /// flipped to match screen coordinates.
+    pub dy: i16,
+    /// Wheel movement towards the user, always 0 without `has_wheel`.
+    pub wheel: i8,
+    /// The buttons held after this event.
+    pub buttons: MouseButtons,
+}
+
+/// Assembles packets from the bytes the mouse sends.
+struct Decoder {
+    packet: [u8; 4],
+    len: usize,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Decoder { packet: [0; 4], len: 0 }
+    }
+
+    /// Feeds one packet byte, returning an event once a whole packet was read.
+    fn add_byte(&mut self, byte: u8, wheel: bool) -> Option<MouseEvent> {
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < if wheel { 4 } else { 3 } {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y, z] = self.packet;
+        // the movement is a 9 bit two's complement number, and meaningless once it overflowed
+        let movement = |value: u8, sign: u8, overflow: u8| match flags & overflow {
+            0 if flags & sign != 0 => i16::from(value) - 256,
+            0 => i16::from(value),
+            _ => 0,
+        };
+        Some(MouseEvent {
+            dx: movement(x, X_SIGN, X_OVERFLOW),
+            dy: -movement(y, Y_SIGN, Y_OVERFLOW),
+            // only the low nibble holds the movement, sign extended from bit 3
+            wheel: if wheel { ((z << 4) as i8) >> 4 } else { 0 },
+            buttons: MouseButtons {
+                left: flags & LEFT != 0,
+                right: flags & RIGHT != 0,
+                middle: flags & MIDDLE != 0,
+            },
+        })
+    }
+}
+
+/// Stream of decoded mouse events, for future graphical interfaces.
+///
+/// Only one may be created, since it takes over the packet queue fed by the mouse interrupt. Stays empty if
+/// `i8042::init` found no mouse.
+pub struct MouseStream {
+    receiver: Receiver<u8>,
+    decoder: Decoder,
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel::channel(128);
+        PACKET_BYTES.try_init_once(|| sender)
+            .expect("MouseStream::new should only be called once");
+        MouseStream { receiver, decoder: Decoder::new() }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MouseEvent>> {
+        loop {
+            let byte = match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(byte)) => byte,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = self.decoder.add_byte(byte, has_wheel()) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_decoder_reads_packets() {
+    let mut decoder = Decoder::new();
+    // a stray byte without the always one bit is skipped
+    assert_eq!(decoder.add_byte(0x00, false), None);
+    // left button, 5 to the right, 3 down
+    assert_eq!(decoder.add_byte(ALWAYS_ONE | LEFT | Y_SIGN, false), None);
+    assert_eq!(decoder.add_byte(5, false), None);
+    let event = decoder.add_byte(0xfd, false).unwrap();
+    assert_eq!((event.dx, event.dy, event.buttons.left), (5, 3, true));
+    // wheel one step away from the user, overflowing x
+    decoder.add_byte(ALWAYS_ONE | X_OVERFLOW, true);
+    decoder.add_byte(0xff, true);
+    decoder.add_byte(0, true);
+    let event = decoder.add_byte(0x0f, true).unwrap();
+    assert_eq!((event.dx, event.wheel, event.buttons), (0, -1, MouseButtons::default()));
+}
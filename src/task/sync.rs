@@ -0,0 +1,295 @@
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Tasks waiting for a lock.
+///
+/// Unlocking wakes all of them and they race for the lock again. Waking just one would hang the rest if
+/// that one was dropped before it was polled again.
+pub(super) struct WaitQueue {
+    wakers: spin::Mutex<Vec<Waker>>,
+}
+
+impl WaitQueue {
+    pub(super) const fn new() -> Self {
+        WaitQueue { wakers: spin::Mutex::new(Vec::new()) }
+    }
+
+    pub(super) fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    pub(super) fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// A mutex for tasks. Waiting for it suspends the task instead of spinning, so the executor keeps running
+/// other tasks, including the one holding the lock.
+///
+/// Must not be used from interrupt handlers or kernel threads that don't run an executor.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct MutexLock<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { locked: AtomicBool::new(false), waiters: WaitQueue::new(), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Resolves to a guard once the lock is free.
+    pub fn lock(&self) -> MutexLock<'_, T> {
+        MutexLock { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking since the borrow is exclusive.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized> Future for MutexLock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+        self.mutex.waiters.register(cx.waker());
+        // the holder may have unlocked before the waker was registered
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_all();
+    }
+}
+
+/// `RwLock::state` while a writer holds the lock. Other values count the readers.
+const WRITE_LOCKED: usize = usize::MAX;
+
+/// A reader-writer lock for tasks, see `Mutex`.
+///
+/// Readers can keep a writer waiting as long as new readers keep arriving.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+/// Future returned by `RwLock::read`.
+pub struct RwLockRead<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+/// Future returned by `RwLock::write`.
+pub struct RwLockWrite<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock { state: AtomicUsize::new(0), waiters: WaitQueue::new(), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Resolves to a shared guard once no writer holds the lock.
+    pub fn read(&self) -> RwLockRead<'_, T> {
+        RwLockRead { lock: self }
+    }
+
+    /// Resolves to an exclusive guard once nobody holds the lock.
+    pub fn write(&self) -> RwLockWrite<'_, T> {
+        RwLockWrite { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| match readers {
+                WRITE_LOCKED => None,
+                readers => readers.checked_add(1).filter(|readers| *readers != WRITE_LOCKED),
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized> Future for RwLockRead<'a, T> {
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockReadGuard<'a, T>> {
+        if let Some(guard) = self.lock.try_read() {
+            return Poll::Ready(guard);
+        }
+        self.lock.waiters.register(cx.waker());
+        match self.lock.try_read() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Future for RwLockWrite<'a, T> {
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockWriteGuard<'a, T>> {
+        if let Some(guard) = self.lock.try_write() {
+            return Poll::Ready(guard);
+        }
+        self.lock.waiters.register(cx.waker());
+        match self.lock.try_write() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // only the last reader can let a writer in
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn test_try_lock() {
+    let mutex = Mutex::new(1);
+    let mut guard = mutex.try_lock().unwrap();
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+}
+
+#[test_case]
+fn test_rwlock_readers_exclude_writer() {
+    let lock = RwLock::new(0);
+    let first = lock.try_read().unwrap();
+    let second = lock.try_read().unwrap();
+    assert!(lock.try_write().is_none());
+    drop(first);
+    assert!(lock.try_write().is_none());
+    drop(second);
+    let mut writer = lock.try_write().unwrap();
+    *writer = 1;
+    assert!(lock.try_read().is_none());
+    drop(writer);
+    assert_eq!(*lock.try_read().unwrap(), 1);
+}
@@ -0,0 +1,310 @@
+use super::work::Work;
+use crate::{
+    memory::{stack::Stack, vmm::VmmError},
+    sync::IrqSpinLock,
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    mem,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Stack size of spawned threads. The pages are mapped on first touch, so most threads use far less.
+pub const THREAD_STACK_SIZE: u64 = 64 * 1024;
+/// Number of timer ticks a thread runs before the next ready thread gets the CPU.
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+static SCHEDULER: IrqSpinLock<Option<Scheduler>> = IrqSpinLock::new(None);
+/// Ticks left in the time slice of the running thread.
+static SLICE_LEFT: AtomicU32 = AtomicU32::new(TIME_SLICE_TICKS);
+/// Frees the stacks of exited threads, which can't happen in the timer interrupt since it allocates and locks.
+static REAP: Work = Work::new(reap);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Owned permission to wait for a thread to exit and take its return value.
+///
+/// Dropping the handle detaches the thread, which keeps running.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+/// Where a thread leaves its return value for the `JoinHandle`.
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    finished: AtomicBool,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns `true` once the thread's function returned.
+    pub fn is_finished(&self) -> bool {
+        self.packet.finished.load(Ordering::Acquire)
+    }
+
+    /// Waits for the thread to exit and returns what its function returned.
+    ///
+    /// Yields the CPU while waiting, so it must not be called from the thread itself or from a future.
+    pub fn join(self) -> T {
+        assert_ne!(current_id(), Some(self.id), "thread joined itself");
+        while !self.is_finished() {
+            yield_now();
+        }
+        self.packet.result.lock().take().expect("thread result taken twice")
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    /// Stack pointer saved by `switch_context` while the thread is not running.
+    rsp: u64,
+    /// Owned only to be freed with the thread. `None` for the boot thread, which runs on the stack it was on.
+    _stack: Option<Stack>,
+    exited: bool,
+}
+
+/// Round-robin scheduler of the kernel threads on the boot CPU.
+struct Scheduler {
+    current: Box<Thread>,
+    ready: VecDeque<Box<Thread>>,
+    /// Exited threads waiting for `reap`, boxed like the others since `switch_context` still stores their
+    /// stack pointer after they were moved here.
+    #[allow(clippy::vec_box)]
+    exited: Vec<Box<Thread>>,
+}
+
+impl Scheduler {
+    fn thread_count(&self) -> usize {
+        1 + self.ready.len() + self.exited.len()
+    }
+}
+
+/// Turns the running code into the boot thread and starts preempting threads on the timer interrupt.
+///
+/// The async executor keeps running on the boot thread, so it shares the CPU with spawned threads and one
+/// misbehaving thread can't starve it. Further calls do nothing.
+pub fn init() {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.is_none() {
+        let boot = Box::new(Thread { id: ThreadId::new(), name: "main", rsp: 0, _stack: None, exited: false });
+        *scheduler = Some(Scheduler { current: boot, ready: VecDeque::new(), exited: Vec::new() });
+    }
+}
+
+/// Starts a kernel thread named `name` that runs `f` on its own guard-paged stack, and returns a handle to
+/// join it.
+///
+/// The thread runs with interrupts enabled and is preempted every `TIME_SLICE_TICKS` timer ticks. It exits
+/// when `f` returns. Panics if `init` was not called.
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, VmmError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack = Stack::new(THREAD_STACK_SIZE, name)?;
+    let packet = Arc::new(Packet { result: Mutex::new(None), finished: AtomicBool::new(false) });
+    let thread_packet = packet.clone();
+    let run = move || {
+        let result = f();
+        *thread_packet.result.lock() = Some(result);
+        thread_packet.finished.store(true, Ordering::Release);
+    };
+    let entry: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(run));
+    let rsp = unsafe { initial_frame(&stack, Box::into_raw(entry) as u64) };
+    let thread = Box::new(Thread { id: ThreadId::new(), name, rsp, _stack: Some(stack), exited: false });
+    let id = thread.id;
+
+    // the scheduler runs in the timer interrupt and must never allocate, so there has to be room for every
+    // thread before one is added
+    loop {
+        let count = {
+            let mut guard = SCHEDULER.lock();
+            let scheduler = guard.as_mut().expect("thread::init not called");
+            let count = scheduler.thread_count() + 1;
+            if scheduler.ready.capacity() >= count && scheduler.exited.capacity() >= count {
+                scheduler.ready.push_back(thread);
+                return Ok(JoinHandle { id, packet });
+            }
+            count
+        };
+        // the lock is taken by the timer interrupt as well, so the room is allocated and freed outside it
+        let mut ready = VecDeque::with_capacity(count * 2);
+        let mut exited = Vec::with_capacity(count * 2);
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("thread::init not called");
+        // other threads may have been spawned in the meantime, then this goes around again
+        if scheduler.thread_count() < ready.capacity().min(exited.capacity()) {
+            ready.extend(scheduler.ready.drain(..));
+            exited.append(&mut scheduler.exited);
+            mem::swap(&mut scheduler.ready, &mut ready);
+            mem::swap(&mut scheduler.exited, &mut exited);
+        }
+        drop(guard);
+    }
+}
+
+/// Gives the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Returns the ID of the running thread, `None` before `init`.
+pub fn current_id() -> Option<ThreadId> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current.id)
+}
+
+/// Returns the name of the running thread, `None` before `init`.
+pub fn current_name() -> Option<&'static str> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current.name)
+}
+
+/// Returns the number of threads that have not been reaped yet, including the boot thread.
+pub fn count() -> usize {
+    SCHEDULER.lock().as_ref().map_or(0, Scheduler::thread_count)
+}
+
+/// Returns `true` if a thread other than the running one is ready, and so needs the timer to get the CPU.
+pub(crate) fn has_ready_threads() -> bool {
+    SCHEDULER.lock().as_ref().map_or(false, |scheduler| !scheduler.ready.is_empty())
+}
+
+/// Called by the timer interrupt after its end of interrupt, switches threads when the time slice is used up.
+///
+/// The interrupted thread keeps its interrupt frame on its stack and returns from the interrupt once it is
+/// switched back to.
+pub(crate) fn preempt() {
+    if SLICE_LEFT.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return;
+    }
+    SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+    schedule();
+}
+
+/// Switches to the next ready thread. Must be called with interrupts disabled.
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = match guard.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        let mut old = mem::replace(&mut scheduler.current, next);
+        let old_rsp: *mut u64 = &mut old.rsp;
+        // moving the box leaves the thread, and with it `old_rsp`, in place; neither push allocates, see `spawn`
+        if old.exited {
+            scheduler.exited.push(old);
+            let _ = REAP.schedule();
+        } else {
+            scheduler.ready.push_back(old);
+        }
+        (old_rsp, scheduler.current.rsp)
+    };
+    SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+fn reap() {
+    // the scheduler keeps room for every thread in `exited`, so it is swapped for a vector as large, allocated
+    // outside the lock
+    let mut exited = loop {
+        let capacity = match SCHEDULER.lock().as_ref() {
+            Some(scheduler) => scheduler.exited.capacity(),
+            None => return,
+        };
+        let mut spare = Vec::with_capacity(capacity);
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler gone");
+        // `spawn` may have made room for more threads in the meantime
+        if spare.capacity() >= scheduler.exited.capacity() {
+            mem::swap(&mut scheduler.exited, &mut spare);
+            break spare;
+        }
+    };
+    // frees the stacks with interrupts enabled, outside the scheduler lock
+    exited.clear();
+}
+
+/// Runs the entry closure of a new thread, reached from `thread_trampoline` with interrupts disabled.
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    interrupts::enable();
+    entry();
+    interrupts::disable();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current.exited = true;
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Prepares the stack of a new thread so that `switch_context` to it enters `thread_trampoline`,
+/// which passes `entry` on to `thread_start`. Returns the stack pointer to switch to.
+///
+/// # Safety
+///
+/// The stack must be unused.
+unsafe fn initial_frame(stack: &Stack, entry: u64) -> u64 {
+    // popped in this order by `switch_context`: rflags with interrupts disabled, r15, r14, r13, r12 holding
+    // the entry argument, rbx, rbp ending backtraces, and the return address
+    let frame = [0x2, 0, 0, 0, entry, 0, 0, thread_trampoline as usize as u64];
+    let top = stack.top().as_u64();
+    let rsp = top - mem::size_of_val(&frame) as u64;
+    (rsp as *mut [u64; 8]).write(frame);
+    rsp
+}
+
+extern "C" {
+    /// Saves the callee-saved registers and flags on the current stack, stores the stack pointer to `old_rsp`,
+    /// and restores the registers of the thread whose stack pointer is `new_rsp`.
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym thread_start,
+);
@@ -0,0 +1,197 @@
+use super::work::Work;
+use crate::{
+    sync::IrqSpinLock,
+    time::{self, Instant},
+};
+use alloc::boxed::Box;
+use lazy_static::lazy_static;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+pub mod wheel;
+
+use wheel::{Timer, TimerId, TimerWheel};
+
+/// Length of the ticks of the timer wheel. Deadlines are rounded up to the next tick.
+const TICK_NS: u64 = 1_000_000;
+
+lazy_static! {
+    /// Sleeping tasks. The timer event of `time::after` is armed for the next expiration of the wheel.
+    static ref TIMERS: IrqSpinLock<Timers> = IrqSpinLock::new(Timers {
+        wheel: TimerWheel::new(Instant::now().as_nanos() / TICK_NS),
+        armed: None,
+    });
+}
+/// Wakes expired sleepers in task context, since dropping wakers may free memory.
+static WAKE_EXPIRED: Work = Work::new(wake_expired);
+
+struct Timers {
+    wheel: TimerWheel<Waker>,
+    /// The instant the timer event is armed for, if any.
+    armed: Option<Instant>,
+}
+
+impl Timers {
+    /// Arms the timer event for the next expiration of the wheel unless it already fires before that.
+    fn arm(&mut self) {
+        let earliest = match self.wheel.next_expiration() {
+            Some(tick) => Instant::from_nanos(tick.saturating_mul(TICK_NS)),
+            None => return,
+        };
+        if self.armed.map_or(true, |armed| earliest < armed) {
+            self.armed = Some(earliest);
+            time::after(earliest.duration_since(Instant::now()).as_nanos() as u64, on_timer_event);
+        }
+    }
+}
+
+/// Resolves once the deadline has passed, see `sleep`.
+pub struct Sleep {
+    deadline: Instant,
+    registered: bool,
+    /// The timer in the wheel, taken out again if the sleep is dropped first.
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // the executor keeps a single waker per task, so registering once is enough
+        if !self.registered {
+            // the deadline saturates at the end of time for endless sleeps, so rounding up must not overflow
+            let nanos = self.deadline.as_nanos();
+            let tick = nanos / TICK_NS + u64::from(nanos % TICK_NS != 0);
+            // allocated before taking the lock, which the timer interrupt's path takes as well
+            let timer = Timer::new(tick, cx.waker().clone());
+            let inserted = {
+                let mut timers = TIMERS.lock();
+                let inserted = timers.wheel.insert(timer);
+                if inserted.is_ok() {
+                    timers.arm();
+                }
+                inserted
+            };
+            match inserted {
+                Ok(id) => self.timer = Some(id),
+                // the tick passed in the meantime, the timer is freed outside the lock
+                Err(timer) => timer.into_value().wake(),
+            }
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    /// Cancels the timer, which would otherwise keep the waker and with it the task until the deadline.
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            let cancelled = TIMERS.lock().wheel.cancel(id);
+            // freed outside the lock, like expired timers
+            drop(cancelled);
+        }
+    }
+}
+
+/// Waits for `duration` without blocking the executor.
+///
+/// Sleepers are kept in a timer wheel with millisecond ticks, so the wait is rounded up to the next millisecond.
+/// They are woken by the timer event of `time::after`, so other users of that event delay it. Without the HPET
+/// it is late by up to one more timer tick.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline` has passed.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, registered: false, timer: None }
+}
+
+/// Returns the number of sleeps waiting in the timer wheel.
+pub fn pending_sleeps() -> usize {
+    TIMERS.lock().wheel.len()
+}
+
+/// Error of `timeout`, returned if the deadline passed before the future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` for at most `duration`, so that waits for hardware can't hang forever.
+///
+/// Resolves to `Err(Elapsed)` after dropping `future` mid-way if it did not complete in time. A future that is
+/// ready when the deadline passes still counts as completed.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future: Box::pin(future), sleep: sleep(duration) }
+}
+
+/// Future returned by `timeout`.
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut self.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Resolves every `period`, see `interval`.
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    /// Waits for the next tick. Ticks that were missed because the task was busy are skipped.
+    pub fn tick(&mut self) -> Sleep {
+        let missed = (Instant::now() - self.next).as_nanos() / self.period.as_nanos();
+        // at most the time since `next`, so it fits
+        let due = self.next + Duration::from_nanos((missed * self.period.as_nanos()) as u64);
+        self.next = due + self.period;
+        sleep_until(due)
+    }
+}
+
+/// Creates an interval whose first tick is one `period` from now. Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+    Interval { next: Instant::now() + period, period }
+}
+
+fn on_timer_event() {
+    let _ = WAKE_EXPIRED.schedule();
+}
+
+fn wake_expired() {
+    let expired = {
+        let mut timers = TIMERS.lock();
+        let expired = timers.wheel.advance(Instant::now().as_nanos() / TICK_NS);
+        timers.armed = None;
+        timers.arm();
+        expired
+    };
+    // woken and freed outside the lock, since waking may run arbitrary waker code
+    for waker in expired {
+        waker.wake();
+    }
+}
@@ -0,0 +1,290 @@
+//! A hierarchical timer wheel, which inserts and expires timers in constant time however many there are.
+//!
+//! Timers are kept by the tick they expire at. The lowest level has a slot for each of the next 64 ticks, and
+//! every level above a slot for each of the next 64 spans of the level below. A timer goes into the lowest level
+//! whose span reaches its tick, and moves down a level whenever time reaches its slot, until it expires from the
+//! lowest one. Timers more than `LEVELS` levels out wait in an overflow list until they fit.
+//!
+//! Slots are lists linked through the timers, which are allocated by `Timer::new`. Inserting, moving and expiring
+//! timers only relinks them, so the wheel can be changed under a lock that must not allocate or free memory.
+
+use alloc::boxed::Box;
+
+/// Bits of a tick that select the slot on each level.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+/// Enough for 2^36 ticks, over two years of milliseconds.
+const LEVELS: usize = 6;
+
+/// A timer carrying a `T`, expiring at a tick, in any unit the owner of the wheel chooses.
+pub struct Timer<T> {
+    node: Box<Node<T>>,
+}
+
+struct Node<T> {
+    when: u64,
+    /// Assigned by `TimerWheel::insert`, unique within the wheel.
+    id: u64,
+    value: T,
+    next: List<T>,
+}
+
+impl<T> Timer<T> {
+    /// Allocates a timer expiring at tick `when`, to be inserted into a wheel.
+    pub fn new(when: u64, value: T) -> Timer<T> {
+        Timer { node: Box::new(Node { when, id: 0, value, next: List::new() }) }
+    }
+
+    pub fn when(&self) -> u64 {
+        self.node.when
+    }
+
+    /// Frees the timer and returns its value.
+    pub fn into_value(self) -> T {
+        let node = *self.node;
+        node.value
+    }
+}
+
+/// Names a timer inserted into a wheel, to cancel it with `TimerWheel::cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    when: u64,
+    id: u64,
+}
+
+/// Timers linked through their nodes, newest first.
+struct List<T> {
+    head: Option<Box<Node<T>>>,
+}
+
+impl<T> List<T> {
+    const fn new() -> List<T> {
+        List { head: None }
+    }
+
+    fn push(&mut self, mut timer: Timer<T>) {
+        timer.node.next.head = self.head.take();
+        self.head = Some(timer.node);
+    }
+
+    fn pop(&mut self) -> Option<Timer<T>> {
+        let mut node = self.head.take()?;
+        self.head = node.next.head.take();
+        Some(Timer { node })
+    }
+
+    /// Unlinks the timer with `id`, if it is in the list.
+    fn remove(&mut self, id: u64) -> Option<Timer<T>> {
+        let mut link = &mut self.head;
+        while link.as_ref().map_or(false, |node| node.id != id) {
+            link = &mut link.as_mut().unwrap().next.head;
+        }
+        let mut node = link.take()?;
+        *link = node.next.head.take();
+        Some(Timer { node })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    fn take(&mut self) -> List<T> {
+        List { head: self.head.take() }
+    }
+}
+
+impl<T> Drop for List<T> {
+    /// Frees the nodes one after the other, where dropping the head would recurse down the whole list.
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// The timers one `TimerWheel::advance` expired, in the order they expired.
+///
+/// Freed as they are taken, so the owner of the wheel can drop its lock before that.
+pub struct Expired<T> {
+    list: List<T>,
+}
+
+impl<T> Iterator for Expired<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.list.pop().map(Timer::into_value)
+    }
+}
+
+/// Timers carrying a `T` each, by the tick they expire at.
+pub struct TimerWheel<T> {
+    /// Every timer up to this tick has expired.
+    elapsed: u64,
+    levels: [Level<T>; LEVELS],
+    overflow: List<T>,
+    len: usize,
+    next_id: u64,
+}
+
+struct Level<T> {
+    slots: [List<T>; SLOTS],
+    /// Bit `n` is set if slot `n` has timers.
+    occupied: u64,
+}
+
+impl<T> TimerWheel<T> {
+    /// Creates an empty wheel whose time starts at tick `now`.
+    pub fn new(now: u64) -> Self {
+        TimerWheel {
+            elapsed: now,
+            levels: core::array::from_fn(|_| Level {
+                slots: core::array::from_fn(|_| List::new()),
+                occupied: 0,
+            }),
+            overflow: List::new(),
+            len: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Returns the number of timers that haven't expired.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the tick up to which every timer has expired, the last one `advance` went to.
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    /// Adds `timer`, or gives it back if its tick has already passed.
+    pub fn insert(&mut self, mut timer: Timer<T>) -> Result<TimerId, Timer<T>> {
+        if timer.when() <= self.elapsed {
+            return Err(timer);
+        }
+        timer.node.id = self.next_id;
+        self.next_id += 1;
+        let id = TimerId { when: timer.when(), id: timer.node.id };
+        self.place(timer);
+        self.len += 1;
+        Ok(id)
+    }
+
+    /// Takes the timer `id` out of the wheel, or returns `None` if it has expired already.
+    ///
+    /// Only searches the slot the timer is in, which its tick gives away like it did to `place`.
+    pub fn cancel(&mut self, id: TimerId) -> Option<Timer<T>> {
+        if id.when <= self.elapsed {
+            return None;
+        }
+        let timer = match self.location(id.when) {
+            Some((level, slot)) => {
+                let level = &mut self.levels[level];
+                let timer = level.slots[slot].remove(id.id)?;
+                if level.slots[slot].is_empty() {
+                    level.occupied &= !(1 << slot);
+                }
+                timer
+            }
+            None => self.overflow.remove(id.id)?,
+        };
+        self.len -= 1;
+        Some(timer)
+    }
+
+    /// Returns the next tick `advance` has work at: a timer expiring, or some moving down a level.
+    ///
+    /// Arming a timer interrupt for it and calling `advance` from there keeps every timer on time.
+    pub fn next_expiration(&self) -> Option<u64> {
+        let next = (0..LEVELS).find_map(|level| self.next_in_level(level));
+        // the overflow list is rechecked once the highest level has gone around
+        let overflow = || self.level_start(LEVELS - 1) + (span(LEVELS - 1) << SLOT_BITS);
+        next.or_else(|| (!self.overflow.is_empty()).then(overflow))
+    }
+
+    /// Moves time on to tick `now` and returns every timer up to it, in the order they expire.
+    ///
+    /// Timers of the same tick expire in no particular order.
+    pub fn advance(&mut self, now: u64) -> Expired<T> {
+        // newest first while collecting, so the oldest ends up first once it is turned around
+        let mut expired = List::new();
+        while let Some(tick) = self.next_expiration().filter(|&tick| tick <= now) {
+            self.elapsed = tick;
+            let level = (0..LEVELS).find(|&level| self.next_in_level(level) == Some(tick));
+            let mut timers = match level {
+                Some(level) => {
+                    let slot = slot_for(tick, level);
+                    self.levels[level].occupied &= !(1 << slot);
+                    self.levels[level].slots[slot].take()
+                }
+                None => self.overflow.take(),
+            };
+            while let Some(timer) = timers.pop() {
+                if timer.when() <= tick {
+                    self.len -= 1;
+                    expired.push(timer);
+                } else {
+                    self.place(timer);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+        let mut list = List::new();
+        while let Some(timer) = expired.pop() {
+            list.push(timer);
+        }
+        Expired { list }
+    }
+
+    /// Puts a timer into the slot for its tick, relative to `elapsed`.
+    fn place(&mut self, timer: Timer<T>) {
+        match self.location(timer.when()) {
+            Some((level, slot)) => {
+                self.levels[level].slots[slot].push(timer);
+                self.levels[level].occupied |= 1 << slot;
+            }
+            None => self.overflow.push(timer),
+        }
+    }
+
+    /// Returns the level and slot for tick `when`, relative to `elapsed`, or `None` for the overflow list.
+    ///
+    /// A timer stays where this puts it until `advance` reaches its slot, since `elapsed` keeps the bits above
+    /// the level in common with `when` until then.
+    fn location(&self, when: u64) -> Option<(usize, usize)> {
+        // the highest bit in which the tick differs from the current one picks the level
+        let differing = (self.elapsed ^ when) | (SLOTS as u64 - 1);
+        let level = ((63 - differing.leading_zeros()) / SLOT_BITS) as usize;
+        (level < LEVELS).then(|| (level, slot_for(when, level)))
+    }
+
+    /// Returns the first tick of the earliest slot of `level` that has timers.
+    fn next_in_level(&self, level: usize) -> Option<u64> {
+        let occupied = self.levels[level].occupied;
+        if occupied == 0 {
+            return None;
+        }
+        let current = slot_for(self.elapsed, level);
+        // slots behind the current one are empty, timers there would be on a higher level
+        let distance = occupied.rotate_right(current as u32).trailing_zeros() as u64;
+        Some(self.level_start(level) + (current as u64 + distance) * span(level))
+    }
+
+    /// Returns the first tick of the turn of `level` that `elapsed` is in.
+    fn level_start(&self, level: usize) -> u64 {
+        self.elapsed & !((span(level) << SLOT_BITS) - 1)
+    }
+}
+
+/// Returns the number of ticks a slot of `level` spans.
+fn span(level: usize) -> u64 {
+    1 << (SLOT_BITS as usize * level)
+}
+
+fn slot_for(tick: u64, level: usize) -> usize {
+    ((tick >> (SLOT_BITS as usize * level)) & (SLOTS as u64 - 1)) as usize
+}
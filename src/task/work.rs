@@ -0,0 +1,75 @@
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crossbeam_queue::ArrayQueue;
+
+/// Number of work items that can be pending at once.
+const QUEUE_SIZE: usize = 64;
+
+static WORK_QUEUE: OnceCell<ArrayQueue<&'static Work>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkError {
+    /// `init` has not been called yet.
+    NotInitialized,
+    /// `QUEUE_SIZE` other items are pending.
+    QueueFull,
+}
+
+/// A function to run later in task context, scheduled from an interrupt handler.
+///
+/// Work items are statics, so scheduling one never allocates. Scheduling an item that is already pending
+/// does nothing, so several interrupts before the queue is drained cause a single run.
+pub struct Work {
+    func: fn(),
+    pending: AtomicBool,
+}
+
+impl Work {
+    pub const fn new(func: fn()) -> Work {
+        Work { func, pending: AtomicBool::new(false) }
+    }
+
+    /// Queues the item to be run by `run_pending`. May be called from interrupt handlers.
+    pub fn schedule(&'static self) -> Result<(), WorkError> {
+        let queue = WORK_QUEUE.try_get().map_err(|_| WorkError::NotInitialized)?;
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        queue.push(self).map_err(|_| {
+            self.pending.store(false, Ordering::Release);
+            WorkError::QueueFull
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+/// Allocates the work queue. Called by `Executor::new`, further calls do nothing.
+pub fn init() {
+    let _ = WORK_QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_SIZE));
+}
+
+/// Runs every pending work item, including those scheduled while draining. Returns the number run.
+///
+/// Must not be called from interrupt context, since work items may block and allocate.
+pub fn run_pending() -> usize {
+    let queue = match WORK_QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    while let Ok(work) = queue.pop() {
+        // clear the flag first, so that an interrupt during the run can schedule the item again
+        work.pending.store(false, Ordering::Release);
+        (work.func)();
+        count += 1;
+    }
+    count
+}
+
+/// Returns `true` if no work item is pending.
+pub fn is_idle() -> bool {
+    WORK_QUEUE.try_get().map_or(true, |queue| queue.is_empty())
+}
@@ -0,0 +1,32 @@
+//! Helpers shared by the unit tests, which run without a heap.
+
+use core::fmt;
+
+/// Formatted text of up to `Line::CAPACITY` bytes, collected without the heap.
+pub struct Line {
+    bytes: [u8; Line::CAPACITY],
+    len: usize,
+}
+
+impl Line {
+    pub const CAPACITY: usize = 128;
+
+    /// Formats `args`, panicking if they don't fit.
+    pub fn format(args: fmt::Arguments) -> Line {
+        let mut line = Line { bytes: [0; Line::CAPACITY], len: 0 };
+        fmt::write(&mut line, args).unwrap();
+        line
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use conquer_once::spin::OnceCell;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+pub mod hpet;
+mod instant;
+pub mod pit;
+pub mod rtc;
+pub mod tickless;
+pub mod tsc;
+
+pub use crate::task::timer::{sleep, sleep_until};
+pub use instant::Instant;
+
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Longest wait `delay_us` and `delay_ms` accept. Anything longer has to `sleep`.
+pub const MAX_DELAY_US: u32 = 5_000;
+
+/// The clock chosen by `init`.
+static CLOCK: OnceCell<&'static dyn ClockSource> = OnceCell::uninit();
+/// Added to the chosen clock, so that `now` continues from the tick count instead of jumping back to zero.
+static CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds from the Unix epoch to when `now` was zero, from the RTC, 0 until `init` read it.
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+/// The one-shot event set by `after`.
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+/// A monotonic clock.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns the nanoseconds elapsed since the clock was started.
+    fn now_ns(&self) -> u64;
+
+    /// Returns the smallest step the clock advances by, in nanoseconds.
+    fn resolution_ns(&self) -> u64;
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    deadline_ns: u64,
+    callback: fn(),
+}
+
+/// Chooses the best clock source: an invariant TSC, then the HPET, then the timer interrupt.
+///
+/// The HPET is also used as event timer whenever the firmware reports one. That needs the I/O APIC,
+/// see `ioapic::init`, to route its interrupt.
+pub fn init() -> &'static dyn ClockSource {
+    let hpet = hpet::init().ok();
+    let clock: &'static dyn ClockSource = match (tsc::init(), hpet) {
+        (Ok(tsc), _) => tsc,
+        (Err(_), Some(hpet)) => hpet,
+        (Err(_), None) => &pit::TICK_CLOCK,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        CLOCK_OFFSET.store(pit::elapsed_ns().wrapping_sub(clock.now_ns()), Ordering::Relaxed);
+        CLOCK.try_init_once(|| clock).expect("time initialized twice");
+    });
+    rtc::init();
+    BOOT_TIME_NS.store(boot_time_ns(), Ordering::Relaxed);
+    clock
+}
+
+/// Returns the clock chosen by `init`, or the PIT based clock before that.
+pub fn clock() -> &'static dyn ClockSource {
+    CLOCK.try_get().copied().unwrap_or(&pit::TICK_CLOCK)
+}
+
+/// Returns the nanoseconds since boot from the best available clock source, see `Instant` for measuring with it.
+///
+/// Before `init` this only advances with the timer interrupt.
+pub fn now() -> u64 {
+    match CLOCK.try_get() {
+        Ok(clock) => clock.now_ns().wrapping_add(CLOCK_OFFSET.load(Ordering::Relaxed)),
+        Err(_) => pit::elapsed_ns(),
+    }
+}
+
+/// Returns the time since 1970-01-01 00:00:00 UTC, see `rtc::DateTime::from_unix_timestamp` for the date.
+///
+/// `init` reads the RTC once and the monotonic clock counts from there, so the wall clock never goes back and
+/// has the resolution of `clock`. Before `init` every call reads the RTC, which only counts whole seconds.
+pub fn wall_clock() -> Duration {
+    let boot_time_ns = match BOOT_TIME_NS.load(Ordering::Relaxed) {
+        0 => boot_time_ns(),
+        boot_time_ns => boot_time_ns,
+    };
+    Duration::from_nanos(boot_time_ns + now())
+}
+
+fn boot_time_ns() -> u64 {
+    (rtc::read().unix_timestamp() * NANOS_PER_SECOND).saturating_sub(now())
+}
+
+/// Runs `callback` from interrupt context once `delay_ns` nanoseconds have passed.
+///
+/// There is a single one-shot event, so this replaces the previous one. Executor timers multiplex it by
+/// always arming the earliest deadline. Without the HPET the deadline is checked on every timer tick, so
+/// the callback is late by up to one tick period.
+pub fn after(delay_ns: u64, callback: fn()) {
+    let deadline_ns = now().saturating_add(delay_ns);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *PENDING.lock() = Some(Pending { deadline_ns, callback });
+        hpet::arm_after(delay_ns);
+    });
+}
+
+/// Returns the deadline of the event set by `after`, if it has not fired yet.
+pub fn next_deadline() -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| PENDING.lock().map(|event| event.deadline_ns))
+}
+
+/// Cancels the event set by `after`, returning `true` if it had not fired yet.
+pub fn cancel() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| PENDING.lock().take().is_some())
+}
+
+/// Runs the pending event if its deadline has passed. Called from timer interrupt handlers.
+pub(crate) fn expire() {
+    let now = now();
+    let due = {
+        let mut pending = PENDING.lock();
+        match *pending {
+            Some(event) if event.deadline_ns <= now => pending.take(),
+            _ => None,
+        }
+    };
+    if let Some(event) = due {
+        (event.callback)();
+    }
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    pit::ticks()
+}
+
+/// Returns the milliseconds since boot, counted by the timer interrupt.
+///
+/// Unlike `clock`, this only advances once per tick, but it works before `init` and in every configuration.
+pub fn uptime_ms() -> u64 {
+    pit::elapsed_ns() / 1_000_000
+}
+
+/// Spins for `us` microseconds, for the short waits of device handshakes in drivers.
+///
+/// Panics if `us` is more than `MAX_DELAY_US`: the CPU does nothing else meanwhile, so tasks wait with `sleep`
+/// instead. Waits at least `us`, measured with the chosen clock, or with PIT channel 2 before `init` and
+/// without a clock finer than a microsecond.
+pub fn delay_us(us: u32) {
+    assert!(us <= MAX_DELAY_US, "delay of {} us is too long to spin, sleep instead", us);
+    let duration = Duration::from_micros(u64::from(us));
+    if clock().resolution_ns() > 1000 {
+        pit::busy_wait_ns(duration.as_nanos() as u64);
+        return;
+    }
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spins for `ms` milliseconds, see `delay_us`. Panics if that is more than `MAX_DELAY_US`.
+pub fn delay_ms(ms: u32) {
+    delay_us(ms.saturating_mul(1000));
+}
+
+/// Counts one tick of the periodic timer interrupt and runs expired events.
+pub(crate) fn tick() {
+    pit::count_tick();
+    expire();
+}
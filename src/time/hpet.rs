@@ -0,0 +1,171 @@
+use super::ClockSource;
+use crate::{
+    acpi::{self, AcpiError},
+    interrupts::{apic, ioapic::{self, Polarity, Route, TriggerMode}, vector},
+    memory::{self, MmioRegion},
+};
+use conquer_once::spin::OnceCell;
+
+pub const NAME: &str = "hpet";
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_COUNTER: usize = 0x0f0;
+const REG_TIMER_BASE: usize = 0x100;
+const TIMER_STRIDE: usize = 0x20;
+const TIMER_CONFIG: usize = 0x00;
+const TIMER_COMPARATOR: usize = 0x08;
+/// Size of the register block covering the three timers every HPET has.
+const REGISTERS_LEN: usize = REG_TIMER_BASE + 3 * TIMER_STRIDE;
+
+const CAPABILITY_64_BIT: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    /// The main counter is only 32 bits wide and would wrap within seconds.
+    Counter32,
+    Map(memory::mmio::MmioError),
+}
+
+/// The high precision event timer, used as clock source and one-shot event timer.
+pub struct Hpet {
+    registers: MmioRegion,
+    period_fs: u64,
+    /// Whether timer 0 could be routed to an interrupt, without which `arm` does nothing.
+    oneshot: bool,
+}
+
+impl Hpet {
+    /// Returns the raw value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.registers.read::<u64>(REG_COUNTER)
+    }
+
+    /// Returns the counter period in femtoseconds.
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// Returns `true` if the HPET can deliver one-shot events.
+    pub fn has_oneshot(&self) -> bool {
+        self.oneshot
+    }
+
+    fn timer_reg(index: usize, reg: usize) -> usize {
+        REG_TIMER_BASE + index * TIMER_STRIDE + reg
+    }
+
+    /// Routes timer 0 through a free I/O APIC input. Needs the I/O APIC and a dynamic vector.
+    fn route_timer_0(&self) -> bool {
+        let destination = match apic::local_apic() {
+            Some(apic) if ioapic::is_enabled() => apic.id() as u8,
+            _ => return false,
+        };
+        let config = self.registers.read::<u64>(Self::timer_reg(0, TIMER_CONFIG));
+        let allowed = (config >> 32) as u32;
+        let gsi = match (0..32).find(|gsi| {
+            allowed & (1 << gsi) != 0 && ioapic::route_of(*gsi).map_or(false, |route| route.masked)
+        }) {
+            Some(gsi) => gsi,
+            None => return false,
+        };
+        let vector = match vector::allocate(super::expire) {
+            Some(vector) => vector,
+            None => return false,
+        };
+        let route = Route { vector, polarity: Polarity::ActiveHigh, trigger: TriggerMode::Edge, destination, masked: false };
+        if ioapic::route_gsi(gsi, route).is_err() {
+            vector::free(vector);
+            return false;
+        }
+        // edge triggered, one-shot, 64-bit comparator
+        let config = u64::from(gsi) << TIMER_ROUTE_SHIFT | TIMER_INTERRUPT_ENABLE;
+        self.registers.write::<u64>(Self::timer_reg(0, TIMER_CONFIG), config);
+        true
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn now_ns(&self) -> u64 {
+        counter_to_ns(self.counter(), self.period_fs)
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        ((self.period_fs + FEMTOS_PER_NANO - 1) / FEMTOS_PER_NANO).max(1)
+    }
+}
+
+/// Returns the HPET once `init` succeeded.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
+
+/// Finds the HPET through the ACPI HPET table, maps it and starts its main counter from zero.
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    if let Some(hpet) = hpet() {
+        return Ok(hpet);
+    }
+    let table = acpi::find_table(b"HPET").map_err(HpetError::Acpi)?;
+    // the base address is the address field of a generic address structure at offset 4 of the body
+    let base = table.read::<u64>(8).ok_or(HpetError::Acpi(AcpiError::Malformed(*b"HPET")))?;
+    let registers = memory::map_mmio(x86_64::PhysAddr::new(base), REGISTERS_LEN).map_err(HpetError::Map)?;
+
+    let capabilities = registers.read::<u64>(REG_CAPABILITIES);
+    if capabilities & CAPABILITY_64_BIT == 0 {
+        return Err(HpetError::Counter32);
+    }
+    let mut hpet = Hpet { registers, period_fs: capabilities >> 32, oneshot: false };
+
+    let config = hpet.registers.read::<u64>(REG_CONFIG);
+    hpet.registers.write::<u64>(REG_CONFIG, config & !CONFIG_ENABLE);
+    hpet.registers.write::<u64>(REG_COUNTER, 0);
+    hpet.oneshot = hpet.route_timer_0();
+    hpet.registers.write::<u64>(REG_CONFIG, config | CONFIG_ENABLE);
+
+    HPET.try_init_once(|| hpet).expect("HPET initialized twice");
+    Ok(HPET.try_get().unwrap())
+}
+
+/// Makes timer 0 interrupt once `delay_ns` nanoseconds have passed, if it could be routed.
+pub(super) fn arm_after(delay_ns: u64) {
+    let hpet = match hpet() {
+        Some(hpet) if hpet.oneshot => hpet,
+        _ => return,
+    };
+    let deadline = hpet.counter().saturating_add(ns_to_counter(delay_ns, hpet.period_fs).max(1));
+    hpet.registers.write::<u64>(Hpet::timer_reg(0, TIMER_COMPARATOR), deadline);
+    // a deadline that passed before the comparator was written would never match
+    if hpet.counter() >= deadline {
+        super::expire();
+    }
+}
+
+fn counter_to_ns(counter: u64, period_fs: u64) -> u64 {
+    (u128::from(counter) * u128::from(period_fs) / u128::from(FEMTOS_PER_NANO)) as u64
+}
+
+fn ns_to_counter(ns: u64, period_fs: u64) -> u64 {
+    (u128::from(ns) * u128::from(FEMTOS_PER_NANO) / u128::from(period_fs)) as u64
+}
+
+#[test_case]
+fn test_counter_conversion() {
+    // QEMU's HPET runs at 100 MHz
+    let period_fs = 10_000_000;
+    assert_eq!(counter_to_ns(123_456, period_fs), 1_234_560);
+    assert_eq!(ns_to_counter(1_234_560, period_fs), 123_456);
+    // a day of counting must not overflow
+    assert_eq!(counter_to_ns(86_400 * 100_000_000, period_fs), 86_400 * 1_000_000_000);
+}
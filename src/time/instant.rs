@@ -0,0 +1,124 @@
+use super::NANOS_PER_SECOND;
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+/// A point in time on the monotonic clock of `time::now`, for measuring how long something took.
+///
+/// Unlike `std::time::Instant`, arithmetic saturates instead of panicking: an instant before boot is boot, and
+/// one too far in the future, like the deadline of a timeout of `Duration::MAX`, is the last one there is.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// The instant `time::now` started counting at.
+    pub const BOOT: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Instant {
+        Instant { nanos: super::now() }
+    }
+
+    /// Returns the instant `nanos` nanoseconds after boot, the unit of `time::now`.
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant { nanos }
+    }
+
+    /// Returns the nanoseconds from boot to this instant.
+    pub const fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    /// Returns the time from `earlier` to this instant, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Returns the time from `earlier` to this instant, `None` if `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos.checked_sub(earlier.nanos).map(Duration::from_nanos)
+    }
+
+    /// Returns the time since this instant, zero if it is in the future.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the instant `duration` after this one, `None` if there is no such instant.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant { nanos: self.nanos.checked_add(nanos)? })
+    }
+
+    /// Returns the instant `duration` before this one, `None` if that would be before boot.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant { nanos: self.nanos.checked_sub(nanos)? })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant { nanos: u64::MAX })
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).unwrap_or(Instant::BOOT)
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+/// The time between two instants, zero if the right one is later, like `duration_since`.
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Prints the time since boot in seconds, like `12.345678901s`.
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}s", self.nanos / NANOS_PER_SECOND, self.nanos % NANOS_PER_SECOND)
+    }
+}
+
+#[test_case]
+fn test_instant_arithmetic_saturates() {
+    let start = Instant::from_nanos(1_000);
+    let later = start + Duration::from_micros(2);
+    assert_eq!(later.as_nanos(), 3_000);
+    assert_eq!(later - start, Duration::from_micros(2));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(start.checked_duration_since(later), None);
+    assert_eq!(start - Duration::from_secs(1), Instant::BOOT);
+    assert_eq!(start.checked_sub(Duration::from_secs(1)), None);
+    assert_eq!((start + Duration::MAX).as_nanos(), u64::MAX);
+    assert_eq!(start.checked_add(Duration::MAX), None);
+    let mut deadline = start;
+    deadline += Duration::from_nanos(500);
+    deadline -= Duration::from_nanos(200);
+    assert_eq!(deadline.as_nanos(), 1_300);
+}
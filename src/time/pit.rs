@@ -0,0 +1,147 @@
+use super::{ClockSource, NANOS_PER_SECOND};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT's input clock.
+pub const FREQUENCY_HZ: u64 = 1_193_182;
+
+/// Timer interrupt frequency programmed by `lib::init`.
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+/// Period of the timer interrupt as programmed by the BIOS, with the maximum reload value of 65536.
+pub const DEFAULT_TICK_PERIOD_NS: u64 = 65536 * NANOS_PER_SECOND / FREQUENCY_HZ;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the channel 2 gate in bit 0 and reflects its output in bit 5.
+const CHANNEL_2_CONTROL: u16 = 0x61;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+const COMMAND_CHANNEL_0_RATE: u8 = 0b0011_0100;
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const COMMAND_CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+
+/// The clock advanced by the timer interrupt, the fallback when there is no HPET.
+pub static TICK_CLOCK: TickClock = TickClock;
+
+/// Frequency the PIT was last programmed to, rounded to whole hertz.
+static TICK_HZ: AtomicU32 = AtomicU32::new(0);
+static TICK_PERIOD_NS: AtomicU64 = AtomicU64::new(DEFAULT_TICK_PERIOD_NS);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts timer interrupts, from the PIT or from the local APIC timer that replaces it.
+pub struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn now_ns(&self) -> u64 {
+        elapsed_ns()
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        TICK_PERIOD_NS.load(Ordering::Relaxed)
+    }
+}
+
+/// Programs PIT channel 0 to interrupt `hz` times per second, returning the frequency actually set.
+///
+/// The frequency is limited to what the 16-bit reload value can express, about 19 Hz to 1.19 MHz.
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (FREQUENCY_HZ / u64::from(hz.max(1))).clamp(1, 65536);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(COMMAND_CHANNEL_0_RATE);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        // a reload value of 0 means 65536
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    });
+    let actual = (FREQUENCY_HZ / divisor) as u32;
+    TICK_HZ.store(actual, Ordering::Relaxed);
+    set_tick_period_ns(divisor * NANOS_PER_SECOND / FREQUENCY_HZ);
+    actual
+}
+
+/// Spins for `duration_ns` measured with PIT channel 2, which works without interrupts or any other clock.
+///
+/// Meant for calibrating other clocks at boot. Waits of more than 54 ms are cut short to 54 ms.
+pub fn busy_wait_ns(duration_ns: u64) {
+    let count = (duration_ns * FREQUENCY_HZ / NANOS_PER_SECOND).clamp(1, 0xffff);
+    let mut control = Port::<u8>::new(CHANNEL_2_CONTROL);
+    let mut channel = Port::<u8>::new(CHANNEL_2);
+    unsafe {
+        // speaker off, gate low while the count is loaded
+        let value = control.read() & !0b11;
+        control.write(value);
+        Port::<u8>::new(COMMAND).write(COMMAND_CHANNEL_2_ONESHOT);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        control.write(value | 1);
+        // the channel 2 output goes high once the count reaches zero
+        while control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(value);
+    }
+}
+
+/// Returns the configured timer interrupt frequency, or `DEFAULT_TICK_HZ` if the PIT was not programmed yet.
+pub fn tick_hz() -> u32 {
+    match TICK_HZ.load(Ordering::Relaxed) {
+        0 => DEFAULT_TICK_HZ,
+        hz => hz,
+    }
+}
+
+/// Records the period of the timer interrupt. Whoever reprograms the timer must call this.
+pub fn set_tick_period_ns(period_ns: u64) {
+    TICK_PERIOD_NS.store(period_ns, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot measured in timer interrupts, in nanoseconds.
+pub(super) fn elapsed_ns() -> u64 {
+    TICK_NANOS.load(Ordering::Relaxed)
+}
+
+pub(super) fn count_tick() {
+    TICK_NANOS.fetch_add(TICK_PERIOD_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts the ticks missing before tick `target`, for the ticks the timer skipped while it was stopped, and
+/// returns how many that were. CPUs that wake up at once from the same stop count them only once.
+pub(super) fn count_ticks_up_to(target: u64) -> u64 {
+    let count = target.saturating_sub(TICKS.fetch_max(target, Ordering::Relaxed));
+    TICK_NANOS.fetch_add(count * TICK_PERIOD_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+    count
+}
+
+/// Returns the period of the timer interrupt in nanoseconds.
+pub(super) fn tick_period_ns() -> u64 {
+    TICK_PERIOD_NS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_default_tick_period() {
+    // the BIOS programs the PIT to roughly 18.2 Hz
+    assert_eq!(DEFAULT_TICK_PERIOD_NS / 1_000_000, 54);
+}
+
+#[test_case]
+fn test_frequency_is_programmed() {
+    assert_eq!(set_frequency(DEFAULT_TICK_HZ), 1000);
+    assert_eq!(TICK_PERIOD_NS.load(Ordering::Relaxed), 999_847);
+    let start = ticks();
+    while ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
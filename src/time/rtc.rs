@@ -0,0 +1,225 @@
+//! The CMOS real time clock, the only clock that keeps the date while the machine is off.
+//!
+//! It counts whole seconds, in BCD or binary and in 12 or 24 hour mode as the firmware set it up, and is taken to
+//! run on UTC. The century is in a register the ACPI FADT names, if at all; without one years are taken to be
+//! in the 2000s.
+
+use crate::acpi;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// Set while the clock updates its registers, which then must not be read.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register for afternoon hours in 12 hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Times the registers are read before the last reading is used, if two in a row never agree.
+const MAX_READS: usize = 8;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Days from 0000-03-01, where the calendar computations start their years, to 1970-01-01.
+const DAYS_TO_EPOCH: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// The CMOS index of the century register, 0 for none, set by `init`.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// A date and time of day in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the date and time `timestamp` seconds after 1970-01-01 00:00:00 UTC.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (days, seconds) = (timestamp / SECONDS_PER_DAY, timestamp % SECONDS_PER_DAY);
+        // years starting on March 1st keep the leap day at their end
+        let days = days + DAYS_TO_EPOCH;
+        let (era, day_of_era) = (days / DAYS_PER_ERA, days % DAYS_PER_ERA);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_month = (5 * day_of_year + 2) / 153;
+        let month = if march_month < 10 { march_month + 3 } else { march_month - 9 };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * march_month + 2) / 5 + 1) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Returns the seconds since 1970-01-01 00:00:00 UTC, 0 for earlier dates.
+    pub fn unix_timestamp(&self) -> u64 {
+        let (month, day) = (u64::from(self.month), u64::from(self.day));
+        let year = u64::from(self.year).saturating_sub(u64::from(month <= 2));
+        let (era, year_of_era) = (year / 400, year % 400);
+        let march_month = (month + 9) % 12;
+        let day_of_year = (153 * march_month + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = match (era * DAYS_PER_ERA + day_of_era).checked_sub(DAYS_TO_EPOCH) {
+            Some(days) => days,
+            None => return 0,
+        };
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        days * SECONDS_PER_DAY + seconds
+    }
+}
+
+/// Prints ISO 8601 style, like `2024-02-29 13:05:09`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The time registers as read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    /// 0 without a century register.
+    century: u8,
+}
+
+impl Registers {
+    /// Converts the registers from the format `status_b` says they are in.
+    fn decode(&self, status_b: u8) -> DateTime {
+        let value = |raw: u8| if status_b & STATUS_B_BINARY != 0 { raw } else { from_bcd(raw) };
+        let mut hour = value(self.hour & !HOURS_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is midnight and 12 PM noon
+            hour %= 12;
+            if self.hour & HOURS_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = match self.century {
+            0 => 20,
+            century => value(century),
+        };
+        DateTime {
+            year: u16::from(century) * 100 + u16::from(value(self.year)),
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+/// Looks up the century register in the ACPI FADT. Needs `memory::init`.
+pub fn init() {
+    let century = acpi::Fadt::parse().map_or(0, |fadt| fadt.century_register);
+    CENTURY_REGISTER.store(century, Ordering::Relaxed);
+}
+
+/// Reads the date and time from the clock, which takes a few milliseconds if it is just updating.
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+        // the registers change one by one during an update, so they are read until two readings agree
+        let mut previous = read_registers(century_register);
+        for _ in 0..MAX_READS {
+            let registers = read_registers(century_register);
+            if registers == previous {
+                break;
+            }
+            previous = registers;
+        }
+        previous.decode(read_register(REG_STATUS_B))
+    })
+}
+
+fn read_registers(century_register: u8) -> Registers {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Registers {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: if century_register == 0 { 0 } else { read_register(century_register) },
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX).write(register);
+        Port::<u8>::new(DATA).read()
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+#[test_case]
+fn test_registers_are_decoded() {
+    let bcd_12_hour = Registers {
+        second: 0x09,
+        minute: 0x05,
+        hour: HOURS_PM | 0x01,
+        day: 0x29,
+        month: 0x02,
+        year: 0x24,
+        century: 0x20,
+    };
+    let expected = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 5, second: 9 };
+    assert_eq!(bcd_12_hour.decode(0), expected);
+    let midnight = Registers { hour: 0x12, ..bcd_12_hour };
+    assert_eq!(midnight.decode(0).hour, 0);
+    let binary = Registers { second: 9, minute: 5, hour: 13, day: 29, month: 2, year: 24, century: 0 };
+    assert_eq!(binary.decode(STATUS_B_BINARY | STATUS_B_24_HOUR), expected);
+}
+
+#[test_case]
+fn test_unix_timestamps_round_trip() {
+    let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 5, second: 9 };
+    assert_eq!(leap_day.unix_timestamp(), 1_709_211_909);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_211_909), leap_day);
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(DateTime::from_unix_timestamp(0), epoch);
+    assert_eq!(DateTime { year: 1969, ..epoch }.unix_timestamp(), 0);
+    let end_of_century = DateTime { year: 2099, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+    assert_eq!(DateTime::from_unix_timestamp(end_of_century.unix_timestamp()), end_of_century);
+}
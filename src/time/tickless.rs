@@ -0,0 +1,93 @@
+use super::{pit, ClockSource, NANOS_PER_SECOND};
+use crate::{
+    interrupts::{
+        apic,
+        stats::{cpu_index, MAX_CPUS},
+    },
+    task::thread,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Idle periods shorter than this many ticks keep the periodic timer running, since reprogramming it costs more.
+const MIN_IDLE_TICKS: u64 = 2;
+/// Longest time the timer is stopped for, even without a pending deadline.
+const MAX_IDLE_NS: u64 = NANOS_PER_SECOND;
+
+/// The idle period of each CPU, whose workers go idle and stop their timers independently.
+static IDLE: [Idle; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const RUNNING: Idle =
+        Idle { stopped: AtomicBool::new(false), start_ns: AtomicU64::new(0), start_ticks: AtomicU64::new(0) };
+    [RUNNING; MAX_CPUS]
+};
+/// Timer interrupts that did not happen because the timer was stopped.
+static SKIPPED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct Idle {
+    /// Set while the periodic timer is stopped by `enter_idle`.
+    stopped: AtomicBool,
+    start_ns: AtomicU64,
+    start_ticks: AtomicU64,
+}
+
+/// Stops the periodic timer interrupt before the CPU halts, and arms the local APIC timer to fire once at the
+/// deadline of the pending `time::after` event instead, or after `MAX_IDLE_NS` without one.
+///
+/// Must be called with interrupts disabled, right before `hlt`. Returns `false` and leaves the timer alone if
+/// it can't be stopped: before the local APIC timer replaced the PIT, when the clock only advances with the
+/// timer interrupt, while other threads are ready, or when the next deadline is too close. If it returns
+/// `true`, `exit_idle` has to be called after the CPU woke up.
+pub fn enter_idle() -> bool {
+    let apic = match apic::local_apic() {
+        Some(apic) => apic,
+        None => return false,
+    };
+    if super::clock().name() == pit::TICK_CLOCK.name() || thread::has_ready_threads() {
+        return false;
+    }
+    let now = super::now();
+    let period_ns = pit::tick_period_ns();
+    let delay_ns = super::next_deadline()
+        .map_or(MAX_IDLE_NS, |deadline| deadline.saturating_sub(now))
+        .min(MAX_IDLE_NS)
+        .min(apic.max_timer_oneshot_ns());
+    if delay_ns < MIN_IDLE_TICKS * period_ns {
+        return false;
+    }
+    let idle = &IDLE[cpu_index()];
+    idle.start_ns.store(now, Ordering::Relaxed);
+    idle.start_ticks.store(pit::ticks(), Ordering::Relaxed);
+    idle.stopped.store(true, Ordering::Relaxed);
+    apic.set_timer_oneshot(delay_ns);
+    true
+}
+
+/// Restarts the periodic timer after an idle period started by `enter_idle`, and counts the ticks that were
+/// skipped, so that `time::ticks` and the tick clock don't fall behind.
+pub fn exit_idle() {
+    interrupts::without_interrupts(|| {
+        let idle = &IDLE[cpu_index()];
+        if !idle.stopped.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(apic) = apic::local_apic() {
+            apic.set_timer_periodic(pit::tick_hz());
+        }
+        let elapsed_ns = super::now().saturating_sub(idle.start_ns.load(Ordering::Relaxed));
+        // other CPUs may have counted ticks, or the skipped ones, in the meantime
+        let expected = idle.start_ticks.load(Ordering::Relaxed) + elapsed_ns / pit::tick_period_ns();
+        let skipped = pit::count_ticks_up_to(expected);
+        SKIPPED_TICKS.fetch_add(skipped, Ordering::Relaxed);
+    });
+}
+
+/// Returns `true` while the periodic timer of the current CPU is stopped.
+pub fn is_stopped() -> bool {
+    IDLE[cpu_index()].stopped.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts saved by stopping the timer while idle.
+pub fn skipped_ticks() -> u64 {
+    SKIPPED_TICKS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,78 @@
+use super::{pit, ClockSource, NANOS_PER_SECOND};
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+pub const NAME: &str = "tsc";
+
+const CALIBRATION_NS: u64 = 50_000_000;
+
+static TSC: OnceCell<Tsc> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscError {
+    /// The TSC may change its rate with the CPU frequency or stop in sleep states.
+    NotInvariant,
+}
+
+/// The time stamp counter, calibrated against the PIT.
+pub struct Tsc {
+    hz: u64,
+    start: u64,
+}
+
+impl Tsc {
+    /// Returns the measured frequency of the counter.
+    pub fn hz(&self) -> u64 {
+        self.hz
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn now_ns(&self) -> u64 {
+        let elapsed = read().wrapping_sub(self.start);
+        (u128::from(elapsed) * u128::from(NANOS_PER_SECOND) / u128::from(self.hz)) as u64
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        1
+    }
+}
+
+/// Returns the raw counter value.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns the TSC once `init` succeeded.
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.try_get().ok()
+}
+
+/// Returns `true` if the CPU reports an invariant TSC, which ticks at a constant rate in every power state.
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Measures the TSC frequency over 50 ms of PIT channel 2.
+///
+/// Only an invariant TSC is accepted, see `is_invariant`.
+pub fn init() -> Result<&'static Tsc, TscError> {
+    if let Some(tsc) = tsc() {
+        return Ok(tsc);
+    }
+    if !is_invariant() {
+        return Err(TscError::NotInvariant);
+    }
+    let hz = x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = read();
+        pit::busy_wait_ns(CALIBRATION_NS);
+        (read() - start) * (NANOS_PER_SECOND / CALIBRATION_NS)
+    });
+    TSC.try_init_once(|| Tsc { hz, start: read() }).expect("TSC initialized twice");
+    Ok(TSC.try_get().unwrap())
+}
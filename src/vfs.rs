@@ -0,0 +1,462 @@
+//! The virtual filesystem: one tree of files that the mounted filesystems make up, whatever their format.
+//!
+//! Filesystems implement `FileSystem`, whose root and everything below it are `Inode`s, each either a `File` to
+//! read and write or a `Dir` to look names up in. `mount` puts a filesystem's root at a path, and the functions
+//! here take absolute paths, find the filesystem mounted closest above them and walk down from its root.
+//! Paths are resolved by name alone, so `..` leads to the parent in the path, even across mount points.
+
+use crate::block::BlockError;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{fmt, future::Future, pin::Pin, time::Duration};
+use spin::Mutex;
+
+pub mod archive;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod path;
+pub mod ramfs;
+
+/// The mounted filesystems, by the path they are mounted at.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// What the asynchronous operations of filesystems return.
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FsError>> + Send + 'a>>;
+
+#[derive(Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// The directory to remove still has entries.
+    NotEmpty,
+    /// The path isn't absolute or has an empty or overlong name in it.
+    InvalidPath,
+    ReadOnly,
+    /// The filesystem doesn't do this, like creating device files on a FAT filesystem.
+    Unsupported,
+    NoSpace,
+    /// The filesystem is mounted, or a filesystem is mounted below the path.
+    Busy,
+    /// The paths are on different filesystems, which `rename` can't move between.
+    CrossDevice,
+    /// The filesystem's structures make no sense.
+    Corrupt(&'static str),
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> FsError {
+        FsError::Block(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+    BlockDevice,
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FileType::File => "file",
+            FileType::Directory => "directory",
+            FileType::CharDevice => "character device",
+            FileType::BlockDevice => "block device",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// The length of a file in bytes, how much a directory takes up on disk, or 0 for devices.
+    pub size: u64,
+    /// Tells the inodes of one filesystem apart, like an inode or cluster number.
+    pub id: u64,
+    /// When the contents last changed, since the Unix epoch, if the filesystem records it.
+    pub modified: Option<Duration>,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file, directory or device in a filesystem.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Returns the inode as a file if it can be read and written, which devices can as well.
+    fn as_file(&self) -> Option<&dyn File> {
+        None
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        None
+    }
+}
+
+/// An inode that holds bytes.
+pub trait File: Inode {
+    /// Reads from `offset` on into `buf`, returning how many bytes it read, 0 at the end of the file.
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes `buf` at `offset`, growing the file if that is past its end, and returns how many bytes it wrote.
+    ///
+    /// The default fails with `FsError::ReadOnly`.
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        let _ = (offset, buf);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Cuts the file off or pads it with zeros to `size` bytes. The default fails with `FsError::ReadOnly`.
+    fn set_len(&self, size: u64) -> FsFuture<'_, ()> {
+        let _ = size;
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+}
+
+/// An inode that holds other inodes by name.
+pub trait Dir: Inode {
+    /// Returns the inode named `name`, which is never `.` or `..`.
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>>;
+
+    /// Returns the entries of the directory, without `.` and `..`.
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>>;
+
+    /// Adds an empty inode of type `file_type` named `name`. The default fails with `FsError::ReadOnly`.
+    fn create<'a>(&'a self, name: &'a str, file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        let _ = (name, file_type);
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+
+    /// Removes the entry `name`, which must be empty if it is a directory. The default fails with
+    /// `FsError::ReadOnly`.
+    fn remove<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        let _ = name;
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+}
+
+/// A tree of inodes that can be mounted.
+pub trait FileSystem: Send + Sync {
+    /// Returns the name of the format, like `fat32`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes everything that is only in memory out. The default does nothing, for filesystems with nothing to
+    /// write.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Moves the entry at `from` to `to`, different normalized paths from the root of the filesystem, neither
+    /// below the other. Replaces a file at `to`, or an empty directory if the entry is a directory.
+    ///
+    /// The default fails with `FsError::Unsupported`, for filesystems that can't move entries.
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> FsFuture<'a, ()> {
+        let _ = (from, to);
+        Box::pin(async { Err(FsError::Unsupported) })
+    }
+}
+
+struct Mount {
+    /// The normalized mount point.
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Puts the root of `fs` at `path`, which must be `/` or a directory. Needs the heap.
+pub async fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    if MOUNTS.lock().iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    if path != "/" && !lookup(&path).await?.metadata().is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+    let mut mounts = MOUNTS.lock();
+    // another task may have mounted something there while the directory was looked up
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Removes the filesystem mounted at `path` after syncing it, and returns it.
+///
+/// Fails with `FsError::Busy` while other filesystems are mounted below it.
+pub async fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let path = path::normalize(path)?;
+    let fs = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts.iter().find(|mount| mount.path == path).ok_or(FsError::NotFound)?;
+        if mounts.iter().any(|other| other.path != path && path::is_within(&other.path, &path)) {
+            return Err(FsError::Busy);
+        }
+        mount.fs.clone()
+    };
+    fs.sync().await?;
+    MOUNTS.lock().retain(|mount| mount.path != path);
+    Ok(fs)
+}
+
+/// Returns the mount points with the name of the filesystem at each, in the order they were mounted.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock().iter().map(|mount| (mount.path.clone(), mount.fs.name())).collect()
+}
+
+/// Syncs every mounted filesystem, going on with the others if one fails and returning the first error.
+pub async fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<Arc<dyn FileSystem>> = MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    let mut result = Ok(());
+    for fs in filesystems {
+        if let Err(err) = fs.sync().await {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+/// Returns the filesystem the normalized path `path` is on, and the mount point it is mounted at.
+fn find_mount(path: &str) -> Result<(Arc<dyn FileSystem>, String), FsError> {
+    let mounts = MOUNTS.lock();
+    // the deepest mount point above the path is the one it is in
+    let mount = mounts
+        .iter()
+        .filter(|mount| path::is_within(path, &mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .ok_or(FsError::NotFound)?;
+    Ok((mount.fs.clone(), mount.path.clone()))
+}
+
+/// Returns the normalized path `path` from the root of the filesystem mounted at `mount`.
+fn relative<'a>(path: &'a str, mount: &str) -> &'a str {
+    match &path[mount.len()..] {
+        "" => "/",
+        // everything under `/` is already from its root
+        _ if mount == "/" => path,
+        rest => rest,
+    }
+}
+
+/// Returns the inode at the absolute path `path`.
+pub async fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let path = path::normalize(path)?;
+    let (fs, mount) = find_mount(&path)?;
+    let mut inode = fs.root();
+    for name in path::names(relative(&path, &mount)) {
+        let next = inode.as_dir().ok_or(FsError::NotADirectory)?.lookup(name).await?;
+        inode = next;
+    }
+    Ok(inode)
+}
+
+pub async fn metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup(path).await?.metadata())
+}
+
+/// Returns the entries of the directory at `path`, sorted by name.
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dir = lookup(path).await?;
+    let mut entries = dir.as_dir().ok_or(FsError::NotADirectory)?.entries().await?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Opens the file at `path`.
+pub async fn open(path: &str) -> Result<OpenFile, FsError> {
+    let inode = lookup(path).await?;
+    OpenFile::new(inode, path)
+}
+
+/// Opens the file at `path`, first creating it if there is none and emptying it if there is.
+pub async fn create(path: &str) -> Result<OpenFile, FsError> {
+    let inode = match lookup(path).await {
+        Ok(inode) => {
+            inode.as_file().ok_or(FsError::IsADirectory)?.set_len(0).await?;
+            inode
+        }
+        Err(FsError::NotFound) => create_inode(path, FileType::File).await?,
+        Err(err) => return Err(err),
+    };
+    OpenFile::new(inode, path)
+}
+
+/// Creates the directory `path`, whose parent must exist.
+pub async fn create_dir(path: &str) -> Result<(), FsError> {
+    create_inode(path, FileType::Directory).await.map(|_| ())
+}
+
+/// Removes the file or empty directory at `path`, which must not be a mount point.
+pub async fn remove(path: &str) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    if MOUNTS.lock().iter().any(|mount| path::is_within(&mount.path, &path)) {
+        return Err(FsError::Busy);
+    }
+    let (parent, name) = path::split(&path).ok_or(FsError::InvalidPath)?;
+    let parent = lookup(parent).await?;
+    parent.as_dir().ok_or(FsError::NotADirectory)?.remove(name).await
+}
+
+/// Moves the file or directory at `from` to `to`, replacing a file there, or an empty directory if `from` is a
+/// directory. Neither may be a mount point, and both must be on one filesystem.
+pub async fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let from = path::normalize(from)?;
+    let to = path::normalize(to)?;
+    if path::split(&from).is_none() || path::split(&to).is_none() {
+        return Err(FsError::InvalidPath);
+    }
+    if MOUNTS.lock().iter().any(|mount| path::is_within(&mount.path, &from) || path::is_within(&mount.path, &to)) {
+        return Err(FsError::Busy);
+    }
+    if from == to {
+        return lookup(&from).await.map(|_| ());
+    }
+    // a directory can't go into itself, and it can't replace one it is in, which isn't empty
+    if path::is_within(&to, &from) {
+        return Err(FsError::InvalidPath);
+    }
+    if path::is_within(&from, &to) {
+        return Err(FsError::NotEmpty);
+    }
+    let (fs, mount) = find_mount(&from)?;
+    if find_mount(&to)?.1 != mount {
+        return Err(FsError::CrossDevice);
+    }
+    fs.rename(relative(&from, &mount), relative(&to, &mount)).await
+}
+
+async fn create_inode(path: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+    let path = path::normalize(path)?;
+    let (parent, name) = path::split(&path).ok_or(FsError::InvalidPath)?;
+    let parent = lookup(parent).await?;
+    let dir = parent.as_dir().ok_or(FsError::NotADirectory)?;
+    match dir.lookup(name).await {
+        Ok(_) => Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => dir.create(name, file_type).await,
+        Err(err) => Err(err),
+    }
+}
+
+/// Where `OpenFile::seek` moves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// A file opened through the VFS, which reads and writes from a position that moves along.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    path: String,
+    position: u64,
+}
+
+impl OpenFile {
+    fn new(inode: Arc<dyn Inode>, path: &str) -> Result<OpenFile, FsError> {
+        if inode.as_file().is_none() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(OpenFile { inode, path: path.to_string(), position: 0 })
+    }
+
+    /// Returns the path the file was opened with.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn file(&self) -> &dyn File {
+        // `new` checked that it is one
+        self.inode.as_file().expect("open inode not a file")
+    }
+
+    /// Reads into `buf` and moves past what it read, returning how many bytes that was, 0 at the end.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let read = self.file().read_at(self.position, buf).await?;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    /// Reads everything from the position to the end of the file.
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut contents = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk).await? {
+                0 => return Ok(contents),
+                read => contents.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    /// Writes `buf` at the position and moves past it, returning how many bytes it wrote.
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        let written = self.file().write_at(self.position, buf).await?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    /// Writes all of `buf`, failing with `FsError::NoSpace` if the file stops taking it.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), FsError> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(FsError::NoSpace),
+                written => buf = &buf[written..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the position and returns the new one. Moving before the start of the file stops at the start.
+    pub fn seek(&mut self, to: SeekFrom) -> u64 {
+        let offset = |base: u64, delta: i64| {
+            if delta < 0 {
+                base.saturating_sub(delta.unsigned_abs())
+            } else {
+                base.saturating_add(delta as u64)
+            }
+        };
+        self.position = match to {
+            SeekFrom::Start(position) => position,
+            SeekFrom::End(delta) => offset(self.metadata().size, delta),
+            SeekFrom::Current(delta) => offset(self.position, delta),
+        };
+        self.position
+    }
+
+    /// Cuts the file off or pads it with zeros to `size` bytes, leaving the position where it is.
+    pub async fn set_len(&self, size: u64) -> Result<(), FsError> {
+        self.file().set_len(size).await
+    }
+}
@@ -0,0 +1,382 @@
+//! Read-only filesystems of ustar and cpio archives in memory, which is what initrds are.
+//!
+//! `ArchiveFs::new` walks the archive once and builds the tree of its directories, whose files point into the
+//! archive, so nothing is copied. Paths may start with `./` or `/`, and directories that only show up in the
+//! paths of other entries are made up. The VFS has no symbolic links or device files to show, so those entries
+//! are left out, as are entries with `..` in their paths.
+//!
+//! Both the POSIX ustar format, with the GNU long names of `tar` on Linux, and the "new" ASCII cpio format of
+//! `cpio -H newc`, which Linux initramfs images use, are read.
+
+use super::{path, Dir, DirEntry, File, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use alloc::{
+    boxed::Box,
+    collections::{btree_map, BTreeMap},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::time::Duration;
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_FILE: u8 = b'0';
+/// What the first tar versions had for files.
+const TAR_OLD_FILE: u8 = 0;
+const TAR_HARD_LINK: u8 = b'1';
+const TAR_DIRECTORY: u8 = b'5';
+const TAR_CONTIGUOUS_FILE: u8 = b'7';
+/// GNU tar's entry whose contents are the name of the next entry, for names too long for the header.
+const TAR_GNU_LONG_NAME: u8 = b'L';
+
+/// The magic numbers of the cpio format without and with checksums, which aren't checked.
+const CPIO_MAGIC: [&[u8]; 2] = [b"070701", b"070702"];
+const CPIO_HEADER: usize = 110;
+/// The name of the entry that ends a cpio archive.
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+const CPIO_MODE: usize = 1;
+const CPIO_MTIME: usize = 5;
+const CPIO_FILE_SIZE: usize = 6;
+const CPIO_NAME_SIZE: usize = 11;
+
+const MODE_TYPE_MASK: u32 = 0o170_000;
+const MODE_DIRECTORY: u32 = 0o040_000;
+const MODE_FILE: u32 = 0o100_000;
+
+/// A read-only filesystem of an archive in memory.
+pub struct ArchiveFs {
+    format: &'static str,
+    root: Arc<ArchiveDir>,
+}
+
+struct ArchiveDir {
+    id: u64,
+    modified: Option<Duration>,
+    entries: BTreeMap<String, Node>,
+}
+
+struct ArchiveFile {
+    id: u64,
+    modified: Option<Duration>,
+    data: &'static [u8],
+}
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<ArchiveFile>),
+    Dir(Arc<ArchiveDir>),
+}
+
+/// A directory while the archive is read.
+#[derive(Default)]
+struct Tree {
+    modified: Option<Duration>,
+    entries: BTreeMap<String, TreeEntry>,
+    /// The number the next inode gets, kept in the root.
+    next_id: u64,
+}
+
+enum TreeEntry {
+    File(Arc<ArchiveFile>),
+    Dir(Tree),
+}
+
+impl ArchiveFs {
+    /// Reads the ustar or cpio archive `archive`. Needs the heap.
+    ///
+    /// Fails with `FsError::Unsupported` if `archive` is in neither format.
+    pub fn new(archive: &'static [u8]) -> Result<ArchiveFs, FsError> {
+        let mut tree = Tree { next_id: 2, ..Tree::default() };
+        let format = if CPIO_MAGIC.iter().any(|magic| archive.starts_with(magic)) {
+            read_cpio(archive, &mut tree)?;
+            "cpio"
+        } else if archive.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+            read_ustar(archive, &mut tree)?;
+            "ustar"
+        } else {
+            return Err(FsError::Unsupported);
+        };
+        Ok(ArchiveFs { format, root: tree.finish(1) })
+    }
+}
+
+impl FileSystem for ArchiveFs {
+    /// Returns `ustar` or `cpio`, whichever the archive is.
+    fn name(&self) -> &'static str {
+        self.format
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl Tree {
+    /// Adds the entry at `path` of the archive, a file with `data` if it has any and a directory otherwise.
+    fn add(&mut self, path: &str, data: Option<&'static [u8]>, modified: Option<Duration>) -> Result<(), FsError> {
+        let names: Vec<&str> = path::names(path).filter(|&name| name != ".").collect();
+        if names.iter().any(|&name| name == "..") {
+            return Ok(());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        match (names.split_last(), data) {
+            (Some((name, parent)), Some(data)) => {
+                let file = Arc::new(ArchiveFile { id, modified, data });
+                self.dir(parent)?.insert(name, TreeEntry::File(file))
+            }
+            (_, Some(_)) => Err(FsError::Corrupt("file without a name")),
+            (_, None) => {
+                self.dir(&names)?.modified = modified;
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the hard link at `path` to the file at `target`, which must have come before it.
+    fn link(&mut self, path: &str, target: &str) -> Result<(), FsError> {
+        let names: Vec<&str> = path::names(target).filter(|&name| name != ".").collect();
+        let (name, parent) = names.split_last().ok_or(FsError::Corrupt("hard link to the root"))?;
+        let file = match self.dir(parent)?.entries.get(*name) {
+            Some(TreeEntry::File(file)) => file.clone(),
+            _ => return Ok(()),
+        };
+        let names: Vec<&str> = path::names(path).filter(|&name| name != ".").collect();
+        match names.split_last() {
+            Some((name, parent)) if !names.contains(&"..") => self.dir(parent)?.insert(name, TreeEntry::File(file)),
+            _ => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, name: &str, entry: TreeEntry) -> Result<(), FsError> {
+        match self.entries.entry(name.to_string()) {
+            btree_map::Entry::Occupied(mut occupied) => match occupied.get() {
+                TreeEntry::Dir(_) => Err(FsError::Corrupt("file over a directory")),
+                // later entries replace earlier ones, as when the archive is unpacked
+                TreeEntry::File(_) => {
+                    occupied.insert(entry);
+                    Ok(())
+                }
+            },
+            btree_map::Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the directory at `names`, making up the ones that aren't there.
+    fn dir(&mut self, names: &[&str]) -> Result<&mut Tree, FsError> {
+        let mut dir = self;
+        for &name in names {
+            let entry = dir.entries.entry(name.to_string()).or_insert_with(|| TreeEntry::Dir(Tree::default()));
+            dir = match entry {
+                TreeEntry::Dir(next) => next,
+                TreeEntry::File(_) => return Err(FsError::Corrupt("file in the path of an entry")),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Turns the tree into inodes, the root numbered `id` and the other directories after the files.
+    fn finish(self, id: u64) -> Arc<ArchiveDir> {
+        let mut next_id = self.next_id;
+        self.finish_with(id, &mut next_id)
+    }
+
+    fn finish_with(self, id: u64, next_id: &mut u64) -> Arc<ArchiveDir> {
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(name, entry)| {
+                let node = match entry {
+                    TreeEntry::File(file) => Node::File(file),
+                    TreeEntry::Dir(tree) => {
+                        let id = *next_id;
+                        *next_id += 1;
+                        Node::Dir(tree.finish_with(id, next_id))
+                    }
+                };
+                (name, node)
+            })
+            .collect();
+        Arc::new(ArchiveDir { id, modified: self.modified, entries })
+    }
+}
+
+fn read_ustar(archive: &'static [u8], tree: &mut Tree) -> Result<(), FsError> {
+    let mut offset = 0;
+    let mut long_name = None;
+    while let Some(header) = archive.get(offset..offset + TAR_BLOCK) {
+        // the archive ends with blocks of zeros
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let checksum = octal(&header[148..156]).ok_or(FsError::Corrupt("bad tar header"))?;
+        // the checksum field itself counts as spaces
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(byte) })
+            .sum();
+        if sum != checksum {
+            return Err(FsError::Corrupt("bad tar header checksum"));
+        }
+        let size = octal(&header[124..136]).ok_or(FsError::Corrupt("bad tar header"))? as usize;
+        let start = offset + TAR_BLOCK;
+        let data = start
+            .checked_add(size)
+            .and_then(|end| archive.get(start..end))
+            .ok_or(FsError::Corrupt("tar entry past the end of the archive"))?;
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let name = text(&header[..100])?;
+                match text(&header[345..500])? {
+                    "" => name.to_string(),
+                    prefix => [prefix, name].join("/"),
+                }
+            }
+        };
+        let modified = octal(&header[136..148]).map(Duration::from_secs);
+        match header[156] {
+            TAR_FILE | TAR_OLD_FILE | TAR_CONTIGUOUS_FILE => tree.add(&name, Some(data), modified)?,
+            TAR_DIRECTORY => tree.add(&name, None, modified)?,
+            TAR_HARD_LINK => tree.link(&name, text(&header[157..257])?)?,
+            TAR_GNU_LONG_NAME => long_name = Some(text(data)?.to_string()),
+            _ => {}
+        }
+        offset = start + (size + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK;
+    }
+    Ok(())
+}
+
+fn read_cpio(archive: &'static [u8], tree: &mut Tree) -> Result<(), FsError> {
+    let mut offset = 0;
+    loop {
+        let header = archive.get(offset..offset + CPIO_HEADER).ok_or(FsError::Corrupt("cpio archive without end"))?;
+        if !CPIO_MAGIC.iter().any(|magic| header.starts_with(magic)) {
+            return Err(FsError::Corrupt("bad cpio header"));
+        }
+        let field = |index: usize| hex(&header[6 + index * 8..][..8]).ok_or(FsError::Corrupt("bad cpio header"));
+        let name_size = field(CPIO_NAME_SIZE)? as usize;
+        let size = field(CPIO_FILE_SIZE)? as usize;
+        let name_start = offset + CPIO_HEADER;
+        // the name ends in a 0, and the name and the data are padded to 4 bytes
+        let name = name_size
+            .checked_sub(1)
+            .and_then(|len| archive.get(name_start..name_start + len))
+            .ok_or(FsError::Corrupt("bad cpio name"))?;
+        let start = align4(name_start + name_size);
+        let data = archive.get(start..start + size).ok_or(FsError::Corrupt("cpio entry past the end of the archive"))?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let name = text(name)?;
+        let modified = Some(Duration::from_secs(u64::from(field(CPIO_MTIME)?)));
+        match field(CPIO_MODE)? & MODE_TYPE_MASK {
+            MODE_FILE => tree.add(name, Some(data), modified)?,
+            MODE_DIRECTORY => tree.add(name, None, modified)?,
+            _ => {}
+        }
+        offset = align4(start + size);
+    }
+}
+
+/// Returns the text in the field `field` up to its first 0.
+fn text(field: &[u8]) -> Result<&str, FsError> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| FsError::Corrupt("name not UTF-8"))
+}
+
+/// Reads the octal number in a tar header field, which may start with spaces and ends in spaces or 0s.
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = field.iter().skip_while(|&&byte| byte == b' ').take_while(|&&byte| byte != 0 && byte != b' ');
+    digits.fold(Some(0u64), |value, &digit| match digit {
+        b'0'..=b'7' => value?.checked_mul(8).map(|value| value + u64::from(digit - b'0')),
+        _ => None,
+    })
+}
+
+/// Reads the 8 hex digits of a cpio header field.
+fn hex(field: &[u8]) -> Option<u32> {
+    u32::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::File,
+            Node::Dir(_) => FileType::Directory,
+        }
+    }
+
+    fn inode(&self) -> Arc<dyn Inode> {
+        match self {
+            Node::File(file) => file.clone(),
+            Node::Dir(dir) => dir.clone(),
+        }
+    }
+}
+
+impl Inode for ArchiveDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::Directory, size: 0, id: self.id, modified: self.modified }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        Some(self)
+    }
+}
+
+impl Dir for ArchiveDir {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move { self.entries.get(name).map(Node::inode).ok_or(FsError::NotFound) })
+    }
+
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            Ok(self
+                .entries
+                .iter()
+                .map(|(name, node)| DirEntry { name: name.clone(), file_type: node.file_type() })
+                .collect())
+        })
+    }
+}
+
+impl Inode for ArchiveFile {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::File, size: self.data.len() as u64, id: self.id, modified: self.modified }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for ArchiveFile {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let rest = self.data.get(offset as usize..).unwrap_or(&[]);
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        })
+    }
+}
+
+#[test_case]
+fn test_header_numbers() {
+    assert_eq!(octal(b"00000001750\0"), Some(1000));
+    assert_eq!(octal(b"   "), Some(0));
+    assert_eq!(octal(b" 1750 \0"), Some(1000));
+    assert_eq!(octal(b"0009"), None);
+    assert_eq!(hex(b"000003E8"), Some(1000));
+    assert_eq!(align4(110 + 6), 116);
+}
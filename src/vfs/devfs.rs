@@ -0,0 +1,352 @@
+//! A filesystem of device files, mounted at `/dev`, which reaches drivers through the ordinary file calls.
+//!
+//! Its one directory holds `console`, `kbd`, `null` and `zero`, the character devices drivers register, and
+//! every registered block device under its name, like `ata0p1`. Block devices are always the ones registered
+//! when the directory is read, nothing has to tell the filesystem about them.
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::{
+    block::{self, BlockDevice},
+    console,
+};
+use alloc::{borrow::ToOwned, boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::convert::TryFrom;
+use spin::Mutex;
+
+/// A device that is read and written as a stream of bytes, without positions.
+pub trait CharDevice: Send + Sync {
+    /// Reads into `buf`, waiting until the device has something, and returns how many bytes it read, 0 if it
+    /// never has more.
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes `buf` and returns how many bytes the device took. The default fails with `FsError::ReadOnly`.
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        let _ = buf;
+        Box::pin(async { Err(FsError::ReadOnly) })
+    }
+}
+
+/// The character devices registered by drivers, in the order they were registered.
+static CHAR_DEVICES: Mutex<Vec<(String, Arc<dyn CharDevice>)>> = Mutex::new(Vec::new());
+
+/// Bytes a block device file reads or writes at a time at most.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// Adds `device` to `/dev` as `name`, failing with `FsError::AlreadyExists` if a device has that name.
+pub fn register(name: &str, device: Arc<dyn CharDevice>) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut devices = CHAR_DEVICES.lock();
+    if builtin(name).is_some() || devices.iter().any(|(other, _)| other == name) || block::find(name).is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    devices.push((name.to_owned(), device));
+    Ok(())
+}
+
+/// Takes the character device `name` out of `/dev`. Files opened on it keep working.
+pub fn unregister(name: &str) -> Option<Arc<dyn CharDevice>> {
+    let mut devices = CHAR_DEVICES.lock();
+    let index = devices.iter().position(|(other, _)| other == name)?;
+    Some(devices.remove(index).1)
+}
+
+/// The devices every kernel has.
+const BUILTIN: [&str; 4] = ["console", "kbd", "null", "zero"];
+
+fn builtin(name: &str) -> Option<Arc<dyn CharDevice>> {
+    Some(match name {
+        "console" => Arc::new(Console),
+        "kbd" => Arc::new(Keyboard),
+        "null" => Arc::new(Null),
+        "zero" => Arc::new(Zero),
+        _ => return None,
+    })
+}
+
+/// Tells the device files apart by their names, which are unique in the directory.
+fn id(name: &str) -> u64 {
+    // FNV-1a, kept off 0, which is the directory's
+    let hash = name
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3));
+    hash.max(1)
+}
+
+/// The filesystem of `/dev`. Every `DevFs` shows the same devices.
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+struct DevDir;
+
+impl DevFs {
+    pub fn new() -> DevFs {
+        DevFs { root: Arc::new(DevDir) }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> DevFs {
+        DevFs::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::Directory, size: 0, id: 0, modified: None }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        Some(self)
+    }
+}
+
+impl Dir for DevDir {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let registered = || {
+                let devices = CHAR_DEVICES.lock();
+                devices.iter().find(|(other, _)| other == name).map(|(_, device)| device.clone())
+            };
+            if let Some(device) = builtin(name).or_else(registered) {
+                return Ok(Arc::new(CharNode { id: id(name), device }) as Arc<dyn Inode>);
+            }
+            let device = block::find(name).ok_or(FsError::NotFound)?;
+            Ok(Arc::new(BlockNode { id: id(name), device }) as Arc<dyn Inode>)
+        })
+    }
+
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let entry = |name: &str, file_type| DirEntry { name: name.to_owned(), file_type };
+            let mut entries: Vec<DirEntry> = BUILTIN.iter().map(|name| entry(name, FileType::CharDevice)).collect();
+            entries.extend(CHAR_DEVICES.lock().iter().map(|(name, _)| entry(name, FileType::CharDevice)));
+            entries.extend(block::devices().iter().map(|(name, _)| entry(name, FileType::BlockDevice)));
+            Ok(entries)
+        })
+    }
+}
+
+/// A character device opened through its file.
+struct CharNode {
+    id: u64,
+    device: Arc<dyn CharDevice>,
+}
+
+impl Inode for CharNode {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::CharDevice, size: 0, id: self.id, modified: None }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for CharNode {
+    /// Reads from the device, which has no positions, so `offset` doesn't matter.
+    fn read_at<'a>(&'a self, _offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        self.device.read(buf)
+    }
+
+    fn write_at<'a>(&'a self, _offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        self.device.write(buf)
+    }
+
+    /// Does nothing, so that `vfs::create` opens devices like it empties files.
+    fn set_len(&self, _size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A block device as a file of its bytes, read and written anywhere, not only at block boundaries.
+struct BlockNode {
+    id: u64,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockNode {
+    /// Returns the first block and the length of the blocks that hold up to `len` bytes from `offset` on, never
+    /// more than `MAX_TRANSFER` of them and never past the end of the device, where in the first block the bytes
+    /// start, and how many of them the blocks hold.
+    fn span(&self, offset: u64, len: usize) -> (u64, usize, usize, usize) {
+        let block_size = self.device.block_size() as u64;
+        let first = offset / block_size;
+        let skip = (offset % block_size) as usize;
+        let rest = usize::try_from(self.device.size() - offset).unwrap_or(usize::MAX);
+        let len = len.min(rest).min(MAX_TRANSFER.max(block_size as usize) - skip);
+        let blocks = (skip + len + block_size as usize - 1) / block_size as usize;
+        (first, blocks * block_size as usize, skip, len)
+    }
+}
+
+impl Inode for BlockNode {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::BlockDevice, size: self.device.size(), id: self.id, modified: None }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for BlockNode {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if offset >= self.device.size() || buf.is_empty() {
+                return Ok(0);
+            }
+            let (first, span, skip, len) = self.span(offset, buf.len());
+            let mut blocks = vec![0; span];
+            self.device.read_blocks(first, &mut blocks).await?;
+            buf[..len].copy_from_slice(&blocks[skip..skip + len]);
+            Ok(len)
+        })
+    }
+
+    /// Writes what fits before the end of the device, reading the blocks it only partly covers first. Fails with
+    /// `FsError::NoSpace` at the end.
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if self.device.is_read_only() {
+                return Err(FsError::ReadOnly);
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            if offset >= self.device.size() {
+                return Err(FsError::NoSpace);
+            }
+            let (first, span, skip, len) = self.span(offset, buf.len());
+            let mut blocks = vec![0; span];
+            if skip != 0 || len != span {
+                self.device.read_blocks(first, &mut blocks).await?;
+            }
+            blocks[skip..skip + len].copy_from_slice(&buf[..len]);
+            self.device.write_blocks(first, &blocks).await?;
+            Ok(len)
+        })
+    }
+}
+
+/// `/dev/null`, empty, taking and dropping whatever is written.
+struct Null;
+
+impl CharDevice for Null {
+    fn read<'a>(&'a self, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move { Ok(buf.len()) })
+    }
+}
+
+/// `/dev/zero`, endless zeros, taking and dropping whatever is written.
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            buf.fill(0);
+            Ok(buf.len())
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move { Ok(buf.len()) })
+    }
+}
+
+/// The part of a line or a character a read had no room for, which the next read of the device returns first.
+struct Pending(Mutex<Vec<u8>>);
+
+impl Pending {
+    const fn new() -> Pending {
+        Pending(Mutex::new(Vec::new()))
+    }
+
+    /// Moves what is pending into `buf`, returning how many bytes that was.
+    fn take(&self, buf: &mut [u8]) -> usize {
+        let mut pending = self.0.lock();
+        let len = pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        pending.drain(..len);
+        len
+    }
+
+    /// Puts as much of `bytes` into `buf` as fits and keeps the rest, returning how many bytes went into `buf`.
+    fn fill(&self, buf: &mut [u8], bytes: &[u8]) -> usize {
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        self.0.lock().extend_from_slice(&bytes[len..]);
+        len
+    }
+}
+
+static CONSOLE_PENDING: Pending = Pending::new();
+static KEYBOARD_PENDING: Pending = Pending::new();
+
+/// `/dev/console`, reading lines with `console::read_line`, each with its line break, and printing what is
+/// written like `print!`.
+struct Console;
+
+impl CharDevice for Console {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let pending = CONSOLE_PENDING.take(buf);
+            if pending > 0 {
+                return Ok(pending);
+            }
+            let mut line = console::read_line().await;
+            line.push('\n');
+            Ok(CONSOLE_PENDING.fill(buf, line.as_bytes()))
+        })
+    }
+
+    /// Prints `buf`, with whatever isn't UTF-8 replaced.
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            crate::print!("{}", String::from_utf8_lossy(buf));
+            Ok(buf.len())
+        })
+    }
+}
+
+/// `/dev/kbd`, the characters typed, in UTF-8 and without echo, as soon as they are typed.
+struct Keyboard;
+
+impl CharDevice for Keyboard {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let pending = KEYBOARD_PENDING.take(buf);
+            if pending > 0 {
+                return Ok(pending);
+            }
+            let c = match console::read_char().await {
+                Some(c) => c,
+                None => return Ok(0),
+            };
+            let mut utf8 = [0; 4];
+            Ok(KEYBOARD_PENDING.fill(buf, c.encode_utf8(&mut utf8).as_bytes()))
+        })
+    }
+}
@@ -0,0 +1,413 @@
+//! ext2, read-only, as `mke2fs -t ext2` and Linux make it.
+//!
+//! The superblock, 1024 bytes into the volume, says how large blocks are and how the volume is split into block
+//! groups, whose descriptors follow it and say where each group's table of inodes is. An inode has the type, size
+//! and times of a file and the numbers of its first 12 blocks, then of a block of block numbers, one of blocks of
+//! them and one three levels deep. Block number 0 stands for a hole, which reads as zeros. Directories are files
+//! of entries that each have an inode number and a name.
+//!
+//! Volumes with features that change how files are found, like the extents of ext4, are refused. A clean ext3
+//! volume reads like an ext2 one, its journal is just another inode.
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::{
+    block::{cache::BlockCache, BlockDevice},
+    bytes::read_at,
+};
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::{convert::TryFrom, time::Duration};
+
+/// Blocks kept in the cache in front of the volume.
+const CACHE_BLOCKS: usize = 256;
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+/// The inode size of revision 0 volumes, which don't say.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Blocks are at most 64 KiB.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+/// Directory entries have their file type, so listing a directory doesn't read every inode.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Block groups' bitmaps and tables are packed together, which the descriptors say.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+/// The features that don't change how to read files. Others, like a journal that needs recovery, extents or
+/// 64 bit block numbers, do.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_LEVELS: usize = 3;
+/// Block numbers in an inode, the direct ones and one for each level of indirect blocks.
+const INODE_BLOCKS: usize = DIRECT_BLOCKS as usize + INDIRECT_LEVELS;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_FILE: u16 = 0x8000;
+
+/// The file types in directory entries.
+const DIR_TYPE_FILE: u8 = 1;
+const DIR_TYPE_DIRECTORY: u8 = 2;
+const DIR_TYPE_CHAR_DEVICE: u8 = 3;
+const DIR_TYPE_BLOCK_DEVICE: u8 = 4;
+/// The size of a directory entry before its name.
+const DIR_ENTRY_HEADER: usize = 8;
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    /// Read once, since `root` can't wait for the device.
+    root: RawInode,
+}
+
+struct Volume {
+    device: Arc<BlockCache>,
+    block_size: usize,
+    block_count: u32,
+    inode_size: usize,
+    inode_count: u32,
+    inodes_per_group: u32,
+    /// Where each block group's inode table starts.
+    inode_tables: Vec<u32>,
+    /// Set if directory entries have their file type.
+    entry_types: bool,
+}
+
+/// An inode as it is on disk, with what reading it takes.
+#[derive(Clone)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    modified: u32,
+    blocks: [u32; INODE_BLOCKS],
+}
+
+impl RawInode {
+    fn file_type(&self) -> Option<FileType> {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => Some(FileType::File),
+            MODE_DIRECTORY => Some(FileType::Directory),
+            MODE_CHAR_DEVICE => Some(FileType::CharDevice),
+            MODE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+            // the VFS has no symbolic links, FIFOs or sockets
+            _ => None,
+        }
+    }
+}
+
+/// A file, directory or device file.
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    raw: RawInode,
+    file_type: FileType,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and the block group descriptors of the ext2 volume on `device`. Needs the heap.
+    ///
+    /// Fails with `FsError::Unsupported` for volumes with incompatible features, and for blocks smaller than the
+    /// blocks of the device.
+    pub async fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2Fs, FsError> {
+        let device = BlockCache::new(device, CACHE_BLOCKS);
+        let device_block = device.block_size();
+        let mut start = vec![0; (SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE).max(device_block)];
+        if device.size() < start.len() as u64 {
+            return Err(FsError::Corrupt("no superblock"));
+        }
+        device.read_blocks(0, &mut start).await?;
+        let superblock = &start[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE];
+        let field = |offset| read_at::<u32>(superblock, offset).unwrap_or(0);
+        if read_at::<u16>(superblock, 56) != Some(MAGIC) {
+            return Err(FsError::Corrupt("no ext2 superblock"));
+        }
+        let inode_count = field(0);
+        let block_count = field(4);
+        let first_data_block = field(20);
+        let log_block_size = field(24);
+        let blocks_per_group = field(32);
+        let inodes_per_group = field(40);
+        let revision = field(76);
+        let incompat = if revision == 0 { 0 } else { field(96) };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if log_block_size > MAX_LOG_BLOCK_SIZE || blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(FsError::Corrupt("bad superblock"));
+        }
+        let block_size = 1024 << log_block_size;
+        if block_size % device_block != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let inode_size = match revision {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => read_at::<u16>(superblock, 88).map_or(0, usize::from),
+        };
+        if !inode_size.is_power_of_two() || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size) {
+            return Err(FsError::Corrupt("bad inode size"));
+        }
+        if u64::from(block_count) * block_size as u64 > device.size() {
+            return Err(FsError::Corrupt("volume larger than the device"));
+        }
+        let data_blocks = u64::from(block_count.saturating_sub(first_data_block));
+        let blocks_per_group = u64::from(blocks_per_group);
+        let groups = ((data_blocks + blocks_per_group - 1) / blocks_per_group) as usize;
+        if groups == 0 || (groups as u64) * u64::from(inodes_per_group) < u64::from(inode_count) {
+            return Err(FsError::Corrupt("bad superblock"));
+        }
+
+        let mut volume = Volume {
+            device,
+            block_size,
+            block_count,
+            inode_size,
+            inode_count,
+            inodes_per_group,
+            inode_tables: Vec::with_capacity(groups),
+            entry_types: incompat & INCOMPAT_FILETYPE != 0,
+        };
+        // the descriptors start in the block after the superblock's
+        let table_blocks = (groups * GROUP_DESCRIPTOR_SIZE + block_size - 1) / block_size;
+        let mut descriptors = vec![0; table_blocks * block_size];
+        for (i, block) in descriptors.chunks_exact_mut(block_size).enumerate() {
+            volume.read_block(first_data_block + 1 + i as u32, block).await?;
+        }
+        for descriptor in descriptors.chunks_exact(GROUP_DESCRIPTOR_SIZE).take(groups) {
+            volume.inode_tables.push(read_at(descriptor, 8).unwrap_or(0));
+        }
+        let volume = Arc::new(volume);
+        let root = volume.read_inode(ROOT_INODE).await?;
+        if root.file_type() != Some(FileType::Directory) {
+            return Err(FsError::Corrupt("root is not a directory"));
+        }
+        Ok(Ext2Fs { volume, root })
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn block_size(&self) -> usize {
+        self.volume.block_size
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let volume = self.volume.clone();
+        Arc::new(Ext2Inode { volume, number: ROOT_INODE, raw: self.root.clone(), file_type: FileType::Directory })
+    }
+}
+
+impl Volume {
+    /// Reads block `number` of the volume into `buf`, which is one block long.
+    async fn read_block(&self, number: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if number >= self.block_count {
+            return Err(FsError::Corrupt("block outside the volume"));
+        }
+        let device_blocks = (self.block_size / self.device.block_size()) as u64;
+        Ok(self.device.read_blocks(u64::from(number) * device_blocks, buf).await?)
+    }
+
+    async fn read_inode(&self, number: u32) -> Result<RawInode, FsError> {
+        if number == 0 || number > self.inode_count {
+            return Err(FsError::Corrupt("inode number out of range"));
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = ((number - 1) % self.inodes_per_group) as usize;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt("inode outside the block groups"))?;
+        let offset = index * self.inode_size;
+        let mut block = vec![0; self.block_size];
+        let table_block = u32::try_from(offset / self.block_size).map_err(|_| FsError::Corrupt("bad inode table"))?;
+        self.read_block(table.saturating_add(table_block), &mut block).await?;
+        let inode = &block[offset % self.block_size..][..self.inode_size];
+        let field = |offset| read_at::<u32>(inode, offset).unwrap_or(0);
+        let mode = read_at::<u16>(inode, 0).unwrap_or(0);
+        let mut size = u64::from(field(4));
+        if mode & MODE_TYPE_MASK == MODE_FILE {
+            // the high half of the size of files, where revision 0 had the ACL of directories
+            size |= u64::from(field(108)) << 32;
+        }
+        let mut blocks = [0; INODE_BLOCKS];
+        for (i, number) in blocks.iter_mut().enumerate() {
+            *number = field(40 + i * 4);
+        }
+        Ok(RawInode { mode, size, modified: field(16), blocks })
+    }
+
+    /// Returns the number of the block at `index` in the file of `inode`, 0 for a hole.
+    async fn file_block(&self, inode: &RawInode, index: u64) -> Result<u32, FsError> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let per_block = (self.block_size / 4) as u64;
+        let mut index = index - DIRECT_BLOCKS;
+        // how many blocks of the file the block number at the top of each level covers
+        let mut span = 1;
+        for level in 0..INDIRECT_LEVELS {
+            span *= per_block;
+            if index < span {
+                let mut block = inode.blocks[DIRECT_BLOCKS as usize + level];
+                let mut span = span;
+                for _ in 0..=level {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    span /= per_block;
+                    block = self.block_pointer(block, (index / span) as usize).await?;
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+        }
+        Err(FsError::Corrupt("file past its last indirect block"))
+    }
+
+    /// Returns the block number at `index` in the indirect block `block`.
+    async fn block_pointer(&self, block: u32, index: usize) -> Result<u32, FsError> {
+        let mut data = vec![0; self.block_size];
+        self.read_block(block, &mut data).await?;
+        read_at(&data, index * 4).ok_or(FsError::Corrupt("bad indirect block"))
+    }
+
+    /// Reads `buf.len()` bytes of the file of `inode` from `offset` on, which it must have.
+    async fn read_file(&self, inode: &RawInode, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.block_size as u64;
+        let mut block = vec![0; self.block_size];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let len = (buf.len() - done).min(self.block_size - start);
+            match self.file_block(inode, position / block_size).await? {
+                0 => block.fill(0),
+                number => self.read_block(number, &mut block).await?,
+            }
+            buf[done..done + len].copy_from_slice(&block[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of the directory `inode` with their inode numbers, leaving out `.` and `..`.
+    async fn read_dir(&self, inode: &RawInode) -> Result<Vec<(String, u32, Option<u8>)>, FsError> {
+        // read at once, so a corrupt size must not claim more memory than the volume has
+        if inode.size > u64::from(self.block_count) * self.block_size as u64 {
+            return Err(FsError::Corrupt("directory larger than the volume"));
+        }
+        let mut data = vec![0; inode.size as usize];
+        self.read_file(inode, 0, &mut data).await?;
+        let mut entries = Vec::new();
+        for block in data.chunks(self.block_size) {
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= block.len() {
+                let entry = &block[offset..];
+                let number = read_at::<u32>(entry, 0).unwrap_or(0);
+                let record_len = read_at::<u16>(entry, 4).map_or(0, usize::from);
+                // volumes without file types in their entries have names of up to 65535 bytes, in theory
+                let name_len = if self.entry_types {
+                    usize::from(entry[6])
+                } else {
+                    read_at::<u16>(entry, 6).map_or(0, usize::from)
+                };
+                if record_len < DIR_ENTRY_HEADER + name_len || record_len > entry.len() {
+                    return Err(FsError::Corrupt("bad directory entry"));
+                }
+                let name = &entry[DIR_ENTRY_HEADER..DIR_ENTRY_HEADER + name_len];
+                // unused entries have inode 0
+                if number != 0 && name != b"." && name != b".." {
+                    let name = core::str::from_utf8(name).map_err(|_| FsError::Corrupt("name not UTF-8"))?;
+                    entries.push((name.into(), number, Some(entry[7]).filter(|_| self.entry_types)));
+                }
+                offset += record_len;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Ext2Inode {
+    fn new(volume: &Arc<Volume>, number: u32, raw: RawInode) -> Result<Ext2Inode, FsError> {
+        let file_type = raw.file_type().ok_or(FsError::Unsupported)?;
+        Ok(Ext2Inode { volume: volume.clone(), number, raw, file_type })
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let size = match self.file_type {
+            FileType::File | FileType::Directory => self.raw.size,
+            _ => 0,
+        };
+        Metadata {
+            file_type: self.file_type,
+            size,
+            id: u64::from(self.number),
+            modified: Some(Duration::from_secs(u64::from(self.raw.modified))),
+        }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        match self.file_type {
+            FileType::File => Some(self),
+            _ => None,
+        }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        match self.file_type {
+            FileType::Directory => Some(self),
+            _ => None,
+        }
+    }
+}
+
+impl File for Ext2Inode {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            if offset >= self.raw.size || buf.is_empty() {
+                return Ok(0);
+            }
+            let len = buf.len().min((self.raw.size - offset) as usize);
+            self.volume.read_file(&self.raw, offset, &mut buf[..len]).await?;
+            Ok(len)
+        })
+    }
+}
+
+impl Dir for Ext2Inode {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let entries = self.volume.read_dir(&self.raw).await?;
+            let &(_, number, _) = entries.iter().find(|(entry, ..)| entry == name).ok_or(FsError::NotFound)?;
+            let raw = self.volume.read_inode(number).await?;
+            Ok(Arc::new(Ext2Inode::new(&self.volume, number, raw)?) as Arc<dyn Inode>)
+        })
+    }
+
+    /// Lists the entries the VFS has a file type for, leaving out symbolic links, FIFOs and sockets.
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            for (name, number, entry_type) in self.volume.read_dir(&self.raw).await? {
+                let file_type = match entry_type {
+                    Some(DIR_TYPE_FILE) => Some(FileType::File),
+                    Some(DIR_TYPE_DIRECTORY) => Some(FileType::Directory),
+                    Some(DIR_TYPE_CHAR_DEVICE) => Some(FileType::CharDevice),
+                    Some(DIR_TYPE_BLOCK_DEVICE) => Some(FileType::BlockDevice),
+                    Some(_) => None,
+                    None => self.volume.read_inode(number).await?.file_type(),
+                };
+                if let Some(file_type) = file_type {
+                    entries.push(DirEntry { name, file_type });
+                }
+            }
+            Ok(entries)
+        })
+    }
+}
@@ -0,0 +1,476 @@
+//! FAT32, as `mkfs.fat -F 32` and every other tool makes it.
+//!
+//! The volume starts with the BPB, which says where the FATs and the clusters are. A file or directory is a chain
+//! of clusters, each FAT entry holding the number of the cluster after its own. Directories hold 32 byte entries
+//! with the short 8.3 name, the first cluster and the size of each file, and long names in entries of up to 13
+//! UTF-16 characters right before the short one. Names are looked up ignoring ASCII case, as Windows does.
+//!
+//! Writes take free clusters from where the last allocation left off and chain them on in every copy of the FAT.
+//! The FSInfo sector keeps the count of free clusters, which `sync` writes back. The volume goes through a
+//! `BlockCache` of its own, since every step along a cluster chain reads the FAT.
+
+use super::{FileSystem, FsError, FsFuture, Inode};
+use crate::{
+    block::{cache::BlockCache, BlockDevice},
+    bytes::read_at,
+    task::sync::Mutex,
+};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::ops::Range;
+
+mod dir;
+mod file;
+
+use dir::{FatDir, RawEntry};
+use file::FatFile;
+
+/// Blocks kept in the cache in front of the volume.
+const CACHE_BLOCKS: usize = 256;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// The low 28 bits of a FAT entry are the cluster number, the rest is reserved.
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_BAD_CLUSTER: u32 = 0x0fff_fff7;
+/// Entries from this one on end a chain.
+const FAT_END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// What new chains are ended with.
+const FAT_END_MARK: u32 = 0x0fff_ffff;
+/// The number of the first cluster, the ones before it stand for the FAT's own first entries.
+const FIRST_CLUSTER: u32 = 2;
+/// Set in the extended flags of the BPB if only the FAT in the low bits is used, not all of them.
+const EXT_FLAGS_NO_MIRRORING: u16 = 1 << 7;
+/// Bytes of the FAT read at once when counting free clusters.
+const FAT_SCAN_CHUNK: usize = 64 * 1024;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+/// What the FSInfo sector says for a count or a cluster it doesn't know.
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// A mounted FAT32 volume.
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+/// Where the parts of the volume are, in bytes from its start.
+struct Volume {
+    device: Arc<BlockCache>,
+    sector_size: usize,
+    cluster_size: u64,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u32,
+    /// The only FAT in use, if the volume doesn't keep the others the same.
+    active_fat: Option<u32>,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    /// Where the FSInfo sector is, if the volume has one.
+    fsinfo: Option<u64>,
+    /// Held while the FAT or a directory changes.
+    allocation: Mutex<Allocation>,
+    /// The files that are in use, by where their entries are, so that every lookup shares one size and chain.
+    files: spin::Mutex<BTreeMap<Location, Weak<FatFile>>>,
+}
+
+/// What the FSInfo sector says, kept up to date as clusters are allocated and freed.
+struct Allocation {
+    free_clusters: u32,
+    /// Where the search for free clusters starts.
+    next_free: u32,
+    /// Set if the FSInfo sector needs writing.
+    dirty: bool,
+}
+
+/// Where the short entry of a file is: the first cluster of its directory and the offset in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    dir: u32,
+    offset: u64,
+}
+
+impl FatFs {
+    /// Reads the boot sector of the FAT32 volume on `device`, and counts its free clusters if the FSInfo sector
+    /// doesn't know how many there are. Needs the heap.
+    ///
+    /// Fails with `FsError::Unsupported` for FAT12 and FAT16 volumes, and for sectors smaller than the blocks of
+    /// the device. The volume is read-only if the device is.
+    pub async fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        let device = BlockCache::new(device, CACHE_BLOCKS);
+        let mut boot = vec![0; device.block_size().max(512)];
+        if device.size() < boot.len() as u64 {
+            return Err(FsError::Corrupt("no boot sector"));
+        }
+        device.read_blocks(0, &mut boot).await?;
+        if boot[510..512] != BOOT_SIGNATURE {
+            return Err(FsError::Corrupt("no boot sector signature"));
+        }
+        let bpb = |offset| read_at::<u16>(&boot, offset).map(u32::from).unwrap_or(0);
+        let bytes_per_sector = bpb(11);
+        let sectors_per_cluster = u32::from(boot[13]);
+        let reserved_sectors = bpb(14);
+        let fat_count = u32::from(boot[16]);
+        let root_entry_count = bpb(17);
+        let total_sectors = match bpb(19) {
+            0 => read_at::<u32>(&boot, 32).unwrap_or(0),
+            sectors => sectors,
+        };
+        let fat_sectors = read_at::<u32>(&boot, 36).unwrap_or(0);
+        let ext_flags = bpb(40) as u16;
+        let root_cluster = read_at::<u32>(&boot, 44).unwrap_or(0);
+        let fsinfo_sector = bpb(48);
+        if bpb(22) != 0 || root_entry_count != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::Corrupt("bad BPB"));
+        }
+        if (bytes_per_sector as usize) < device.block_size() {
+            return Err(FsError::Unsupported);
+        }
+        let sector = u64::from(bytes_per_sector);
+        let data_sector = u64::from(reserved_sectors) + u64::from(fat_count) * u64::from(fat_sectors);
+        let data_sectors = u64::from(total_sectors).checked_sub(data_sector).ok_or(FsError::Corrupt("bad BPB"))?;
+        // the FAT must have an entry for every cluster
+        let cluster_count = (data_sectors / u64::from(sectors_per_cluster))
+            .min(u64::from(fat_sectors) * sector / 4 - u64::from(FIRST_CLUSTER)) as u32;
+        if u64::from(total_sectors) * sector > device.size() {
+            return Err(FsError::Corrupt("volume larger than the device"));
+        }
+        let active_fat = Some(u32::from(ext_flags & 0xf)).filter(|_| ext_flags & EXT_FLAGS_NO_MIRRORING != 0);
+        if active_fat.map_or(false, |active| active >= fat_count) {
+            return Err(FsError::Corrupt("active FAT out of range"));
+        }
+        let mut volume = Volume {
+            device,
+            sector_size: bytes_per_sector as usize,
+            cluster_size: sector * u64::from(sectors_per_cluster),
+            fat_start: u64::from(reserved_sectors) * sector,
+            fat_size: u64::from(fat_sectors) * sector,
+            fat_count,
+            active_fat,
+            data_start: data_sector * sector,
+            cluster_count,
+            root_cluster,
+            fsinfo: Some(u64::from(fsinfo_sector) * sector)
+                .filter(|_| fsinfo_sector != 0 && fsinfo_sector < reserved_sectors),
+            allocation: Mutex::new(Allocation { free_clusters: 0, next_free: FIRST_CLUSTER, dirty: false }),
+            files: spin::Mutex::new(BTreeMap::new()),
+        };
+        if !volume.is_cluster(root_cluster) {
+            return Err(FsError::Corrupt("root directory outside the volume"));
+        }
+        let allocation = volume.read_fsinfo().await?;
+        *volume.allocation.get_mut() = allocation;
+        Ok(FatFs { volume: Arc::new(volume) })
+    }
+
+    /// Returns the size of a cluster in bytes, the unit files take up space in.
+    pub fn cluster_size(&self) -> u64 {
+        self.volume.cluster_size
+    }
+
+    /// Returns the number of clusters that are free.
+    pub async fn free_clusters(&self) -> u32 {
+        self.volume.allocation.lock().await.free_clusters
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatDir::new(self.volume.clone(), self.volume.root_cluster, None))
+    }
+
+    /// Writes the FSInfo sector and everything in the cache out.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let mut allocation = self.volume.allocation.lock().await;
+            if allocation.dirty && !self.volume.device.is_read_only() {
+                self.volume.write_fsinfo(&allocation).await?;
+                allocation.dirty = false;
+            }
+            Ok(self.volume.device.flush().await?)
+        })
+    }
+}
+
+impl Volume {
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.cluster_size
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.device.is_read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads `buf.len()` bytes from `offset` on, which need not be whole blocks.
+    async fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.device.block_size() as u64;
+        if offset % block_size == 0 && buf.len() as u64 % block_size == 0 {
+            return Ok(self.device.read_blocks(offset / block_size, buf).await?);
+        }
+        let first = offset / block_size;
+        let end = (offset + buf.len() as u64 + block_size - 1) / block_size;
+        let mut blocks = vec![0; ((end - first) * block_size) as usize];
+        self.device.read_blocks(first, &mut blocks).await?;
+        let start = (offset - first * block_size) as usize;
+        buf.copy_from_slice(&blocks[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes `buf` at `offset`, reading the blocks it only covers in part first.
+    async fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block_size = self.device.block_size() as u64;
+        if offset % block_size == 0 && buf.len() as u64 % block_size == 0 {
+            return Ok(self.device.write_blocks(offset / block_size, buf).await?);
+        }
+        let first = offset / block_size;
+        let end = (offset + buf.len() as u64 + block_size - 1) / block_size;
+        let mut blocks = vec![0; ((end - first) * block_size) as usize];
+        self.device.read_blocks(first, &mut blocks).await?;
+        let start = (offset - first * block_size) as usize;
+        blocks[start..start + buf.len()].copy_from_slice(buf);
+        Ok(self.device.write_blocks(first, &blocks).await?)
+    }
+
+    /// Returns where the FAT that is read starts.
+    fn fat_offset(&self) -> u64 {
+        self.fat_start + u64::from(self.active_fat.unwrap_or(0)) * self.fat_size
+    }
+
+    /// Returns the FAT entry of `cluster`, the next cluster of its chain or a marker.
+    async fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let mut entry = [0; 4];
+        self.read_bytes(self.fat_offset() + u64::from(cluster) * 4, &mut entry).await?;
+        Ok(u32::from_le_bytes(entry) & FAT_ENTRY_MASK)
+    }
+
+    /// Sets the FAT entry of `cluster` in every FAT in use, keeping the reserved bits.
+    async fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.fat_count,
+        };
+        for copy in copies {
+            let offset = self.fat_start + u64::from(copy) * self.fat_size + u64::from(cluster) * 4;
+            let mut entry = [0; 4];
+            self.read_bytes(offset, &mut entry).await?;
+            let entry = (u32::from_le_bytes(entry) & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK);
+            self.write_bytes(offset, &entry.to_le_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the clusters of the chain starting at `first`, none for 0, which empty files have.
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            if !self.is_cluster(cluster) {
+                return Err(FsError::Corrupt("cluster chain leaves the volume"));
+            }
+            if chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupt("cluster chain loops"));
+            }
+            chain.push(cluster);
+            cluster = match self.fat_entry(cluster).await? {
+                next if next >= FAT_END_OF_CHAIN => 0,
+                FAT_BAD_CLUSTER | FAT_FREE => {
+                    return Err(FsError::Corrupt("cluster chain ends in a free or bad cluster"));
+                }
+                next => next,
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Splits the `len` bytes from `offset` on in the clusters of `chain` into pieces that don't cross a gap
+    /// between clusters, each with where it starts on the volume and its range in the buffer.
+    fn pieces(&self, chain: &[u32], offset: u64, len: usize) -> Vec<(u64, Range<usize>)> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let index = (position / self.cluster_size) as usize;
+            // clusters that follow each other on disk are transferred at once
+            let run = chain[index..].iter().enumerate().take_while(|&(i, &cluster)| cluster == chain[index] + i as u32);
+            let run_bytes = run.count() as u64 * self.cluster_size - position % self.cluster_size;
+            let piece = (len - done).min(run_bytes as usize);
+            pieces.push((self.cluster_offset(chain[index]) + position % self.cluster_size, done..done + piece));
+            done += piece;
+        }
+        pieces
+    }
+
+    /// Reads `buf.len()` bytes from `offset` on in the clusters of `chain`, which must have them.
+    async fn read_chain(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        for (start, range) in self.pieces(chain, offset, buf.len()) {
+            self.read_bytes(start, &mut buf[range]).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` from `offset` on in the clusters of `chain`, which must have room for it.
+    async fn write_chain(&self, chain: &[u32], offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        for (start, range) in self.pieces(chain, offset, buf.len()) {
+            self.write_bytes(start, &buf[range]).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes `count` free clusters, zeroes them and chains them together and onto `tail`, the last cluster of
+    /// the chain they extend, if any.
+    async fn allocate(
+        &self,
+        allocation: &mut Allocation,
+        count: usize,
+        tail: Option<u32>,
+    ) -> Result<Vec<u32>, FsError> {
+        if count as u64 > u64::from(allocation.free_clusters) {
+            return Err(FsError::NoSpace);
+        }
+        let mut clusters = Vec::with_capacity(count);
+        let mut cluster = allocation.next_free;
+        for _ in 0..self.cluster_count {
+            if clusters.len() == count {
+                break;
+            }
+            if !self.is_cluster(cluster) {
+                cluster = FIRST_CLUSTER;
+            }
+            if self.fat_entry(cluster).await? == FAT_FREE {
+                clusters.push(cluster);
+            }
+            cluster += 1;
+        }
+        if clusters.len() < count {
+            // the count was off, now it is right
+            allocation.free_clusters = clusters.len() as u32;
+            allocation.dirty = true;
+            return Err(FsError::NoSpace);
+        }
+        let zeros = vec![0; self.cluster_size as usize];
+        for (index, &cluster) in clusters.iter().enumerate() {
+            self.write_bytes(self.cluster_offset(cluster), &zeros).await?;
+            self.set_fat_entry(cluster, clusters.get(index + 1).copied().unwrap_or(FAT_END_MARK)).await?;
+        }
+        if let (Some(tail), Some(&first)) = (tail, clusters.first()) {
+            self.set_fat_entry(tail, first).await?;
+        }
+        allocation.free_clusters -= count as u32;
+        allocation.next_free = cluster;
+        allocation.dirty = true;
+        Ok(clusters)
+    }
+
+    /// Marks `clusters` free.
+    async fn free(&self, allocation: &mut Allocation, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FAT_FREE).await?;
+        }
+        allocation.free_clusters = (allocation.free_clusters + clusters.len() as u32).min(self.cluster_count);
+        allocation.dirty = true;
+        Ok(())
+    }
+
+    /// Reads the FSInfo sector, counting the free clusters if it doesn't know them and the volume is writable.
+    async fn read_fsinfo(&self) -> Result<Allocation, FsError> {
+        let mut free_clusters = FSINFO_UNKNOWN;
+        let mut next_free = FSINFO_UNKNOWN;
+        if let Some(offset) = self.fsinfo {
+            let mut sector = vec![0; self.sector_size];
+            self.read_bytes(offset, &mut sector).await?;
+            if read_at::<u32>(&sector, 0) == Some(FSINFO_LEAD_SIGNATURE)
+                && read_at::<u32>(&sector, 484) == Some(FSINFO_STRUCT_SIGNATURE)
+            {
+                free_clusters = read_at(&sector, FSINFO_FREE_COUNT).unwrap_or(FSINFO_UNKNOWN);
+                next_free = read_at(&sector, FSINFO_NEXT_FREE).unwrap_or(FSINFO_UNKNOWN);
+            }
+        }
+        let mut dirty = false;
+        if free_clusters > self.cluster_count && !self.device.is_read_only() {
+            free_clusters = self.count_free().await?;
+            dirty = true;
+        }
+        Ok(Allocation {
+            free_clusters: free_clusters.min(self.cluster_count),
+            next_free: if self.is_cluster(next_free) { next_free } else { FIRST_CLUSTER },
+            dirty,
+        })
+    }
+
+    async fn write_fsinfo(&self, allocation: &Allocation) -> Result<(), FsError> {
+        let offset = match self.fsinfo {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        let mut sector = vec![0; self.sector_size];
+        self.read_bytes(offset, &mut sector).await?;
+        if read_at::<u32>(&sector, 0) != Some(FSINFO_LEAD_SIGNATURE) {
+            return Ok(());
+        }
+        sector[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4].copy_from_slice(&allocation.free_clusters.to_le_bytes());
+        sector[FSINFO_NEXT_FREE..FSINFO_NEXT_FREE + 4].copy_from_slice(&allocation.next_free.to_le_bytes());
+        self.write_bytes(offset, &sector).await
+    }
+
+    /// Counts the free entries in the FAT.
+    async fn count_free(&self) -> Result<u32, FsError> {
+        let mut chunk = vec![0; FAT_SCAN_CHUNK];
+        let end = u64::from(FIRST_CLUSTER + self.cluster_count) * 4;
+        let mut position = u64::from(FIRST_CLUSTER) * 4;
+        let mut free = 0;
+        while position < end {
+            let len = (end - position).min(FAT_SCAN_CHUNK as u64) as usize;
+            self.read_bytes(self.fat_offset() + position, &mut chunk[..len]).await?;
+            free += chunk[..len]
+                .chunks_exact(4)
+                .filter(|entry| read_at::<u32>(entry, 0).map_or(false, |entry| entry & FAT_ENTRY_MASK == FAT_FREE))
+                .count();
+            position += len as u64;
+        }
+        Ok(free as u32)
+    }
+
+    /// Returns the inode for `entry` of the directory starting at cluster `dir`.
+    fn inode(self: &Arc<Self>, dir: u32, entry: &RawEntry) -> Arc<dyn Inode> {
+        if entry.is_dir() {
+            return Arc::new(FatDir::new(self.clone(), entry.first_cluster, entry.modified));
+        }
+        let location = Location { dir, offset: entry.offset };
+        let mut files = self.files.lock();
+        if let Some(file) = files.get(&location).and_then(Weak::upgrade) {
+            return file;
+        }
+        files.retain(|_, file| file.strong_count() > 0);
+        let file = Arc::new(FatFile::new(self.clone(), location, entry));
+        files.insert(location, Arc::downgrade(&file));
+        file
+    }
+}
@@ -0,0 +1,545 @@
+//! Directories: their entries, the long and short names in them, and creating and removing entries.
+
+use super::{Location, Volume};
+use crate::{
+    bytes::read_at,
+    time::{self, rtc::DateTime},
+    vfs::{Dir, DirEntry, FileType, FsError, FsFuture, Inode, Metadata},
+};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{convert::TryFrom, mem, time::Duration};
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// Directories can't have more entries than this.
+const MAX_DIR_ENTRIES: usize = 65536;
+/// The first name byte of the entry after the last one of a directory.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands for a first name byte of 0xe5, which would look deleted.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of a long name entry, which no short entry has.
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// Bits of the reserved byte of short entries that Windows uses for names in lower case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+/// Marks the long name entry that comes first, which holds the end of the name.
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDER_MASK: u8 = 0x1f;
+const LFN_CHARS: usize = 13;
+/// The offsets of the three runs of characters in a long name entry, and how many each holds.
+const LFN_RUNS: [(usize, usize); 3] = [(1, 5), (14, 6), (28, 2)];
+/// Pads the last part of a long name after its terminating 0.
+const LFN_PADDING: u16 = 0xffff;
+
+/// The characters other than letters and digits short names may have.
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters no name may have.
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+
+/// An entry of a directory, with its long name if it has one.
+pub(super) struct RawEntry {
+    pub(super) name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    pub(super) first_cluster: u32,
+    pub(super) size: u32,
+    pub(super) modified: Option<Duration>,
+    /// Where the short entry is in the directory.
+    pub(super) offset: u64,
+    /// Where the first of its long name entries is, the offset of the short entry without a long name.
+    first_offset: u64,
+}
+
+impl RawEntry {
+    pub(super) fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    fn file_type(&self) -> FileType {
+        if self.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+}
+
+/// Returns the entries in the directory `data`, leaving out `.`, `..`, the volume label and deleted entries.
+fn parse_dir(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long_name = LongName::default();
+    for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        let offset = (index * DIR_ENTRY_SIZE) as u64;
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name = LongName::default();
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = entry[11];
+        if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            long_name.add(entry, offset);
+            continue;
+        }
+        let pending = mem::take(&mut long_name);
+        if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&entry[..11]);
+        let (name, first_offset) = match pending.finish(&short_name) {
+            Some(long) => long,
+            None => (format_short_name(&short_name, entry[12]), offset),
+        };
+        let field = |offset| read_at::<u16>(entry, offset).unwrap_or(0);
+        entries.push(RawEntry {
+            name,
+            short_name,
+            attributes,
+            first_cluster: u32::from(field(20)) << 16 | u32::from(field(26)),
+            size: read_at::<u32>(entry, 28).unwrap_or(0),
+            modified: timestamp(field(24), field(22)),
+            offset,
+            first_offset,
+        });
+    }
+    entries
+}
+
+/// Returns where `count` free entries in a row start in the directory `data`, which may be at its end or past
+/// it if the directory needs to grow.
+fn free_entries(data: &[u8], count: usize) -> usize {
+    let mut run_start = None;
+    for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        match entry[0] {
+            // everything from the end on is free
+            ENTRY_END => return run_start.unwrap_or(index) * DIR_ENTRY_SIZE,
+            ENTRY_DELETED => {
+                let start = *run_start.get_or_insert(index);
+                if index + 1 - start == count {
+                    return start * DIR_ENTRY_SIZE;
+                }
+            }
+            _ => run_start = None,
+        }
+    }
+    run_start.map_or(data.len(), |start| start * DIR_ENTRY_SIZE)
+}
+
+/// The parts of a long name collected so far.
+#[derive(Default)]
+struct LongName {
+    chars: Vec<u16>,
+    /// The order number of the part that should come next, 0 once all came.
+    next: u8,
+    checksum: u8,
+    /// Where the first part is in the directory.
+    offset: u64,
+    /// Set if a part was missing or came out of order.
+    broken: bool,
+}
+
+impl LongName {
+    fn add(&mut self, entry: &[u8], offset: u64) {
+        let order = entry[0] & LFN_ORDER_MASK;
+        if entry[0] & LFN_LAST != 0 {
+            // entries are stored last part first
+            *self = LongName {
+                chars: vec![LFN_PADDING; usize::from(order) * LFN_CHARS],
+                next: order,
+                checksum: entry[13],
+                offset,
+                broken: false,
+            };
+        }
+        if order == 0 || order != self.next || entry[13] != self.checksum {
+            self.broken = true;
+            return;
+        }
+        let mut position = usize::from(order - 1) * LFN_CHARS;
+        for &(offset, count) in LFN_RUNS.iter() {
+            for i in 0..count {
+                self.chars[position] = read_at::<u16>(entry, offset + i * 2).unwrap_or(0);
+                position += 1;
+            }
+        }
+        self.next -= 1;
+    }
+
+    /// Returns the long name and where it starts if all of it came right before the short entry `short_name`.
+    fn finish(self, short_name: &[u8; 11]) -> Option<(String, u64)> {
+        if self.chars.is_empty() || self.broken || self.next != 0 || self.checksum != checksum(short_name) {
+            return None;
+        }
+        let chars = self.chars.into_iter().take_while(|&c| c != 0 && c != LFN_PADDING);
+        let name = char::decode_utf16(chars).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+        Some((name, self.offset))
+    }
+}
+
+/// Returns the long name entries for `name` in the order they are stored, last part first.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.len() % LFN_CHARS != 0 {
+        chars.push(0);
+    }
+    while chars.len() % LFN_CHARS != 0 {
+        chars.push(LFN_PADDING);
+    }
+    let parts = chars.len() / LFN_CHARS;
+    (0..parts)
+        .rev()
+        .map(|part| {
+            let mut entry = [0; DIR_ENTRY_SIZE];
+            entry[0] = (part + 1) as u8 | if part + 1 == parts { LFN_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let mut chars = chars[part * LFN_CHARS..(part + 1) * LFN_CHARS].iter();
+            for &(offset, count) in LFN_RUNS.iter() {
+                for (i, &c) in chars.by_ref().take(count).enumerate() {
+                    entry[offset + i * 2..offset + i * 2 + 2].copy_from_slice(&c.to_le_bytes());
+                }
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Returns a short entry named `short_name`, last modified `now`.
+fn short_entry(short_name: &[u8; 11], case: u8, attributes: u8, first_cluster: u32, now: Duration) -> [u8; 32] {
+    let (date, time) = encode_timestamp(now);
+    let mut entry = [0; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = attributes;
+    entry[12] = case;
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    set_first_cluster(&mut entry, first_cluster);
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry
+}
+
+/// Sets the first cluster, size and modification time of the short entry `entry`.
+pub(super) fn update_entry(entry: &mut [u8], first_cluster: u32, size: u32, now: Duration) {
+    let (date, time) = encode_timestamp(now);
+    set_first_cluster(entry, first_cluster);
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// The checksum of a short name that its long name entries carry.
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Turns the padded `NAME    EXT` of a short entry into `NAME.EXT`, in lower case where `case` says so.
+fn format_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let mut part: String = bytes.iter().map(|&byte| char::from(byte)).collect();
+        part.truncate(part.trim_end_matches(' ').len());
+        if lower {
+            part.make_ascii_lowercase();
+        }
+        part
+    };
+    let mut base = short_name[..8].to_vec();
+    if base[0] == ENTRY_KANJI_E5 {
+        base[0] = ENTRY_DELETED;
+    }
+    let mut name = part(&base, case & CASE_LOWER_BASE != 0);
+    let extension = part(&short_name[8..], case & CASE_LOWER_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Fails with `FsError::InvalidPath` for names FAT can't store.
+fn check_name(name: &str) -> Result<(), FsError> {
+    let forbidden = |c: char| c < ' ' || FORBIDDEN.contains(c);
+    if name.is_empty() || name.encode_utf16().count() > 255 || name.ends_with(['.', ' ']) || name.contains(forbidden) {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// Returns the byte for `c` in a short name, in upper case, if short names may have it.
+fn short_char(c: char) -> Option<u8> {
+    let byte = u8::try_from(c).ok().filter(u8::is_ascii)?;
+    if byte.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&byte) {
+        Some(byte.to_ascii_uppercase())
+    } else {
+        None
+    }
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    }
+}
+
+/// Returns the short name that stands for `name` on its own, with the case bits for it, if there is one.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = split_extension(name);
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || base.contains('.') {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    for (part, range, lower) in [(base, 0..8, CASE_LOWER_BASE), (extension, 8..11, CASE_LOWER_EXTENSION)] {
+        // a part in mixed case needs a long name
+        if part.contains(|c: char| c.is_ascii_lowercase()) {
+            if part.contains(|c: char| c.is_ascii_uppercase()) {
+                return None;
+            }
+            case |= lower;
+        }
+        for (slot, c) in short_name[range].iter_mut().zip(part.chars()) {
+            *slot = short_char(c)?;
+        }
+    }
+    Some((short_name, case))
+}
+
+/// Makes up a short name like `LONGFI~1.TXT` for `name` that no entry of `entries` has.
+fn numbered_short_name(name: &str, entries: &[RawEntry]) -> Result<[u8; 11], FsError> {
+    let (base, extension) = split_extension(name);
+    let clean = |part: &str, len: usize| -> Vec<u8> {
+        part.chars().filter(|&c| c != ' ' && c != '.').map(|c| short_char(c).unwrap_or(b'_')).take(len).collect()
+    };
+    let base = clean(base, 8);
+    let extension = clean(extension, 3);
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let mut short_name = [b' '; 11];
+        let keep = base.len().min(8 - tail.len());
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + extension.len()].copy_from_slice(&extension);
+        if !entries.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+/// Converts the date and time fields of an entry, counting from 1980 in local time, taken here as UTC.
+fn timestamp(date: u16, time: u16) -> Option<Duration> {
+    if date == 0 {
+        return None;
+    }
+    let date_time = DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3f) as u8,
+        second: ((time & 0x1f) * 2) as u8,
+    };
+    Some(Duration::from_secs(date_time.unix_timestamp()))
+}
+
+/// Returns the date and time fields for `time` since the Unix epoch, which are limited to 1980 to 2107.
+fn encode_timestamp(time: Duration) -> (u16, u16) {
+    let date_time = DateTime::from_unix_timestamp(time.as_secs());
+    if date_time.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let year = (date_time.year - 1980).min(127);
+    let date = year << 9 | u16::from(date_time.month) << 5 | u16::from(date_time.day);
+    let time = u16::from(date_time.hour) << 11 | u16::from(date_time.minute) << 5 | u16::from(date_time.second / 2);
+    (date, time)
+}
+
+pub(super) struct FatDir {
+    volume: Arc<Volume>,
+    first_cluster: u32,
+    modified: Option<Duration>,
+}
+
+impl FatDir {
+    pub(super) fn new(volume: Arc<Volume>, first_cluster: u32, modified: Option<Duration>) -> FatDir {
+        FatDir { volume, first_cluster, modified }
+    }
+
+    /// Returns the clusters of the directory and what is in them.
+    async fn read(&self) -> Result<(Vec<u32>, Vec<u8>), FsError> {
+        let chain = self.volume.chain(self.first_cluster).await?;
+        let mut data = vec![0; chain.len() * self.volume.cluster_size as usize];
+        self.volume.read_chain(&chain, 0, &mut data).await?;
+        Ok((chain, data))
+    }
+
+    async fn read_entries(&self) -> Result<Vec<RawEntry>, FsError> {
+        let (_, data) = self.read().await?;
+        Ok(parse_dir(&data))
+    }
+
+    /// Writes the `.` and `..` entries into the new directory starting at `cluster`.
+    async fn write_dot_entries(&self, cluster: u32, now: Duration) -> Result<(), FsError> {
+        // `..` of a directory in the root says 0, not the root's cluster
+        let parent = if self.first_cluster == self.volume.root_cluster { 0 } else { self.first_cluster };
+        let mut entries = [0; 2 * DIR_ENTRY_SIZE];
+        entries[..DIR_ENTRY_SIZE].copy_from_slice(&short_entry(b".          ", 0, ATTR_DIRECTORY, cluster, now));
+        entries[DIR_ENTRY_SIZE..].copy_from_slice(&short_entry(b"..         ", 0, ATTR_DIRECTORY, parent, now));
+        self.volume.write_bytes(self.volume.cluster_offset(cluster), &entries).await
+    }
+}
+
+impl Inode for FatDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::Directory, size: 0, id: u64::from(self.first_cluster), modified: self.modified }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        Some(self)
+    }
+}
+
+impl Dir for FatDir {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let entries = self.read_entries().await?;
+            let entry = entries.iter().find(|entry| entry.name.eq_ignore_ascii_case(name)).ok_or(FsError::NotFound)?;
+            Ok(self.volume.inode(self.first_cluster, entry))
+        })
+    }
+
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            Ok(self
+                .read_entries()
+                .await?
+                .into_iter()
+                .map(|entry| DirEntry { file_type: entry.file_type(), name: entry.name })
+                .collect())
+        })
+    }
+
+    /// Adds a file or directory, with a long name unless `name` is a short name already.
+    fn create<'a>(&'a self, name: &'a str, file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let volume = &self.volume;
+            volume.check_writable()?;
+            let attributes = match file_type {
+                FileType::File => ATTR_ARCHIVE,
+                FileType::Directory => ATTR_DIRECTORY,
+                _ => return Err(FsError::Unsupported),
+            };
+            check_name(name)?;
+            let mut allocation = volume.allocation.lock().await;
+            let (mut chain, data) = self.read().await?;
+            let entries = parse_dir(&data);
+            if entries.iter().any(|entry| entry.name.eq_ignore_ascii_case(name)) {
+                return Err(FsError::AlreadyExists);
+            }
+            let (short_name, case, mut slots) = match exact_short_name(name) {
+                Some((short_name, case)) if !entries.iter().any(|entry| entry.short_name == short_name) => {
+                    (short_name, case, Vec::new())
+                }
+                _ => {
+                    let short_name = numbered_short_name(name, &entries)?;
+                    (short_name, 0, long_name_entries(name, checksum(&short_name)))
+                }
+            };
+            let first_offset = free_entries(&data, slots.len() + 1);
+            let end = first_offset + (slots.len() + 1) * DIR_ENTRY_SIZE;
+            if end > MAX_DIR_ENTRIES * DIR_ENTRY_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            if end > data.len() {
+                let cluster_size = volume.cluster_size as usize;
+                let count = (end - data.len() + cluster_size - 1) / cluster_size;
+                chain.extend(volume.allocate(&mut allocation, count, chain.last().copied()).await?);
+            }
+            let now = time::wall_clock();
+            let first_cluster = match file_type {
+                FileType::Directory => {
+                    let cluster = volume.allocate(&mut allocation, 1, None).await?[0];
+                    self.write_dot_entries(cluster, now).await?;
+                    cluster
+                }
+                _ => 0,
+            };
+            slots.push(short_entry(&short_name, case, attributes, first_cluster, now));
+            let bytes: Vec<u8> = slots.iter().flatten().copied().collect();
+            volume.write_chain(&chain, first_offset as u64, &bytes).await?;
+            let entry = RawEntry {
+                name: name.into(),
+                short_name,
+                attributes,
+                first_cluster,
+                size: 0,
+                modified: Some(now),
+                offset: (end - DIR_ENTRY_SIZE) as u64,
+                first_offset: first_offset as u64,
+            };
+            Ok(volume.inode(self.first_cluster, &entry))
+        })
+    }
+
+    fn remove<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let volume = &self.volume;
+            volume.check_writable()?;
+            let mut allocation = volume.allocation.lock().await;
+            let (chain, data) = self.read().await?;
+            let entries = parse_dir(&data);
+            let entry = entries.iter().find(|entry| entry.name.eq_ignore_ascii_case(name)).ok_or(FsError::NotFound)?;
+            if entry.is_dir() {
+                let dir = FatDir::new(volume.clone(), entry.first_cluster, None);
+                if !dir.read_entries().await?.is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+            }
+            for offset in (entry.first_offset..=entry.offset).step_by(DIR_ENTRY_SIZE) {
+                volume.write_chain(&chain, offset, &[ENTRY_DELETED]).await?;
+            }
+            if entry.first_cluster != 0 {
+                let clusters = volume.chain(entry.first_cluster).await?;
+                volume.free(&mut allocation, &clusters).await?;
+            }
+            volume.forget(Location { dir: self.first_cluster, offset: entry.offset });
+            Ok(())
+        })
+    }
+}
+
+#[test_case]
+fn test_short_name_checksum() {
+    // what the specification's ((sum & 1) << 7) + (sum >> 1) + byte comes to
+    assert_eq!(checksum(b"LONGFI~1TXT"), 0xd4);
+}
+
+#[test_case]
+fn test_timestamps_round_trip() {
+    // 2024-02-29 13:05:08
+    let time = Duration::from_secs(1_709_211_908);
+    let (date, time_of_day) = encode_timestamp(time);
+    assert_eq!((date, time_of_day), (44 << 9 | 2 << 5 | 29, 13 << 11 | 5 << 5 | 4));
+    assert_eq!(timestamp(date, time_of_day), Some(time));
+    assert_eq!(encode_timestamp(Duration::ZERO), (1 << 5 | 1, 0));
+}
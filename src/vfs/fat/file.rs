@@ -0,0 +1,203 @@
+//! Files: reading and writing their clusters, and keeping their entries up to date.
+
+use super::{dir::RawEntry, dir::update_entry, Allocation, Location, Volume, FAT_END_MARK};
+use crate::{
+    time,
+    vfs::{File, FileType, FsError, FsFuture, Inode, Metadata},
+};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{cmp::Ordering, convert::TryFrom, time::Duration};
+
+pub(super) struct FatFile {
+    volume: Arc<Volume>,
+    location: Location,
+    state: spin::Mutex<FileState>,
+}
+
+struct FileState {
+    /// 0 while the file has no clusters.
+    first_cluster: u32,
+    size: u32,
+    modified: Option<Duration>,
+    /// The clusters of the file, once it was first needed.
+    chain: Option<Arc<Vec<u32>>>,
+    /// Set once the entry is gone, after which the clusters may belong to another file.
+    removed: bool,
+}
+
+impl FatFile {
+    pub(super) fn new(volume: Arc<Volume>, location: Location, entry: &RawEntry) -> FatFile {
+        let state = FileState {
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            modified: entry.modified,
+            chain: None,
+            removed: false,
+        };
+        FatFile { volume, location, state: spin::Mutex::new(state) }
+    }
+
+    /// Returns the clusters and the size of the file.
+    async fn load(&self) -> Result<(Arc<Vec<u32>>, u32), FsError> {
+        let (first_cluster, size) = {
+            let state = self.state.lock();
+            if state.removed {
+                return Err(FsError::NotFound);
+            }
+            if let Some(chain) = state.chain.clone() {
+                return Ok((chain, state.size));
+            }
+            (state.first_cluster, state.size)
+        };
+        let chain = Arc::new(self.volume.chain(first_cluster).await?);
+        if (chain.len() as u64) * self.volume.cluster_size < u64::from(size) {
+            return Err(FsError::Corrupt("file larger than its clusters"));
+        }
+        let mut state = self.state.lock();
+        // a write may have come first
+        if state.first_cluster == first_cluster && state.chain.is_none() {
+            state.chain = Some(chain.clone());
+        }
+        Ok((chain, size))
+    }
+
+    /// Adds clusters to `chain` until it holds `size` bytes.
+    async fn grow(&self, allocation: &mut Allocation, chain: &mut Vec<u32>, size: u64) -> Result<(), FsError> {
+        let cluster_size = self.volume.cluster_size;
+        let needed = ((size + cluster_size - 1) / cluster_size) as usize;
+        if needed > chain.len() {
+            let clusters = self.volume.allocate(allocation, needed - chain.len(), chain.last().copied()).await?;
+            chain.extend(clusters);
+        }
+        Ok(())
+    }
+
+    /// Zeroes the bytes from `start` to `end` in the clusters of `chain`.
+    async fn zero(&self, chain: &[u32], start: u64, end: u64) -> Result<(), FsError> {
+        let zeros = vec![0; self.volume.cluster_size as usize];
+        let mut position = start;
+        while position < end {
+            let len = (end - position).min(zeros.len() as u64);
+            self.volume.write_chain(chain, position, &zeros[..len as usize]).await?;
+            position += len;
+        }
+        Ok(())
+    }
+
+    /// Makes `chain` and `size` the file's, in its entry as well.
+    async fn commit(&self, chain: Vec<u32>, size: u32) -> Result<(), FsError> {
+        let first_cluster = chain.first().copied().unwrap_or(0);
+        let now = time::wall_clock();
+        self.volume.update_entry(self.location, |entry| update_entry(entry, first_cluster, size, now)).await?;
+        let mut state = self.state.lock();
+        state.first_cluster = first_cluster;
+        state.size = size;
+        state.modified = Some(now);
+        state.chain = Some(Arc::new(chain));
+        Ok(())
+    }
+
+    pub(super) fn mark_removed(&self) {
+        self.state.lock().removed = true;
+    }
+}
+
+impl Inode for FatFile {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        Metadata {
+            file_type: FileType::File,
+            size: u64::from(state.size),
+            // files without clusters have no number of their own, their entry tells them apart
+            id: u64::from(self.location.dir) << 32 | (self.location.offset / 32),
+            modified: state.modified,
+        }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for FatFile {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let (chain, size) = self.load().await?;
+            let size = u64::from(size);
+            if offset >= size || buf.is_empty() {
+                return Ok(0);
+            }
+            let len = buf.len().min((size - offset) as usize);
+            self.volume.read_chain(&chain, offset, &mut buf[..len]).await?;
+            Ok(len)
+        })
+    }
+
+    /// Writes `buf` at `offset`, allocating the clusters it needs. Files can't grow past 4 GiB minus a byte.
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.volume.check_writable()?;
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let end = offset.checked_add(buf.len() as u64).filter(|&end| end <= u64::from(u32::MAX));
+            let end = end.ok_or(FsError::NoSpace)?;
+            let mut allocation = self.volume.allocation.lock().await;
+            let (chain, size) = self.load().await?;
+            let mut chain = (*chain).clone();
+            let allocated = chain.len() as u64 * self.volume.cluster_size;
+            self.grow(&mut allocation, &mut chain, end).await?;
+            // new clusters are zeroed, but the end of the last old one may hold anything
+            self.zero(&chain, u64::from(size), offset.min(allocated)).await?;
+            self.volume.write_chain(&chain, offset, buf).await?;
+            self.commit(chain, u64::from(size).max(end) as u32).await?;
+            Ok(buf.len())
+        })
+    }
+
+    fn set_len(&self, new_size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            self.volume.check_writable()?;
+            let new_size = u32::try_from(new_size).map_err(|_| FsError::NoSpace)?;
+            let mut allocation = self.volume.allocation.lock().await;
+            let (chain, size) = self.load().await?;
+            let mut chain = (*chain).clone();
+            match new_size.cmp(&size) {
+                Ordering::Less => {
+                    let cluster_size = self.volume.cluster_size;
+                    let keep = ((u64::from(new_size) + cluster_size - 1) / cluster_size) as usize;
+                    let freed = chain.split_off(keep.min(chain.len()));
+                    if let Some(&last) = chain.last() {
+                        self.volume.set_fat_entry(last, FAT_END_MARK).await?;
+                    }
+                    self.volume.free(&mut allocation, &freed).await?;
+                }
+                Ordering::Greater => {
+                    let allocated = chain.len() as u64 * self.volume.cluster_size;
+                    self.grow(&mut allocation, &mut chain, u64::from(new_size)).await?;
+                    self.zero(&chain, u64::from(size), u64::from(new_size).min(allocated)).await?;
+                }
+                Ordering::Equal => return Ok(()),
+            }
+            self.commit(chain, new_size).await
+        })
+    }
+}
+
+impl Volume {
+    /// Changes the short entry at `location` with `change`.
+    async fn update_entry(&self, location: Location, change: impl FnOnce(&mut [u8])) -> Result<(), FsError> {
+        let chain = self.chain(location.dir).await?;
+        let mut entry = [0; 32];
+        self.read_chain(&chain, location.offset, &mut entry).await?;
+        change(&mut entry);
+        self.write_chain(&chain, location.offset, &entry).await
+    }
+
+    /// Drops the file whose entry was at `location`, which was removed.
+    pub(super) fn forget(&self, location: Location) {
+        if let Some(file) = self.files.lock().remove(&location).and_then(|file| file.upgrade()) {
+            file.mark_removed();
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Absolute paths, as the VFS takes them.
+
+use super::FsError;
+use alloc::{string::String, vec::Vec};
+
+/// The longest name a path may have in it, which is what FAT long names and ext2 allow.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Resolves the `.` and `..` and repeated slashes in the absolute path `path`, so it becomes `/` or a `/` before
+/// each name. `..` of the root is the root. Needs the heap.
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut resolved: Vec<&str> = Vec::new();
+    for name in names(path) {
+        match name {
+            "." => {}
+            ".." => {
+                resolved.pop();
+            }
+            _ if name.len() > MAX_NAME_LEN || name.contains('\0') => return Err(FsError::InvalidPath),
+            _ => resolved.push(name),
+        }
+    }
+    if resolved.is_empty() {
+        return Ok(String::from("/"));
+    }
+    let mut normalized = String::new();
+    for name in resolved {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    Ok(normalized)
+}
+
+/// Returns the names in `path` from the top down.
+pub fn names(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Splits the normalized path `path` into its parent and its last name, or returns `None` for `/`.
+pub fn split(path: &str) -> Option<(&str, &str)> {
+    let slash = path.rfind('/')?;
+    let name = &path[slash + 1..];
+    if name.is_empty() {
+        return None;
+    }
+    Some((if slash == 0 { "/" } else { &path[..slash] }, name))
+}
+
+/// Returns `true` if the normalized path `path` is `base` or below it.
+pub fn is_within(path: &str, base: &str) -> bool {
+    base == "/" || path == base || (path.starts_with(base) && path.as_bytes()[base.len()] == b'/')
+}
+
+#[test_case]
+fn test_paths_are_split() {
+    assert_eq!(split("/mnt/fat"), Some(("/mnt", "fat")));
+    assert_eq!(split("/mnt"), Some(("/", "mnt")));
+    assert_eq!(split("/"), None);
+    assert!(is_within("/mnt/fat/a", "/mnt/fat"));
+    assert!(is_within("/mnt", "/"));
+    assert!(!is_within("/mnt/fatter", "/mnt/fat"));
+    let mut names = names("/mnt//fat/");
+    assert_eq!((names.next(), names.next(), names.next()), (Some("mnt"), Some("fat"), None));
+}
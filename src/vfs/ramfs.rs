@@ -0,0 +1,302 @@
+//! A filesystem that lives on the heap, for the root before any disk is mounted, for `/tmp` and for tests.
+//!
+//! Directories hold their entries in a map behind a lock of their own. Changes to the tree, creating, removing
+//! and renaming, also take a lock for the whole filesystem, so that the ones that lock two directories can't
+//! deadlock with each other. Removed files stay readable and writable through the inodes that are still held.
+
+use super::{path, Dir, DirEntry, File, FileSystem, FileType, FsError, FsFuture, Inode, Metadata};
+use crate::time;
+use alloc::{borrow::ToOwned, boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+/// The id of the next inode, unique across every `RamFs`.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A filesystem in memory, empty when created. Needs the heap.
+pub struct RamFs {
+    root: Arc<RamDir>,
+}
+
+/// Held while the tree changes.
+type TreeLock = Arc<Mutex<()>>;
+
+struct RamDir {
+    id: u64,
+    tree: TreeLock,
+    state: Mutex<DirState>,
+}
+
+struct DirState {
+    entries: BTreeMap<String, Node>,
+    modified: Duration,
+    /// Set once the directory is removed, after which nothing can be created in it.
+    removed: bool,
+}
+
+struct RamFile {
+    id: u64,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    contents: Vec<u8>,
+    modified: Duration,
+}
+
+#[derive(Clone)]
+enum Node {
+    File(Arc<RamFile>),
+    Dir(Arc<RamDir>),
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::File,
+            Node::Dir(_) => FileType::Directory,
+        }
+    }
+
+    fn inode(&self) -> Arc<dyn Inode> {
+        match self {
+            Node::File(file) => file.clone(),
+            Node::Dir(dir) => dir.clone(),
+        }
+    }
+}
+
+fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl RamFs {
+    pub fn new() -> RamFs {
+        RamFs { root: RamDir::new(Arc::new(Mutex::new(()))) }
+    }
+
+    /// Returns the directory at the normalized path `path`.
+    fn dir_at(&self, path: &str) -> Result<Arc<RamDir>, FsError> {
+        let mut dir = self.root.clone();
+        for name in path::names(path) {
+            let next = match dir.state.lock().entries.get(name) {
+                Some(Node::Dir(next)) => next.clone(),
+                Some(Node::File(_)) => return Err(FsError::NotADirectory),
+                None => return Err(FsError::NotFound),
+            };
+            dir = next;
+        }
+        Ok(dir)
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> RamFs {
+        RamFs::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let (from_parent, from_name) = path::split(from).ok_or(FsError::InvalidPath)?;
+            let (to_parent, to_name) = path::split(to).ok_or(FsError::InvalidPath)?;
+            let _tree = self.root.tree.lock();
+            let source = self.dir_at(from_parent)?;
+            let target = self.dir_at(to_parent)?;
+            if Arc::ptr_eq(&source, &target) {
+                let mut state = source.state.lock();
+                let node = check_move(&state.entries, from_name, &state.entries, to_name)?;
+                state.entries.remove(from_name);
+                state.insert(to_name, node);
+                return Ok(());
+            }
+            let mut source = source.state.lock();
+            let mut target = target.state.lock();
+            let node = check_move(&source.entries, from_name, &target.entries, to_name)?;
+            source.entries.remove(from_name);
+            source.modified = time::wall_clock();
+            target.insert(to_name, node);
+            Ok(())
+        })
+    }
+}
+
+/// Returns the entry `from_name` of `source`, if it can replace whatever `to_name` of `target` is.
+fn check_move(
+    source: &BTreeMap<String, Node>,
+    from_name: &str,
+    target: &BTreeMap<String, Node>,
+    to_name: &str,
+) -> Result<Node, FsError> {
+    let node = source.get(from_name).ok_or(FsError::NotFound)?;
+    match (node, target.get(to_name)) {
+        (Node::Dir(_), Some(Node::File(_))) => Err(FsError::NotADirectory),
+        (Node::File(_), Some(Node::Dir(_))) => Err(FsError::IsADirectory),
+        (Node::Dir(_), Some(Node::Dir(replaced))) if !replaced.state.lock().entries.is_empty() => {
+            Err(FsError::NotEmpty)
+        }
+        _ => Ok(node.clone()),
+    }
+}
+
+impl DirState {
+    /// Puts `node` at `name`, marking a directory it replaces removed.
+    fn insert(&mut self, name: &str, node: Node) {
+        if let Some(Node::Dir(replaced)) = self.entries.insert(name.to_owned(), node) {
+            replaced.state.lock().removed = true;
+        }
+        self.modified = time::wall_clock();
+    }
+}
+
+impl RamDir {
+    fn new(tree: TreeLock) -> Arc<RamDir> {
+        let state = DirState { entries: BTreeMap::new(), modified: time::wall_clock(), removed: false };
+        Arc::new(RamDir { id: next_id(), tree, state: Mutex::new(state) })
+    }
+}
+
+impl Inode for RamDir {
+    fn metadata(&self) -> Metadata {
+        let modified = self.state.lock().modified;
+        Metadata { file_type: FileType::Directory, size: 0, id: self.id, modified: Some(modified) }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        Some(self)
+    }
+}
+
+impl Dir for RamDir {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move { self.state.lock().entries.get(name).map(Node::inode).ok_or(FsError::NotFound) })
+    }
+
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let state = self.state.lock();
+            Ok(state
+                .entries
+                .iter()
+                .map(|(name, node)| DirEntry { name: name.clone(), file_type: node.file_type() })
+                .collect())
+        })
+    }
+
+    /// Adds an empty file or directory. Device files fail with `FsError::Unsupported`, with no driver behind them.
+    fn create<'a>(&'a self, name: &'a str, file_type: FileType) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let node = match file_type {
+                FileType::File => {
+                    let state = FileState { contents: Vec::new(), modified: time::wall_clock() };
+                    Node::File(Arc::new(RamFile { id: next_id(), state: Mutex::new(state) }))
+                }
+                FileType::Directory => Node::Dir(RamDir::new(self.tree.clone())),
+                _ => return Err(FsError::Unsupported),
+            };
+            let _tree = self.tree.lock();
+            let mut state = self.state.lock();
+            if state.removed {
+                return Err(FsError::NotFound);
+            }
+            if state.entries.contains_key(name) {
+                return Err(FsError::AlreadyExists);
+            }
+            let inode = node.inode();
+            state.insert(name, node);
+            Ok(inode)
+        })
+    }
+
+    fn remove<'a>(&'a self, name: &'a str) -> FsFuture<'a, ()> {
+        Box::pin(async move {
+            let _tree = self.tree.lock();
+            let mut state = self.state.lock();
+            if let Node::Dir(dir) = state.entries.get(name).ok_or(FsError::NotFound)? {
+                let mut dir = dir.state.lock();
+                if !dir.entries.is_empty() {
+                    return Err(FsError::NotEmpty);
+                }
+                dir.removed = true;
+            }
+            state.entries.remove(name);
+            state.modified = time::wall_clock();
+            Ok(())
+        })
+    }
+}
+
+impl Inode for RamFile {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        Metadata {
+            file_type: FileType::File,
+            size: state.contents.len() as u64,
+            id: self.id,
+            modified: Some(state.modified),
+        }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for RamFile {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let state = self.state.lock();
+            let rest = usize::try_from(offset).ok().and_then(|offset| state.contents.get(offset..)).unwrap_or(&[]);
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        })
+    }
+
+    /// Fails with `FsError::NoSpace` if the heap can't hold the file.
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let offset = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+            let end = offset.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+            let mut state = self.state.lock();
+            if end > state.contents.len() {
+                resize(&mut state.contents, end)?;
+            }
+            state.contents[offset..end].copy_from_slice(buf);
+            state.modified = time::wall_clock();
+            Ok(buf.len())
+        })
+    }
+
+    fn set_len(&self, size: u64) -> FsFuture<'_, ()> {
+        Box::pin(async move {
+            let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+            let mut state = self.state.lock();
+            resize(&mut state.contents, size)?;
+            state.modified = time::wall_clock();
+            Ok(())
+        })
+    }
+}
+
+/// Cuts `contents` off or pads it with zeros to `size` bytes, failing instead of running out of heap.
+fn resize(contents: &mut Vec<u8>, size: usize) -> Result<(), FsError> {
+    if size > contents.len() {
+        contents.try_reserve(size - contents.len()).map_err(|_| FsError::NoSpace)?;
+    }
+    contents.resize(size, 0);
+    Ok(())
+}
@@ -1,23 +1,47 @@
-use core::fmt;
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU8, AtomicUsize, Ordering},
+};
 
-use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::{
+    console::ConsoleBackend,
+    framebuffer::{font::Font, Framebuffer, FramebufferError},
+    interrupts::stats::{self, MAX_CPUS},
+    sync::{IrqSpinLock, IrqSpinLockGuard},
+    task::work::Work,
+};
+use alloc::{boxed::Box, vec::Vec};
 use volatile::Volatile;
+use self::ansi::{Action, Parser};
+use x86_64::instructions::port::Port;
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+mod ansi;
+pub mod cp437;
+mod fbcon;
+pub mod terminal;
+pub mod window;
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe {&mut *(0xb8000 as *mut Buffer) },
-    });
-}
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+/// Number of lines that scrolled off the screen kept for scrolling back, see `init_scrollback`.
+pub const SCROLLBACK_LINES: usize = 300;
+
+/// The buffer shown on the screen, VGA text memory until `use_framebuffer` moves the screen into the heap.
+static SCREEN: AtomicPtr<Buffer> = AtomicPtr::new(0xb8000 as *mut Buffer);
+static CURSOR_HIDDEN: AtomicBool = AtomicBool::new(false);
+/// What the CRT controller's cursor was last set to, a cell index or one of the two values below.
+static HARDWARE_CURSOR: AtomicU16 = AtomicU16::new(CURSOR_UNKNOWN);
+const CURSOR_UNKNOWN: u16 = u16::MAX;
+const CURSOR_OFF: u16 = u16::MAX - 1;
+/// Set once the panic screen took the screen over, after which writers only update their buffers.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+pub static WRITER: IrqSpinLock<Writer> = IrqSpinLock::new(Writer::new(true));
 
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
@@ -26,11 +50,164 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Like `print!`, but never waits for a console lock, so it is safe in interrupt handlers.
+///
+/// The text is staged in a buffer of the current CPU and goes to the consoles once task context flushes it.
+#[macro_export]
+macro_rules! irq_print {
+    ($($arg:tt)*) => ($crate::vga_buffer::_irq_print(format_args!($($arg)*)));
+}
+
+/// Like `println!`, but never waits for a console lock, see `irq_print!`.
+#[macro_export]
+macro_rules! irq_println {
+    () => ($crate::irq_print!("\n"));
+    ($($arg:tt)*) => ($crate::irq_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// The console backend of the VGA text screen, which writes to the kernel log terminal, `WRITER`.
+pub struct VgaConsole;
+
+pub static VGA_CONSOLE: VgaConsole = VgaConsole;
+
+impl ConsoleBackend for VgaConsole {
+    fn write_str(&self, s: &str) {
+        WRITER.lock().write_string(s);
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) {
+        use core::fmt::Write;
+        let mut writer = WRITER.lock();
+        writer.write_fmt(args).unwrap();
+        writer.flush();
+    }
+
+    fn write_colored(&self, foreground: Color, background: Color, args: fmt::Arguments) {
+        use core::fmt::Write;
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.color_code = ColorCode::new(foreground, background);
+        writer.write_fmt(args).unwrap();
+        writer.color_code = previous;
+        writer.flush();
+    }
+
+    fn clear(&self) {
+        WRITER.lock().clear();
+    }
+
+    fn set_color(&self, foreground: Color, background: Color) {
+        WRITER.lock().color_code = ColorCode::new(foreground, background);
+    }
+
+    fn color(&self) -> (Color, Color) {
+        let ColorCode(code) = WRITER.lock().color_code;
+        (Color::ALL[usize::from(code & 0x0f)], Color::ALL[usize::from(code >> 4)])
+    }
+
+    fn dimensions(&self) -> (usize, usize) {
+        (BUFFER_WIDTH, BUFFER_HEIGHT)
+    }
+}
+
+/// Size of the staging buffer of each CPU in bytes.
+const STAGING_SIZE: usize = 4096;
+
+static STAGING: [StagingBuffer; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: StagingBuffer = StagingBuffer::new();
+    [EMPTY; MAX_CPUS]
+};
+
+/// Writes text staged by `irq_print!` to the consoles, scheduled by the first `irq_print!` after each flush.
+static FLUSH: Work = Work::new(flush_staged);
+
+/// A ring of bytes filled by `irq_print!` on one CPU and drained by `flush_staged`.
+///
+/// Writers on a CPU are serialized by disabling interrupts, the reader by holding `DRAINING`. Only NMI handlers
+/// could still interleave with a writer.
+struct StagingBuffer {
+    bytes: [AtomicU8; STAGING_SIZE],
+    /// Total number of bytes written, the next write goes to `head % STAGING_SIZE`.
+    head: AtomicUsize,
+    /// Total number of bytes read.
+    tail: AtomicUsize,
+    /// Bytes dropped because the buffer was full, reported on the next flush.
+    dropped: AtomicUsize,
+}
+
+impl StagingBuffer {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU8 = AtomicU8::new(0);
+        StagingBuffer {
+            bytes: [ZERO; STAGING_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl fmt::Write for &StagingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut head = self.head.load(Ordering::Relaxed);
+        let free = STAGING_SIZE - (head - self.tail.load(Ordering::Acquire));
+        let len = s.len().min(free);
+        for byte in &s.as_bytes()[..len] {
+            self.bytes[head % STAGING_SIZE].store(*byte, Ordering::Relaxed);
+            head += 1;
+        }
+        self.head.store(head, Ordering::Release);
+        if len < s.len() {
+            self.dropped.fetch_add(s.len() - len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
+pub fn _irq_print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(||WRITER.lock().write_fmt(args).unwrap());
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = (&STAGING[stats::cpu_index()]).write_fmt(args);
+    });
+    // before the executor runs, the text waits for a later flush
+    let _ = FLUSH.schedule();
+}
+
+/// Serializes `flush_staged`, the reader of the staging buffers.
+static DRAINING: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// Writes the text staged by `irq_print!` on every CPU to the consoles. Must not be called from interrupt handlers.
+pub fn flush_staged() {
+    let _draining = DRAINING.lock();
+    for staging in &STAGING {
+        let head = staging.head.load(Ordering::Acquire);
+        let mut tail = staging.tail.load(Ordering::Relaxed);
+        while tail < head {
+            let mut chunk = [0; 64];
+            let len = (head - tail).min(chunk.len());
+            for (offset, byte) in chunk[..len].iter_mut().enumerate() {
+                *byte = staging.bytes[(tail + offset) % STAGING_SIZE].load(Ordering::Relaxed);
+            }
+            // a character cut in two by the end of the chunk starts the next one
+            let valid = core::str::from_utf8(&chunk[..len]).map_or_else(|err| err.valid_up_to(), |_| len);
+            if valid == 0 {
+                // only where a full buffer cut a character short
+                crate::print!("?");
+                tail += 1;
+            } else {
+                crate::print!("{}", core::str::from_utf8(&chunk[..valid]).unwrap());
+                tail += valid;
+            }
+        }
+        staging.tail.store(head, Ordering::Release);
+        let dropped = staging.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            crate::println!("[{} bytes of interrupt output dropped]", dropped);
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -55,16 +232,70 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// Every color, in the order of their numbers.
+    pub const ALL: [Color; 16] = [
+        Color::Black,
+        Color::Blue,
+        Color::Green,
+        Color::Cyan,
+        Color::Red,
+        Color::Magenta,
+        Color::Brown,
+        Color::LightGray,
+        Color::DarkGray,
+        Color::LightBlue,
+        Color::LightGreen,
+        Color::LightCyan,
+        Color::LightRed,
+        Color::Pink,
+        Color::Yellow,
+        Color::White,
+    ];
+
+    /// Returns the SGR parameter that sets the color as foreground, 30 to 37 or 90 to 97. The one for the background
+    /// is 10 more.
+    pub fn ansi_code(self) -> u8 {
+        let base = ANSI_COLORS.iter().position(|color| *color as u8 == self as u8 & !BRIGHT).unwrap() as u8;
+        if self as u8 & BRIGHT != 0 { 90 + base } else { 30 + base }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 struct ColorCode(u8);
 
+/// The colors before any escape sequence changed them, and after `\x1b[0m`.
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | foreground & 0x0f)
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode((background & 0x0f) << 4 | self.0 & 0x0f)
+    }
 }
 
+/// VGA colors in the order of the ANSI color numbers: black, red, green, yellow, blue, magenta, cyan and white.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+/// Added to a color to get its bright variant.
+const BRIGHT: u8 = 8;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar {
@@ -72,26 +303,182 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK: ScreenChar = ScreenChar { ascii_character: b' ', color_code: DEFAULT_COLOR };
+/// A bit for every row in `Writer::dirty`.
+const ALL_ROWS: u32 = (1 << BUFFER_HEIGHT) - 1;
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Writes text to a shadow of the screen, scrolling once the last row is full.
+///
+/// Rows that changed are copied to the screen by `flush`, which happens on every new line and at the end of
+/// every `print!`, so that the slow screen memory is written once per change and scrolling doesn't flicker.
+/// Understands the CSI escape sequences for colors (`\x1b[31m`, `\x1b[0m`), cursor positioning (`H`, `A` to `D`)
+/// and clearing (`J`, `K`), so that output code does not need VGA specific APIs. Other sequences are dropped.
+/// `set_scroll_region`, or `\x1b[<top>;<bottom>r`, confines scrolling to some rows and leaves the others in place.
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
+    /// The rows that scroll when a new line starts in the last of them.
+    scroll_region: Range<usize>,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    /// Set by `\x1b[1m`, shows every foreground color chosen until `\x1b[22m` in its bright variant.
+    bold: bool,
+    shadow: [Line; BUFFER_HEIGHT],
+    /// One bit per row of `shadow` that changed since the last flush.
+    dirty: u32,
+    escape: Parser,
+    scrollback: Option<Scrollback>,
+    /// Set for the terminal shown on the screen, the one that flushes to it.
+    visible: bool,
+}
+
+/// Lines that scrolled off the top of the screen, and how far the screen is scrolled back into them.
+///
+/// The storage is allocated once by `init_scrollback`, so that printing never allocates.
+struct Scrollback {
+    /// A ring of up to `SCROLLBACK_LINES` lines.
+    lines: Vec<Line>,
+    /// Index of the oldest line once the ring is full.
+    oldest: usize,
+    /// Number of lines the screen shows above the live output, 0 while showing the live output.
+    offset: usize,
+}
+
+impl Scrollback {
+    fn push(&mut self, line: Line) {
+        if self.lines.len() < SCROLLBACK_LINES {
+            self.lines.push(line);
+        } else {
+            self.lines[self.oldest] = line;
+            self.oldest = (self.oldest + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    /// Returns the line `index` lines below the oldest one.
+    fn line(&self, index: usize) -> &Line {
+        &self.lines[(self.oldest + index) % self.lines.len()]
+    }
+}
+
+/// Starts keeping the lines that scroll off the screen, so that `scroll_back` can show them again.
+///
+/// Needs the heap, so output before this is called is lost once it scrolls off.
+pub fn init_scrollback() {
+    let scrollback = Scrollback {
+        lines: Vec::with_capacity(SCROLLBACK_LINES),
+        oldest: 0,
+        offset: 0,
+    };
+    WRITER.lock().scrollback.get_or_insert(scrollback);
+}
+
+/// Scrolls the screen back by up to `lines` lines into the output that scrolled off it.
+///
+/// Only the kernel log keeps a scrollback, so this does nothing while another terminal is shown. New output
+/// scrolls the screen forward to the live output again.
+pub fn scroll_back(lines: usize) {
+    let mut writer = active_writer();
+    let offset = writer.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset);
+    writer.show_scrollback(offset.saturating_add(lines));
+}
+
+/// Scrolls the screen forward by up to `lines` lines, towards the live output.
+pub fn scroll_forward(lines: usize) {
+    let mut writer = active_writer();
+    let offset = writer.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset);
+    writer.show_scrollback(offset.saturating_sub(lines));
+}
+
+/// Shows the blinking cursor at the write position of the terminal shown, as it is after boot.
+pub fn show_cursor() {
+    CURSOR_HIDDEN.store(false, Ordering::Relaxed);
+    active_writer().flush();
+}
+
+/// Hides the blinking cursor, for output that is not typed into.
+pub fn hide_cursor() {
+    CURSOR_HIDDEN.store(true, Ordering::Relaxed);
+    active_writer().flush();
+}
+
+/// Programs the cursor of the CRT controller, doing nothing if it already is where `cursor` says.
+fn set_hardware_cursor(cursor: Option<(usize, usize)>) {
+    let state = cursor.map_or(CURSOR_OFF, |(row, col)| (row * BUFFER_WIDTH + col) as u16);
+    let previous = HARDWARE_CURSOR.swap(state, Ordering::Relaxed);
+    if state == previous {
+        return;
+    }
+    let mut index = Port::<u8>::new(0x3d4);
+    let mut data = Port::<u8>::new(0x3d5);
+    unsafe {
+        if state == CURSOR_OFF || previous == CURSOR_OFF || previous == CURSOR_UNKNOWN {
+            // bit 5 of the cursor start register turns the cursor off, the other bits keep its shape
+            index.write(0x0a);
+            let start = data.read();
+            data.write(if state == CURSOR_OFF { start | 0x20 } else { start & !0x20 });
+        }
+        if state != CURSOR_OFF {
+            // cursor location low and high registers
+            index.write(0x0f);
+            data.write(state as u8);
+            index.write(0x0e);
+            data.write((state >> 8) as u8);
+        }
+    }
 }
 
+/// Copies what the kernel log printed since the last flush to the screen, see `Writer::flush`.
+pub fn flush() {
+    WRITER.lock().flush();
+}
+
+/// Writes without flushing, so that formatted output is flushed once at its end or on its new lines.
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
+        self.write_text(s);
         Ok(())
     }
 }
 
+fn active_writer() -> IrqSpinLockGuard<'static, Writer> {
+    terminal::terminal(terminal::active()).unwrap_or(&WRITER).lock()
+}
+
+/// Returns the memory shown on the screen. Only the visible writer may write to it, with its lock held.
+fn screen() -> &'static mut Buffer {
+    unsafe { &mut *SCREEN.load(Ordering::Relaxed) }
+}
+
 impl Writer {
+    const fn new(visible: bool) -> Writer {
+        Writer {
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            scroll_region: 0..BUFFER_HEIGHT,
+            color_code: DEFAULT_COLOR,
+            bold: false,
+            shadow: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            dirty: 0,
+            escape: Parser::new(),
+            scrollback: None,
+            visible,
+        }
+    }
+
+    /// Writes `byte` and flushes.
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.flush();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        self.show_scrollback(0);
         match byte {
             b'\n' => self.new_line(),
             byte => {
@@ -99,49 +486,377 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.shadow[row][col] = ScreenChar {
                     ascii_character: byte,
                     color_code,
-                });
+                };
+                self.dirty |= 1 << row;
                 self.column_position += 1;
             }
         }
     }
 
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    /// Copies the rows that changed since the last flush to the screen, and moves the cursor to the write position.
+    ///
+    /// Does nothing for terminals that aren't shown.
+    pub fn flush(&mut self) {
+        if !self.visible || FROZEN.load(Ordering::Relaxed) {
+            return;
+        }
+        let screen = screen();
+        for (index, row) in screen.chars.iter_mut().enumerate() {
+            if self.dirty & (1 << index) == 0 {
+                continue;
+            }
+            for (cell, character) in row.iter_mut().zip(self.shown_line(index)) {
+                cell.write(*character);
             }
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.dirty = 0;
+
+        let cursor = match CURSOR_HIDDEN.load(Ordering::Relaxed) {
+            true => None,
+            false => Some((self.row_position, self.column_position.min(BUFFER_WIDTH - 1))),
+        };
+        if !fbcon::refresh(screen, cursor) {
+            set_hardware_cursor(cursor);
+        }
+    }
+
+    /// Returns the line shown in `row` of the screen, which is in the scrollback while scrolled back.
+    ///
+    /// Only the scroll region is scrolled back, the rows outside of it never scroll.
+    fn shown_line(&self, row: usize) -> &Line {
+        let scrollback = match &self.scrollback {
+            Some(scrollback) if self.scroll_region.contains(&row) => scrollback,
+            _ => return &self.shadow[row],
+        };
+        let region_row = row - self.scroll_region.start;
+        if region_row < scrollback.offset {
+            scrollback.line(scrollback.lines.len() - scrollback.offset + region_row)
+        } else {
+            &self.shadow[row - scrollback.offset]
+        }
+    }
+
+    /// Returns the column of the current row the next byte is written to.
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Moves the write position within the current row, and the blinking cursor with it.
+    pub fn set_column(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.flush();
+    }
+
+    /// Blanks the whole screen and moves the write position to the top left corner of the scroll region.
+    pub fn clear(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = self.scroll_region.start;
+        self.column_position = 0;
+        self.flush();
+    }
+
+    /// Blanks the current row from the write position on, without moving it.
+    pub fn clear_to_end_of_line(&mut self) {
+        self.clear_cells(self.row_position, self.column_position..BUFFER_WIDTH);
+        self.flush();
+    }
+
+    /// Makes only `rows` scroll from now on, so that the rows above and below them stay as they are.
+    ///
+    /// Moves the write position to the start of the last row of `rows` if it is outside of them.
+    /// Panics if `rows` is empty or extends past the screen.
+    pub fn set_scroll_region(&mut self, rows: Range<usize>) {
+        assert!(rows.start < rows.end && rows.end <= BUFFER_HEIGHT, "invalid scroll region {:?}", rows);
+        self.show_scrollback(0);
+        if !rows.contains(&self.row_position) {
+            self.row_position = rows.end - 1;
+            self.column_position = 0;
+        }
+        self.scroll_region = rows;
+        self.flush();
+    }
+
+    fn new_line(&mut self) {
         self.column_position = 0;
+        let (top, end) = (self.scroll_region.start, self.scroll_region.end);
+        if self.row_position + 1 != end {
+            // below the scroll region the last row of the screen is overwritten instead of scrolled
+            self.row_position = (self.row_position + 1).min(BUFFER_HEIGHT - 1);
+            self.flush();
+            return;
+        }
+        // the screen has to show the finished line before a framebuffer can move the picture
+        self.flush();
+        if let Some(scrollback) = &mut self.scrollback {
+            scrollback.push(self.shadow[top]);
+        }
+        self.shadow.copy_within(top + 1..end, top);
+        self.dirty = ALL_ROWS;
+        if self.visible && self.scroll_region == (0..BUFFER_HEIGHT) {
+            fbcon::scrolled();
+        }
+        self.clear_row(end - 1);
     }
 
     fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0..BUFFER_WIDTH);
+    }
+
+    /// Shows the screen scrolled back by `offset` lines.
+    fn show_scrollback(&mut self, offset: usize) {
+        let scrollback = match &mut self.scrollback {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+        let offset = offset.min(scrollback.lines.len());
+        if offset == scrollback.offset {
+            return;
+        }
+        scrollback.offset = offset;
+        self.dirty = ALL_ROWS;
+        self.flush();
+    }
+
+    fn clear_cells(&mut self, row: usize, cols: core::ops::Range<usize>) {
+        self.show_scrollback(0);
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in cols {
+            self.shadow[row][col] = blank;
         }
+        self.dirty |= 1 << row;
     }
 
+    /// Writes `s` and flushes.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ascii or new line
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // non printable characters use a filled in square
-                _ => self.write_byte(0xfe),
+        self.write_text(s);
+        self.flush();
+    }
+
+    fn write_text(&mut self, s: &str) {
+        for c in s.chars() {
+            // other characters end escape sequences like any byte that doesn't belong in them
+            let byte = if c.is_ascii() { c as u8 } else { 0x80 };
+            match self.escape.advance(byte) {
+                Action::Print(b'\n') => self.put_byte(b'\n'),
+                // characters the font lacks and control characters use a filled in square
+                Action::Print(_) => self.put_byte(cp437::encode(c).unwrap_or(0xfe)),
+                Action::Csi { params, count, final_byte } => self.apply_csi(&params[..count], final_byte),
+                Action::None => {}
+            }
+        }
+    }
+
+    fn apply_csi(&mut self, params: &[u16], final_byte: u8) {
+        let param = |index: usize| usize::from(params.get(index).copied().unwrap_or(0));
+        // movements by 0 move by 1
+        let distance = param(0).max(1);
+        match final_byte {
+            b'm' => self.apply_sgr(params),
+            b'H' | b'f' => {
+                self.row_position = (param(0).max(1) - 1).min(BUFFER_HEIGHT - 1);
+                self.column_position = (param(1).max(1) - 1).min(BUFFER_WIDTH - 1);
+            }
+            b'A' => self.row_position = self.row_position.saturating_sub(distance),
+            b'B' => self.row_position = (self.row_position + distance).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column_position = (self.column_position + distance).min(BUFFER_WIDTH - 1),
+            b'D' => self.column_position = self.column_position.saturating_sub(distance),
+            b'J' => {
+                let (row, col) = (self.row_position, self.column_position.min(BUFFER_WIDTH));
+                let (rows, cols) = match param(0) {
+                    0 => (row + 1..BUFFER_HEIGHT, col..BUFFER_WIDTH),
+                    1 => (0..row, 0..(col + 1).min(BUFFER_WIDTH)),
+                    _ => (0..BUFFER_HEIGHT, 0..0),
+                };
+                self.clear_cells(row, cols);
+                for row in rows {
+                    self.clear_row(row);
+                }
+            }
+            b'r' => {
+                let top = param(0).max(1) - 1;
+                let bottom = match param(1) {
+                    0 => BUFFER_HEIGHT,
+                    bottom => bottom.min(BUFFER_HEIGHT),
+                };
+                if top < bottom {
+                    self.set_scroll_region(top..bottom);
+                }
+            }
+            b'K' => {
+                let col = self.column_position.min(BUFFER_WIDTH);
+                let cols = match param(0) {
+                    0 => col..BUFFER_WIDTH,
+                    1 => 0..(col + 1).min(BUFFER_WIDTH),
+                    _ => 0..BUFFER_WIDTH,
+                };
+                self.clear_cells(self.row_position, cols);
+            }
+            _ => {}
+        }
+    }
+
+    /// Applies a select graphic rendition sequence, the `m` in `\x1b[1;31m`.
+    fn apply_sgr(&mut self, params: &[u16]) {
+        // `\x1b[m` resets like `\x1b[0m`
+        let params = if params.is_empty() { &[0][..] } else { params };
+        for &param in params {
+            let color = |base: u16| ANSI_COLORS[usize::from(param - base)] as u8;
+            // bold text is shown in the bright variant of its color, also of colors chosen after it
+            let bold = if self.bold { BRIGHT } else { 0 };
+            self.color_code = match param {
+                0 => {
+                    self.bold = false;
+                    DEFAULT_COLOR
+                }
+                1 => {
+                    self.bold = true;
+                    ColorCode(self.color_code.0 | BRIGHT)
+                }
+                22 => {
+                    self.bold = false;
+                    ColorCode(self.color_code.0 & !BRIGHT)
+                }
+                30..=37 => self.color_code.with_foreground(color(30) | bold),
+                39 => self.color_code.with_foreground(DEFAULT_COLOR.0 | bold),
+                40..=47 => self.color_code.with_background(color(40)),
+                49 => self.color_code.with_background(DEFAULT_COLOR.0 >> 4),
+                90..=97 => self.color_code.with_foreground(color(90) | BRIGHT),
+                100..=107 => self.color_code.with_background(color(100) | BRIGHT),
+                _ => self.color_code,
+            };
+        }
+    }
+}
+
+/// Shows the screen on `framebuffer` from now on instead of in VGA text mode, drawn with the built in font.
+///
+/// The screen keeps its 80x25 cells, in the top left corner of the framebuffer, and `print!` and the terminals
+/// work as before. Needs the heap. Fails if the framebuffer is too small or there is no memory to draw in.
+pub fn use_framebuffer(framebuffer: Framebuffer) -> Result<(), FramebufferError> {
+    terminal::with_shown(|writer| {
+        fbcon::install(framebuffer, Font::builtin())?;
+        let chars = core::array::from_fn(|_| core::array::from_fn(|_| Volatile::new(BLANK)));
+        let screen = Box::leak(Box::new(Buffer { chars }));
+        SCREEN.store(screen, Ordering::Relaxed);
+        writer.dirty = ALL_ROWS;
+        writer.flush();
+        Ok(())
+    })
+}
+
+/// Calls `f` with every non-empty line on the screen, oldest first, with trailing blanks removed.
+///
+/// Reads the buffer without taking `WRITER`, so it works in crash handlers that interrupted a print,
+/// at the cost of possibly seeing a half written line.
+pub fn for_each_screen_line(mut f: impl FnMut(&str)) {
+    let buffer = SCREEN.load(Ordering::Relaxed) as *const ScreenChar;
+    for row in 0..BUFFER_HEIGHT {
+        let mut line = [b' '; BUFFER_WIDTH];
+        for (col, byte) in line.iter_mut().enumerate() {
+            let character = unsafe { buffer.add(row * BUFFER_WIDTH + col).read_volatile() }.ascii_character;
+            *byte = if (0x20..=0x7e).contains(&character) { character } else { b'?' };
+        }
+        let len = line.iter().rposition(|byte| *byte != b' ').map_or(0, |last| last + 1);
+        if len > 0 {
+            // only printable ASCII was kept
+            f(core::str::from_utf8(&line[..len]).unwrap());
+        }
+    }
+}
+
+/// Stops the writers from drawing to the screen, blanks it in `background` and returns it for drawing directly.
+///
+/// For the panic screen: nothing else shows up on the screen afterwards, even if other CPUs keep printing.
+pub fn take_over_screen(foreground: Color, background: Color) -> RawScreen {
+    FROZEN.store(true, Ordering::Relaxed);
+    set_hardware_cursor(None);
+    let mut screen = RawScreen {
+        row: 0,
+        column: 0,
+        color: ColorCode::new(foreground, background),
+    };
+    screen.clear();
+    screen
+}
+
+/// The screen, drawn to without a writer or lock, see `take_over_screen`.
+///
+/// Text wraps at the right edge, and what doesn't fit above the bottom edge is dropped. Escape sequences are not
+/// interpreted. On a framebuffer the text only shows up on `present`, in text mode it does right away.
+pub struct RawScreen {
+    row: usize,
+    column: usize,
+    color: ColorCode,
+}
+
+impl RawScreen {
+    /// Uses `foreground` on `background` for the text written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color = ColorCode::new(foreground, background);
+    }
+
+    /// Returns the row text is written to next, `BUFFER_HEIGHT` once the screen is full.
+    pub fn row(&self) -> usize {
+        self.row
+    }
+
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.row = row.min(BUFFER_HEIGHT);
+        self.column = column.min(BUFFER_WIDTH);
+    }
+
+    /// Blanks the screen in the current color and moves the write position to the top left corner.
+    pub fn clear(&mut self) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color,
+        };
+        for row in screen().chars.iter_mut() {
+            for cell in row.iter_mut() {
+                cell.write(blank);
+            }
+        }
+        self.set_position(0, 0);
+    }
+
+    /// Draws the screen onto the framebuffer, if it is shown on one and nothing else is drawing on it.
+    pub fn present(&self) {
+        fbcon::try_refresh(screen(), None);
+    }
+}
+
+impl fmt::Write for RawScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                self.set_position(self.row + 1, 0);
+                continue;
+            }
+            if self.column >= BUFFER_WIDTH {
+                self.set_position(self.row + 1, 0);
             }
+            if self.row >= BUFFER_HEIGHT {
+                break;
+            }
+            screen().chars[self.row][self.column].write(ScreenChar {
+                ascii_character: cp437::encode(c).unwrap_or(0xfe),
+                color_code: self.color,
+            });
+            self.column += 1;
         }
+        Ok(())
     }
 }
 
@@ -157,12 +872,145 @@ fn test_println_many() {
     }
 }
 
+#[test_case]
+fn test_screen_lines_are_read_back() {
+    println!("screen line read back");
+    let mut found = false;
+    for_each_screen_line(|line| found |= line == "screen line read back");
+    assert!(found);
+}
+
+#[test_case]
+fn test_irq_println_is_staged() {
+    irq_println!("staged interrupt output");
+    let mut found = false;
+    for_each_screen_line(|line| found |= line == "staged interrupt output");
+    assert!(!found);
+    flush_staged();
+    for_each_screen_line(|line| found |= line == "staged interrupt output");
+    assert!(found);
+}
+
 #[test_case]
 fn test_println_output() {
     let s = "Some test string that fits on a single line";
     println!("{}", s);
     for (i, c) in s.chars().enumerate() {
-        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        let screen_char = screen().chars[BUFFER_HEIGHT - 2][i].read();
         assert_eq!(char::from(screen_char.ascii_character), c);
     }
 }
+
+#[test_case]
+fn test_escape_sequences_set_colors() {
+    println!();
+    println!("plain \x1b[31mred\x1b[1;44m bold\x1b[0m plain");
+    let writer = WRITER.lock();
+    let row = &writer.shadow[writer.row_position - 1];
+    let color = |col: usize| row[col].color_code;
+    assert_eq!(row[6].ascii_character, b'r');
+    assert_eq!(color(0), DEFAULT_COLOR);
+    assert_eq!(color(6), DEFAULT_COLOR.with_foreground(Color::Red as u8));
+    assert_eq!(color(10), ColorCode::new(Color::LightRed, Color::Blue));
+    assert_eq!(color(15), DEFAULT_COLOR);
+}
+
+#[test_case]
+fn test_bold_brightens_colors_set_after_it() {
+    println!();
+    println!("\x1b[1;31mred\x1b[32mgreen\x1b[22mdim\x1b[0m");
+    let writer = WRITER.lock();
+    let row = &writer.shadow[writer.row_position - 1];
+    assert_eq!(row[0].color_code, DEFAULT_COLOR.with_foreground(Color::LightRed as u8));
+    assert_eq!(row[3].color_code, DEFAULT_COLOR.with_foreground(Color::LightGreen as u8));
+    assert_eq!(row[8].color_code, DEFAULT_COLOR.with_foreground(Color::Green as u8));
+}
+
+#[test_case]
+fn test_escape_sequences_move_and_clear() {
+    println!();
+    print!("abcdef\x1b[3D\x1b[K");
+    let column = WRITER.lock().column();
+    println!();
+    let writer = WRITER.lock();
+    let row = &writer.shadow[writer.row_position - 1];
+    assert_eq!(column, 3);
+    assert_eq!(row[2].ascii_character, b'c');
+    assert_eq!(row[3].ascii_character, b' ');
+}
+
+#[test_case]
+fn test_with_color_restores_colors() {
+    let before = WRITER.lock().color_code;
+    crate::console::with_color(Color::Green, Color::Black, || {
+        assert_eq!(WRITER.lock().color_code, ColorCode::new(Color::Green, Color::Black));
+        crate::warn!("colored warning");
+        // the macros restore the colors they replaced
+        assert_eq!(WRITER.lock().color_code, ColorCode::new(Color::Green, Color::Black));
+    });
+    assert_eq!(WRITER.lock().color_code, before);
+    let writer = WRITER.lock();
+    let row = &writer.shadow[writer.row_position - 1];
+    assert_eq!(row[0].color_code, ColorCode::new(Color::LightRed, Color::Black));
+}
+
+#[test_case]
+fn test_colored_logs_leave_the_next_row_uncolored() {
+    crate::error!("colored error");
+    let writer = WRITER.lock();
+    assert_eq!(writer.shadow[writer.row_position - 1][0].color_code, ColorCode::new(Color::White, Color::Red));
+    assert_eq!(writer.shadow[writer.row_position][0], BLANK);
+}
+
+#[test_case]
+fn test_output_is_flushed_on_new_lines() {
+    use core::fmt::Write;
+    println!();
+    let mut writer = WRITER.lock();
+    let row = writer.row_position;
+    let shown = |col: usize| screen().chars[row][col].read().ascii_character;
+    write!(writer, "unflushed").unwrap();
+    assert_eq!(shown(0), b' ');
+    writer.flush();
+    assert_eq!(shown(0), b'u');
+    writeln!(writer, " until now").unwrap();
+    assert_eq!(shown(10), b'u');
+}
+
+#[test_case]
+fn test_cursor_follows_output() {
+    println!();
+    print!("abc");
+    let position = || {
+        let writer = WRITER.lock();
+        (writer.row_position * BUFFER_WIDTH + writer.column_position) as u16
+    };
+    assert_eq!(HARDWARE_CURSOR.load(Ordering::Relaxed), position());
+    hide_cursor();
+    assert_eq!(HARDWARE_CURSOR.load(Ordering::Relaxed), CURSOR_OFF);
+    show_cursor();
+    assert_eq!(HARDWARE_CURSOR.load(Ordering::Relaxed), position());
+    println!();
+}
+
+#[test_case]
+fn test_unicode_is_shown_in_code_page_437() {
+    println!();
+    println!("grün ─€");
+    let writer = WRITER.lock();
+    let row = &writer.shadow[writer.row_position - 1];
+    let bytes: [u8; 7] = core::array::from_fn(|col| row[col].ascii_character);
+    assert_eq!(&bytes, b"gr\x81n \xc4\xfe");
+}
+
+#[test_case]
+fn test_scroll_region_keeps_other_rows() {
+    print!("\x1b[1;1Hpinned\x1b[2r");
+    for _ in 0..BUFFER_HEIGHT {
+        println!("scrolled");
+    }
+    let mut writer = WRITER.lock();
+    assert_eq!(writer.shadow[0][0].ascii_character, b'p');
+    assert_eq!(writer.shadow[1][0].ascii_character, b's');
+    writer.set_scroll_region(0..BUFFER_HEIGHT);
+}
@@ -0,0 +1,100 @@
+//! A parser for the subset of ANSI escape sequences the VGA writer understands: CSI sequences like
+//! `\x1b[31m`. Other escape sequences are dropped.
+
+const ESC: u8 = 0x1b;
+/// Parameters past this many are ignored.
+pub(super) const MAX_PARAMS: usize = 4;
+
+/// What to do with a byte fed to `Parser::advance`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
+    /// Not part of an escape sequence.
+    Print(u8),
+    /// A complete CSI sequence. Parameters that were left out are 0.
+    Csi { params: [u16; MAX_PARAMS], count: usize, final_byte: u8 },
+    /// Part of an unfinished or ignored escape sequence.
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub(super) struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    /// Number of parameters seen so far, possibly more than `MAX_PARAMS`.
+    count: usize,
+}
+
+impl Parser {
+    pub(super) const fn new() -> Self {
+        Parser { state: State::Ground, params: [0; MAX_PARAMS], count: 0 }
+    }
+
+    pub(super) fn advance(&mut self, byte: u8) -> Action {
+        match self.state {
+            State::Ground if byte == ESC => {
+                self.state = State::Escape;
+                Action::None
+            }
+            State::Ground => Action::Print(byte),
+            State::Escape if byte == b'[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.count = 0;
+                Action::None
+            }
+            State::Escape => {
+                self.state = State::Ground;
+                Action::None
+            }
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    self.count = self.count.max(1);
+                    if let Some(param) = self.params.get_mut(self.count - 1) {
+                        *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                    }
+                    Action::None
+                }
+                b';' => {
+                    self.count = self.count.max(1) + 1;
+                    Action::None
+                }
+                // intermediate bytes and private markers like the `?` of `\x1b[?25l`
+                0x20..=0x2f | b'<'..=b'?' => Action::None,
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    Action::Csi { params: self.params, count: self.count.min(MAX_PARAMS), final_byte: byte }
+                }
+                // anything else cancels the sequence
+                _ => {
+                    self.state = State::Ground;
+                    Action::None
+                }
+            },
+        }
+    }
+}
+
+#[test_case]
+fn test_parser_reads_csi_sequences() {
+    let mut parser = Parser::new();
+    assert_eq!(parser.advance(b'a'), Action::Print(b'a'));
+    for &byte in b"\x1b[1;31" {
+        assert_eq!(parser.advance(byte), Action::None);
+    }
+    assert_eq!(parser.advance(b'm'), Action::Csi { params: [1, 31, 0, 0], count: 2, final_byte: b'm' });
+    // a left out first parameter
+    for &byte in b"\x1b[;5" {
+        assert_eq!(parser.advance(byte), Action::None);
+    }
+    assert_eq!(parser.advance(b'H'), Action::Csi { params: [0, 5, 0, 0], count: 2, final_byte: b'H' });
+    // other escape sequences are dropped
+    assert_eq!(parser.advance(0x1b), Action::None);
+    assert_eq!(parser.advance(b'c'), Action::None);
+    assert_eq!(parser.advance(b'b'), Action::Print(b'b'));
+}
@@ -0,0 +1,52 @@
+//! Translation of Unicode characters to code page 437, the character set of the VGA text mode font.
+
+/// The characters of the bytes 0x80 to 0xff: accented Latin letters, box drawing, Greek and math symbols.
+static UPPER: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// The symbols of the control bytes 0x01 to 0x1f, which the font draws like any other character.
+static LOWER: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►',
+    '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Returns the code page 437 byte that shows `c`, or `None` if the font has no such character.
+///
+/// Printable ASCII maps to itself. A few common characters without their own glyph, like typographic quotes and
+/// dashes, map to the closest ASCII character.
+pub fn encode(c: char) -> Option<u8> {
+    if matches!(c, ' '..='~') {
+        return Some(c as u8);
+    }
+    let approximation = match c {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' => Some(b'\''),
+        '\u{201c}' | '\u{201d}' | '\u{201e}' => Some(b'"'),
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => Some(b'-'),
+        '\u{2302}' => Some(0x7f),
+        _ => None,
+    };
+    approximation
+        .or_else(|| UPPER.iter().position(|upper| *upper == c).map(|index| 0x80 + index as u8))
+        .or_else(|| LOWER.iter().position(|lower| *lower == c).map(|index| 0x01 + index as u8))
+}
+
+#[test_case]
+fn test_encode_maps_to_code_page_437() {
+    assert_eq!(encode('a'), Some(b'a'));
+    assert_eq!(encode('ä'), Some(0x84));
+    assert_eq!(encode('ß'), Some(0xe1));
+    assert_eq!(encode('─'), Some(0xc4));
+    assert_eq!(encode('╬'), Some(0xce));
+    assert_eq!(encode('→'), Some(0x1a));
+    assert_eq!(encode('\u{2019}'), Some(b'\''));
+    assert_eq!(encode('\n'), None);
+    assert_eq!(encode('€'), None);
+}
@@ -0,0 +1,142 @@
+//! Draws the screen buffer onto a framebuffer, for machines without VGA text mode.
+//!
+//! Writers keep writing to a buffer of text cells. On every update of the terminal shown, the cells that changed
+//! since the last one are drawn with a bitmap font, so only a scroll has to redraw the whole screen.
+
+use super::{Buffer, Color, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::{
+    framebuffer::{font::Font, Framebuffer, FramebufferError, Rgb},
+    sync::IrqSpinLock,
+};
+use alloc::boxed::Box;
+
+/// Locked after the writer of the terminal shown.
+static DISPLAY: IrqSpinLock<Option<Display>> = IrqSpinLock::new(None);
+
+/// Rows of pixels at the bottom of a cell that the cursor covers.
+const CURSOR_HEIGHT: usize = 2;
+
+struct Display {
+    framebuffer: Framebuffer,
+    font: Font,
+    /// What each cell shows, `None` for cells that have to be drawn again.
+    shown: Box<[[Option<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT]>,
+    /// The row and column the cursor is drawn at.
+    cursor: Option<(usize, usize)>,
+    /// Set if the picture moved since it was last presented, so that all of it has to be presented.
+    scrolled: bool,
+}
+
+impl Display {
+    fn draw_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        let (width, height) = (self.font.width(), self.font.height());
+        let foreground = Rgb::from(Color::ALL[usize::from(character.color_code.0 & 0x0f)]);
+        let background = Rgb::from(Color::ALL[usize::from(character.color_code.0 >> 4)]);
+        let (x, y) = (col * width, row * height);
+        let glyph = usize::from(character.ascii_character);
+        self.framebuffer.draw_glyph(x, y, &self.font, glyph, foreground, background);
+        if self.cursor == Some((row, col)) {
+            self.framebuffer.fill_rect(x, y + height - CURSOR_HEIGHT, width, CURSOR_HEIGHT, foreground);
+        }
+        self.shown[row][col] = Some(character);
+    }
+}
+
+/// Shows the screen on `framebuffer` from the next `refresh` on, drawn with `font`.
+///
+/// The text is drawn in the top left corner, so the framebuffer must have room for every cell.
+pub(super) fn install(mut framebuffer: Framebuffer, font: Font) -> Result<(), FramebufferError> {
+    if framebuffer.width() < BUFFER_WIDTH * font.width() || framebuffer.height() < BUFFER_HEIGHT * font.height() {
+        return Err(FramebufferError::UnsupportedMode);
+    }
+    // scrolling reads the picture back, which is slow from device memory
+    framebuffer.enable_double_buffering()?;
+    framebuffer.clear(Rgb::BLACK);
+    framebuffer.present();
+    *DISPLAY.lock() = Some(Display {
+        framebuffer,
+        font,
+        shown: Box::new([[None; BUFFER_WIDTH]; BUFFER_HEIGHT]),
+        cursor: None,
+        scrolled: false,
+    });
+    Ok(())
+}
+
+/// Draws the cells of `buffer` that changed since the last call, and the cursor at `cursor`, if not hidden.
+///
+/// Returns `false` if no framebuffer is installed.
+pub(super) fn refresh(buffer: &Buffer, cursor: Option<(usize, usize)>) -> bool {
+    match DISPLAY.lock().as_mut() {
+        Some(display) => {
+            draw_changes(display, buffer, cursor);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Like `refresh`, but gives up if the display is locked, for the panic screen.
+pub(super) fn try_refresh(buffer: &Buffer, cursor: Option<(usize, usize)>) -> bool {
+    match DISPLAY.try_lock() {
+        Some(mut display) => match display.as_mut() {
+            Some(display) => {
+                draw_changes(display, buffer, cursor);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
+fn draw_changes(display: &mut Display, buffer: &Buffer, cursor: Option<(usize, usize)>) {
+    if display.cursor != cursor {
+        for (row, col) in [display.cursor, cursor].iter().flatten() {
+            display.shown[*row][*col] = None;
+        }
+        display.cursor = cursor;
+    }
+
+    // the rows drawn to, from `first` up to `end`
+    let (mut first, mut end) = (BUFFER_HEIGHT, 0);
+    for (row, cells) in buffer.chars.iter().enumerate() {
+        for (col, cell) in cells.iter().enumerate() {
+            let character = cell.read();
+            if display.shown[row][col] != Some(character) {
+                display.draw_cell(row, col, character);
+                first = first.min(row);
+                end = row + 1;
+            }
+        }
+    }
+    if display.scrolled {
+        display.scrolled = false;
+        (first, end) = (0, BUFFER_HEIGHT);
+    }
+    if first < end {
+        let height = display.font.height();
+        display.framebuffer.present_rows(first * height, (end - first) * height);
+    }
+}
+
+/// Moves the picture up by one row of cells, after the screen buffer did the same.
+pub(super) fn scrolled() {
+    let mut display = DISPLAY.lock();
+    let display = match display.as_mut() {
+        Some(display) => display,
+        None => return,
+    };
+    display.shown.copy_within(1.., 0);
+    display.shown[BUFFER_HEIGHT - 1] = [None; BUFFER_WIDTH];
+    // the cursor moved up with its cell but stays where it was, like the one of text mode
+    if let Some((row, col)) = display.cursor {
+        if let Some(above) = row.checked_sub(1) {
+            display.shown[above][col] = None;
+        }
+        display.shown[row][col] = None;
+    }
+    let height = display.font.height();
+    display.framebuffer.scroll_up(height, Rgb::BLACK);
+    display.scrolled = true;
+}
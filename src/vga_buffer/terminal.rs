@@ -0,0 +1,80 @@
+//! Virtual terminals, each with its own text and cursor, of which the active one is shown on the screen.
+//!
+//! Terminal 0 is `WRITER`, which `print!` writes to. The others exist once `init` has allocated them.
+
+use super::{Writer, ALL_ROWS, WRITER};
+use crate::sync::IrqSpinLock;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub const COUNT: usize = 4;
+/// The terminal of the kernel log, the one `print!` writes to.
+pub const LOG: usize = 0;
+/// The terminal of the interactive shell.
+pub const SHELL: usize = 1;
+
+/// Terminals 1 and up.
+static OTHERS: OnceCell<Vec<IrqSpinLock<Writer>>> = OnceCell::uninit();
+/// Serializes switches. Locked before any terminal.
+static SWITCH: IrqSpinLock<()> = IrqSpinLock::new(());
+static ACTIVE: AtomicUsize = AtomicUsize::new(LOG);
+
+/// Creates the terminals other than the kernel log. Needs the heap.
+pub fn init() {
+    let _ = OTHERS.try_init_once(|| (1..COUNT).map(|_| IrqSpinLock::new(Writer::new(false))).collect());
+}
+
+/// Returns the writer of terminal `index`, or `None` if there is no such terminal (yet).
+pub fn terminal(index: usize) -> Option<&'static IrqSpinLock<Writer>> {
+    match index {
+        LOG => Some(&WRITER),
+        _ => OTHERS.get()?.get(index - 1),
+    }
+}
+
+/// Returns the index of the terminal shown on the screen.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Shows terminal `index` on the screen, returning `false` if there is no such terminal.
+pub fn switch(index: usize) -> bool {
+    let next = match terminal(index) {
+        Some(next) => next,
+        None => return false,
+    };
+    let _switch = SWITCH.lock();
+    let previous = active();
+    if previous == index {
+        return true;
+    }
+    // the previous terminal stops flushing to the screen, the next one redraws all of it
+    terminal(previous).unwrap().lock().visible = false;
+    let mut next = next.lock();
+    next.visible = true;
+    next.dirty = ALL_ROWS;
+    next.flush();
+    ACTIVE.store(index, Ordering::Relaxed);
+    true
+}
+
+/// Calls `f` with the writer of the terminal shown, with switches held off.
+pub(super) fn with_shown<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    let _switch = SWITCH.lock();
+    let mut writer = terminal(active()).unwrap().lock();
+    f(&mut writer)
+}
+
+/// Prints `args` to terminal `index`, whether it is shown or not. Does nothing if there is no such terminal.
+pub fn print(index: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(writer) = terminal(index) {
+        let mut writer = writer.lock();
+        writer.write_fmt(args).unwrap();
+        writer.flush();
+    }
+}
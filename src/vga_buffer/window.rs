@@ -0,0 +1,302 @@
+//! Rectangular windows on a terminal, each with its own write position, colors, scrolling and border.
+//!
+//! A monitor screen can keep a status bar in the top row and a log in a window below it, while
+//! `Writer::set_scroll_region` keeps the terminal's own output from scrolling them away:
+//!
+//! ```ignore
+//! let mut status = Window::new(terminal::LOG, Rect::new(0, 0, BUFFER_WIDTH, 1)).unwrap();
+//! status.set_line(0, "uptime 12 s");
+//! terminal::terminal(terminal::LOG).unwrap().lock().set_scroll_region(1..BUFFER_HEIGHT);
+//! ```
+
+use super::{cp437, terminal, Color, ColorCode, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, DEFAULT_COLOR};
+use crate::sync::IrqSpinLock;
+use core::{fmt, ops::Range};
+
+/// A rectangle of cells, in rows and columns from the top left corner of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub top: usize,
+    pub left: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(top: usize, left: usize, width: usize, height: usize) -> Rect {
+        Rect { top, left, width, height }
+    }
+
+    fn rows(&self) -> Range<usize> {
+        self.top..self.top + self.height
+    }
+
+    fn cols(&self) -> Range<usize> {
+        self.left..self.left + self.width
+    }
+
+    /// Returns the rectangle with `by` cells taken off every side.
+    fn shrink(&self, by: usize) -> Rect {
+        Rect {
+            top: self.top + by,
+            left: self.left + by,
+            width: self.width.saturating_sub(2 * by),
+            height: self.height.saturating_sub(2 * by),
+        }
+    }
+}
+
+/// The frame drawn around a window, with the box drawing characters of code page 437.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Border {
+    None,
+    Single,
+    Double,
+}
+
+impl Border {
+    /// Returns the top left, top right, bottom left and bottom right corners, and the horizontal and vertical edges.
+    fn characters(self) -> Option<[char; 6]> {
+        match self {
+            Border::None => None,
+            Border::Single => Some(['┌', '┐', '└', '┘', '─', '│']),
+            Border::Double => Some(['╔', '╗', '╚', '╝', '═', '║']),
+        }
+    }
+}
+
+/// A rectangle of a terminal that text is written to and scrolled in independently of the rest of it.
+///
+/// Text wraps at the right edge of the window and scrolls it once its bottom row is full. Escape sequences
+/// are not interpreted. The window draws into the terminal like its own output does, so the two overwrite
+/// each other where they overlap. Every write is flushed to the screen if the terminal is shown.
+pub struct Window {
+    writer: &'static IrqSpinLock<Writer>,
+    area: Rect,
+    border: Border,
+    title: Option<&'static str>,
+    color: ColorCode,
+    /// The write position within the inside of the window.
+    row: usize,
+    column: usize,
+}
+
+impl Window {
+    /// Creates a window covering `area` of terminal `terminal` and blanks it.
+    ///
+    /// Returns `None` if there is no such terminal (yet). Panics if `area` is empty or extends past the screen.
+    pub fn new(terminal: usize, area: Rect) -> Option<Window> {
+        assert!(
+            area.width > 0 && area.height > 0 && area.cols().end <= BUFFER_WIDTH && area.rows().end <= BUFFER_HEIGHT,
+            "window {:?} is not on the screen",
+            area
+        );
+        let mut window = Window {
+            writer: terminal::terminal(terminal)?,
+            area,
+            border: Border::None,
+            title: None,
+            color: DEFAULT_COLOR,
+            row: 0,
+            column: 0,
+        };
+        window.redraw();
+        Some(window)
+    }
+
+    /// Draws `border` around the window, inside of its area, and blanks the window.
+    pub fn with_border(mut self, border: Border) -> Window {
+        self.border = border;
+        self.redraw();
+        self
+    }
+
+    /// Shows `title` in the top edge of the border, and blanks the window. Needs a border.
+    pub fn with_title(mut self, title: &'static str) -> Window {
+        self.title = Some(title);
+        self.redraw();
+        self
+    }
+
+    /// Uses `foreground` on `background` for the border and the text, and blanks the window.
+    pub fn with_color(mut self, foreground: Color, background: Color) -> Window {
+        self.color = ColorCode::new(foreground, background);
+        self.redraw();
+        self
+    }
+
+    /// Uses `foreground` on `background` for the text written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color = ColorCode::new(foreground, background);
+    }
+
+    /// Returns the number of columns text is written to, the width of the window without its border.
+    pub fn width(&self) -> usize {
+        self.inside().width
+    }
+
+    /// Returns the number of rows text is written to, the height of the window without its border.
+    pub fn height(&self) -> usize {
+        self.inside().height
+    }
+
+    /// Blanks the inside of the window and moves the write position to its top left corner.
+    pub fn clear(&mut self) {
+        let inside = self.inside();
+        let mut writer = self.writer.lock();
+        for row in inside.rows() {
+            self.fill(&mut writer, row, inside.cols());
+        }
+        self.row = 0;
+        self.column = 0;
+        writer.flush();
+    }
+
+    /// Moves the write position to `column` of `row` of the inside of the window, or as close as possible.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.row = row.min(self.height().saturating_sub(1));
+        self.column = column.min(self.width());
+    }
+
+    /// Replaces the text of `row` with `text`, cut off at the right edge, without moving the write position.
+    ///
+    /// Suits status lines that are rewritten in place. Does nothing if the window has no such row.
+    pub fn set_line(&mut self, row: usize, text: &str) {
+        let inside = self.inside();
+        if row >= inside.height {
+            return;
+        }
+        let row = inside.top + row;
+        let mut writer = self.writer.lock();
+        let mut chars = text.chars().filter(|c| *c != '\n');
+        for col in inside.cols() {
+            let character = chars.next().map_or(b' ', encode);
+            writer.shadow[row][col] = ScreenChar {
+                ascii_character: character,
+                color_code: self.color,
+            };
+        }
+        writer.dirty |= 1 << row;
+        writer.flush();
+    }
+
+    /// Returns the part of the window inside of its border.
+    fn inside(&self) -> Rect {
+        match self.border {
+            Border::None => self.area,
+            _ => self.area.shrink(1),
+        }
+    }
+
+    /// Blanks the whole area, draws the border and title and moves the write position to the top left corner.
+    fn redraw(&mut self) {
+        let mut writer = self.writer.lock();
+        for row in self.area.rows() {
+            self.fill(&mut writer, row, self.area.cols());
+        }
+        if let Some([top_left, top_right, bottom_left, bottom_right, horizontal, vertical]) = self.border.characters() {
+            let (top, bottom) = (self.area.top, self.area.rows().end - 1);
+            let (left, right) = (self.area.left, self.area.cols().end - 1);
+            for col in left + 1..right {
+                self.put(&mut writer, top, col, horizontal);
+                self.put(&mut writer, bottom, col, horizontal);
+            }
+            for row in top + 1..bottom {
+                self.put(&mut writer, row, left, vertical);
+                self.put(&mut writer, row, right, vertical);
+            }
+            self.put(&mut writer, top, left, top_left);
+            self.put(&mut writer, top, right, top_right);
+            self.put(&mut writer, bottom, left, bottom_left);
+            self.put(&mut writer, bottom, right, bottom_right);
+            if let Some(title) = self.title {
+                // a blank on each side of the title, cut off before the right corner
+                let title = core::iter::once(' ').chain(title.chars()).chain(core::iter::once(' '));
+                for (col, c) in (left + 1..right).zip(title) {
+                    self.put(&mut writer, top, col, c);
+                }
+            }
+        }
+        self.row = 0;
+        self.column = 0;
+        writer.flush();
+    }
+
+    fn put(&self, writer: &mut Writer, row: usize, col: usize, c: char) {
+        writer.shadow[row][col] = ScreenChar {
+            ascii_character: encode(c),
+            color_code: self.color,
+        };
+        writer.dirty |= 1 << row;
+    }
+
+    fn fill(&self, writer: &mut Writer, row: usize, cols: Range<usize>) {
+        for col in cols {
+            self.put(writer, row, col, ' ');
+        }
+    }
+
+    fn new_line(&mut self, writer: &mut Writer) {
+        let inside = self.inside();
+        self.column = 0;
+        if self.row + 1 < inside.height {
+            self.row += 1;
+            return;
+        }
+        for row in inside.top..inside.rows().end - 1 {
+            let below = writer.shadow[row + 1];
+            writer.shadow[row][inside.cols()].copy_from_slice(&below[inside.cols()]);
+            writer.dirty |= 1 << row;
+        }
+        self.fill(writer, inside.rows().end - 1, inside.cols());
+    }
+}
+
+/// Writes text at the write position and flushes it.
+impl fmt::Write for Window {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let inside = self.inside();
+        if inside.width == 0 || inside.height == 0 {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock();
+        for c in s.chars() {
+            if c == '\n' {
+                self.new_line(&mut writer);
+                continue;
+            }
+            if self.column >= inside.width {
+                self.new_line(&mut writer);
+            }
+            self.put(&mut writer, inside.top + self.row, inside.left + self.column, c);
+            self.column += 1;
+        }
+        writer.flush();
+        Ok(())
+    }
+}
+
+/// Returns the byte showing `c`, a filled in square if the font lacks it, like the writer does.
+fn encode(c: char) -> u8 {
+    cp437::encode(c).unwrap_or(0xfe)
+}
+
+#[test_case]
+fn test_windows_scroll_on_their_own() {
+    use core::fmt::Write;
+    let mut window = Window::new(terminal::LOG, Rect::new(2, 50, 12, 4))
+        .unwrap()
+        .with_border(Border::Single)
+        .with_title("log");
+    assert_eq!((window.width(), window.height()), (10, 2));
+    write!(window, "one\ntwo\nthree and more").unwrap();
+    window.set_line(0, "status");
+
+    let writer = window.writer.lock();
+    // the 12 columns of the window, without the heap, which unit tests don't have
+    let text = |row: usize| -> [u8; 12] { core::array::from_fn(|col| writer.shadow[row][50 + col].ascii_character) };
+    assert_eq!(&text(2), b"\xda log \xc4\xc4\xc4\xc4\xc4\xbf");
+    assert_eq!(&text(3), b"\xb3status    \xb3");
+    assert_eq!(&text(4), b"\xb3more      \xb3");
+    assert_eq!(&text(5), b"\xc0\xc4\xc4\xc4\xc4\xc4\xc4\xc4\xc4\xc4\xc4\xd9");
+}
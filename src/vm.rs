@@ -0,0 +1,92 @@
+use crate::memory::{
+    self,
+    swap,
+    tlb,
+    vmm::{self, RegionKind, VirtualRegion, VmmError},
+};
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameDeallocator, Mapper, PageTableFlags},
+    VirtAddr,
+};
+
+/// Flags of anonymous pages, which hold data only.
+const ANONYMOUS_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// A range of kernel virtual memory backed by zeroed frames that are allocated on first touch.
+///
+/// Dropping the mapping unmaps it and frees every frame and swap slot behind it.
+pub struct AnonymousMapping {
+    region: VirtualRegion,
+    len: usize,
+}
+
+impl AnonymousMapping {
+    pub fn start(&self) -> VirtAddr {
+        self.region.start()
+    }
+
+    /// Returns the requested length in bytes. The mapping itself is rounded up to whole pages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.region.start().as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.region.start().as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for AnonymousMapping {
+    fn drop(&mut self) {
+        memory::with_kernel_memory(|memory| {
+            for page in self.region.pages() {
+                match memory.mapper.unmap(page) {
+                    Ok((frame, flush)) => {
+                        flush.ignore();
+                        tlb::flush(page);
+                        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                    }
+                    Err(_) => {
+                        swap::discard(memory, page);
+                    }
+                }
+            }
+        });
+        vmm::release(self.region.start()).expect("anonymous mapping released twice");
+    }
+}
+
+/// Reserves `len` bytes of kernel virtual memory that are backed lazily with zeroed frames.
+///
+/// No physical memory is used until the pages are touched, so this suits scratch buffers that are
+/// too large for the heap. The pages are never swapped out and may be accessed from interrupt handlers.
+pub fn map_anonymous(len: usize) -> Result<AnonymousMapping, VmmError> {
+    let region = vmm::allocate_on_demand(len as u64, RegionKind::Anonymous, ANONYMOUS_FLAGS)?;
+    Ok(AnonymousMapping { region, len })
+}
+
+/// Like `map_anonymous`, but cold pages of the mapping may be written to the swap device.
+///
+/// The mapping must not be accessed from interrupt handlers or with the kernel memory lock held.
+pub fn map_anonymous_swappable(len: usize) -> Result<AnonymousMapping, VmmError> {
+    let region = vmm::allocate_swappable(len as u64, RegionKind::Anonymous, ANONYMOUS_FLAGS)?;
+    Ok(AnonymousMapping { region, len })
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, address_space, debug, AddressSpace};
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Lies in level 4 entry 300, which the kernel does not use.
+const PRIVATE_ADDR: u64 = 0x_9600_0000_0000;
+
+#[test_case]
+fn private_mappings_are_isolated() {
+    let addr = VirtAddr::new(PRIVATE_ADDR);
+    let page = Page::containing_address(addr);
+    let mut first = AddressSpace::new().expect("failed to create address space");
+    let second = AddressSpace::new().expect("failed to create address space");
+    assert!(first.is_private(addr));
+
+    memory::with_kernel_memory(|kernel| {
+        let frame = kernel.frame_allocator.allocate_frame().expect("out of frames");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            first.mapper()
+                .map_to(page, frame, flags, &mut kernel.frame_allocator)
+                .expect("failed to map private page")
+                .ignore();
+        }
+        assert!(kernel.mapper.translate_addr(addr).is_none());
+    })
+    .unwrap();
+
+    first.activate();
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+    second.activate();
+    assert!(second.is_active());
+    assert!(first.mapper().translate_addr(addr).is_some());
+
+    address_space::activate_kernel();
+    assert!(!first.is_active() && !second.is_active());
+}
+
+#[test_case]
+fn kernel_mappings_are_shared() {
+    static VALUE: u64 = 7;
+    let space = AddressSpace::new().expect("failed to create address space");
+    space.activate();
+    let heap_value = Box::new(99);
+    assert_eq!(unsafe { core::ptr::read_volatile(&VALUE) }, 7);
+    address_space::activate_kernel();
+    assert_eq!(*heap_value, 99);
+}
+
+#[test_case]
+fn dump_reports_private_mappings() {
+    let addr = VirtAddr::new(PRIVATE_ADDR);
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    memory::with_kernel_memory(|kernel| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in Page::range(Page::containing_address(addr), Page::containing_address(addr + 0x3000u64)) {
+            let frame = kernel.frame_allocator.allocate_frame().expect("out of frames");
+            unsafe {
+                space.mapper()
+                    .map_to(page, frame, flags, &mut kernel.frame_allocator)
+                    .expect("failed to map private page")
+                    .ignore();
+            }
+        }
+    })
+    .unwrap();
+
+    let mut pages = 0;
+    debug::for_each_mapping(space.l4_frame(), PRIVATE_ADDR..PRIVATE_ADDR + 0x1_0000, |mapping| {
+        assert!(mapping.start >= addr && mapping.end() <= PRIVATE_ADDR + 0x3000);
+        assert!(mapping.flags.contains(PageTableFlags::NO_EXECUTE));
+        pages += mapping.size / 4096;
+    });
+    assert_eq!(pages, 3);
+    debug::dump_table(space.l4_frame(), addr..addr + 0x3000u64);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{ahci, ata::SECTOR_SIZE},
+    interrupts::apic,
+    memory,
+    pci::driver,
+    task::block_on,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("local APIC initialization failed");
+    rust_os::time::init();
+    driver::register(&ahci::DRIVER);
+    driver::probe_all();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Needs the SATA drive that `test-args` in `Cargo.toml` attaches, on QEMU's first AHCI port.
+#[test_case]
+fn sectors_survive_a_round_trip() {
+    // completions come by interrupt, which wakes `block_on`
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    let disk = *ahci::disks().first().expect("no AHCI disk attached");
+    assert!(disk.sectors > 0);
+    let mut original = [0u8; 2 * SECTOR_SIZE];
+    block_on(disk.read_sectors(1, &mut original)).expect("read failed");
+
+    // differs from the original in every bit
+    let pattern = original.map(|byte| !byte);
+    block_on(disk.write_sectors(1, &pattern)).expect("write failed");
+    let mut read_back = [0u8; 2 * SECTOR_SIZE];
+    block_on(disk.read_sectors(1, &mut read_back)).expect("read failed");
+    block_on(disk.write_sectors(1, &original)).expect("restoring the original sectors failed");
+    assert!(pattern[..] == read_back[..]);
+
+    block_on(disk.read_sectors(1, &mut read_back)).expect("read failed");
+    assert!(original[..] == read_back[..]);
+}
+
+#[test_case]
+fn adapter_is_bound_once() {
+    let bound = driver::bindings().iter().filter(|(_, name)| *name == Some("ahci")).count();
+    assert!(bound <= 1);
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    interrupts::{self, apic, irq},
+    memory,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("local APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn timer_is_counting_down() {
+    let apic = apic::local_apic().unwrap();
+    let first = apic.timer_current_count();
+    let mut changed = false;
+    for _ in 0..1_000_000 {
+        if apic.timer_current_count() != first {
+            changed = true;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    assert!(changed, "APIC timer is not running");
+}
+
+#[test_case]
+fn timer_interrupts_are_acknowledged() {
+    // a missed EOI would block every further timer interrupt, so halting twice only returns if they keep coming
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+}
+
+#[test_case]
+fn timer_fires_and_keyboard_is_quiet() {
+    let timer = irq::vector(irq::TIMER);
+    let keyboard = irq::vector(irq::KEYBOARD);
+    let start = interrupts::stats::count_of(timer);
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+    // every halt ends with an interrupt, and with nobody typing almost all of them are timer ticks
+    assert!(interrupts::stats::count_of(timer) > start);
+    let keyboard_count = interrupts::stats()
+        .iter()
+        .find(|stats| stats.vector == keyboard)
+        .map_or(0, |stats| stats.total());
+    assert!(keyboard_count < 10, "{} keyboard interrupts without input", keyboard_count);
+}
@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use rust_os::{
+    memory,
+    task::block_on,
+    vfs::{self, archive::ArchiveFs, FileSystem, FileType, FsError, Inode},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const MODIFIED: u64 = 1_709_211_908;
+
+/// Appends a ustar entry of type `kind` to `archive`, with `link` as the name it links to.
+fn tar_entry(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, data: &[u8]) {
+    let mut header = [0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", MODIFIED).as_bytes());
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 511) / 512 * 512, 0);
+}
+
+/// Returns a tar archive as GNU tar makes it of a `./` with a long name in it, a hard link and a symbolic link.
+fn tar() -> Vec<u8> {
+    let long_name = format!("./etc/{}.conf", "long".repeat(30));
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, "./", b'5', "", &[]);
+    tar_entry(&mut archive, "./etc/", b'5', "", &[]);
+    tar_entry(&mut archive, "./etc/hostname", b'0', "", b"rust-os\n");
+    tar_entry(&mut archive, "././@LongLink", b'L', "", format!("{}\0", long_name).as_bytes());
+    tar_entry(&mut archive, &long_name[..100], b'0', "", b"long");
+    tar_entry(&mut archive, "./bin/init", b'0', "", &[0x7f; 700]);
+    tar_entry(&mut archive, "./bin/sh", b'1', "./bin/init", &[]);
+    tar_entry(&mut archive, "./bin/link", b'2', "init", &[]);
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let fields = [1, mode, 0, 0, 1, MODIFIED as u32, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields.iter() {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize((archive.len() + 3) & !3, 0);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+/// Returns a cpio archive as `find . | cpio -o -H newc` makes it.
+fn cpio() -> Vec<u8> {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, ".", 0o040_755, &[]);
+    cpio_entry(&mut archive, "init", 0o100_755, b"#!/bin/sh\n");
+    cpio_entry(&mut archive, "dev", 0o040_755, &[]);
+    cpio_entry(&mut archive, "dev/console", 0o020_600, &[]);
+    cpio_entry(&mut archive, "lib/modules/empty", 0o100_644, &[]);
+    cpio_entry(&mut archive, "TRAILER!!!", 0, &[]);
+    archive.resize(512, 0);
+    archive
+}
+
+fn leak(archive: Vec<u8>) -> &'static [u8] {
+    Box::leak(archive.into_boxed_slice())
+}
+
+fn lookup(fs: &ArchiveFs, path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let mut inode = fs.root();
+    for name in vfs::path::names(path) {
+        let next = block_on(inode.as_dir().ok_or(FsError::NotADirectory)?.lookup(name))?;
+        inode = next;
+    }
+    Ok(inode)
+}
+
+fn names(fs: &ArchiveFs, path: &str) -> Vec<String> {
+    let dir = lookup(fs, path).unwrap();
+    block_on(dir.as_dir().unwrap().entries()).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+#[test_case]
+fn tar_archives_are_read() {
+    let fs = ArchiveFs::new(leak(tar())).unwrap();
+    assert_eq!(fs.name(), "ustar");
+    assert_eq!(names(&fs, "/"), ["bin", "etc"]);
+    assert_eq!(names(&fs, "/bin"), ["init", "sh"]);
+    assert_eq!(common::read_all(&lookup(&fs, "/etc/hostname").unwrap()), b"rust-os\n");
+    let long = format!("/etc/{}.conf", "long".repeat(30));
+    assert_eq!(common::read_all(&lookup(&fs, &long).unwrap()), b"long");
+    let init = lookup(&fs, "/bin/init").unwrap();
+    assert_eq!(common::read_all(&init), [0x7f; 700]);
+    assert_eq!(init.metadata().modified, Some(Duration::from_secs(MODIFIED)));
+    assert_eq!(lookup(&fs, "/bin/sh").unwrap().metadata().id, init.metadata().id);
+    assert!(matches!(lookup(&fs, "/bin/link"), Err(FsError::NotFound)));
+}
+
+#[test_case]
+fn cpio_archives_are_read() {
+    let fs = ArchiveFs::new(leak(cpio())).unwrap();
+    assert_eq!(fs.name(), "cpio");
+    assert_eq!(names(&fs, "/"), ["dev", "init", "lib"]);
+    assert!(names(&fs, "/dev").is_empty());
+    assert_eq!(common::read_all(&lookup(&fs, "/init").unwrap()), b"#!/bin/sh\n");
+    assert_eq!(lookup(&fs, "/lib/modules").unwrap().metadata().file_type, FileType::Directory);
+    assert!(common::read_all(&lookup(&fs, "/lib/modules/empty").unwrap()).is_empty());
+}
+
+#[test_case]
+fn archives_are_mounted_read_only() {
+    block_on(vfs::mount("/", Arc::new(ArchiveFs::new(leak(cpio())).unwrap()))).unwrap();
+    let mut init = block_on(vfs::open("/init")).unwrap();
+    assert_eq!(block_on(init.read_to_end()).unwrap(), b"#!/bin/sh\n");
+    assert!(matches!(block_on(init.write(b"x")), Err(FsError::ReadOnly)));
+    assert!(matches!(block_on(vfs::create_dir("/tmp")), Err(FsError::ReadOnly)));
+    block_on(vfs::unmount("/")).unwrap();
+}
+
+#[test_case]
+fn bad_archives_are_refused() {
+    assert!(matches!(ArchiveFs::new(leak(vec![0; 1024])), Err(FsError::Unsupported)));
+    let mut tar = tar();
+    tar[0] ^= 1;
+    assert!(matches!(ArchiveFs::new(leak(tar)), Err(FsError::Corrupt(_))));
+    let mut cpio = cpio();
+    // the archive ends before its trailer
+    cpio.truncate(120);
+    assert!(matches!(ArchiveFs::new(leak(cpio)), Err(FsError::Corrupt(_))));
+}
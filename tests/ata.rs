@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{
+        ata::{self, AtaError, Channel, Drive, SECTOR_SIZE},
+        BlockDevice,
+    },
+    memory,
+    task::block_on,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::time::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// QEMU boots from the disk image as the primary master, so its first sector is the boot sector.
+fn boot_disk() -> ata::AtaDisk {
+    ata::identify(Channel::Primary, Drive::Master)
+        .expect("IDENTIFY failed")
+        .expect("no primary master disk")
+}
+
+#[test_case]
+fn boot_disk_is_identified() {
+    let disk = boot_disk();
+    assert!(disk.sectors > 0);
+    assert!(ata::detect().contains(&disk));
+}
+
+#[test_case]
+fn boot_sector_is_read_by_polling() {
+    let mut sector = [0u8; SECTOR_SIZE];
+    boot_disk().read_sectors(0, &mut sector).expect("read failed");
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+}
+
+#[test_case]
+fn reads_by_interrupt_match_polled_ones() {
+    let disk = boot_disk();
+    let mut polled = [0u8; 4 * SECTOR_SIZE];
+    disk.read_sectors(1, &mut polled).unwrap();
+    ata::enable_interrupts(Channel::Primary).expect("failed to register the ATA IRQ");
+    let mut interrupted = [0u8; 4 * SECTOR_SIZE];
+    disk.read_sectors(1, &mut interrupted).unwrap();
+    ata::disable_interrupts(Channel::Primary).unwrap();
+    assert!(polled[..] == interrupted[..]);
+}
+
+#[test_case]
+fn requests_are_checked() {
+    let disk = boot_disk();
+    let mut sector = [0u8; SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(disk.sectors, &mut sector), Err(AtaError::OutOfRange));
+    assert_eq!(disk.read_sectors(0, &mut sector[..100]), Err(AtaError::BufferLength(100)));
+}
+
+#[test_case]
+fn boot_disk_is_a_block_device() {
+    let disk = boot_disk();
+    assert_eq!(disk.name(), "ata0");
+    let mut direct = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut direct).unwrap();
+    let mut through_trait = [0u8; SECTOR_SIZE];
+    block_on(disk.read_blocks(0, &mut through_trait)).unwrap();
+    assert!(direct[..] == through_trait[..]);
+    assert_eq!(disk.block_count(), u64::from(disk.sectors));
+}
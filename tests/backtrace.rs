@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::backtrace::symbols;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    assert!(symbols::init(&boot_info.memory_map).expect("no kernel symbols") > 0);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn some_function() -> u64 {
+    some_function as usize as u64
+}
+
+#[test_case]
+fn addresses_resolve_to_their_function() {
+    let addr = some_function();
+    let symbol = symbols::resolve(addr + 1).expect("address not resolved");
+    assert_eq!(symbol.offset, 1);
+    assert_eq!(alloc::format!("{}", symbol), "backtrace::some_function+0x1");
+}
+
+#[test_case]
+fn addresses_outside_functions_do_not_resolve() {
+    assert_eq!(symbols::resolve(0x1000), None);
+}
@@ -0,0 +1,204 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{
+        self,
+        cache::{self, BlockCache},
+        partition::{self, PartitionKind},
+        ram::RamDisk,
+        BlockDevice, BlockError,
+    },
+    memory,
+    task::block_on,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn ram_disk_round_trips() {
+    let disk = RamDisk::new(512, 8);
+    assert_eq!(disk.size(), 4096);
+    let data = [0x5a; 1024];
+    block_on(disk.write_blocks(3, &data)).unwrap();
+    let mut read_back = [0; 1536];
+    block_on(disk.read_blocks(2, &mut read_back)).unwrap();
+    assert!(read_back[..512].iter().all(|&byte| byte == 0));
+    assert!(read_back[512..].iter().all(|&byte| byte == 0x5a));
+}
+
+#[test_case]
+fn requests_are_checked() {
+    let disk = RamDisk::new(512, 8);
+    let mut buf = [0; 1024];
+    assert!(matches!(block_on(disk.read_blocks(7, &mut buf)), Err(BlockError::OutOfRange)));
+    assert!(matches!(block_on(disk.read_blocks(u64::MAX, &mut buf)), Err(BlockError::OutOfRange)));
+    assert!(matches!(block_on(disk.read_blocks(0, &mut buf[..100])), Err(BlockError::BufferLength(100))));
+    let read_only = RamDisk::new(512, 8).read_only();
+    assert!(matches!(block_on(read_only.write_blocks(0, &buf)), Err(BlockError::ReadOnly)));
+}
+
+#[test_case]
+fn devices_are_found_by_name() {
+    block::register("ram0", Arc::new(RamDisk::new(512, 4))).unwrap();
+    assert!(matches!(block::register("ram0", Arc::new(RamDisk::new(512, 4))), Err(BlockError::NameTaken(_))));
+    assert_eq!(block::find("ram0").unwrap().block_count(), 4);
+    assert!(block::devices().iter().any(|(name, _)| name == "ram0"));
+    assert!(block::unregister("ram0").is_some());
+    assert!(block::find("ram0").is_none());
+}
+
+#[test_case]
+fn cached_writes_reach_the_disk_on_flush() {
+    let disk = Arc::new(RamDisk::new(512, 16));
+    let cache = BlockCache::new(disk.clone(), 4);
+    let data = [0xa5; 1024];
+    block_on(cache.write_blocks(2, &data)).unwrap();
+    let mut on_disk = [0; 1024];
+    block_on(disk.read_blocks(2, &mut on_disk)).unwrap();
+    assert!(on_disk.iter().all(|&byte| byte == 0));
+    let mut cached = [0; 1024];
+    block_on(cache.read_blocks(2, &mut cached)).unwrap();
+    assert_eq!(cached, data);
+    assert_eq!(cache.stats().unwrap().dirty, 2);
+
+    block_on(cache.flush()).unwrap();
+    block_on(disk.read_blocks(2, &mut on_disk)).unwrap();
+    assert_eq!(on_disk, data);
+    assert_eq!(cache.stats().unwrap().dirty, 0);
+}
+
+#[test_case]
+fn evicted_blocks_are_written_back() {
+    let disk = Arc::new(RamDisk::new(512, 16));
+    let cache = BlockCache::new(disk.clone(), 2);
+    block_on(cache.write_blocks(0, &[1; 512])).unwrap();
+    block_on(cache.write_blocks(1, &[2; 512])).unwrap();
+    block_on(cache.write_blocks(2, &[3; 512])).unwrap();
+    let mut block = [0; 512];
+    block_on(disk.read_blocks(0, &mut block)).unwrap();
+    assert!(block.iter().all(|&byte| byte == 1));
+    assert_eq!(cache.stats().unwrap().cached, 2);
+}
+
+#[test_case]
+fn repeated_reads_hit_the_cache() {
+    let cache = BlockCache::new(Arc::new(RamDisk::new(512, 16)), 8);
+    let mut buf = [0; 2048];
+    block_on(cache.read_blocks(4, &mut buf)).unwrap();
+    block_on(cache.read_blocks(4, &mut buf)).unwrap();
+    let stats = cache.stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (4, 4));
+}
+
+#[test_case]
+fn transfers_larger_than_the_cache_see_cached_blocks() {
+    let disk = Arc::new(RamDisk::new(512, 16));
+    let cache = BlockCache::new(disk.clone(), 2);
+    block_on(cache.write_blocks(5, &[7; 512])).unwrap();
+    let mut buf = [0; 4096];
+    block_on(cache.read_blocks(0, &mut buf)).unwrap();
+    assert!(buf[5 * 512..6 * 512].iter().all(|&byte| byte == 7));
+    block_on(cache::sync()).unwrap();
+    block_on(disk.read_blocks(0, &mut buf)).unwrap();
+    assert!(buf[5 * 512..6 * 512].iter().all(|&byte| byte == 7));
+}
+
+/// Fills in entry `slot` of the MBR or EBR in `table`.
+fn mbr_entry(table: &mut [u8], slot: usize, system_id: u8, start: u32, count: u32) {
+    let entry = &mut table[446 + slot * 16..][..16];
+    entry[4] = system_id;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+    table[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
+
+#[test_case]
+fn mbr_partitions_are_registered() {
+    let mut image = vec![0; 512 * 64];
+    mbr_entry(&mut image, 0, 0x0c, 2, 8);
+    mbr_entry(&mut image, 1, 0x05, 16, 32);
+    // two logical partitions in the extended one, each behind its EBR
+    mbr_entry(&mut image[16 * 512..], 0, 0x83, 1, 4);
+    mbr_entry(&mut image[16 * 512..], 1, 0x05, 8, 8);
+    mbr_entry(&mut image[24 * 512..], 0, 0x83, 1, 6);
+    image[2 * 512..3 * 512].fill(0x11);
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(512, image));
+    let entries = block_on(partition::read_partitions(&*disk)).unwrap();
+    let layout: Vec<(usize, u64, u64)> =
+        entries.iter().map(|entry| (entry.number, entry.start, entry.block_count)).collect();
+    assert_eq!(layout, [(1, 2, 8), (5, 17, 4), (6, 25, 6)]);
+
+    assert_eq!(block::attach("mbr0", disk).unwrap(), 3);
+    let first = block::find("mbr0p1").unwrap();
+    assert_eq!(first.block_count(), 8);
+    let mut block = [0; 512];
+    block_on(first.read_blocks(0, &mut block)).unwrap();
+    assert!(block.iter().all(|&byte| byte == 0x11));
+    assert!(matches!(block_on(first.read_blocks(8, &mut block)), Err(BlockError::OutOfRange)));
+    assert_eq!(block::find("mbr0p6").unwrap().block_count(), 6);
+    for name in ["mbr0", "mbr0p1", "mbr0p5", "mbr0p6"] {
+        assert!(block::unregister(name).is_some());
+    }
+}
+
+#[test_case]
+fn gpt_partitions_are_found() {
+    let mut image = vec![0; 512 * 64];
+    mbr_entry(&mut image, 0, 0xee, 1, 63);
+    let header = &mut image[512..1024];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    // the second of the four entries, the first is unused
+    let entry = &mut image[2 * 512 + 128..][..128];
+    entry[..16].copy_from_slice(&[0xaf; 16]);
+    entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&29u64.to_le_bytes());
+    for (i, unit) in "data".encode_utf16().enumerate() {
+        entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    let disk = RamDisk::from_bytes(512, image);
+    let entries = block_on(partition::read_partitions(&disk)).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].number, entries[0].start, entries[0].block_count), (2, 10, 20));
+    assert_eq!(entries[0].kind, PartitionKind::Gpt([0xaf; 16]));
+    assert_eq!(entries[0].name, "data");
+}
+
+#[test_case]
+fn disks_without_a_table_have_no_partitions() {
+    let disk = RamDisk::new(512, 8);
+    assert!(block_on(partition::read_partitions(&disk)).unwrap().is_empty());
+}
@@ -0,0 +1,27 @@
+//! Helpers for the filesystem tests, included by each test kernel that needs them with `mod common;`.
+
+// every test kernel uses only some of them
+#![allow(dead_code)]
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use rust_os::{
+    task::block_on,
+    vfs::{self, FileSystem, Inode},
+};
+
+/// Mounts the filesystem `fs` creates at `/` unless something is mounted already, so that the tests of a kernel,
+/// which share the mount table, mount it once. Returns `true` for the call that mounted it.
+pub fn mount_root_once(fs: impl FnOnce() -> Arc<dyn FileSystem>) -> bool {
+    if !vfs::mounts().is_empty() {
+        return false;
+    }
+    block_on(vfs::mount("/", fs())).unwrap();
+    true
+}
+
+/// Reads the whole file `inode` in a single call.
+pub fn read_all(inode: &Arc<dyn Inode>) -> Vec<u8> {
+    let mut contents = vec![0; inode.metadata().size as usize];
+    assert_eq!(block_on(inode.as_file().unwrap().read_at(0, &mut contents)).unwrap(), contents.len());
+    contents
+}
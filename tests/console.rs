@@ -0,0 +1,142 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, string::String};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    console::LineEditor,
+    task::keyboard::{KeyCode, KeyEvent, KeyState, ModifierState},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn press(editor: &mut LineEditor, code: KeyCode, unicode: Option<char>) -> Option<String> {
+    let event = KeyEvent { code, state: KeyState::Down, unicode, modifiers: ModifierState::default(), repeat: false };
+    editor.handle(&event)
+}
+
+fn type_text(editor: &mut LineEditor, text: &str) {
+    for c in text.chars() {
+        // the key code does not matter for printable characters
+        press(editor, KeyCode::A, Some(c));
+    }
+}
+
+#[test_case]
+fn edits_at_the_cursor() {
+    let history = VecDeque::new();
+    let mut editor = LineEditor::new(40, &history);
+    type_text(&mut editor, "helo");
+    press(&mut editor, KeyCode::ArrowLeft, None);
+    type_text(&mut editor, "l");
+    assert_eq!((editor.line(), editor.cursor()), ("hello", 4));
+    press(&mut editor, KeyCode::Home, None);
+    press(&mut editor, KeyCode::Delete, Some('\x7f'));
+    press(&mut editor, KeyCode::End, None);
+    press(&mut editor, KeyCode::Backspace, Some('\x08'));
+    assert_eq!(press(&mut editor, KeyCode::Enter, Some('\n')).as_deref(), Some("ell"));
+}
+
+#[test_case]
+fn edits_characters_beyond_ascii() {
+    let history = VecDeque::new();
+    let mut editor = LineEditor::new(40, &history);
+    type_text(&mut editor, "grün€");
+    assert_eq!((editor.line(), editor.cursor()), ("grün", 4));
+    press(&mut editor, KeyCode::ArrowLeft, None);
+    press(&mut editor, KeyCode::Backspace, Some('\x08'));
+    type_text(&mut editor, "ö");
+    assert_eq!((editor.line(), editor.cursor()), ("grön", 3));
+}
+
+#[test_case]
+fn lines_are_limited_to_max_len() {
+    let history = VecDeque::new();
+    let mut editor = LineEditor::new(3, &history);
+    type_text(&mut editor, "abcdef");
+    assert_eq!(editor.line(), "abc");
+}
+
+#[test_case]
+fn arrows_browse_the_history() {
+    let history: VecDeque<String> = ["first", "second"].iter().map(|line| String::from(*line)).collect();
+    let mut editor = LineEditor::new(40, &history);
+    type_text(&mut editor, "draft");
+    press(&mut editor, KeyCode::ArrowUp, None);
+    assert_eq!(editor.line(), "second");
+    press(&mut editor, KeyCode::ArrowUp, None);
+    press(&mut editor, KeyCode::ArrowUp, None);
+    assert_eq!((editor.line(), editor.cursor()), ("first", 5));
+    press(&mut editor, KeyCode::ArrowDown, None);
+    press(&mut editor, KeyCode::ArrowDown, None);
+    assert_eq!(editor.line(), "draft");
+}
+
+#[test_case]
+fn scrollback_shows_lines_that_scrolled_off() {
+    use rust_os::{println, vga_buffer};
+
+    vga_buffer::init_scrollback();
+    println!("scrolled off line");
+    for _ in 0..vga_buffer::BUFFER_HEIGHT {
+        println!();
+    }
+    let on_screen = || {
+        let mut found = false;
+        vga_buffer::for_each_screen_line(|line| found |= line == "scrolled off line");
+        found
+    };
+    assert!(!on_screen());
+    vga_buffer::scroll_back(vga_buffer::BUFFER_HEIGHT);
+    assert!(on_screen());
+    vga_buffer::scroll_forward(vga_buffer::BUFFER_HEIGHT);
+    assert!(!on_screen());
+}
+
+#[test_case]
+fn terminals_keep_their_own_text() {
+    use rust_os::vga_buffer::{self, terminal};
+
+    terminal::init();
+    terminal::print(1, format_args!("text on terminal 2\n"));
+    let on_screen = || {
+        let mut found = false;
+        vga_buffer::for_each_screen_line(|line| found |= line == "text on terminal 2");
+        found
+    };
+    assert!(!on_screen());
+    assert!(terminal::switch(1));
+    assert_eq!(terminal::active(), 1);
+    assert!(on_screen());
+    assert!(terminal::switch(terminal::LOG));
+    assert!(!on_screen());
+    assert!(!terminal::switch(terminal::COUNT));
+}
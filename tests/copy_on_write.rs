@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, cow, vmm::{self, RegionKind}};
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Maps a fresh page holding `value` and shares it copy-on-write with a second page.
+fn shared_pair(value: u64) -> (*mut u64, *mut u64) {
+    let region = vmm::allocate(2 * 4096, RegionKind::Heap).expect("out of virtual memory");
+    let src = Page::containing_address(region.start());
+    let dst = src + 1;
+    memory::with_kernel_memory(|memory| {
+        memory.map_zeroed(src, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).unwrap();
+        unsafe { src.start_address().as_mut_ptr::<u64>().write_volatile(value) };
+        cow::share(memory, src, dst).unwrap();
+    }).expect("kernel memory not installed");
+    (src.start_address().as_mut_ptr(), dst.start_address().as_mut_ptr())
+}
+
+#[test_case]
+fn shared_page_reads_same_data() {
+    let (src, dst) = shared_pair(7);
+    unsafe {
+        assert_eq!(src.read_volatile(), 7);
+        assert_eq!(dst.read_volatile(), 7);
+    }
+}
+
+#[test_case]
+fn write_copies_the_frame() {
+    let (src, dst) = shared_pair(1);
+    unsafe {
+        dst.write_volatile(2);
+        assert_eq!(src.read_volatile(), 1);
+        assert_eq!(dst.read_volatile(), 2);
+        // the source is the only owner of the original frame now
+        src.write_volatile(3);
+        assert_eq!(src.read_volatile(), 3);
+        assert_eq!(dst.read_volatile(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::{task::work::{self, Work}, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    work::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+static COUNT_RUN: Work = Work::new(|| {
+    RUNS.fetch_add(1, Ordering::SeqCst);
+});
+
+#[test_case]
+fn pending_work_is_coalesced() {
+    let start = RUNS.load(Ordering::SeqCst);
+    COUNT_RUN.schedule().unwrap();
+    COUNT_RUN.schedule().unwrap();
+    assert!(COUNT_RUN.is_pending());
+    assert_eq!(work::run_pending(), 1);
+    assert_eq!(RUNS.load(Ordering::SeqCst), start + 1);
+    assert!(!COUNT_RUN.is_pending());
+    assert!(work::is_idle());
+}
+
+#[test_case]
+fn work_scheduled_from_interrupt_runs_in_task_context() {
+    static FROM_IRQ: Work = Work::new(|| {
+        assert!(x86_64::instructions::interrupts::are_enabled());
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    });
+    let start = RUNS.load(Ordering::SeqCst);
+    time::after(1_000_000, || FROM_IRQ.schedule().unwrap());
+    while !FROM_IRQ.is_pending() {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(RUNS.load(Ordering::SeqCst), start);
+    work::run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), start + 1);
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    memory::{self, vmm::{self, RegionKind}},
+    vm,
+};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn touched_pages_are_mapped_and_zeroed() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::allocate_on_demand(64 * 1024 * 1024, RegionKind::Stack("demand paging test"), flags)
+        .expect("failed to reserve on-demand region");
+
+    for page in region.pages().step_by(1024) {
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(page.start_address().as_u64());
+            assert_eq!(ptr.read_volatile(), page.start_address().as_u64());
+        }
+    }
+}
+
+#[test_case]
+fn anonymous_mapping_is_zeroed_and_unmapped_on_drop() {
+    let mut mapping = vm::map_anonymous(3 * 4096 + 100).expect("failed to map anonymous memory");
+    let start = mapping.start();
+    assert_eq!(mapping.len(), 3 * 4096 + 100);
+    assert!(mapping.as_slice().iter().all(|&byte| byte == 0));
+    mapping.as_mut_slice().fill(0xab);
+    assert_eq!(mapping.as_slice()[3 * 4096 + 99], 0xab);
+
+    drop(mapping);
+    let mapped = memory::with_kernel_memory(|memory| memory.mapper.translate_addr(start).is_some()).unwrap();
+    assert!(!mapped);
+    assert!(vmm::region_containing(start).is_none());
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::{self, ram::RamDisk},
+    memory,
+    task::block_on,
+    vfs::{
+        self,
+        devfs::{self, CharDevice, DevFs},
+        FileType, FsError, FsFuture, SeekFrom,
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Mounts the devices at `/` and adds a disk.
+fn mount() {
+    if common::mount_root_once(|| Arc::new(DevFs::new())) {
+        let contents = (0..DISK_SIZE).map(|i| i as u8).collect();
+        block::register("ram7", Arc::new(RamDisk::from_bytes(512, contents))).unwrap();
+    }
+}
+
+const DISK_SIZE: usize = 8 * 512;
+
+struct Echo(spin::Mutex<Vec<u8>>);
+
+impl CharDevice for Echo {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let mut written = self.0.lock();
+            let len = written.len().min(buf.len());
+            buf[..len].copy_from_slice(&written[..len]);
+            written.drain(..len);
+            Ok(len)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        })
+    }
+}
+
+#[test_case]
+fn devices_are_listed() {
+    mount();
+    let entries = block_on(vfs::read_dir("/")).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(&names[..4], ["console", "kbd", "null", "zero"]);
+    let disk = entries.iter().find(|entry| entry.name == "ram7").unwrap();
+    assert_eq!(disk.file_type, FileType::BlockDevice);
+    assert_eq!(block_on(vfs::metadata("/null")).unwrap().file_type, FileType::CharDevice);
+    assert!(matches!(block_on(vfs::open("/nothing")), Err(FsError::NotFound)));
+    assert!(matches!(block_on(vfs::create_dir("/new")), Err(FsError::ReadOnly)));
+}
+
+#[test_case]
+fn null_and_zero_are_read_and_written() {
+    mount();
+    let mut null = block_on(vfs::create("/null")).unwrap();
+    block_on(null.write_all(b"dropped")).unwrap();
+    assert_eq!(block_on(null.read_to_end()).unwrap(), b"");
+    let mut zero = block_on(vfs::open("/zero")).unwrap();
+    let mut buf = [1; 100];
+    assert_eq!(block_on(zero.read(&mut buf)).unwrap(), 100);
+    assert!(buf.iter().all(|&byte| byte == 0));
+    let mut kbd = block_on(vfs::open("/kbd")).unwrap();
+    assert!(matches!(block_on(kbd.write(b"x")), Err(FsError::ReadOnly)));
+}
+
+#[test_case]
+fn block_devices_are_read_and_written_at_any_offset() {
+    mount();
+    let mut disk = block_on(vfs::open("/ram7")).unwrap();
+    assert_eq!(disk.metadata().size, DISK_SIZE as u64);
+    let mut across = [0; 100];
+    disk.seek(SeekFrom::Start(500));
+    assert_eq!(block_on(disk.read(&mut across)).unwrap(), 100);
+    assert!(across.iter().enumerate().all(|(i, &byte)| byte == (500 + i) as u8));
+
+    disk.seek(SeekFrom::Start(1000));
+    block_on(disk.write_all(&[0xaa; 30])).unwrap();
+    let device = block::find("ram7").unwrap();
+    let mut blocks = vec![0; 1024];
+    block_on(device.read_blocks(1, &mut blocks)).unwrap();
+    assert!(blocks[..1000 - 512].iter().enumerate().all(|(i, &byte)| byte == (512 + i) as u8));
+    assert!(blocks[1000 - 512..1030 - 512].iter().all(|&byte| byte == 0xaa));
+    assert_eq!(blocks[1030 - 512], (1030 % 256) as u8);
+
+    disk.seek(SeekFrom::Start(DISK_SIZE as u64 - 10));
+    assert!(matches!(block_on(disk.write_all(&[0; 20])), Err(FsError::NoSpace)));
+    assert_eq!(block_on(disk.read_to_end()).unwrap(), [0; 10]);
+}
+
+#[test_case]
+fn drivers_register_character_devices() {
+    mount();
+    devfs::register("echo", Arc::new(Echo(spin::Mutex::new(Vec::new())))).unwrap();
+    let taken = devfs::register("null", Arc::new(Echo(spin::Mutex::new(Vec::new()))));
+    assert!(matches!(taken, Err(FsError::AlreadyExists)));
+    assert!(matches!(devfs::register("ram7", Arc::new(Echo(spin::Mutex::new(Vec::new())))), Err(_)));
+    let mut echo = block_on(vfs::open("/echo")).unwrap();
+    block_on(echo.write_all(b"hello")).unwrap();
+    assert_eq!(block_on(echo.read_to_end()).unwrap(), b"hello");
+    assert!(devfs::unregister("echo").is_some());
+    assert!(matches!(block_on(vfs::open("/echo")), Err(FsError::NotFound)));
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory;
+use x86_64::structures::paging::Translate;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn dma_buffer_is_contiguous_and_aligned() {
+    let buffer = memory::alloc_dma(3 * 4096, 0x10000).expect("DMA allocation failed");
+    assert!(buffer.phys_addr().is_aligned(0x10000u64));
+
+    memory::with_kernel_memory(|memory| {
+        for offset in (0..buffer.len() as u64).step_by(4096) {
+            let phys = memory.mapper.translate_addr(buffer.virt_addr() + offset);
+            assert_eq!(phys, Some(buffer.phys_addr() + offset));
+        }
+    });
+
+    let bytes = unsafe { core::slice::from_raw_parts(buffer.as_mut_ptr::<u8>(), buffer.len()) };
+    assert!(bytes.iter().all(|&b| b == 0));
+    memory::free_dma(buffer);
+}
+
+#[test_case]
+fn mmio_region_aliases_device_memory() {
+    use x86_64::PhysAddr;
+
+    // the VGA text buffer is device memory that is also reachable through the physical memory mapping
+    let vga = memory::map_mmio(PhysAddr::new(0xb8000 + 2), 160).expect("MMIO mapping failed");
+    let direct: *const u16 = memory::phys_to_virt(PhysAddr::new(0xb8000 + 2)).as_ptr();
+
+    vga.write::<u16>(0, 0x0f41);
+    assert_eq!(unsafe { direct.read_volatile() }, 0x0f41);
+    assert_eq!(vga.read::<u16>(0), 0x0f41);
+}
+
+#[test_case]
+fn no_mapping_is_writable_and_executable() {
+    use memory::wx::{self, Policy};
+
+    let vga = memory::map_mmio(x86_64::PhysAddr::new(0xb8000), 4096).expect("MMIO mapping failed");
+    let buffer = memory::alloc_dma(4096, 4096).expect("DMA allocation failed");
+    wx::enforce();
+    assert_eq!(wx::check(Policy::Warn), 0);
+    memory::free_dma(buffer);
+    drop(vga);
+}
+
+#[test_case]
+fn zone_restricted_allocations_stay_in_zone() {
+    use memory::Zone;
+    use x86_64::structures::paging::FrameDeallocator;
+
+    let frame = memory::with_kernel_memory(|memory| memory.frame_allocator.allocate_frame_in(Zone::Dma))
+        .unwrap()
+        .expect("no frame left below 16 MiB");
+    assert_eq!(Zone::containing(frame.start_address()), Zone::Dma);
+    memory::with_kernel_memory(|memory| unsafe { memory.frame_allocator.deallocate_frame(frame) });
+
+    let buffer = memory::alloc_dma_in(Zone::Dma, 2 * 4096, 4096).expect("ISA DMA allocation failed");
+    assert!(buffer.phys_addr().as_u64() + buffer.len() as u64 <= Zone::Dma.range().end);
+    memory::free_dma(buffer);
+}
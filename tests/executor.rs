@@ -0,0 +1,367 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use rust_os::task::{executor::{self, Executor}, thread, work, Task};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    work::init();
+    thread::init();
+    // the executor gets its own thread, so that the tests can keep running while it does
+    thread::spawn("executor", || Executor::new().run()).expect("failed to start the executor thread");
+    while executor::spawner().is_err() {
+        thread::yield_now();
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn wait_for(flag: &AtomicBool) {
+    while !flag.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn spawner_adds_tasks_to_running_executor() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    executor::spawner().unwrap().spawn(Task::new(async { RAN.store(true, Ordering::SeqCst) })).unwrap();
+    wait_for(&RAN);
+}
+
+#[test_case]
+fn tasks_spawn_tasks() {
+    static INNER_RAN: AtomicBool = AtomicBool::new(false);
+    let outer = async {
+        let inner = Task::new(async { INNER_RAN.store(true, Ordering::SeqCst) });
+        executor::spawner().unwrap().clone().spawn(inner).unwrap();
+    };
+    executor::spawner().unwrap().spawn(Task::new(outer)).unwrap();
+    wait_for(&INNER_RAN);
+}
+
+#[test_case]
+fn join_handle_returns_result() {
+    static JOINED: AtomicBool = AtomicBool::new(false);
+    let spawner = executor::spawner().unwrap();
+    let handle = spawner.spawn_joinable(async { 6 * 7 }).unwrap();
+    spawner
+        .spawn(Task::new(async move {
+            assert_eq!(handle.await, Ok(42));
+            JOINED.store(true, Ordering::SeqCst);
+        }))
+        .unwrap();
+    wait_for(&JOINED);
+}
+
+#[test_case]
+fn join_handle_reports_completion() {
+    let handle = executor::spawner().unwrap().spawn_joinable(async { "done" }).unwrap();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.try_take(), Some("done"));
+    assert_eq!(handle.try_take(), None);
+}
+
+#[test_case]
+fn sleep_waits_for_deadline() {
+    use core::time::Duration;
+    use rust_os::{task::timer, time::Instant};
+
+    let start = Instant::now();
+    let handle = executor::spawner().unwrap().spawn_joinable(async move {
+        timer::sleep(Duration::from_millis(5)).await;
+        let mut interval = timer::interval(Duration::from_millis(2));
+        for _ in 0..3 {
+            interval.tick().await;
+        }
+        start.elapsed()
+    });
+    let handle = handle.unwrap();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert!(handle.try_take().unwrap() >= Duration::from_millis(11));
+}
+
+#[test_case]
+fn channel_delivers_in_order_and_ends() {
+    use futures_util::StreamExt;
+    use rust_os::task::channel::{self, TrySendError};
+
+    let (sender, mut receiver) = channel::channel(2);
+    sender.try_send(1).unwrap();
+    sender.try_send(2).unwrap();
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+
+    let spawner = executor::spawner().unwrap();
+    let producer = spawner.spawn_joinable(async move {
+        // waits for the receiver to make room
+        for value in 3..=5 {
+            sender.send(value).await.unwrap();
+        }
+    });
+    let consumer = spawner.spawn_joinable(async move {
+        let mut sum = 0;
+        while let Some(value) = receiver.next().await {
+            sum += value;
+        }
+        sum
+    });
+    let (producer, consumer) = (producer.unwrap(), consumer.unwrap());
+    while !consumer.is_finished() {
+        thread::yield_now();
+    }
+    assert!(producer.is_finished());
+    assert_eq!(consumer.try_take(), Some(15));
+}
+
+#[test_case]
+fn async_mutex_suspends_waiters() {
+    use alloc::sync::Arc;
+    use core::time::Duration;
+    use rust_os::task::{sync::Mutex, timer};
+
+    let shared = Arc::new(Mutex::new(alloc::vec::Vec::new()));
+    let spawner = executor::spawner().unwrap();
+    let holder = shared.clone();
+    let first = spawner.spawn_joinable(async move {
+        let mut log = holder.lock().await;
+        // keeps the lock across a suspension point, so the second task has to wait
+        timer::sleep(Duration::from_millis(2)).await;
+        log.push(1);
+    });
+    let waiter = shared.clone();
+    let second = spawner.spawn_joinable(async move {
+        waiter.lock().await.push(2);
+    });
+    let (first, second) = (first.unwrap(), second.unwrap());
+    while !(first.is_finished() && second.is_finished()) {
+        thread::yield_now();
+    }
+    assert_eq!(*shared.try_lock().unwrap(), [1, 2]);
+}
+
+#[test_case]
+fn abort_stops_task() {
+    use rust_os::task::cancel::CancellationToken;
+
+    let never = CancellationToken::new();
+    let waiting = never.clone();
+    let handle = executor::spawner().unwrap().spawn_joinable(async move { waiting.cancelled().await }).unwrap();
+    thread::yield_now();
+    // blocked on a token nobody cancels, so it can only finish by being aborted
+    assert!(!handle.is_finished());
+    handle.abort();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.try_take(), None);
+    assert!(!never.is_cancelled());
+}
+
+#[test_case]
+fn awaiting_aborted_task_fails() {
+    use rust_os::task::join::JoinError;
+
+    let handle = executor::spawner().unwrap().spawn_joinable(async {
+        let spawner = executor::spawner().unwrap();
+        let inner = spawner.spawn_joinable(core::future::pending::<()>()).unwrap();
+        inner.abort();
+        inner.await
+    });
+    let handle = handle.unwrap();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.try_take(), Some(Err(JoinError::Aborted)));
+}
+
+#[test_case]
+fn run_until_cancelled_drops_work() {
+    use rust_os::task::cancel::CancellationToken;
+
+    let token = CancellationToken::new();
+    let watched = token.clone();
+    let handle = executor::spawner().unwrap().spawn_joinable(async move {
+        watched.run_until_cancelled(core::future::pending::<()>()).await
+    });
+    let handle = handle.unwrap();
+    thread::yield_now();
+    token.cancel();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.try_take(), Some(None));
+}
+
+#[test_case]
+fn yield_now_interleaves_tasks() {
+    use alloc::{sync::Arc, vec::Vec};
+    use rust_os::task::yield_now;
+
+    let log = Arc::new(spin::Mutex::new(Vec::new()));
+    let spawner = executor::spawner().unwrap();
+    let handles: Vec<_> = [1, 2]
+        .iter()
+        .map(|&id| {
+            let log = log.clone();
+            spawner
+                .spawn_joinable(async move {
+                    for _ in 0..2 {
+                        log.lock().push(id);
+                        yield_now().await;
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        thread::yield_now();
+    }
+    assert_eq!(*log.lock(), [1, 2, 1, 2]);
+}
+
+#[test_case]
+fn stats_count_polls_and_wakeups() {
+    use rust_os::task::yield_now;
+
+    let spawner = executor::spawner().unwrap();
+    let before = spawner.stats();
+    let handle = spawner
+        .spawn_joinable(async {
+            yield_now().await;
+            yield_now().await;
+        })
+        .unwrap();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    let after = spawner.stats();
+    // the join task and the task awaiting it may still be around, so only the totals are compared
+    assert!(after.finished_tasks > before.finished_tasks);
+    assert!(after.total_polls() >= before.total_polls() + 3);
+    assert!(after.total_wakeups() >= before.total_wakeups() + 2);
+    assert!(after.total_poll_cycles() > before.total_poll_cycles());
+}
+
+#[test_case]
+fn stats_show_one_worker_per_running_cpu() {
+    let stats = executor::spawner().unwrap().stats();
+    assert_eq!(stats.workers, 1);
+    assert!(stats.tasks.iter().all(|task| task.worker == 0));
+}
+
+#[test_case]
+fn timeout_bounds_waits() {
+    use core::time::Duration;
+    use futures_util::future;
+    use rust_os::task::{timeout, Elapsed};
+
+    let handle = executor::spawner().unwrap().spawn_joinable(async {
+        let hung = timeout(Duration::from_millis(2), future::pending::<()>()).await;
+        let done = timeout(Duration::from_millis(2), async { 7 }).await;
+        (hung, done)
+    });
+    let handle = handle.unwrap();
+    while !handle.is_finished() {
+        thread::yield_now();
+    }
+    assert_eq!(handle.try_take().unwrap(), (Err(Elapsed), Ok(7)));
+}
+
+#[test_case]
+fn endless_timeouts_wait_for_their_future() {
+    use core::time::Duration;
+    use rust_os::task::{block_on, timeout, yield_now};
+
+    // the first poll registers a sleep until the end of time
+    let value = block_on(timeout(Duration::MAX, async {
+        yield_now().await;
+        7
+    }));
+    assert_eq!(value, Ok(7));
+}
+
+#[test_case]
+fn dropped_timeouts_leave_the_wheel() {
+    use core::{future::Future, pin::Pin, task::Poll, time::Duration};
+    use futures_util::future;
+    use rust_os::task::{block_on, timeout, timer};
+
+    let pending = timer::pending_sleeps();
+    for duration in [Duration::from_secs(60), Duration::MAX] {
+        let mut hung = timeout(duration, future::pending::<()>());
+        // the first poll puts the sleep into the wheel
+        block_on(future::poll_fn(|cx| {
+            assert!(Pin::new(&mut hung).poll(cx).is_pending());
+            Poll::Ready(())
+        }));
+        assert_eq!(timer::pending_sleeps(), pending + 1);
+        drop(hung);
+        assert_eq!(timer::pending_sleeps(), pending);
+    }
+}
+
+#[test_case]
+fn named_tasks_are_listed() {
+    use rust_os::task::{cancel::CancellationToken, metrics::TaskState};
+
+    let spawner = executor::spawner().unwrap();
+    let token = CancellationToken::new();
+    let waiting = token.clone();
+    spawner.spawn_named("listed", async move { waiting.cancelled().await }).unwrap();
+    let listed = loop {
+        let tasks = spawner.tasks();
+        match tasks.into_iter().find(|task| task.name == Some("listed")) {
+            Some(task) if task.state == TaskState::Waiting => break task,
+            _ => thread::yield_now(),
+        }
+    };
+    assert_eq!(listed.polls, 1);
+    token.cancel();
+    while spawner.tasks().iter().any(|task| task.name == Some("listed")) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn block_on_drives_a_future_without_the_executor() {
+    use core::time::Duration;
+    use rust_os::task::{block_on, timer};
+
+    let value = block_on(async {
+        timer::sleep(Duration::from_millis(2)).await;
+        42
+    });
+    assert_eq!(value, 42);
+}
@@ -0,0 +1,234 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use rust_os::{
+    block::ram::RamDisk,
+    memory,
+    task::block_on,
+    vfs::{self, ext2::Ext2Fs, FileSystem, FileType, FsError},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const BLOCK: usize = 1024;
+const BLOCKS: usize = 64;
+const INODES: usize = 32;
+const INODE_SIZE: usize = 128;
+const INODE_TABLE: usize = 5;
+const MODIFIED: u32 = 1_709_211_908;
+
+/// Block numbers in an indirect block.
+const PER_BLOCK: usize = BLOCK / 4;
+/// The file with direct blocks and a single indirect block, and a hole in its fourth block.
+const BIG_SIZE: usize = 12 * BLOCK + 2000;
+/// The file whose only block is the first one under its double indirect block.
+const SPARSE_SIZE: usize = (12 + PER_BLOCK) * BLOCK + 10;
+
+fn put<const N: usize>(image: &mut [u8], offset: usize, bytes: [u8; N]) {
+    image[offset..offset + N].copy_from_slice(&bytes);
+}
+
+fn put_inode(image: &mut [u8], number: usize, mode: u16, size: usize, blocks: &[(usize, u32)]) {
+    let inode = (INODE_TABLE * BLOCK) + (number - 1) * INODE_SIZE;
+    put(image, inode, mode.to_le_bytes());
+    put(image, inode + 4, (size as u32).to_le_bytes());
+    put(image, inode + 16, MODIFIED.to_le_bytes());
+    put(image, inode + 26, 1u16.to_le_bytes());
+    for &(index, block) in blocks {
+        put(image, inode + 40 + index * 4, block.to_le_bytes());
+    }
+}
+
+/// Fills block `block` with directory entries of inode numbers, file types and names.
+fn put_dir(image: &mut [u8], block: usize, entries: &[(u32, u8, &str)]) {
+    let mut offset = block * BLOCK;
+    for (i, &(inode, file_type, name)) in entries.iter().enumerate() {
+        let len = if i == entries.len() - 1 { block * BLOCK + BLOCK - offset } else { (8 + name.len() + 3) & !3 };
+        put(image, offset, inode.to_le_bytes());
+        put(image, offset + 4, (len as u16).to_le_bytes());
+        image[offset + 6] = name.len() as u8;
+        image[offset + 7] = file_type;
+        image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        offset += len;
+    }
+}
+
+fn block_contents(block: usize) -> Vec<u8> {
+    (0..BLOCK).map(|i| (block * 7 + i) as u8).collect()
+}
+
+/// The contents of `big.bin`, whose fourth block is a hole.
+fn big_contents() -> Vec<u8> {
+    // the indirect block 24 comes between the direct blocks and the ones it points to
+    let blocks = (12..24).chain(25..27);
+    let mut contents: Vec<u8> = blocks.flat_map(block_contents).collect();
+    contents[3 * BLOCK..4 * BLOCK].fill(0);
+    contents.truncate(BIG_SIZE);
+    contents
+}
+
+/// Builds an ext2 volume of 1 KiB blocks in a single group:
+///
+/// - `hello.txt`, in block 11, and a hard link to it in `sub`
+/// - `big.bin`, in the direct blocks 12 to 23 but 15, and blocks 25 and 26 through the indirect block 24
+/// - `sparse.bin`, with block 27 through the double indirect block 28 and the indirect block 29
+/// - `link`, a symbolic link
+fn image() -> Vec<u8> {
+    let mut image = vec![0; BLOCKS * BLOCK];
+    let superblock = BLOCK;
+    put(&mut image, superblock, (INODES as u32).to_le_bytes());
+    put(&mut image, superblock + 4, (BLOCKS as u32).to_le_bytes());
+    put(&mut image, superblock + 20, 1u32.to_le_bytes());
+    put(&mut image, superblock + 32, 8192u32.to_le_bytes());
+    put(&mut image, superblock + 40, (INODES as u32).to_le_bytes());
+    put(&mut image, superblock + 56, 0xef53u16.to_le_bytes());
+    put(&mut image, superblock + 76, 1u32.to_le_bytes());
+    put(&mut image, superblock + 84, 11u32.to_le_bytes());
+    put(&mut image, superblock + 88, (INODE_SIZE as u16).to_le_bytes());
+    put(&mut image, superblock + 96, 2u32.to_le_bytes());
+    // the one group descriptor: block bitmap, inode bitmap, inode table
+    put(&mut image, 2 * BLOCK, 3u32.to_le_bytes());
+    put(&mut image, 2 * BLOCK + 4, 4u32.to_le_bytes());
+    put(&mut image, 2 * BLOCK + 8, (INODE_TABLE as u32).to_le_bytes());
+
+    put_inode(&mut image, 2, 0x41ed, BLOCK, &[(0, 9)]);
+    put_dir(&mut image, 9, &[
+        (2, 2, "."),
+        (2, 2, ".."),
+        (11, 1, "hello.txt"),
+        (12, 2, "sub"),
+        (0, 1, "deleted"),
+        (13, 1, "big.bin"),
+        (14, 1, "sparse.bin"),
+        (15, 7, "link"),
+    ]);
+    put_inode(&mut image, 11, 0x81a4, 6, &[(0, 11)]);
+    image[11 * BLOCK..][..6].copy_from_slice(b"hello\n");
+    put_inode(&mut image, 12, 0x41ed, BLOCK, &[(0, 10)]);
+    put_dir(&mut image, 10, &[(12, 2, "."), (2, 2, ".."), (11, 1, "inner")]);
+
+    let direct: Vec<(usize, u32)> = (0..12).filter(|&i| i != 3).map(|i| (i, 12 + i as u32)).chain([(12, 24)]).collect();
+    put_inode(&mut image, 13, 0x81a4, BIG_SIZE, &direct);
+    for block in (12..24).chain(25..27) {
+        image[block * BLOCK..][..BLOCK].copy_from_slice(&block_contents(block));
+    }
+    put(&mut image, 24 * BLOCK, 25u32.to_le_bytes());
+    put(&mut image, 24 * BLOCK + 4, 26u32.to_le_bytes());
+
+    put_inode(&mut image, 14, 0x81a4, SPARSE_SIZE, &[(13, 28)]);
+    put(&mut image, 28 * BLOCK, 29u32.to_le_bytes());
+    put(&mut image, 29 * BLOCK, 27u32.to_le_bytes());
+    image[27 * BLOCK..][..BLOCK].copy_from_slice(&block_contents(27));
+
+    // a fast symbolic link keeps its target in its block numbers
+    put_inode(&mut image, 15, 0xa1ff, 9, &[]);
+    image[INODE_TABLE * BLOCK + 14 * INODE_SIZE + 40..][..9].copy_from_slice(b"hello.txt");
+    image
+}
+
+/// Mounts the volume `image` builds at `/`.
+fn mount() {
+    common::mount_root_once(|| Arc::new(block_on(Ext2Fs::new(Arc::new(RamDisk::from_bytes(512, image())))).unwrap()));
+}
+
+#[test_case]
+fn directories_are_listed() {
+    mount();
+    let entries = block_on(vfs::read_dir("/")).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["big.bin", "hello.txt", "sparse.bin", "sub"]);
+    assert_eq!(entries[3].file_type, FileType::Directory);
+    let sub: Vec<_> = block_on(vfs::read_dir("/sub")).unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(sub, ["inner"]);
+}
+
+#[test_case]
+fn files_are_read_through_their_block_pointers() {
+    mount();
+    let mut hello = block_on(vfs::open("/hello.txt")).unwrap();
+    assert_eq!(block_on(hello.read_to_end()).unwrap(), b"hello\n");
+    assert_eq!(hello.metadata().modified, Some(Duration::from_secs(u64::from(MODIFIED))));
+    let mut big = block_on(vfs::open("/big.bin")).unwrap();
+    assert_eq!(block_on(big.read_to_end()).unwrap(), big_contents());
+    let mut across = [0; 100];
+    big.seek(vfs::SeekFrom::Start(12 * BLOCK as u64 - 50));
+    assert_eq!(block_on(big.read(&mut across)).unwrap(), 100);
+    assert_eq!(&across[..], &big_contents()[12 * BLOCK - 50..12 * BLOCK + 50]);
+}
+
+#[test_case]
+fn double_indirect_blocks_and_holes_are_read() {
+    mount();
+    let mut sparse = block_on(vfs::open("/sparse.bin")).unwrap();
+    let contents = block_on(sparse.read_to_end()).unwrap();
+    assert_eq!(contents.len(), SPARSE_SIZE);
+    assert!(contents[..SPARSE_SIZE - 10].iter().all(|&byte| byte == 0));
+    assert_eq!(&contents[SPARSE_SIZE - 10..], &block_contents(27)[..10]);
+}
+
+#[test_case]
+fn hard_links_are_one_inode() {
+    mount();
+    let hello = block_on(vfs::metadata("/hello.txt")).unwrap();
+    let inner = block_on(vfs::metadata("/sub/inner")).unwrap();
+    assert_eq!((hello.id, inner.id), (11, 11));
+    assert!(matches!(block_on(vfs::open("/link")), Err(FsError::Unsupported)));
+    assert!(matches!(block_on(vfs::open("/Hello.txt")), Err(FsError::NotFound)));
+    let mut file = block_on(vfs::open("/hello.txt")).unwrap();
+    assert!(matches!(block_on(file.write(b"x")), Err(FsError::ReadOnly)));
+    assert!(matches!(block_on(vfs::create_dir("/new")), Err(FsError::ReadOnly)));
+}
+
+#[test_case]
+fn other_volumes_are_refused() {
+    let mut extents = image();
+    // ext4's extents replace the block pointers
+    put(&mut extents, BLOCK + 96, 0x42u32.to_le_bytes());
+    let fs = block_on(Ext2Fs::new(Arc::new(RamDisk::from_bytes(512, extents))));
+    assert!(matches!(fs, Err(FsError::Unsupported)));
+    let blank = block_on(Ext2Fs::new(Arc::new(RamDisk::new(512, 8))));
+    assert!(matches!(blank, Err(FsError::Corrupt(_))));
+    let fs = block_on(Ext2Fs::new(Arc::new(RamDisk::from_bytes(512, image())))).unwrap();
+    assert_eq!((fs.name(), fs.block_size()), ("ext2", 1024));
+}
+
+#[test_case]
+fn directories_larger_than_the_volume_are_corrupt() {
+    let mut image = image();
+    put_inode(&mut image, 2, 0x41ed, u32::MAX as usize, &[(0, 9)]);
+    let fs = block_on(Ext2Fs::new(Arc::new(RamDisk::from_bytes(512, image)))).unwrap();
+    let entries = block_on(fs.root().as_dir().unwrap().entries());
+    assert!(matches!(entries, Err(FsError::Corrupt(_))));
+}
@@ -0,0 +1,329 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use rust_os::{
+    block::{ram::RamDisk, BlockDevice},
+    memory,
+    task::block_on,
+    vfs::{self, fat::FatFs, FileSystem, FileType, FsError},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const SECTOR: usize = 512;
+const RESERVED_SECTORS: usize = 32;
+const FAT_SECTORS: usize = 8;
+const DATA_SECTORS: usize = 512;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+/// Clusters 2, 3, 5, 6 and 7 are taken.
+const FREE_CLUSTERS: u32 = DATA_SECTORS as u32 - 5;
+const FSINFO_SECTOR: usize = 1;
+/// 2024-02-29 13:05:08 in the date and time fields of an entry.
+const DATE: u16 = 44 << 9 | 2 << 5 | 29;
+const TIME: u16 = 13 << 11 | 5 << 5 | 4;
+
+fn hello_contents() -> Vec<u8> {
+    (0..700).map(|i| (i % 251) as u8).collect()
+}
+
+fn short_entry(name: &[u8; 11], attributes: u8, case: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[12] = case;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&TIME.to_le_bytes());
+    entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Returns the long name entries for `name` in the order they are stored, last part first.
+fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let checksum = short_name.iter().fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte));
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    chars.push(0);
+    while chars.len() % 13 != 0 {
+        chars.push(0xffff);
+    }
+    let parts = chars.len() / 13;
+    (0..parts)
+        .rev()
+        .map(|part| {
+            let mut entry = [0; 32];
+            entry[0] = (part as u8 + 1) | if part == parts - 1 { 0x40 } else { 0 };
+            entry[11] = 0x0f;
+            entry[13] = checksum;
+            let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+            for (offset, &c) in offsets.zip(chars[part * 13..].iter()) {
+                entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Builds a FAT32 volume with one sector per cluster:
+///
+/// - `Hello World.txt`, 700 bytes in clusters 3 and 7
+/// - `readme.txt`, a short name in lower case, in cluster 6
+/// - `SUB`, a directory in cluster 5 with an empty `INNER.BIN` and a deleted entry
+///
+/// Cluster 4 is free between the taken ones.
+fn image() -> Vec<u8> {
+    let mut image = vec![0; (RESERVED_SECTORS + 2 * FAT_SECTORS + DATA_SECTORS) * SECTOR];
+    let total = (image.len() / SECTOR) as u32;
+    let boot = &mut image[..SECTOR];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"mkfs.fat");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[32..36].copy_from_slice(&total.to_le_bytes());
+    boot[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    // an FSInfo sector that doesn't know how many clusters are free
+    let fsinfo = &mut image[FSINFO_SECTOR * SECTOR..][..SECTOR];
+    fsinfo[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    fsinfo[488..496].copy_from_slice(&[0xff; 8]);
+    fsinfo[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let fat: [u32; 8] = [0x0fff_fff8, END_OF_CHAIN, END_OF_CHAIN, 7, 0, END_OF_CHAIN, END_OF_CHAIN, END_OF_CHAIN];
+    for copy in 0..2 {
+        let start = (RESERVED_SECTORS + copy * FAT_SECTORS) * SECTOR;
+        for (i, entry) in fat.iter().enumerate() {
+            image[start + i * 4..start + i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+
+    let cluster = |number: usize| (RESERVED_SECTORS + 2 * FAT_SECTORS + number - 2) * SECTOR;
+    let mut root = vec![*b"TESTVOL    \x08\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"];
+    root.extend(long_name_entries("Hello World.txt", b"HELLOW~1TXT"));
+    root.push(short_entry(b"HELLOW~1TXT", 0x20, 0, 3, 700));
+    root.push(short_entry(b"README  TXT", 0x20, 0x18, 6, 3));
+    root.push(short_entry(b"SUB        ", 0x10, 0, 5, 0));
+    for (i, entry) in root.iter().enumerate() {
+        image[cluster(2) + i * 32..][..32].copy_from_slice(entry);
+    }
+    let mut deleted = short_entry(b"GONE    TXT", 0x20, 0, 0, 0);
+    deleted[0] = 0xe5;
+    let sub = [
+        short_entry(b".          ", 0x10, 0, 5, 0),
+        short_entry(b"..         ", 0x10, 0, 0, 0),
+        deleted,
+        short_entry(b"INNER   BIN", 0x20, 0, 0, 0),
+    ];
+    for (i, entry) in sub.iter().enumerate() {
+        image[cluster(5) + i * 32..][..32].copy_from_slice(entry);
+    }
+    let hello = hello_contents();
+    image[cluster(3)..][..SECTOR].copy_from_slice(&hello[..SECTOR]);
+    image[cluster(7)..][..hello.len() - SECTOR].copy_from_slice(&hello[SECTOR..]);
+    image[cluster(6)..][..3].copy_from_slice(b"hi\n");
+    image
+}
+
+/// Mounts the volume `image` builds at `/`.
+fn mount() {
+    common::mount_root_once(|| Arc::new(block_on(FatFs::new(Arc::new(RamDisk::from_bytes(SECTOR, image())))).unwrap()));
+}
+
+#[test_case]
+fn entries_have_their_long_names() {
+    mount();
+    let entries = block_on(vfs::read_dir("/")).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["Hello World.txt", "SUB", "readme.txt"]);
+    assert_eq!(entries[1].file_type, FileType::Directory);
+    let sub: Vec<_> = block_on(vfs::read_dir("/SUB")).unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(sub, ["INNER.BIN"]);
+}
+
+#[test_case]
+fn fragmented_files_are_read() {
+    mount();
+    let mut file = block_on(vfs::open("/hello world.TXT")).unwrap();
+    assert_eq!(file.metadata().size, 700);
+    assert_eq!(block_on(file.read_to_end()).unwrap(), hello_contents());
+    let mut across = [0; 20];
+    let mut file = block_on(vfs::open("/Hello World.txt")).unwrap();
+    file.seek(vfs::SeekFrom::Start(500));
+    assert_eq!(block_on(file.read(&mut across)).unwrap(), 20);
+    assert_eq!(&across[..], &hello_contents()[500..520]);
+}
+
+#[test_case]
+fn small_and_empty_files_are_read() {
+    mount();
+    let mut readme = block_on(vfs::open("/readme.txt")).unwrap();
+    assert_eq!(block_on(readme.read_to_end()).unwrap(), b"hi\n");
+    assert_eq!(readme.metadata().modified, Some(Duration::from_secs(1_709_211_908)));
+    let mut inner = block_on(vfs::open("/sub/inner.bin")).unwrap();
+    assert!(block_on(inner.read_to_end()).unwrap().is_empty());
+    assert!(matches!(block_on(vfs::open("/sub/gone.txt")), Err(FsError::NotFound)));
+}
+
+#[test_case]
+fn read_only_devices_are_not_written() {
+    let fs = block_on(FatFs::new(Arc::new(RamDisk::from_bytes(SECTOR, image()).read_only()))).unwrap();
+    let root = fs.root();
+    let dir = root.as_dir().unwrap();
+    let readme = block_on(dir.lookup("readme.txt")).unwrap();
+    assert!(matches!(block_on(readme.as_file().unwrap().write_at(0, b"x")), Err(FsError::ReadOnly)));
+    assert!(matches!(block_on(dir.create("new", FileType::Directory)), Err(FsError::ReadOnly)));
+    assert!(matches!(block_on(dir.remove("readme.txt")), Err(FsError::ReadOnly)));
+}
+
+/// Returns a writable volume as `image` builds it, and the disk it is on.
+fn writable() -> (FatFs, Arc<RamDisk>) {
+    let disk = Arc::new(RamDisk::from_bytes(SECTOR, image()));
+    (block_on(FatFs::new(disk.clone())).unwrap(), disk)
+}
+
+#[test_case]
+fn files_are_written_across_clusters() {
+    let (fs, _) = writable();
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS);
+    let root = fs.root();
+    let dir = root.as_dir().unwrap();
+    let file = block_on(dir.create("A longer name.dat", FileType::File)).unwrap();
+    let contents: Vec<u8> = (0..1500).map(|i| (i % 239) as u8).collect();
+    assert_eq!(block_on(file.as_file().unwrap().write_at(0, &contents)).unwrap(), 1500);
+    // the free cluster 4 and the two after the taken ones
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS - 3);
+    assert_eq!(block_on(file.as_file().unwrap().write_at(1000, b"patched")).unwrap(), 7);
+    let found = block_on(dir.lookup("a longer NAME.dat")).unwrap();
+    assert_eq!(found.metadata().size, 1500);
+    let patched = [&contents[995..1000], &b"patched"[..], &contents[1007..1010]].concat();
+    assert_eq!(&common::read_all(&found)[995..1010], &patched[..]);
+    let names: Vec<_> = block_on(dir.entries()).unwrap().into_iter().map(|entry| entry.name).collect();
+    assert!(names.iter().any(|name| name == "A longer name.dat"));
+    assert!(matches!(block_on(dir.create("a LONGER name.DAT", FileType::File)), Err(FsError::AlreadyExists)));
+    assert!(matches!(block_on(dir.create("what?", FileType::File)), Err(FsError::InvalidPath)));
+}
+
+#[test_case]
+fn files_are_truncated_and_grown() {
+    let (fs, _) = writable();
+    let root = fs.root();
+    let dir = root.as_dir().unwrap();
+    let file = block_on(dir.create("DATA.BIN", FileType::File)).unwrap();
+    let data = file.as_file().unwrap();
+    block_on(data.write_at(0, &[0xaa; 2000])).unwrap();
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS - 4);
+    block_on(data.set_len(600)).unwrap();
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS - 2);
+    block_on(data.set_len(1000)).unwrap();
+    let contents = common::read_all(&file);
+    assert_eq!((&contents[..600], &contents[600..]), (&[0xaa; 600][..], &[0; 400][..]));
+    // writing past the end leaves zeros in between
+    block_on(data.write_at(1200, b"end")).unwrap();
+    assert_eq!(&common::read_all(&file)[1000..], &[&[0; 200][..], &b"end"[..]].concat()[..]);
+    block_on(data.set_len(0)).unwrap();
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS);
+    assert!(common::read_all(&file).is_empty());
+}
+
+#[test_case]
+fn directories_are_created_and_removed() {
+    let (fs, _) = writable();
+    let root = fs.root();
+    let dir = root.as_dir().unwrap();
+    let nested = block_on(dir.create("Nested", FileType::Directory)).unwrap();
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS - 1);
+    let inner = block_on(nested.as_dir().unwrap().create("inner.txt", FileType::File)).unwrap();
+    block_on(inner.as_file().unwrap().write_at(0, b"inside")).unwrap();
+    let entries = block_on(nested.as_dir().unwrap().entries()).unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["inner.txt"]);
+    assert!(matches!(block_on(dir.remove("nested")), Err(FsError::NotEmpty)));
+    block_on(nested.as_dir().unwrap().remove("INNER.TXT")).unwrap();
+    assert!(matches!(block_on(inner.as_file().unwrap().write_at(0, b"x")), Err(FsError::NotFound)));
+    block_on(dir.remove("nested")).unwrap();
+    assert!(matches!(block_on(dir.lookup("Nested")), Err(FsError::NotFound)));
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS);
+}
+
+#[test_case]
+fn directories_grow_when_full() {
+    let (fs, _) = writable();
+    let root = fs.root();
+    let dir = root.as_dir().unwrap();
+    // the root's one cluster has room for 16 entries, 6 of them taken
+    for i in 0..12 {
+        block_on(dir.create(&format!("FILE{}.TXT", i), FileType::File)).unwrap();
+    }
+    assert_eq!(block_on(fs.free_clusters()), FREE_CLUSTERS - 1);
+    assert_eq!(block_on(dir.entries()).unwrap().len(), 15);
+    assert!(block_on(dir.lookup("file11.txt")).is_ok());
+}
+
+#[test_case]
+fn changes_are_kept_across_mounts() {
+    let (fs, disk) = writable();
+    let root = fs.root();
+    let file = block_on(root.as_dir().unwrap().create("Kept after sync.txt", FileType::File)).unwrap();
+    block_on(file.as_file().unwrap().write_at(0, &hello_contents())).unwrap();
+    block_on(fs.sync()).unwrap();
+    let mut fsinfo = [0; SECTOR];
+    block_on(disk.read_blocks(FSINFO_SECTOR as u64, &mut fsinfo)).unwrap();
+    assert_eq!(fsinfo[488..492], (FREE_CLUSTERS - 2).to_le_bytes());
+
+    let again = block_on(FatFs::new(disk)).unwrap();
+    assert_eq!(block_on(again.free_clusters()), FREE_CLUSTERS - 2);
+    let root = again.root();
+    let found = block_on(root.as_dir().unwrap().lookup("kept after sync.txt")).unwrap();
+    assert_eq!(common::read_all(&found), hello_contents());
+    assert!(found.metadata().modified.is_some());
+}
+
+#[test_case]
+fn other_fat_types_are_refused() {
+    let mut fat16 = image();
+    // a FAT16 BPB has a root directory of fixed size and a FAT size in the 16 bit field
+    fat16[17..19].copy_from_slice(&512u16.to_le_bytes());
+    fat16[22..24].copy_from_slice(&8u16.to_le_bytes());
+    let fs = block_on(FatFs::new(Arc::new(RamDisk::from_bytes(SECTOR, fat16))));
+    assert!(matches!(fs, Err(FsError::Unsupported)));
+    let blank = block_on(FatFs::new(Arc::new(RamDisk::new(SECTOR, 64))));
+    assert!(matches!(blank, Err(FsError::Corrupt(_))));
+    let fs = block_on(FatFs::new(Arc::new(RamDisk::from_bytes(SECTOR, image())))).unwrap();
+    assert_eq!((fs.name(), fs.cluster_size()), ("fat32", 512));
+}
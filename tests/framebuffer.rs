@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::framebuffer::{self, FramebufferError, Rgb};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn drawing_is_clipped_and_double_buffered() {
+    let mut fb = match framebuffer::init_bochs(640, 480) {
+        Ok(fb) => fb,
+        // not every machine has the emulated adapter
+        Err(FramebufferError::NoDevice) => return,
+        Err(err) => panic!("framebuffer initialization failed: {:?}", err),
+    };
+    let red = Rgb::new(0xff, 0, 0);
+    fb.clear(Rgb::BLACK);
+    fb.fill_rect(630, 470, 100, 100, red);
+    assert_eq!(fb.pixel(639, 479), Some(red));
+    assert_eq!(fb.pixel(629, 479), Some(Rgb::BLACK));
+    assert_eq!(fb.pixel(640, 0), None);
+
+    fb.enable_double_buffering().unwrap();
+    fb.blit(0, 0, 2, &[Rgb::WHITE, red, red, Rgb::WHITE]);
+    assert_eq!(fb.pixel(1, 0), Some(red));
+    let front = fb.info();
+    assert_eq!((front.width, front.height), (640, 480));
+    fb.present();
+}
+
+#[test_case]
+fn framebuffer_console_uses_the_whole_screen() {
+    use rust_os::{console::ConsoleBackend, framebuffer::{console::FramebufferConsole, font::Font}};
+    let fb = match framebuffer::init_bochs(640, 480) {
+        Ok(fb) => fb,
+        Err(FramebufferError::NoDevice) => return,
+        Err(err) => panic!("framebuffer initialization failed: {:?}", err),
+    };
+    let console = FramebufferConsole::new(fb, Font::builtin()).expect("framebuffer console failed");
+    assert_eq!(console.dimensions(), (80, 30));
+    for line in 0..40 {
+        console.write_fmt(format_args!("line {}\n", line));
+    }
+}
+
+#[test_case]
+fn console_is_drawn_on_the_framebuffer() {
+    use rust_os::{println, vga_buffer};
+    let fb = match framebuffer::init_bochs(800, 600) {
+        Ok(fb) => fb,
+        Err(FramebufferError::NoDevice) => return,
+        Err(err) => panic!("framebuffer initialization failed: {:?}", err),
+    };
+    vga_buffer::use_framebuffer(fb).expect("framebuffer console failed");
+    println!("printed on the framebuffer");
+    let mut found = false;
+    vga_buffer::for_each_screen_line(|line| found |= line == "printed on the framebuffer");
+    assert!(found);
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::gdt;
+use x86_64::instructions::tables::sgdt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn secondary_tables_are_separate() {
+    let boot_gdt = { sgdt().base };
+    let first = gdt::init_secondary_cpu().tss().interrupt_stack_table;
+    let current_gdt = { sgdt().base };
+    assert_ne!(current_gdt, boot_gdt);
+    let second = gdt::init_secondary_cpu().tss().interrupt_stack_table;
+    let ist = usize::from(gdt::DOUBLE_FAULT_IST_INDEX);
+    assert_ne!(first[ist], second[ist]);
+}
+
+#[test_case]
+fn interrupts_work_on_secondary_tables() {
+    gdt::init_secondary_cpu();
+    x86_64::instructions::interrupts::int3();
+    // the page fault IST stack is heap memory and must be mapped
+    let stacks = gdt::init_secondary_cpu().tss().interrupt_stack_table;
+    let top = stacks[usize::from(gdt::PAGE_FAULT_IST_INDEX)];
+    assert!(rust_os::memory::debug::is_mapped(top - 8u64));
+}
+
+#[test_case]
+fn user_selectors_have_ring_3() {
+    use x86_64::PrivilegeLevel;
+
+    let selectors = gdt::selectors();
+    assert_eq!(selectors.user_code.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(selectors.user_data.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(selectors.kernel_code.rpl(), PrivilegeLevel::Ring0);
+    // sysret loads SS from STAR + 8 and CS from STAR + 16
+    assert_eq!(selectors.user_code.index(), selectors.user_data.index() + 1);
+    assert_eq!(selectors.kernel_data.index(), selectors.kernel_code.index() + 1);
+    let privilege_stack = gdt::init_secondary_cpu().tss().privilege_stack_table;
+    assert!(rust_os::memory::debug::is_mapped(privilege_stack[0] - 8u64));
+}
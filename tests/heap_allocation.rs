@@ -23,6 +23,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -70,4 +71,104 @@ fn many_boxes_long_lived() {
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    use rust_os::allocator::HEAP_SIZE;
+    let big = alloc::vec![0xabu8; 4 * HEAP_SIZE];
+    assert!(big.iter().all(|&b| b == 0xab));
+}
+
+#[test_case]
+fn slab_reuses_freed_slots() {
+    use rust_os::allocator::slab::SlabCache;
+    static CACHE: SlabCache<[u64; 4]> = SlabCache::new();
+
+    let first = CACHE.alloc().expect("slab allocation failed");
+    unsafe { CACHE.free(first) };
+    let second = CACHE.alloc().expect("slab allocation failed");
+    assert_eq!(first, second);
+    unsafe { CACHE.free(second) };
+}
+
+#[test_case]
+fn slab_boxes_span_many_pages() {
+    use alloc::vec::Vec;
+    use rust_os::allocator::slab::{SlabBox, SlabCache};
+    static CACHE: SlabCache<u64> = SlabCache::new();
+
+    let boxes: Vec<SlabBox<u64>> = (0..2000)
+        .map(|i| SlabBox::new_in(i, &CACHE).expect("slab allocation failed"))
+        .collect();
+    for (i, value) in boxes.iter().enumerate() {
+        assert_eq!(**value, i as u64);
+    }
+}
+
+#[test_case]
+fn allocation_stats_track_live_bytes() {
+    use rust_os::allocator::allocation_stats;
+
+    let before = allocation_stats();
+    let value = Box::new([0u8; 48]);
+    let during = allocation_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 48);
+
+    drop(value);
+    let after = allocation_stats();
+    assert_eq!(after.frees, before.frees + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+
+    // the block freed above is reused straight from the 64 byte free list
+    let _again = Box::new([0u8; 48]);
+    let class = allocation_stats().size_classes.iter().find(|c| c.block_size == 64).copied().unwrap();
+    assert!(class.hits > 0);
+}
+
+#[test_case]
+fn dropped_structures_release_memory() {
+    use alloc::vec::Vec;
+    use rust_os::allocator;
+
+    let before = allocator::stats();
+    let large: Vec<Vec<u64>> = (0..64).map(|i| alloc::vec![i; 100]).collect();
+    let during = allocator::stats();
+    assert!(during.used >= before.used + 64 * 100 * 8);
+    assert!(during.peak_used >= during.used);
+
+    drop(large);
+    let after = allocator::stats();
+    assert_eq!(after.used, before.used);
+    assert!(after.largest_free_block <= after.free);
+}
+
+#[test_case]
+fn failed_allocation_runs_reclaimers() {
+    use core::{alloc::Layout, sync::atomic::{AtomicBool, Ordering}};
+    use rust_os::allocator::{self, HEAP_MAX_SIZE};
+    static RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+    assert!(allocator::register_reclaimer(|| {
+        RECLAIMED.store(true, Ordering::SeqCst);
+        0
+    }));
+    let failures = allocator::allocation_stats().failures;
+    let layout = Layout::from_size_align(HEAP_MAX_SIZE, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+    assert!(RECLAIMED.load(Ordering::SeqCst));
+    assert_eq!(allocator::allocation_stats().failures, failures + 1);
+}
+
+#[test_case]
+fn slab_shrink_frees_empty_pages() {
+    use rust_os::allocator::slab::SlabCache;
+    static CACHE: SlabCache<[u64; 8]> = SlabCache::new();
+
+    let slot = CACHE.alloc().expect("slab allocation failed");
+    assert_eq!(CACHE.shrink(), 0);
+    unsafe { CACHE.free(slot) };
+    assert_eq!(CACHE.shrink(), 4096);
+}
@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::task::keyboard::{
+    hotkey::{self, HotkeyTaken},
+    Hotkey,
+    KeyCode,
+    KeyEvent,
+    KeyState,
+    ModifierState,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn event(code: KeyCode, state: KeyState, modifiers: ModifierState) -> KeyEvent {
+    KeyEvent { code, state, unicode: None, modifiers, repeat: false }
+}
+
+const CTRL: ModifierState = ModifierState {
+    shift: false,
+    ctrl: true,
+    alt: false,
+    alt_gr: false,
+    caps_lock: false,
+    num_lock: false,
+    scroll_lock: false,
+};
+
+#[test_case]
+fn registered_hotkeys_run_on_presses() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let id = hotkey::register(Hotkey::new(KeyCode::F9).with_ctrl(), || {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    })
+    .expect("hotkey taken");
+
+    assert!(hotkey::dispatch(&event(KeyCode::F9, KeyState::Down, CTRL)));
+    // releases, other modifiers and other keys pass through
+    assert!(!hotkey::dispatch(&event(KeyCode::F9, KeyState::Up, CTRL)));
+    assert!(!hotkey::dispatch(&event(KeyCode::F9, KeyState::Down, ModifierState::default())));
+    assert!(!hotkey::dispatch(&event(KeyCode::F10, KeyState::Down, CTRL)));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+    assert!(hotkey::unregister(id));
+    assert!(!hotkey::dispatch(&event(KeyCode::F9, KeyState::Down, CTRL)));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert!(!hotkey::unregister(id));
+}
+
+#[test_case]
+fn taken_hotkeys_are_rejected() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let key = Hotkey::new(KeyCode::F11).with_ctrl();
+    let first = hotkey::register(key, || {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    })
+    .expect("hotkey taken");
+    assert_eq!(hotkey::register(key, || panic!("second handler ran")), Err(HotkeyTaken(first)));
+    // the same key with other modifiers is another hotkey
+    let shifted = hotkey::register(key.with_shift(), || {}).expect("hotkey taken");
+
+    assert!(hotkey::dispatch(&event(KeyCode::F11, KeyState::Down, CTRL)));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert!(hotkey::unregister(first));
+    assert!(hotkey::unregister(shifted));
+    assert_eq!(hotkey::register(key, || {}).map(hotkey::unregister), Ok(true));
+}
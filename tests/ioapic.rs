@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    acpi::{Fadt, Madt},
+    interrupts::{apic, ioapic::{self, Polarity, Route, TriggerMode}, irq},
+    memory,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("local APIC initialization failed");
+    ioapic::init().expect("I/O APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_processors_and_io_apic() {
+    let madt = Madt::parse().expect("failed to parse MADT");
+    assert!(!madt.io_apics.is_empty());
+    let bsp = apic::local_apic().unwrap().id();
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled && cpu.apic_id == bsp));
+}
+
+#[test_case]
+fn fadt_has_power_management_registers() {
+    let fadt = Fadt::parse().expect("failed to parse FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert!(fadt.sci_interrupt < 16);
+}
+
+#[test_case]
+fn keyboard_is_routed() {
+    let (gsi, _, _) = ioapic::isa_irq_to_gsi(irq::KEYBOARD).unwrap();
+    let route = ioapic::route_of(gsi).unwrap();
+    assert_eq!(route.vector, irq::vector(irq::KEYBOARD));
+    assert!(!route.masked);
+}
+
+#[test_case]
+fn routes_round_trip() {
+    let (gsi, _, _) = ioapic::isa_irq_to_gsi(irq::COM1).unwrap();
+    let route = Route {
+        vector: 0x60,
+        polarity: Polarity::ActiveLow,
+        trigger: TriggerMode::Level,
+        destination: apic::local_apic().unwrap().id() as u8,
+        masked: true,
+    };
+    ioapic::route_gsi(gsi, route).unwrap();
+    assert_eq!(ioapic::route_of(gsi).unwrap(), route);
+    ioapic::set_masked(gsi, true).unwrap();
+    assert!(ioapic::route_of(gsi).unwrap().masked);
+}
+
+#[test_case]
+fn unknown_gsi_is_rejected() {
+    assert!(ioapic::route_of(100_000).is_err());
+}
+
+#[test_case]
+fn registered_irqs_are_routed() {
+    fn rtc_handler() {}
+    let (gsi, _, _) = ioapic::isa_irq_to_gsi(irq::RTC).unwrap();
+    irq::register_irq(irq::RTC, rtc_handler).expect("failed to register RTC handler");
+    let route = ioapic::route_of(gsi).unwrap();
+    assert_eq!(route.vector, irq::vector(irq::RTC));
+    assert!(!route.masked);
+    irq::unregister_irq(irq::RTC, rtc_handler).unwrap();
+    assert!(ioapic::route_of(gsi).unwrap().masked);
+}
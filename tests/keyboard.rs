@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use futures_util::stream::{self, StreamExt};
+use rust_os::task::{
+    block_on,
+    keyboard::{KeyCode, KeyEvent, KeyRepeat, KeyState, ModifierState, Typematic},
+    work,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    // expired sleeps are woken by deferred work, which `block_on` runs
+    work::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn key_repeat_synthesizes_presses_of_held_keys() {
+    let press = KeyEvent {
+        code: KeyCode::A,
+        state: KeyState::Down,
+        unicode: Some('a'),
+        modifiers: ModifierState::default(),
+        repeat: false,
+    };
+    let hardware_repeat = KeyEvent { repeat: true, ..press };
+    let typematic = Typematic { delay: Duration::from_millis(2), period: Duration::from_millis(1) };
+    let events = stream::iter([press, hardware_repeat]).chain(stream::pending());
+    let repeats = block_on(KeyRepeat::new(events, typematic).take(3).map(|event| event.repeat).collect::<Vec<_>>());
+    assert_eq!(repeats, [false, true, true]);
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use rust_os::serial_print;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use rust_os::{exit_qemu, println, QemuExitCode, serial_println};
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_nmi_handler)
+                .set_stack_index(rust_os::gdt::NMI_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+/// Like the kernel's NMI handler, but exits instead of halting once the dump is written.
+extern "x86-interrupt" fn test_nmi_handler(stack_frame: InterruptStackFrame) {
+    // the serial lock is held, so the dump must not need it
+    let serial = rust_os::serial::SERIAL1.lock();
+    rust_os::interrupts::nmi::dump_machine_state("TEST NMI", &stack_frame);
+    drop(serial);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("nmi::nmi_dumps_machine_state...\t");
+
+    rust_os::gdt::init();
+    init_test_idt();
+
+    println!("last line before the NMI");
+    unsafe { core::arch::asm!("int 2") };
+
+    panic!("Execution continued after NMI");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
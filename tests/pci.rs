@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use rust_os::{
+    interrupts::{apic, vector},
+    memory,
+    pci::{
+        self,
+        driver::{self, Driver, Match, ProbeError},
+        msi, PciAddress, PciDevice,
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("local APIC initialization failed");
+    // machines without an MCFG keep using the configuration ports
+    let _ = pci::ecam::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn host_bridge_is_enumerated() {
+    let devices = pci::enumerate();
+    let host_bridge = PciAddress::new(0, 0, 0);
+    assert!(devices.contains(&host_bridge));
+    assert_eq!(host_bridge.class().0, 0x06);
+}
+
+#[test_case]
+fn host_bridge_is_found_by_class() {
+    let host_bridge = pci::find_by_class(0x06, 0x00)
+        .find(|device| device.address == PciAddress::new(0, 0, 0))
+        .expect("no host bridge");
+    assert_ne!(host_bridge.vendor_id, 0xffff);
+    assert!(pci::devices().all(|device| device.vendor_id != 0xffff));
+}
+
+static HOST_BRIDGE_PROBES: AtomicUsize = AtomicUsize::new(0);
+
+fn probe_host_bridge(device: &PciDevice) -> Result<(), ProbeError> {
+    HOST_BRIDGE_PROBES.fetch_add(1, Ordering::Relaxed);
+    if device.address == PciAddress::new(0, 0, 0) {
+        Ok(())
+    } else {
+        Err(ProbeError::Unsupported)
+    }
+}
+
+static DECLINING: Driver = Driver {
+    name: "declining",
+    matches: &[Match::Class { class: 0x06, subclass: 0x00 }],
+    probe: |_| Err(ProbeError::Unsupported),
+};
+
+static HOST_BRIDGE: Driver = Driver {
+    name: "host-bridge",
+    matches: &[Match::Class { class: 0x06, subclass: 0x00 }],
+    probe: probe_host_bridge,
+};
+
+#[test_case]
+fn drivers_are_bound_once() {
+    let host_bridge = PciAddress::new(0, 0, 0);
+    driver::register(&DECLINING);
+    driver::probe_all();
+    assert_eq!(driver::bound_driver(host_bridge), None);
+
+    driver::register(&HOST_BRIDGE);
+    assert_eq!(driver::bound_driver(host_bridge), Some("host-bridge"));
+    let probes = HOST_BRIDGE_PROBES.load(Ordering::Relaxed);
+    driver::probe_all();
+    assert_eq!(driver::bindings().iter().filter(|(_, name)| *name == Some("host-bridge")).count(), 1);
+    let reprobed = HOST_BRIDGE_PROBES.load(Ordering::Relaxed) - probes;
+    assert_eq!(reprobed, pci::find_by_class(0x06, 0x00).count() - 1, "bound function probed again");
+}
+
+#[test_case]
+fn bars_past_the_sixth_are_none() {
+    for device in pci::enumerate() {
+        assert_eq!((device.bar(6), device.bar(7)), (None, None));
+    }
+}
+
+#[test_case]
+fn capability_lists_are_walkable() {
+    for device in pci::enumerate() {
+        for (_, offset) in device.capabilities() {
+            assert!(offset >= 0x40, "capability of {} inside the header", device);
+        }
+    }
+}
+
+#[test_case]
+fn extended_configuration_space_is_reachable_with_ecam() {
+    let host_bridge = PciAddress::new(0, 0, 0);
+    if !pci::ecam::is_enabled() {
+        assert_eq!(host_bridge.read_u32(0x100), u32::MAX);
+        assert_eq!(host_bridge.extended_capabilities().count(), 0);
+        return;
+    }
+    for device in pci::enumerate() {
+        for (_, offset) in device.extended_capabilities() {
+            assert!(offset >= 0x100, "extended capability of {} in the legacy part", device);
+        }
+    }
+}
+
+#[test_case]
+fn msi_devices_get_dynamic_vectors() {
+    for device in pci::enumerate() {
+        if device.find_capability(msi::CAPABILITY_MSI).is_none() {
+            continue;
+        }
+        let vector = msi::enable_msi(device, || {}).expect("failed to enable MSI");
+        assert!((vector::FIRST_DYNAMIC..vector::END_DYNAMIC).contains(&vector));
+        assert!(vector::is_allocated(vector));
+        msi::disable_msi(device, vector);
+        assert!(!vector::is_allocated(vector));
+    }
+}
+
+#[test_case]
+fn vectors_run_out_and_come_back() {
+    let mut vectors = alloc::vec::Vec::new();
+    while let Some(vector) = vector::allocate(|| {}) {
+        vectors.push(vector);
+    }
+    assert!(!vectors.is_empty());
+    for vector in &vectors {
+        vector::free(*vector);
+    }
+    assert!(vector::allocate(|| {}).map(vector::free).is_some());
+}
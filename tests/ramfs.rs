@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    memory,
+    task::block_on,
+    vfs::{self, ramfs::RamFs, FileType, FsError, SeekFrom},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Mounts one RAM filesystem at `/` and another at `/tmp`, as the kernel does.
+fn mount() {
+    if common::mount_root_once(|| Arc::new(RamFs::new())) {
+        block_on(vfs::create_dir("/tmp")).unwrap();
+        block_on(vfs::mount("/tmp", Arc::new(RamFs::new()))).unwrap();
+    }
+}
+
+fn names(path: &str) -> Vec<String> {
+    block_on(vfs::read_dir(path)).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+fn contents(path: &str) -> Vec<u8> {
+    block_on(block_on(vfs::open(path)).unwrap().read_to_end()).unwrap()
+}
+
+#[test_case]
+fn files_are_written_and_read() {
+    mount();
+    let mut file = block_on(vfs::create("/tmp/notes.txt")).unwrap();
+    block_on(file.write_all(b"hello, ")).unwrap();
+    block_on(file.write_all(b"world")).unwrap();
+    assert_eq!(contents("/tmp/notes.txt"), b"hello, world");
+    file.seek(SeekFrom::Start(20));
+    block_on(file.write_all(b"!")).unwrap();
+    assert_eq!(contents("/tmp/notes.txt"), b"hello, world\0\0\0\0\0\0\0\0!");
+    block_on(file.set_len(5)).unwrap();
+    assert_eq!(contents("/tmp/notes.txt"), b"hello");
+    // creating it again empties it
+    block_on(vfs::create("/tmp/notes.txt")).unwrap();
+    assert!(contents("/tmp/notes.txt").is_empty());
+    assert!(block_on(vfs::metadata("/tmp/notes.txt")).unwrap().modified.is_some());
+}
+
+#[test_case]
+fn directories_are_created_and_removed() {
+    mount();
+    block_on(vfs::create_dir("/a")).unwrap();
+    block_on(vfs::create_dir("/a/b")).unwrap();
+    block_on(vfs::create("/a/b/c.txt")).unwrap();
+    assert_eq!(names("/a/b"), ["c.txt"]);
+    assert_eq!(block_on(vfs::metadata("/a")).unwrap().file_type, FileType::Directory);
+    assert!(matches!(block_on(vfs::create_dir("/a/b")), Err(FsError::AlreadyExists)));
+    assert!(matches!(block_on(vfs::remove("/a")), Err(FsError::NotEmpty)));
+    assert!(matches!(block_on(vfs::create("/a/b/c.txt/d")), Err(FsError::NotADirectory)));
+    block_on(vfs::remove("/a/b/c.txt")).unwrap();
+    block_on(vfs::remove("/a/b")).unwrap();
+    block_on(vfs::remove("/a")).unwrap();
+    assert!(matches!(block_on(vfs::metadata("/a")), Err(FsError::NotFound)));
+    assert!(matches!(block_on(vfs::remove("/tmp")), Err(FsError::Busy)));
+}
+
+#[test_case]
+fn removed_files_stay_open() {
+    mount();
+    let mut file = block_on(vfs::create("/tmp/unlinked")).unwrap();
+    block_on(file.write_all(b"still here")).unwrap();
+    block_on(vfs::remove("/tmp/unlinked")).unwrap();
+    assert!(matches!(block_on(vfs::open("/tmp/unlinked")), Err(FsError::NotFound)));
+    file.seek(SeekFrom::Start(0));
+    assert_eq!(block_on(file.read_to_end()).unwrap(), b"still here");
+}
+
+#[test_case]
+fn entries_are_renamed() {
+    mount();
+    block_on(vfs::create_dir("/src")).unwrap();
+    block_on(vfs::create_dir("/dst")).unwrap();
+    block_on(block_on(vfs::create("/src/one")).unwrap().write_all(b"1")).unwrap();
+    block_on(block_on(vfs::create("/src/two")).unwrap().write_all(b"2")).unwrap();
+    block_on(vfs::rename("/src/one", "/src/first")).unwrap();
+    assert_eq!(names("/src"), ["first", "two"]);
+    // replacing a file
+    block_on(vfs::rename("/src/two", "/src/first")).unwrap();
+    assert_eq!((names("/src"), contents("/src/first")), (["first".to_string()].to_vec(), b"2".to_vec()));
+    // across directories, and a whole directory
+    block_on(vfs::rename("/src/first", "/dst/moved")).unwrap();
+    block_on(vfs::rename("/dst", "/src/dst")).unwrap();
+    assert_eq!(contents("/src/dst/moved"), b"2");
+    assert!(names("/").iter().all(|name| name != "dst"));
+
+    assert!(matches!(block_on(vfs::rename("/src", "/src/dst/inside")), Err(FsError::InvalidPath)));
+    assert!(matches!(block_on(vfs::rename("/src/dst/moved", "/src")), Err(FsError::NotEmpty)));
+    assert!(matches!(block_on(vfs::rename("/src/dst/moved", "/src/dst")), Err(FsError::NotEmpty)));
+    assert!(matches!(block_on(vfs::rename("/src/dst/moved", "/tmp/moved")), Err(FsError::CrossDevice)));
+    assert!(matches!(block_on(vfs::rename("/tmp", "/elsewhere")), Err(FsError::Busy)));
+    assert!(matches!(block_on(vfs::rename("/src/missing", "/src/found")), Err(FsError::NotFound)));
+    block_on(vfs::create_dir("/src/empty")).unwrap();
+    assert!(matches!(block_on(vfs::rename("/src/dst/moved", "/src/empty")), Err(FsError::IsADirectory)));
+    block_on(vfs::rename("/src/dst", "/src/empty")).unwrap();
+    assert_eq!(names("/src"), ["empty"]);
+    assert_eq!(contents("/src/empty/moved"), b"2");
+}
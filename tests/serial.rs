@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::StreamExt;
+use rust_os::{
+    serial::{self, SerialStream, SERIAL1},
+    task::block_on,
+};
+use x86_64::instructions::hlt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Sends `bytes` to COM1 itself and waits until its interrupt handler dropped `dropped` of them.
+///
+/// Nothing else may print meanwhile, since that would be received too.
+fn receive(bytes: &[u8], dropped: u64) {
+    let before = serial::dropped_bytes();
+    SERIAL1.lock().set_loopback(true);
+    for &byte in bytes {
+        SERIAL1.lock().send(byte);
+    }
+    while serial::dropped_bytes() < before + dropped {
+        hlt();
+    }
+    SERIAL1.lock().set_loopback(false);
+}
+
+/// The stream can only be created once, so this is the one test.
+#[test_case]
+fn received_bytes_are_queued_or_counted() {
+    let mut stream = SerialStream::with_capacity(2);
+    let before = serial::dropped_bytes();
+    receive(b"abc", 1);
+    assert_eq!(block_on(stream.next()), Some(b'a'));
+    assert_eq!(block_on(stream.next()), Some(b'b'));
+    assert_eq!(serial::dropped_bytes(), before + 1);
+
+    // without a stream nothing is queued
+    drop(stream);
+    receive(b"d", 1);
+    assert_eq!(serial::dropped_bytes(), before + 2);
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    block::ram::RamDisk,
+    memory::{
+        swap::{self, BlockSwap, SwapDevice, SLOT_SIZE},
+        vmm::{self, RegionKind},
+    },
+};
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    // small blocks, so that every slot spans several of them
+    let disk = BlockSwap::new(Arc::new(RamDisk::new(512, 64 * SLOT_SIZE / 512))).expect("RAM disk refused for swap");
+    swap::enable(Box::new(disk));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn swapped_pages_fault_back_in() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::allocate_swappable(8 * 4096, RegionKind::Anonymous, flags)
+        .expect("failed to reserve swappable region");
+    for page in region.pages() {
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe { ptr.write_volatile(page.start_address().as_u64()) };
+    }
+
+    assert_eq!(swap::swap_out(8), Ok(8));
+    assert_eq!(swap::used_slots(), 8);
+
+    for page in region.pages() {
+        let ptr: *const u64 = page.start_address().as_ptr();
+        assert_eq!(unsafe { ptr.read_volatile() }, page.start_address().as_u64());
+    }
+    assert_eq!(swap::used_slots(), 0);
+}
+
+#[test_case]
+fn swap_out_stops_when_nothing_is_resident() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::allocate_swappable(2 * 4096, RegionKind::Anonymous, flags)
+        .expect("failed to reserve swappable region");
+    let ptr: *mut u64 = region.start().as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+
+    let swapped = swap::swap_out(usize::MAX).expect("swap out failed");
+    assert!(swapped >= 1);
+    assert_eq!(swap::swap_out(1), Ok(0));
+    assert_eq!(unsafe { ptr.read_volatile() }, 1);
+}
+
+#[test_case]
+fn dropping_a_mapping_frees_its_swap_slots() {
+    let mut mapping = rust_os::vm::map_anonymous_swappable(2 * 4096).expect("failed to map anonymous memory");
+    mapping.as_mut_slice().fill(3);
+    swap::swap_out(usize::MAX).expect("swap out failed");
+
+    let used = swap::used_slots();
+    drop(mapping);
+    assert_eq!(swap::used_slots(), used - 2);
+}
+
+#[test_case]
+fn block_swap_counts_whole_slots() {
+    let disk = BlockSwap::new(Arc::new(RamDisk::new(512, 3 * SLOT_SIZE / 512 + 7))).expect("RAM disk refused");
+    assert_eq!(disk.slot_count(), 3);
+}
+
+#[test_case]
+fn block_swap_refuses_unusable_devices() {
+    assert!(BlockSwap::new(Arc::new(RamDisk::new(512, 64).read_only())).is_none());
+    assert!(BlockSwap::new(Arc::new(RamDisk::new(2 * SLOT_SIZE, 4))).is_none());
+}
+
+#[test_case]
+fn block_swap_round_trips_a_slot() {
+    let mut disk = BlockSwap::new(Arc::new(RamDisk::new(512, 2 * SLOT_SIZE / 512))).expect("RAM disk refused");
+    let mut written = [0; SLOT_SIZE];
+    written.iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+    disk.write_slot(1, &written).expect("write failed");
+
+    let mut read = [0xff; SLOT_SIZE];
+    disk.read_slot(0, &mut read).expect("read failed");
+    assert!(read.iter().all(|&byte| byte == 0));
+    disk.read_slot(1, &mut read).expect("read failed");
+    assert_eq!(read, written);
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use rust_os::task::{thread, work};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    work::init();
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawned_thread_runs_and_exits() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let before = thread::count();
+    let handle = thread::spawn("runs once", || RAN.store(true, Ordering::SeqCst)).unwrap();
+    assert_ne!(Some(handle.id()), thread::current_id());
+    while !RAN.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    // let the thread finish exiting, then free it
+    thread::yield_now();
+    work::run_pending();
+    assert_eq!(thread::count(), before);
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static SPINS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    thread::spawn("spinner", || {
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS.fetch_add(1, Ordering::SeqCst);
+        }
+    })
+    .unwrap();
+    // never yields, so the spinner only runs if the timer takes the CPU away from this thread
+    while SPINS.load(Ordering::SeqCst) == 0 {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    // and it is preempted in turn, or this thread would never get here
+    assert_eq!(thread::current_name(), Some("main"));
+}
+
+#[test_case]
+fn join_returns_thread_result() {
+    let handle = thread::spawn("adder", || (1..=10u64).sum::<u64>()).unwrap();
+    assert_eq!(handle.join(), 55);
+}
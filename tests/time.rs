@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rust_os::{
+    interrupts::{apic, ioapic},
+    memory,
+    time::{self, hpet, tsc},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("local APIC initialization failed");
+    ioapic::init().expect("I/O APIC initialization failed");
+    time::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn best_clock_is_chosen() {
+    let expected = if tsc::tsc().is_some() {
+        tsc::NAME
+    } else if hpet::hpet().is_some() {
+        hpet::NAME
+    } else {
+        "pit"
+    };
+    assert_eq!(time::clock().name(), expected);
+}
+
+#[test_case]
+fn now_continues_from_uptime() {
+    let uptime_ns = time::uptime_ms() * 1_000_000;
+    let now = time::now();
+    // the tick clock lags by up to a tick, and the calibration of the chosen clock is not exact
+    let tolerance = 2_000_000 + uptime_ns / 100;
+    assert!(now + tolerance >= uptime_ns, "now {} ns, uptime {} ns", now, uptime_ns);
+    assert!(now <= uptime_ns + tolerance, "now {} ns, uptime {} ns", now, uptime_ns);
+}
+
+#[test_case]
+fn tsc_frequency_is_plausible() {
+    if let Some(tsc) = tsc::tsc() {
+        assert!(tsc.hz() > 100_000_000, "TSC runs at {} Hz", tsc.hz());
+    }
+}
+
+#[test_case]
+fn clock_advances() {
+    let start = time::now();
+    while time::now() == start {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::now() > start);
+}
+
+#[test_case]
+fn oneshot_event_fires() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    let start = time::now();
+    time::after(1_000_000, || FIRED.store(true, Ordering::SeqCst));
+    while !FIRED.load(Ordering::SeqCst) {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::now() - start >= 1_000_000);
+    assert!(!time::cancel());
+}
+
+#[test_case]
+fn cancelled_event_does_not_fire() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    time::after(1_000_000, || FIRED.store(true, Ordering::SeqCst));
+    assert!(time::cancel());
+    let start = time::now();
+    while time::now() - start < 2_000_000 {
+        x86_64::instructions::hlt();
+    }
+    assert!(!FIRED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn uptime_follows_ticks() {
+    let start_ticks = time::ticks();
+    let start_ms = time::uptime_ms();
+    while time::ticks() < start_ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed_ms = time::uptime_ms() - start_ms;
+    assert!((9..=11).contains(&elapsed_ms), "10 ticks took {} ms", elapsed_ms);
+}
+
+#[test_case]
+fn idle_stops_the_periodic_timer_until_the_deadline() {
+    use time::tickless;
+    use x86_64::instructions::interrupts;
+
+    // no thread scheduler here, so only the clock decides whether the timer can be stopped
+    if time::clock().name() == "pit" {
+        return;
+    }
+    fn fired() {}
+    time::after(20_000_000, fired);
+    let start_ticks = time::ticks();
+    let start = time::now();
+    interrupts::disable();
+    assert!(tickless::enter_idle());
+    assert!(tickless::is_stopped());
+    interrupts::enable_and_hlt();
+    tickless::exit_idle();
+    assert!(!tickless::is_stopped());
+    // stopped until the deadline, and the ticks that were skipped are still counted
+    let elapsed = time::now() - start;
+    assert!(elapsed >= 19_000_000, "woke up after {} ns", elapsed);
+    assert!(time::ticks() - start_ticks >= 18);
+    assert!(tickless::skipped_ticks() > 0);
+}
+
+#[test_case]
+fn delay_waits_at_least_as_long() {
+    use core::time::Duration;
+
+    let start = time::Instant::now();
+    time::delay_us(time::MAX_DELAY_US);
+    // the tick clock only advances once per millisecond
+    let tolerance = Duration::from_nanos(time::clock().resolution_ns());
+    assert!(start.elapsed() + tolerance >= Duration::from_micros(time::MAX_DELAY_US.into()));
+}
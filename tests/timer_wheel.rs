@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::task::timer::wheel::{Timer, TimerWheel};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn timers_expire_in_order() {
+    let mut wheel = TimerWheel::new(100);
+    for when in [5_000u64, 101, 170, 100_000, 163, 1 << 40] {
+        assert!(wheel.insert(Timer::new(when, when)).is_ok());
+    }
+    assert_eq!(wheel.insert(Timer::new(100, 100)).map_err(Timer::into_value), Err(100));
+    assert_eq!(wheel.next_expiration(), Some(101));
+
+    let expired: Vec<u64> = wheel.advance(10_000).collect();
+    assert_eq!(expired, [101, 163, 170, 5_000]);
+    assert_eq!(wheel.len(), 2);
+    assert_eq!(wheel.elapsed(), 10_000);
+}
+
+#[test_case]
+fn far_timers_move_down_and_expire_on_time() {
+    let mut wheel = TimerWheel::new(0);
+    assert!(wheel.insert(Timer::new(1 << 40, ())).is_ok());
+    let mut fired_at = None;
+    // follow the expirations like the timer interrupt does
+    while let Some(tick) = wheel.next_expiration() {
+        if wheel.advance(tick).count() > 0 {
+            fired_at = Some(tick);
+        }
+    }
+    assert_eq!(fired_at, Some(1 << 40));
+    assert!(wheel.is_empty());
+}
+
+#[test_case]
+fn cancelled_timers_leave_the_wheel() {
+    let mut wheel = TimerWheel::new(0);
+    let ids: Vec<_> = [3u64, 3, 700, 1 << 40]
+        .iter()
+        .map(|&when| wheel.insert(Timer::new(when, when)).ok().expect("timer in the past"))
+        .collect();
+    assert_eq!(wheel.cancel(ids[1]).map(Timer::into_value), Some(3));
+    assert!(wheel.cancel(ids[1]).is_none());
+    // the timer at 700 moves down a level and is found there
+    assert_eq!(wheel.advance(650).collect::<Vec<_>>(), [3]);
+    assert!(wheel.cancel(ids[0]).is_none());
+    assert_eq!(wheel.cancel(ids[2]).map(Timer::into_value), Some(700));
+    assert_eq!(wheel.cancel(ids[3]).map(Timer::into_value), Some(1 << 40));
+    assert!(wheel.is_empty());
+    assert_eq!(wheel.next_expiration(), None);
+}
+
+#[test_case]
+fn timers_move_and_expire_without_allocating() {
+    use rust_os::allocator;
+
+    let mut wheel = TimerWheel::new(0);
+    for when in 1..=5_000u64 {
+        assert!(wheel.insert(Timer::new(when * 97, ())).is_ok());
+    }
+    let allocations = allocator::allocation_stats().allocations;
+    let mut expired = 0;
+    while let Some(tick) = wheel.next_expiration() {
+        expired += wheel.advance(tick).count();
+    }
+    assert_eq!(expired, 5_000);
+    assert_eq!(allocator::allocation_stats().allocations, allocations);
+}
@@ -0,0 +1,183 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::ToString, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{
+    memory,
+    task::block_on,
+    vfs::{self, path, Dir, DirEntry, File, FileSystem, FileType, FsError, FsFuture, Inode, Metadata, SeekFrom},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// A read-only filesystem of a directory `sub` and a file `hello.txt` that names the filesystem.
+struct TinyFs {
+    id: u64,
+}
+
+struct TinyDir {
+    id: u64,
+    root: bool,
+}
+
+struct TinyFile {
+    contents: &'static [u8],
+}
+
+impl FileSystem for TinyFs {
+    fn name(&self) -> &'static str {
+        "tiny"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(TinyDir { id: self.id, root: true })
+    }
+}
+
+impl Inode for TinyDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::Directory, size: 0, id: self.id, modified: None }
+    }
+
+    fn as_dir(&self) -> Option<&dyn Dir> {
+        Some(self)
+    }
+}
+
+impl Dir for TinyDir {
+    fn lookup<'a>(&'a self, name: &'a str) -> FsFuture<'a, Arc<dyn Inode>> {
+        Box::pin(async move {
+            let contents: &'static [u8] = if self.id == 1 { b"first" } else { b"second" };
+            match name {
+                "hello.txt" => Ok(Arc::new(TinyFile { contents }) as Arc<dyn Inode>),
+                "sub" if self.root => Ok(Arc::new(TinyDir { id: self.id, root: false }) as Arc<dyn Inode>),
+                _ => Err(FsError::NotFound),
+            }
+        })
+    }
+
+    fn entries(&self) -> FsFuture<'_, Vec<DirEntry>> {
+        Box::pin(async move {
+            let mut entries = vec![DirEntry { name: "hello.txt".to_string(), file_type: FileType::File }];
+            if self.root {
+                entries.push(DirEntry { name: "sub".to_string(), file_type: FileType::Directory });
+            }
+            Ok(entries)
+        })
+    }
+}
+
+impl Inode for TinyFile {
+    fn metadata(&self) -> Metadata {
+        Metadata { file_type: FileType::File, size: self.contents.len() as u64, id: 0, modified: None }
+    }
+
+    fn as_file(&self) -> Option<&dyn File> {
+        Some(self)
+    }
+}
+
+impl File for TinyFile {
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        Box::pin(async move {
+            let rest = self.contents.get(offset as usize..).unwrap_or(&[]);
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        })
+    }
+}
+
+fn assert_send<T: Send>(_: &T) {}
+
+/// Mounts one filesystem at `/` and another on its `sub`, which every test starts from.
+fn mount_both() {
+    if vfs::mounts().is_empty() {
+        block_on(vfs::mount("/", Arc::new(TinyFs { id: 1 }))).unwrap();
+        block_on(vfs::mount("/sub", Arc::new(TinyFs { id: 2 }))).unwrap();
+    }
+}
+
+#[test_case]
+fn paths_are_normalized() {
+    assert_eq!(path::normalize("/mnt//fat/./a/../hello.txt").unwrap(), "/mnt/fat/hello.txt");
+    assert_eq!(path::normalize("/..").unwrap(), "/");
+    assert!(matches!(path::normalize("relative"), Err(FsError::InvalidPath)));
+}
+
+#[test_case]
+fn files_are_found_through_mounts() {
+    mount_both();
+    let mut file = block_on(vfs::open("/hello.txt")).unwrap();
+    assert_eq!(block_on(file.read_to_end()).unwrap(), b"first");
+    let mut mounted = block_on(vfs::open("/sub/hello.txt")).unwrap();
+    assert_eq!(block_on(mounted.read_to_end()).unwrap(), b"second");
+    let mut back = block_on(vfs::open("/sub/../hello.txt")).unwrap();
+    assert_eq!(block_on(back.read_to_end()).unwrap(), b"first");
+    assert!(matches!(block_on(vfs::open("/missing")), Err(FsError::NotFound)));
+    assert!(matches!(block_on(vfs::open("/sub")), Err(FsError::IsADirectory)));
+    assert!(matches!(block_on(vfs::open("/hello.txt/x")), Err(FsError::NotADirectory)));
+}
+
+#[test_case]
+fn open_files_read_from_their_position() {
+    mount_both();
+    let mut file = block_on(vfs::open("/hello.txt")).unwrap();
+    let mut buf = [0; 3];
+    assert_eq!(block_on(file.read(&mut buf)).unwrap(), 3);
+    assert_eq!(&buf, b"fir");
+    assert_eq!(file.seek(SeekFrom::End(-2)), 3);
+    assert_eq!(block_on(file.read(&mut buf)).unwrap(), 2);
+    assert_eq!(&buf[..2], b"st");
+    assert_eq!(block_on(file.read(&mut buf)).unwrap(), 0);
+    assert!(matches!(block_on(file.write(b"x")), Err(FsError::ReadOnly)));
+}
+
+#[test_case]
+fn directories_are_listed_and_mounts_are_kept() {
+    mount_both();
+    let names: Vec<_> = block_on(vfs::read_dir("/")).unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["hello.txt", "sub"]);
+    assert!(matches!(block_on(vfs::mount("/sub", Arc::new(TinyFs { id: 3 }))), Err(FsError::Busy)));
+    assert!(matches!(block_on(vfs::mount("/hello.txt", Arc::new(TinyFs { id: 3 }))), Err(FsError::NotADirectory)));
+    assert!(matches!(block_on(vfs::unmount("/")), Err(FsError::Busy)));
+    assert!(matches!(block_on(vfs::remove("/sub")), Err(FsError::Busy)));
+    assert!(matches!(block_on(vfs::create("/new.txt")), Err(FsError::ReadOnly)));
+    assert_eq!(vfs::mounts(), [("/".to_string(), "tiny"), ("/sub".to_string(), "tiny")]);
+}
+
+#[test_case]
+fn vfs_futures_can_be_spawned() {
+    assert_send(&vfs::open("/hello.txt"));
+    assert_send(&vfs::mount("/", Arc::new(TinyFs { id: 1 })));
+    assert_send(&vfs::remove("/hello.txt"));
+}